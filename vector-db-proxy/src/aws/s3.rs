@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::config::{Builder, Credentials, Region};
+use aws_sdk_s3::Client;
+use tokio::sync::OnceCell;
+
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::utils::file_operations::DownloadWriter;
+
+// Building a client resolves credentials and endpoints so it is done once and shared
+static S3_CLIENT: OnceCell<Client> = OnceCell::const_new();
+
+async fn build_client() -> Client {
+    let global_data = GLOBAL_DATA.read().await;
+    let mut loader = aws_config::defaults(BehaviorVersion::latest())
+        .region(Region::new(global_data.s3_region.clone()));
+    // without explicit keys the usual AWS credential chain is used (environment, profile, instance role)
+    if !global_data.s3_access_key_id.is_empty() {
+        loader = loader.credentials_provider(Credentials::new(
+            global_data.s3_access_key_id.clone(),
+            global_data.s3_secret_access_key.clone(),
+            None,
+            None,
+            "vector-db-proxy",
+        ));
+    }
+    if !global_data.s3_endpoint.is_empty() {
+        loader = loader.endpoint_url(global_data.s3_endpoint.clone());
+    }
+    let sdk_config = loader.load().await;
+    // MinIO and most other self-hosted stores only support path style addressing
+    let config = Builder::from(&sdk_config)
+        .force_path_style(global_data.s3_force_path_style)
+        .build();
+    Client::from_conf(config)
+}
+
+pub async fn s3_client() -> &'static Client {
+    S3_CLIENT.get_or_init(build_client).await
+}
+
+///
+///
+/// # Arguments
+///
+/// * `bucket`: The bucket holding the object, the configured `S3_BUCKET` is used when this is empty
+/// * `key`: The key of the object
+/// * `destination`: The file the object is written to
+///
+/// The object is written to disk as it is received rather than being held in memory, and the
+/// download fails if it is larger than the maximum file size.
+///
+/// returns: Result<u64, Error> The number of bytes written
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn download_object_from_s3(bucket: &str, key: &str, destination: &Path) -> Result<u64> {
+    let bucket = if bucket.is_empty() {
+        GLOBAL_DATA.read().await.s3_bucket.clone()
+    } else {
+        bucket.to_string()
+    };
+    if bucket.is_empty() {
+        return Err(anyhow!("No bucket was given for object {} and S3_BUCKET is not set", key));
+    }
+    let object = s3_client()
+        .await
+        .get_object()
+        .bucket(bucket.as_str())
+        .key(key)
+        .send()
+        .await
+        .map_err(|e| anyhow!("An error occurred while fetching {} from bucket {}. Error: {:?}", key, bucket, e))?;
+    let expected_length = object.content_length.and_then(|length| u64::try_from(length).ok());
+    let mut writer = DownloadWriter::create(destination, expected_length).await?;
+    let mut body = object.body;
+    while let Some(bytes) = body.next().await {
+        let bytes = bytes.map_err(|e| anyhow!("An error occurred while downloading {}. Error: {}", key, e))?;
+        writer.write(&bytes).await?;
+    }
+    writer.finish().await
+}
@@ -0,0 +1,249 @@
+//! A bounded crawl of the pages of a single site.
+//!
+//! Starting from one page, links are followed breadth first to pages on the same host, up to a
+//! maximum depth and number of pages. Links can be narrowed down with include and exclude patterns,
+//! and the site's robots.txt, including its crawl delay, is honoured unless told otherwise. Only HTML
+//! pages are returned, other documents linked from the pages are not followed.
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::Deserialize;
+use tempfile::TempDir;
+use tokio::time::sleep;
+use url::Url;
+
+use crate::data::markup::html_links;
+use crate::data::models::FileType;
+use crate::crawl::fetch::{fetch_text, fetch_to_file, ROBOTS_AGENT_TOKEN};
+use crate::crawl::robots::RobotsTxt;
+
+// A site asking for a longer delay than this would take hours to crawl, it is capped
+const MAX_CRAWL_DELAY: Duration = Duration::from_secs(10);
+
+/// How far a crawl goes, as sent in the `crawl` field of a `url` message
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CrawlOptions {
+    /// How many links away from the first page to go, 0 only fetches the first page
+    pub max_depth: usize,
+    pub max_pages: usize,
+    /// Regular expressions, a link is only followed if its URL matches one of them. Every link
+    /// is followed when empty
+    pub include: Vec<String>,
+    /// Regular expressions, a link is not followed if its URL matches any of them
+    pub exclude: Vec<String>,
+    pub respect_robots_txt: bool,
+}
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        CrawlOptions {
+            max_depth: 2,
+            max_pages: 50,
+            include: vec![],
+            exclude: vec![],
+            respect_robots_txt: true,
+        }
+    }
+}
+
+pub struct CrawledPage {
+    /// The URL the page was served from, after following redirects
+    pub url: Url,
+    /// Where the page was saved to, inside the crawler's directory
+    pub file_path: PathBuf,
+    /// When the page was fetched, in RFC 3339
+    pub fetched_at: String,
+}
+
+pub struct Crawler {
+    host: String,
+    queue: VecDeque<(Url, usize)>,
+    seen: HashSet<String>,
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    robots: Option<RobotsTxt>,
+    max_depth: usize,
+    max_pages: usize,
+    fetched: usize,
+    requested: usize,
+    directory: TempDir,
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|e| anyhow!("Invalid crawl pattern '{}'. Error: {}", pattern, e))
+        })
+        .collect()
+}
+
+// Fragments point inside a page, not at another page
+fn normalise(mut url: Url) -> Url {
+    url.set_fragment(None);
+    url
+}
+
+fn is_html(content_type: Option<&str>) -> bool {
+    matches!(content_type.and_then(FileType::from_mime), Some(FileType::HTML))
+}
+
+impl Crawler {
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `start`: The first page, its host is the only one crawled
+    /// * `options`: How far to crawl
+    /// * `max_pages_cap`: A ceiling on `options.max_pages` set by the deployment
+    ///
+    /// returns: Result<Crawler, Error> Fails if the URL has no host, a pattern is not a valid regular
+    /// expression or the directory pages are saved to can not be created
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn new(start: Url, options: CrawlOptions, max_pages_cap: usize) -> Result<Self> {
+        let host = start
+            .host_str()
+            .ok_or_else(|| anyhow!("Can not crawl {} as it has no host", start))?
+            .to_lowercase();
+        let include = compile_patterns(&options.include)?;
+        let exclude = compile_patterns(&options.exclude)?;
+        let robots = if options.respect_robots_txt {
+            let robots_url = start.join("/robots.txt")?;
+            match fetch_text(&robots_url).await {
+                Ok(Some(text)) => Some(RobotsTxt::parse(&text, ROBOTS_AGENT_TOKEN)),
+                Ok(None) => None,
+                // a robots.txt that can not be read is taken as permission to crawl nothing
+                Err(e) => {
+                    return Err(anyhow!("Could not read robots.txt of {}. Error: {}", host, e));
+                }
+            }
+        } else {
+            None
+        };
+        let directory = tempfile::Builder::new()
+            .prefix("crawl-")
+            .tempdir()
+            .map_err(|e| anyhow!("Could not create a directory for crawled pages: {}", e))?;
+        let start = normalise(start);
+        Ok(Crawler {
+            host,
+            seen: HashSet::from([start.to_string()]),
+            queue: VecDeque::from([(start, 0)]),
+            include,
+            exclude,
+            robots,
+            max_depth: options.max_depth,
+            max_pages: options.max_pages.min(max_pages_cap),
+            fetched: 0,
+            requested: 0,
+            directory,
+        })
+    }
+
+    fn is_same_host(&self, url: &Url) -> bool {
+        url.host_str()
+            .is_some_and(|host| host.eq_ignore_ascii_case(self.host.as_str()))
+    }
+
+    fn is_allowed_by_robots(&self, url: &Url) -> bool {
+        let Some(robots) = &self.robots else {
+            return true;
+        };
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        robots.is_allowed(path.as_str())
+    }
+
+    fn should_follow(&self, url: &Url) -> bool {
+        let link = url.as_str();
+        matches!(url.scheme(), "http" | "https")
+            && self.is_same_host(url)
+            && (self.include.is_empty() || self.include.iter().any(|pattern| pattern.is_match(link)))
+            && !self.exclude.iter().any(|pattern| pattern.is_match(link))
+    }
+
+    fn queue_links(&mut self, page: &CrawledPage, depth: usize) {
+        let html = match fs::read(&page.file_path) {
+            Ok(html) => html,
+            Err(e) => {
+                println!("Could not read crawled page {}. Error: {}", page.url, e);
+                return;
+            }
+        };
+        for link in html_links(&String::from_utf8_lossy(&html)) {
+            let Ok(url) = page.url.join(link.as_str()).map(normalise) else {
+                continue;
+            };
+            if self.should_follow(&url) && self.seen.insert(url.to_string()) {
+                self.queue.push_back((url, depth + 1));
+            }
+        }
+    }
+
+    ///
+    ///
+    /// Fetches pages until one is an HTML page of the site, queueing the links on it.
+    ///
+    /// returns: Option<CrawledPage> The next page, or `None` once there are no pages left to fetch
+    /// or the maximum number of pages has been fetched
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn next_page(&mut self) -> Option<CrawledPage> {
+        while self.fetched < self.max_pages {
+            let (url, depth) = self.queue.pop_front()?;
+            if !self.is_allowed_by_robots(&url) {
+                println!("Skipping {} which robots.txt disallows", url);
+                continue;
+            }
+            if self.requested > 0 {
+                if let Some(delay) = self.robots.as_ref().and_then(|robots| robots.crawl_delay()) {
+                    sleep(delay.min(MAX_CRAWL_DELAY)).await;
+                }
+            }
+            self.requested += 1;
+            let file_path = self.directory.path().join(format!("page-{}.html", self.requested));
+            let fetched = match fetch_to_file(&url, &file_path).await {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    println!("Could not fetch {}. Error: {}", url, e);
+                    continue;
+                }
+            };
+            // redirects can lead off the site or to a page that has been crawled already
+            let final_url = normalise(fetched.url);
+            let redirected_elsewhere = final_url != url
+                && (!self.is_same_host(&final_url) || !self.seen.insert(final_url.to_string()));
+            if redirected_elsewhere || !is_html(fetched.content_type.as_deref()) {
+                let _ = fs::remove_file(&file_path);
+                continue;
+            }
+            let page = CrawledPage {
+                url: final_url,
+                file_path,
+                fetched_at: fetched.fetched_at,
+            };
+            if depth < self.max_depth {
+                self.queue_links(&page, depth);
+            }
+            self.fetched += 1;
+            return Some(page);
+        }
+        None
+    }
+}
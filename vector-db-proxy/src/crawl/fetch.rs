@@ -0,0 +1,282 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{redirect, Client, Response};
+use tokio::sync::OnceCell;
+use tokio::time::timeout;
+use url::{Host, Url};
+
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::utils::file_operations::{DownloadWriter, FileRefused};
+
+/// Sent with every request so site owners can tell where requests come from
+pub const USER_AGENT: &str = concat!("agentcloud-vector-db-proxy/", env!("CARGO_PKG_VERSION"));
+/// The name robots.txt rules are matched against
+pub const ROBOTS_AGENT_TOKEN: &str = "agentcloud";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// how long to wait for the response headers and then for each part of the body, so that large
+// files keep downloading for as long as data arrives
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_REDIRECTS: usize = 10;
+
+// Cloud metadata services that sit in ranges which can otherwise be allowed
+const METADATA_ADDRESSES: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::new(100, 100, 100, 200)),
+    IpAddr::V6(Ipv6Addr::new(0xfd00, 0x0ec2, 0, 0, 0, 0, 0, 0x0254)),
+];
+
+/// Which addresses may be fetched. Link local and metadata addresses never may, loopback and
+/// private addresses only if they are in one of the allowed networks.
+struct AddressPolicy {
+    allowed_networks: Vec<IpNet>,
+}
+
+impl AddressPolicy {
+    fn permits(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        if METADATA_ADDRESSES.contains(&ip) {
+            return false;
+        }
+        let (never, private) = match ip {
+            IpAddr::V4(v4) => (
+                v4.is_unspecified() || v4.is_link_local() || v4.is_broadcast() || v4.is_multicast(),
+                // 100.64.0.0/10 is the shared address space used inside carrier and cloud networks
+                v4.is_loopback() || v4.is_private() || (v4.octets()[0] == 100 && v4.octets()[1] & 0xc0 == 64),
+            ),
+            IpAddr::V6(v6) => (
+                v6.is_unspecified() || v6.is_multicast() || v6.segments()[0] & 0xffc0 == 0xfe80,
+                v6.is_loopback() || v6.segments()[0] & 0xfe00 == 0xfc00,
+            ),
+        };
+        if never {
+            return false;
+        }
+        !private || self.allowed_networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Checks the host of a URL that is an IP address, host names are checked once resolved
+    fn check_url(&self, url: &Url) -> Result<()> {
+        check_scheme(url)?;
+        let ip = match url.host() {
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            _ => return Ok(()),
+        };
+        if self.permits(ip) {
+            Ok(())
+        } else {
+            Err(anyhow!(FileRefused(format!("Can not fetch {}, the address is not allowed", url))))
+        }
+    }
+}
+
+// Resolves host names with the system resolver, dropping the addresses that may not be fetched.
+// Checking the addresses connected to rather than the URL also covers redirects and host names
+// that resolve to internal addresses.
+struct CheckedResolver {
+    policy: Arc<AddressPolicy>,
+}
+
+impl Resolve for CheckedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = Arc::clone(&self.policy);
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|address| policy.permits(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(anyhow!("{} does not resolve to any address that is allowed", host).into());
+            }
+            let addresses: Addrs = Box::new(addresses.into_iter());
+            Ok(addresses)
+        })
+    }
+}
+
+// One client, and so one connection pool, for every download
+static HTTP_CLIENT: OnceCell<(Client, Arc<AddressPolicy>)> = OnceCell::const_new();
+
+async fn http_client() -> Result<&'static (Client, Arc<AddressPolicy>)> {
+    HTTP_CLIENT
+        .get_or_try_init(|| async {
+            let allowed_networks = GLOBAL_DATA.read().await.crawl_allowed_networks.clone();
+            let policy = Arc::new(AddressPolicy { allowed_networks });
+            let redirect_policy = Arc::clone(&policy);
+            let client = Client::builder()
+                .user_agent(USER_AGENT)
+                .connect_timeout(CONNECT_TIMEOUT)
+                .dns_resolver(Arc::new(CheckedResolver {
+                    policy: Arc::clone(&policy),
+                }))
+                .redirect(redirect::Policy::custom(move |attempt| {
+                    if attempt.previous().len() >= MAX_REDIRECTS {
+                        return attempt.error(anyhow!("Too many redirects"));
+                    }
+                    match redirect_policy.check_url(attempt.url()) {
+                        Ok(()) => attempt.follow(),
+                        Err(e) => attempt.error(e),
+                    }
+                }))
+                .build()
+                .map_err(|e| anyhow!("Could not build HTTP client. Error: {}", e))?;
+            Ok((client, policy))
+        })
+        .await
+}
+
+// Sends a GET request, waiting at most the read timeout for the response headers
+async fn get(url: &Url) -> Result<Response> {
+    let (client, policy) = http_client().await?;
+    policy.check_url(url)?;
+    match timeout(READ_TIMEOUT, client.get(url.clone()).send()).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(e)) => Err(anyhow!("An error occurred while fetching {}. Error: {}", url, e)),
+        Err(_) => Err(anyhow!("Timed out waiting for a response from {}", url)),
+    }
+}
+
+pub struct FetchedFile {
+    /// The URL the file was served from, after following redirects
+    pub url: Url,
+    /// The `Content-Type` header of the response
+    pub content_type: Option<String>,
+    /// When the file was fetched, in RFC 3339
+    pub fetched_at: String,
+    pub bytes: u64,
+}
+
+fn check_scheme(url: &Url) -> Result<()> {
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(anyhow!(FileRefused(format!(
+            "Can not fetch {}, only http and https URLs are supported not {}",
+            url, scheme
+        )))),
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `url`: The URL to download
+/// * `destination`: The file the response body is written to
+///
+/// The body is written to disk as it is received rather than being held in memory. Responses other
+/// than 2xx, bodies larger than the maximum file size and URLs, or redirects, to addresses that are
+/// not allowed are errors.
+///
+/// returns: Result<FetchedFile, Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn fetch_to_file(url: &Url, destination: &Path) -> Result<FetchedFile> {
+    let fetched_at = Utc::now().to_rfc3339();
+    let mut response = get(url).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("Fetching {} failed with status {}", url, response.status()));
+    }
+    let final_url = response.url().clone();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let mut writer = DownloadWriter::create(destination, response.content_length()).await?;
+    while let Some(chunk) = timeout(READ_TIMEOUT, response.chunk())
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for more of {}", url))?
+        .map_err(|e| anyhow!("An error occurred while downloading {}. Error: {}", url, e))?
+    {
+        writer.write(&chunk).await?;
+    }
+    Ok(FetchedFile {
+        url: final_url,
+        content_type,
+        fetched_at,
+        bytes: writer.finish().await?,
+    })
+}
+
+/// Fetches a small text resource such as robots.txt. A resource that is missing or refused with a
+/// 4xx status is `None` rather than an error
+pub async fn fetch_text(url: &Url) -> Result<Option<String>> {
+    let response = get(url).await?;
+    match response.status() {
+        status if status.is_success() => match timeout(READ_TIMEOUT, response.text()).await {
+            Ok(text) => Ok(Some(text?)),
+            Err(_) => Err(anyhow!("Timed out waiting for more of {}", url)),
+        },
+        status if status.is_client_error() => Ok(None),
+        status => Err(anyhow!("Fetching {} failed with status {}", url, status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowed_networks: &[&str]) -> AddressPolicy {
+        AddressPolicy {
+            allowed_networks: allowed_networks.iter().map(|n| n.parse().unwrap()).collect(),
+        }
+    }
+
+    fn permits(policy: &AddressPolicy, ip: &str) -> bool {
+        policy.permits(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        let policy = policy(&[]);
+        assert!(permits(&policy, "93.184.216.34"));
+        assert!(permits(&policy, "2606:2800:220:1::248"));
+    }
+
+    #[test]
+    fn private_addresses_need_an_allowed_network() {
+        let denied = policy(&[]);
+        let allowed = policy(&["10.0.0.0/8", "127.0.0.1/32", "fc00::/7"]);
+        for ip in ["10.1.2.3", "127.0.0.1", "fd12::1", "::ffff:10.1.2.3"] {
+            assert!(!permits(&denied, ip), "{}", ip);
+            assert!(permits(&allowed, ip), "{}", ip);
+        }
+        assert!(!permits(&allowed, "192.168.1.1"));
+        assert!(!permits(&denied, "100.64.0.1"));
+    }
+
+    #[test]
+    fn link_local_and_metadata_addresses_are_never_allowed() {
+        let policy = policy(&["0.0.0.0/0", "::/0"]);
+        for ip in ["169.254.169.254", "::ffff:169.254.169.254", "fe80::1", "100.100.100.200", "fd00:ec2::254", "0.0.0.0"] {
+            assert!(!permits(&policy, ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn ip_hosts_are_checked_before_fetching() {
+        let policy = policy(&[]);
+        // refused URLs are rejected rather than retried
+        for url in ["http://169.254.169.254/latest/meta-data", "http://[::1]:8080/", "ftp://example.com/"] {
+            let error = policy.check_url(&Url::parse(url).unwrap()).unwrap_err();
+            assert!(error.is::<FileRefused>(), "{}", url);
+        }
+        assert!(policy.check_url(&Url::parse("https://example.com/").unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,3 @@
+pub mod crawler;
+pub mod fetch;
+pub mod robots;
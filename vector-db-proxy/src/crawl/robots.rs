@@ -0,0 +1,196 @@
+//! A robots.txt parser covering what crawlers are expected to honour: user agent groups, `Allow` and
+//! `Disallow` rules with `*` and `$` wildcards, and `Crawl-delay`.
+use regex::Regex;
+use std::time::Duration;
+
+struct Rule {
+    allow: bool,
+    /// Length of the path in the rule, the longest matching rule wins
+    length: usize,
+    pattern: Regex,
+}
+
+#[derive(Default)]
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<f64>,
+}
+
+#[derive(Default)]
+pub struct RobotsTxt {
+    rules: Vec<Rule>,
+    crawl_delay: Option<f64>,
+}
+
+fn rule_pattern(path: &str) -> Option<Regex> {
+    let (path, anchored) = match path.strip_suffix('$') {
+        Some(path) => (path, true),
+        None => (path, false),
+    };
+    let pattern = path
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<String>>()
+        .join(".*");
+    Regex::new(&format!("^{}{}", pattern, if anchored { "$" } else { "" })).ok()
+}
+
+impl RobotsTxt {
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `text`: The content of robots.txt
+    /// * `agent_token`: The crawler's name. Its own group is used if there is one, otherwise the `*` group
+    ///
+    /// returns: RobotsTxt
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub fn parse(text: &str, agent_token: &str) -> Self {
+        let agent_token = agent_token.to_lowercase();
+        let mut groups: Vec<Group> = vec![];
+        let mut current = Group::default();
+        let mut in_rules = false;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let Some((field, value)) = line.split_once(':') else {
+                continue;
+            };
+            let field = field.trim().to_lowercase();
+            let value = value.trim();
+            match field.as_str() {
+                "user-agent" => {
+                    // a user agent line after rules starts a new group
+                    if in_rules {
+                        groups.push(std::mem::take(&mut current));
+                        in_rules = false;
+                    }
+                    current.agents.push(value.to_lowercase());
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    // an empty disallow allows everything, which is the same as having no rule
+                    if value.is_empty() {
+                        continue;
+                    }
+                    if let Some(pattern) = rule_pattern(value) {
+                        current.rules.push(Rule {
+                            allow: field == "allow",
+                            length: value.len(),
+                            pattern,
+                        });
+                    }
+                }
+                "crawl-delay" => {
+                    in_rules = true;
+                    current.crawl_delay = value.parse().ok();
+                }
+                _ => {}
+            }
+        }
+        groups.push(current);
+        let is_own_group = |group: &Group| {
+            group
+                .agents
+                .iter()
+                .any(|agent| agent != "*" && agent_token.contains(agent.as_str()))
+        };
+        let group = match groups.iter().position(is_own_group) {
+            Some(position) => Some(groups.swap_remove(position)),
+            None => groups
+                .into_iter()
+                .find(|group| group.agents.iter().any(|agent| agent == "*")),
+        };
+        match group {
+            Some(group) => RobotsTxt {
+                rules: group.rules,
+                crawl_delay: group.crawl_delay,
+            },
+            None => RobotsTxt::default(),
+        }
+    }
+
+    /// Whether a path, including its query string, may be fetched
+    pub fn is_allowed(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| rule.pattern.is_match(path))
+            // on a tie between allow and disallow the allow rule wins
+            .max_by_key(|rule| (rule.length, rule.allow))
+            .is_none_or(|rule| rule.allow)
+    }
+
+    pub fn crawl_delay(&self) -> Option<Duration> {
+        self.crawl_delay
+            .filter(|delay| delay.is_finite() && *delay > 0.)
+            .map(Duration::from_secs_f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROBOTS: &str = "
+User-agent: *
+Disallow: /private
+Crawl-delay: 2.5
+
+User-agent: OtherBot
+User-agent: agentcloud
+Disallow: /
+Allow: /docs/
+Allow: /*.html$
+Disallow: /docs/drafts # work in progress
+Disallow:
+";
+
+    #[test]
+    fn own_group_is_preferred_over_the_wildcard_group() {
+        let robots = RobotsTxt::parse(ROBOTS, "AgentCloud-Crawler");
+        assert!(!robots.is_allowed("/private"));
+        assert!(!robots.is_allowed("/about"));
+        assert!(robots.is_allowed("/docs/intro"));
+        assert_eq!(robots.crawl_delay(), None);
+
+        let robots = RobotsTxt::parse(ROBOTS, "SomeBot");
+        assert!(!robots.is_allowed("/private/page"));
+        assert!(robots.is_allowed("/about"));
+        assert_eq!(robots.crawl_delay(), Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn longest_rule_wins() {
+        let robots = RobotsTxt::parse(ROBOTS, "agentcloud");
+        assert!(!robots.is_allowed("/docs/drafts/one"));
+        assert!(!robots.is_allowed("/docs/drafts-list"));
+        assert!(robots.is_allowed("/docs/draft"));
+    }
+
+    #[test]
+    fn wildcards_and_anchors_match() {
+        let robots = RobotsTxt::parse(ROBOTS, "agentcloud");
+        assert!(robots.is_allowed("/blog/post.html"));
+        assert!(!robots.is_allowed("/blog/post.html?page=2"));
+        assert!(!robots.is_allowed("/blog/post.htm"));
+    }
+
+    #[test]
+    fn allow_wins_a_tie() {
+        let robots = RobotsTxt::parse("User-agent: *\nDisallow: /page\nAllow: /page\n", "agentcloud");
+        assert!(robots.is_allowed("/page"));
+    }
+
+    #[test]
+    fn missing_groups_allow_everything() {
+        let robots = RobotsTxt::parse("User-agent: OtherBot\nDisallow: /\n", "agentcloud");
+        assert!(robots.is_allowed("/anything"));
+        let robots = RobotsTxt::parse("", "agentcloud");
+        assert!(robots.is_allowed("/anything"));
+    }
+}
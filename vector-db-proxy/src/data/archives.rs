@@ -0,0 +1,329 @@
+//! Expansion of zip, tar and tar.gz uploads into the files they contain.
+//!
+//! Archives come from users so none of their headers are trusted. Entries are written inside a
+//! temporary directory that is deleted once the archive has been processed, entry names that would
+//! escape that directory are rejected, and the number of entries and the number of bytes actually
+//! written are capped so that a zip bomb can not fill the disk.
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use tempfile::TempDir;
+use zip::ZipArchive;
+
+use crate::init::env_variables::GLOBAL_DATA;
+
+const UNIX_FILE_TYPE_MASK: u32 = 0o170000;
+const UNIX_SYMLINK: u32 = 0o120000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    /// Recognises an archive from its first bytes
+    pub fn sniff(content: &[u8]) -> Option<ArchiveKind> {
+        if content.starts_with(b"PK\x03\x04") {
+            Some(ArchiveKind::Zip)
+        } else if content.starts_with(&[0x1f, 0x8b]) {
+            Some(ArchiveKind::TarGz)
+        } else if content.get(257..262) == Some(b"ustar".as_slice()) {
+            Some(ArchiveKind::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ArchiveLimits {
+    /// The most bytes written for all entries together
+    pub max_total_bytes: u64,
+    /// The most files taken from a single archive
+    pub max_entries: usize,
+}
+
+impl ArchiveLimits {
+    pub async fn from_global_data() -> Self {
+        let global_data = GLOBAL_DATA.read().await;
+        ArchiveLimits {
+            max_total_bytes: global_data.archive_max_bytes,
+            max_entries: global_data.archive_max_entries,
+        }
+    }
+}
+
+pub struct ArchiveEntry {
+    /// Where the entry was written to on disk
+    pub file_path: PathBuf,
+    /// The path of the entry inside the archive
+    pub archive_path: String,
+}
+
+impl ArchiveEntry {
+    pub fn file_name(&self) -> String {
+        self.archive_path
+            .rsplit('/')
+            .next()
+            .unwrap_or(self.archive_path.as_str())
+            .to_string()
+    }
+}
+
+/// The files of an expanded archive. They are deleted, along with the directory they were
+/// written to, when this is dropped.
+pub struct ExpandedArchive {
+    _directory: TempDir,
+    pub entries: Vec<ArchiveEntry>,
+}
+
+// Normalises an entry name to a relative path, rejecting anything that could point outside the
+// directory the archive is expanded into. Entries made on Windows can use backslashes.
+fn sanitise_entry_path(name: &str) -> Option<PathBuf> {
+    let name = name.replace('\\', "/");
+    let mut path = PathBuf::new();
+    for component in Path::new(name.as_str()).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+// Entries that are only there because of the tool that made the archive
+fn is_ignored_entry(path: &Path) -> bool {
+    path.components().any(|component| match component {
+        Component::Normal(part) => {
+            let part = part.to_string_lossy();
+            part == "__MACOSX" || part.starts_with('.')
+        }
+        _ => false,
+    })
+}
+
+struct Expander {
+    directory: TempDir,
+    limits: ArchiveLimits,
+    written_bytes: u64,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl Expander {
+    fn new(limits: ArchiveLimits) -> Result<Self> {
+        let directory = tempfile::Builder::new()
+            .prefix("archive-")
+            .tempdir()
+            .map_err(|e| anyhow!("Could not create a directory to expand the archive in: {}", e))?;
+        Ok(Expander {
+            directory,
+            limits,
+            written_bytes: 0,
+            entries: vec![],
+        })
+    }
+
+    fn add_entry(&mut self, name: &str, reader: &mut dyn Read) -> Result<()> {
+        let Some(relative_path) = sanitise_entry_path(name) else {
+            return Err(anyhow!("Archive entry '{}' points outside of the archive", name));
+        };
+        if is_ignored_entry(&relative_path) {
+            return Ok(());
+        }
+        if self.entries.len() >= self.limits.max_entries {
+            return Err(anyhow!(
+                "Archive has more than the maximum of {} files",
+                self.limits.max_entries
+            ));
+        }
+        let file_path = self.directory.path().join(&relative_path);
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(&file_path)?;
+        // the sizes in entry headers can be forged so count what is actually written
+        let remaining = self.limits.max_total_bytes - self.written_bytes;
+        let written = io::copy(&mut reader.take(remaining + 1), &mut file)?;
+        if written > remaining {
+            return Err(anyhow!(
+                "Archive expands to more than the maximum of {} bytes",
+                self.limits.max_total_bytes
+            ));
+        }
+        self.written_bytes += written;
+        self.entries.push(ArchiveEntry {
+            file_path,
+            archive_path: relative_path.to_string_lossy().replace('\\', "/"),
+        });
+        Ok(())
+    }
+
+    fn expand_zip(&mut self, file: File) -> Result<()> {
+        let mut archive = ZipArchive::new(file).map_err(|e| anyhow!("Could not read zip archive: {}", e))?;
+        for i in 0..archive.len() {
+            let mut entry = archive
+                .by_index(i)
+                .map_err(|e| anyhow!("Could not read zip archive entry: {}", e))?;
+            let name = entry.name().to_string();
+            let is_symlink = entry
+                .unix_mode()
+                .is_some_and(|mode| mode & UNIX_FILE_TYPE_MASK == UNIX_SYMLINK);
+            if name.ends_with('/') || is_symlink {
+                continue;
+            }
+            self.add_entry(name.as_str(), &mut entry)?;
+        }
+        Ok(())
+    }
+
+    fn expand_tar<R: Read>(&mut self, reader: R) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            // links, devices and directories are skipped, links could point anywhere on the host
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            self.add_entry(name.as_str(), &mut entry)?;
+        }
+        Ok(())
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `path`: Path to the archive
+/// * `limits`: Caps on the number of entries and the total expanded size
+///
+/// Archives inside the archive are not expanded further. Exceeding a limit or an entry that tries
+/// to escape the archive fails the whole archive rather than ingesting part of it.
+///
+/// returns: Result<ExpandedArchive, Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn expand_archive(path: &str, limits: ArchiveLimits) -> Result<ExpandedArchive> {
+    let mut head = [0u8; 262];
+    let mut file = File::open(path).map_err(|e| anyhow!("Could not open archive: {}", e))?;
+    let read = file.read(&mut head)?;
+    let kind = ArchiveKind::sniff(&head[..read])
+        .ok_or_else(|| anyhow!("File is not a zip, tar or tar.gz archive"))?;
+    let file = File::open(path)?;
+    let mut expander = Expander::new(limits)?;
+    match kind {
+        ArchiveKind::Zip => expander.expand_zip(file)?,
+        ArchiveKind::Tar => expander.expand_tar(file)?,
+        ArchiveKind::TarGz => expander.expand_tar(GzDecoder::new(file))?,
+    }
+    Ok(ExpandedArchive {
+        _directory: expander.directory,
+        entries: expander.entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+
+    fn write_zip(entries: &[(&str, &[u8])]) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = zip::ZipWriter::new(file.reopen().unwrap());
+        for (name, content) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap();
+        file
+    }
+
+    fn limits(max_total_bytes: u64, max_entries: usize) -> ArchiveLimits {
+        ArchiveLimits {
+            max_total_bytes,
+            max_entries,
+        }
+    }
+
+    #[test]
+    fn entry_paths_are_kept_inside_the_archive() {
+        assert_eq!(sanitise_entry_path("docs/./a.txt"), Some(PathBuf::from("docs/a.txt")));
+        assert_eq!(sanitise_entry_path("docs\\b.txt"), Some(PathBuf::from("docs/b.txt")));
+        assert_eq!(sanitise_entry_path("../a.txt"), None);
+        assert_eq!(sanitise_entry_path("docs/../../a.txt"), None);
+        assert_eq!(sanitise_entry_path("/etc/passwd"), None);
+        assert_eq!(sanitise_entry_path("..\\a.txt"), None);
+        assert_eq!(sanitise_entry_path("./"), None);
+    }
+
+    #[test]
+    fn files_are_expanded_and_tool_entries_skipped() {
+        let archive = write_zip(&[
+            ("docs/a.txt", b"first"),
+            ("__MACOSX/docs/._a.txt", b"resource fork"),
+            (".DS_Store", b"finder"),
+            ("b.txt", b"second"),
+        ]);
+        let expanded = expand_archive(archive.path().to_str().unwrap(), limits(100, 10)).unwrap();
+        let paths: Vec<&str> = expanded.entries.iter().map(|e| e.archive_path.as_str()).collect();
+        assert_eq!(paths, vec!["docs/a.txt", "b.txt"]);
+        assert_eq!(expanded.entries[0].file_name(), "a.txt");
+        assert_eq!(fs::read_to_string(&expanded.entries[1].file_path).unwrap(), "second");
+    }
+
+    #[test]
+    fn entries_escaping_the_archive_fail_it() {
+        let archive = write_zip(&[("a.txt", b"first"), ("../b.txt", b"second")]);
+        assert!(expand_archive(archive.path().to_str().unwrap(), limits(100, 10)).is_err());
+    }
+
+    #[test]
+    fn too_many_entries_fail_the_archive() {
+        let archive = write_zip(&[("a.txt", b"1"), ("b.txt", b"2"), ("c.txt", b"3")]);
+        let path = archive.path().to_str().unwrap();
+        assert!(expand_archive(path, limits(100, 3)).is_ok());
+        assert!(expand_archive(path, limits(100, 2)).is_err());
+    }
+
+    #[test]
+    fn too_many_bytes_fail_the_archive() {
+        let archive = write_zip(&[("a.txt", b"12345"), ("b.txt", b"67890")]);
+        let path = archive.path().to_str().unwrap();
+        assert!(expand_archive(path, limits(10, 10)).is_ok());
+        assert!(expand_archive(path, limits(9, 10)).is_err());
+    }
+
+    #[test]
+    fn tar_links_are_skipped() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut builder = tar::Builder::new(file.reopen().unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "a.txt", "first".as_bytes()).unwrap();
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Symlink);
+        link.set_size(0);
+        builder.append_link(&mut link, "passwd", "/etc/passwd").unwrap();
+        builder.finish().unwrap();
+        let expanded = expand_archive(file.path().to_str().unwrap(), limits(100, 10)).unwrap();
+        let paths: Vec<&str> = expanded.entries.iter().map(|e| e.archive_path.as_str()).collect();
+        assert_eq!(paths, vec!["a.txt"]);
+    }
+}
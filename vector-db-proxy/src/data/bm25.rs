@@ -0,0 +1,112 @@
+//! BM25 style sparse term vectors used for keyword matching alongside the dense model vectors.
+//!
+//! Qdrant does not keep corpus statistics for us so the document side only carries the saturated
+//! term frequency part of BM25 (normalised by an assumed average chunk length). The query side is a
+//! plain binary term vector, which means the dot product Qdrant computes is the BM25 score without IDF.
+use std::collections::HashMap;
+
+/// Name of the sparse named vector stored next to the dense model vector
+pub const SPARSE_VECTOR_NAME: &str = "bm25";
+
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+// Chunks are produced by our own splitters so this is a reasonable stand in for the real average
+const AVERAGE_DOCUMENT_LENGTH: f32 = 256.0;
+
+/// Lower cases and splits text into terms. Identifiers such as `ERR-1042` or `sku_993` are kept
+/// whole and additionally split into their parts so that both exact and partial matches score.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    for word in text
+        .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || c == '.'))
+        .map(|w| w.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|w| !w.is_empty())
+    {
+        let word = word.to_lowercase();
+        if word.contains(['-', '_', '.']) {
+            for part in word.split(['-', '_', '.']).filter(|p| !p.is_empty()) {
+                tokens.push(part.to_string());
+            }
+        }
+        tokens.push(word);
+    }
+    tokens
+}
+
+/// Stable 32 bit FNV-1a hash of a term. The id must be identical between ingestion and query time
+/// (and across releases) so we can not use the std hasher here.
+pub fn term_id(term: &str) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in term.as_bytes() {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+fn term_frequencies(tokens: &[String]) -> HashMap<u32, f32> {
+    let mut frequencies: HashMap<u32, f32> = HashMap::new();
+    for token in tokens {
+        *frequencies.entry(term_id(token)).or_insert(0.0) += 1.0;
+    }
+    frequencies
+}
+
+/// Sparse vector for a chunk of text being ingested
+pub fn document_sparse_vector(text: &str) -> Vec<(u32, f32)> {
+    let tokens = tokenize(text);
+    let document_length = tokens.len() as f32;
+    let mut vector: Vec<(u32, f32)> = term_frequencies(&tokens)
+        .into_iter()
+        .map(|(id, tf)| {
+            let norm = K1 * (1.0 - B + B * document_length / AVERAGE_DOCUMENT_LENGTH);
+            (id, tf * (K1 + 1.0) / (tf + norm))
+        })
+        .collect();
+    vector.sort_by_key(|(id, _)| *id);
+    vector
+}
+
+/// Sparse vector for a search query
+pub fn query_sparse_vector(text: &str) -> Vec<(u32, f32)> {
+    let mut vector: Vec<(u32, f32)> = term_frequencies(&tokenize(text))
+        .into_keys()
+        .map(|id| (id, 1.0))
+        .collect();
+    vector.sort_by_key(|(id, _)| *id);
+    vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_lower_cased_and_punctuation_dropped() {
+        assert_eq!(tokenize("Hello, World! (again)"), vec!["hello", "world", "again"]);
+    }
+
+    #[test]
+    fn identifiers_are_kept_whole_and_split_into_parts() {
+        assert_eq!(tokenize("See ERR-1042."), vec!["see", "err", "1042", "err-1042"]);
+        assert_eq!(tokenize("sku_993"), vec!["sku", "993", "sku_993"]);
+        assert_eq!(tokenize("v1.2.3"), vec!["v1", "2", "3", "v1.2.3"]);
+    }
+
+    #[test]
+    fn query_terms_match_document_terms() {
+        let document: Vec<u32> = document_sparse_vector("Order sku_993 shipped").into_iter().map(|(id, _)| id).collect();
+        for (id, weight) in query_sparse_vector("SKU_993") {
+            assert!(document.contains(&id));
+            assert_eq!(weight, 1.0);
+        }
+    }
+
+    #[test]
+    fn repeated_terms_saturate() {
+        let weight = |text: &str| document_sparse_vector(text)[0].1;
+        let (once, twice, many) = (weight("rust"), weight("rust rust"), weight(&"rust ".repeat(50)));
+        assert!(once < twice && twice < many);
+        assert!(many < K1 + 1.0);
+    }
+}
@@ -1,22 +1,31 @@
 use crate::data::{models::Document, text_splitting::Chunker};
+use crate::data::code_splitting::chunk_code;
+use crate::data::markup::{html_to_text, markdown_to_text, sections, MARKDOWN_CONTENT_FORMAT};
+use crate::data::office::{extract_odt, extract_presentation, extract_spreadsheet, is_odt};
+use crate::data::pdf::{assign_page_numbers, extract_pdf};
+use crate::data::parts::apply_part_offsets;
+use crate::data::recursive_splitting::{chunk_recursively, ChunkSize};
+use crate::data::segments::{chunk_rows, decode_segments, Segment, SegmentContent, SEGMENTS_KEY};
+use crate::data::tabular::{ingest_delimited_file, RowCounts, RowText};
 use crate::mongo::models::ChunkingStrategy;
 use anyhow::{anyhow, Result};
 
 use lopdf::{Dictionary, Object};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::Read;
 use std::sync::Arc;
 
 extern crate dotext;
 
-use crate::llm::models::EmbeddingModels;
+use crate::llm::providers::EmbeddingProviders;
+use crate::llm::utils::embed_text_chunks_async;
+use crate::qdrant::dedup::{content_hash, StoredDocument, CONTENT_HASH_KEY};
 use dotext::*;
 use mongodb::Database;
-use qdrant_client::client::QdrantClient;
 use tokio::sync::{RwLock};
-use crate::queue::add_tasks_to_queues::add_message_to_embedding_queue;
-use crate::queue::queuing::MyQueue;
+use crate::llm::providers::get_embedding_provider;
+use crate::mongo::queries::{get_datasource, get_embedding_model};
 
 pub trait Chunking {
     type Item;
@@ -25,25 +34,29 @@ pub trait Chunking {
     fn extract_text_from_pdf(&self, path: String) -> Result<(String, HashMap<String, String>)>;
     fn extract_text_from_docx(&self, path: String) -> Result<(String, HashMap<String, String>)>;
     fn extract_text_from_txt(&self, path: String) -> Result<(String, HashMap<String, String>)>;
+    fn extract_text_from_markdown(&self, path: String) -> Result<(String, HashMap<String, String>)>;
+    fn extract_text_from_html(&self, path: String) -> Result<(String, HashMap<String, String>)>;
+    fn extract_text_from_spreadsheet(&self, path: String) -> Result<(String, HashMap<String, String>)>;
+    fn extract_text_from_presentation(&self, path: String) -> Result<(String, HashMap<String, String>)>;
     fn detect_pdf_fonts(&self, doc: &lopdf::Document) -> HashMap<String, String>;
     async fn extract_text_from_csv(
         &self,
         path: String,
+        delimiter: u8,
+        document_name: String,
+        extra_metadata: HashMap<String, String>,
         datasource_id: String,
-        queue: Arc<RwLock<MyQueue<String>>>,
-        qdrant_conn: Arc<RwLock<QdrantClient>>,
         mongo_conn: Arc<RwLock<Database>>,
-        // redis_conn_pool: Arc<Mutex<RedisConnection>>,
-    );
+        stored: &mut StoredDocument,
+    ) -> Result<RowCounts>;
     async fn chunk(
         &self,
         data: String,
         metadata: Option<HashMap<String, String>>,
         strategy: ChunkingStrategy,
         chunking_character: Option<String>,
-        embedding_model: EmbeddingModels,
-        mongo_conn: Arc<RwLock<Database>>,
-        datasource_id: String,
+        chunk_size: ChunkSize,
+        embedding_provider: Arc<EmbeddingProviders>,
     ) -> Result<Vec<Document>>;
 }
 
@@ -103,46 +116,18 @@ impl Chunking for TextChunker {
     }
 
     fn extract_text_from_pdf(&self, path: String) -> Result<(String, HashMap<String, String>)> {
-        match lopdf::Document::load(path.as_str()) {
-            Ok(doc) => {
-                let mut metadata = self.detect_pdf_fonts(&doc);
-                let mut res = (String::new(), metadata);
-                let pages = doc.get_pages();
-                if let Some((_, page)) = pages.into_iter().next() {
-                    return match pdf_extract::extract_text(path.as_str()) {
-                        Ok(text) => {
-                            let page_dict = doc.get_dictionary(page)?;
-                            metadata = self.dictionary_to_hashmap(page_dict);
-                            if text.is_empty() {
-                                return Err(anyhow!(
-                                    "Unable to extract text from PDF document: {}",
-                                    path
-                                ));
-                            }
-                            metadata.insert("character count".to_string(), text.len().to_string());
-                            res = (text, metadata);
-                            Ok(res)
-                        }
-                        Err(e) => Err(anyhow!(
-                            "An error occurred while trying to extract text from pdf. Error: {}",
-                            e
-                        )),
-                    };
-                }
-                Ok(res)
-            }
-            Err(e) => Err(anyhow!(
-                "An error occurred while attempting to load PDF doc: {}",
-                e
-            )),
-        }
+        extract_pdf(path.as_str())
     }
-    // this method covers docx, xlsx and pptx
+    // this method covers docx and odt, which is an OpenDocument archive rather than a Word one
     fn extract_text_from_docx(&self, path: String) -> Result<(String, HashMap<String, String>)> {
+        if is_odt(path.as_str()) {
+            return extract_odt(path.as_str());
+        }
         let metadata = HashMap::new();
         let mut docx = String::new();
-        let mut file = Docx::open(path.as_str()).expect("Cannot open file");
-        file.read_to_string(&mut docx).unwrap();
+        let mut file = Docx::open(path.as_str()).map_err(|e| anyhow!("Could not open docx file. Error: {}", e))?;
+        file.read_to_string(&mut docx)
+            .map_err(|e| anyhow!("Could not read docx file. Error: {}", e))?;
 
         let results = (docx, metadata);
         Ok(results)
@@ -162,6 +147,42 @@ impl Chunking for TextChunker {
         Ok(results)
     }
 
+    fn extract_text_from_markdown(&self, path: String) -> Result<(String, HashMap<String, String>)> {
+        let markdown = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Could not read Markdown file. Error: {}", e))?;
+        let (text, title) = markdown_to_text(&markdown);
+        let mut metadata = HashMap::from([(
+            "content_format".to_string(),
+            MARKDOWN_CONTENT_FORMAT.to_string(),
+        )]);
+        if let Some(title) = title {
+            metadata.insert("title".to_string(), title);
+        }
+        Ok((text, metadata))
+    }
+
+    fn extract_text_from_html(&self, path: String) -> Result<(String, HashMap<String, String>)> {
+        // pages are not always valid UTF-8, keep what we can rather than failing the whole file
+        let bytes = fs::read(path).map_err(|e| anyhow!("Could not read HTML file. Error: {}", e))?;
+        let (text, title) = html_to_text(&String::from_utf8_lossy(&bytes));
+        let mut metadata = HashMap::from([(
+            "content_format".to_string(),
+            MARKDOWN_CONTENT_FORMAT.to_string(),
+        )]);
+        if let Some(title) = title {
+            metadata.insert("title".to_string(), title);
+        }
+        Ok((text, metadata))
+    }
+
+    fn extract_text_from_spreadsheet(&self, path: String) -> Result<(String, HashMap<String, String>)> {
+        extract_spreadsheet(path.as_str())
+    }
+
+    fn extract_text_from_presentation(&self, path: String) -> Result<(String, HashMap<String, String>)> {
+        extract_presentation(path.as_str())
+    }
+
     fn detect_pdf_fonts(&self, doc: &lopdf::Document) -> HashMap<String, String> {
         let mut metadata = HashMap::new();
         // Iterate over all pages
@@ -196,61 +217,280 @@ impl Chunking for TextChunker {
     async fn extract_text_from_csv(
         &self,
         path: String,
+        delimiter: u8,
+        document_name: String,
+        extra_metadata: HashMap<String, String>,
         datasource_id: String,
-        queue: Arc<RwLock<MyQueue<String>>>,
-        qdrant_conn: Arc<RwLock<QdrantClient>>,
         mongo_conn: Arc<RwLock<Database>>,
-        // redis_conn_pool: Arc<Mutex<RedisConnection>>,
-    ) {
-        match csv::Reader::from_path(path) {
-            Ok(mut rdr) => {
-                for row in rdr.records() {
-                    match row {
-                        Ok(record) => {
-                            let string_record = record.iter().collect::<Vec<&str>>().join(", ");
-                            let queue = Arc::clone(&queue);
-                            let qdrant_conn = Arc::clone(&qdrant_conn);
-                            let mongo_conn = Arc::clone(&mongo_conn);
-                            let ds_clone = datasource_id.clone();
-                            add_message_to_embedding_queue(queue, qdrant_conn, mongo_conn, (ds_clone, string_record)).await;
+        stored: &mut StoredDocument,
+    ) -> Result<RowCounts> {
+        let mongodb_connection = mongo_conn.read().await;
+        let datasource = get_datasource(&mongodb_connection, datasource_id.as_str())
+            .await?
+            .ok_or_else(|| anyhow!("Could not find datasource: {}", datasource_id))?;
+        let model = get_embedding_model(&mongodb_connection, datasource_id.as_str())
+            .await?
+            .ok_or_else(|| {
+                anyhow!("There was no embedding model associated with datasource: {}", datasource_id)
+            })?;
+        let embedding_provider = get_embedding_provider(&mongodb_connection, datasource_id.as_str()).await?;
+        let row_text = RowText::from(&datasource);
+        let mut metadata = extra_metadata;
+        metadata.insert(String::from("document name"), document_name);
+        ingest_delimited_file(
+            path.as_str(),
+            delimiter,
+            &metadata,
+            &row_text,
+            &embedding_provider,
+            model.embeddingLength as u64,
+            stored,
+        )
+            .await
+    }
+
+    async fn chunk(
+        &self,
+        data: String,
+        mut metadata: Option<HashMap<String, String>>,
+        strategy: ChunkingStrategy,
+        chunking_character: Option<String>,
+        chunk_size: ChunkSize,
+        embedding_provider: Arc<EmbeddingProviders>,
+    ) -> Result<Vec<Document>> {
+        let segments = metadata
+            .as_mut()
+            .and_then(|m| m.remove(SEGMENTS_KEY));
+        let is_markdown = metadata
+            .as_ref()
+            .and_then(|m| m.get("content_format"))
+            .is_some_and(|format| format == MARKDOWN_CONTENT_FORMAT);
+        let mut documents = if let Some(segments) = segments {
+            self.chunk_segments(
+                decode_segments(&segments)?,
+                metadata,
+                strategy,
+                chunking_character,
+                chunk_size,
+                embedding_provider,
+            )
+                .await?
+        } else if is_markdown {
+            self.chunk_sections(data, metadata, strategy, chunking_character, chunk_size, embedding_provider)
+                .await?
+        } else {
+            self.chunk_text(data, metadata, strategy, chunking_character, chunk_size, embedding_provider)
+                .await?
+        };
+        assign_page_numbers(&mut documents);
+        apply_part_offsets(&mut documents);
+        Ok(documents)
+    }
+}
+
+impl TextChunker {
+    async fn chunk_segments(
+        &self,
+        segments: Vec<Segment>,
+        metadata: Option<HashMap<String, String>>,
+        strategy: ChunkingStrategy,
+        chunking_character: Option<String>,
+        chunk_size: ChunkSize,
+        embedding_provider: Arc<EmbeddingProviders>,
+    ) -> Result<Vec<Document>> {
+        let mut documents = vec![];
+        for segment in segments {
+            let mut segment_metadata = metadata.clone().unwrap_or_default();
+            segment_metadata.extend(segment.metadata);
+            let result = match segment.content {
+                SegmentContent::Text(text) => {
+                    self.chunk_text(
+                        text,
+                        Some(segment_metadata),
+                        strategy,
+                        chunking_character.clone(),
+                        chunk_size,
+                        Arc::clone(&embedding_provider),
+                    )
+                        .await
+                }
+                SegmentContent::Rows { header, rows } => {
+                    chunk_rows(header, rows, segment_metadata, chunk_size, Arc::clone(&embedding_provider)).await
+                }
+            };
+            // a segment left out would have its stored chunks deleted as stale, so the document fails
+            // as a whole and is retried instead
+            let mut segment_documents =
+                result.map_err(|e| anyhow!("An error occurred while chunking a document segment: {}", e))?;
+            documents.append(&mut segment_documents);
+        }
+        if documents.is_empty() {
+            return Err(anyhow!("Chunker returned an empty document!"));
+        }
+        Ok(documents)
+    }
+
+    async fn chunk_sections(
+        &self,
+        data: String,
+        metadata: Option<HashMap<String, String>>,
+        strategy: ChunkingStrategy,
+        chunking_character: Option<String>,
+        chunk_size: ChunkSize,
+        embedding_provider: Arc<EmbeddingProviders>,
+    ) -> Result<Vec<Document>> {
+        // chunk each section on its own so that no chunk straddles two headings
+        let mut documents = vec![];
+        for section in sections(&data) {
+            let mut section_metadata = metadata.clone().unwrap_or_default();
+            if !section.headings.is_empty() {
+                section_metadata.insert("headings".to_string(), section.headings.join(" > "));
+            }
+            // as with segments, a section left out would have its stored chunks deleted as stale
+            let mut section_documents = self
+                .chunk_text(
+                    section.text,
+                    Some(section_metadata),
+                    strategy,
+                    chunking_character.clone(),
+                    chunk_size,
+                    Arc::clone(&embedding_provider),
+                )
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "An error occurred while chunking section '{}': {}",
+                        section.headings.join(" > "),
+                        e
+                    )
+                })?;
+            // offsets from the splitters are relative to the section, make them relative to the document
+            for document in section_documents.iter_mut() {
+                if let Some(m) = document.metadata.as_mut() {
+                    for key in ["start_index", "end_index"] {
+                        if let Some(index) = m.get(key).and_then(|i| i.parse::<usize>().ok()) {
+                            m.insert(key.to_string(), (index + section.start_index).to_string());
                         }
-                        Err(e) => { println!("An error occurred {}", e); }
                     }
                 }
             }
-            Err(e) => {
-                println!("An error occurred: {} ", e);
-            }
+            documents.append(&mut section_documents);
         }
+        if documents.is_empty() {
+            return Err(anyhow!("Chunker returned an empty document!"));
+        }
+        Ok(documents)
     }
 
-
-    async fn chunk(
+    async fn chunk_text(
         &self,
         data: String,
         metadata: Option<HashMap<String, String>>,
         strategy: ChunkingStrategy,
         chunking_character: Option<String>,
-        embedding_model: EmbeddingModels,
-        mongo_conn: Arc<RwLock<Database>>,
-        datasource_id: String,
+        chunk_size: ChunkSize,
+        embedding_provider: Arc<EmbeddingProviders>,
     ) -> Result<Vec<Document>> {
+        match strategy {
+            ChunkingStrategy::RECURSIVE_CHUNKING => {
+                return chunk_recursively(data, metadata, chunk_size, embedding_provider).await;
+            }
+            ChunkingStrategy::CODE_SPLIT => {
+                return chunk_code(data, metadata, chunk_size, embedding_provider).await;
+            }
+            _ => {}
+        }
         let chunker = Chunker::new(
-            embedding_model,
+            embedding_provider,
             true,
             Some(strategy),
             chunking_character,
-            mongo_conn,
-            datasource_id,
         );
         let doc = Document {
             page_content: data,
             metadata,
             embedding_vector: None,
         };
-        let Ok(results) = chunker.split_documents(vec![doc]).await else {
-            return Err(anyhow!("Chunker returned an empty document!"));
-        };
-        Ok(results)
+        chunker
+            .split_documents(vec![doc])
+            .await
+            .map_err(|e| anyhow!("Chunker could not split the document. Error: {}", e))
+    }
+}
+
+/// The chunks of a document ready to upload, and how many more could not be embedded
+pub struct EmbeddedChunks {
+    pub chunks: Vec<Document>,
+    pub failed: usize,
+}
+
+///
+///
+/// # Arguments
+///
+/// * `documents`: The chunks of a document
+/// * `stored`: The chunks of the document that are already in the collection
+/// * `embedding_provider`: The provider to embed the chunks with
+///
+/// Sets the content hash on every chunk and embeds the chunks that do not have an embedding yet.
+/// Chunks whose text is already stored are not embedded again and are returned without an
+/// embedding. Chunks that fail to embed are left out and counted.
+///
+/// returns: Result<EmbeddedChunks, Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn embed_new_chunks(
+    mut documents: Vec<Document>,
+    stored: &StoredDocument,
+    embedding_provider: &EmbeddingProviders,
+) -> Result<EmbeddedChunks> {
+    let mut pending = vec![];
+    for (i, document) in documents.iter_mut().enumerate() {
+        let hash = content_hash(document.page_content.as_str());
+        if document.embedding_vector.is_none() && !stored.is_stored(hash.as_str()) {
+            pending.push(i);
+        }
+        document
+            .metadata
+            .get_or_insert_with(HashMap::new)
+            .insert(CONTENT_HASH_KEY.to_string(), hash);
+    }
+    println!(
+        "Embedding {} of {} chunks of document {}, the rest are unchanged or already embedded",
+        pending.len(),
+        documents.len(),
+        stored.document_id()
+    );
+    let embeddings = embed_text_chunks_async(
+        embedding_provider,
+        pending.iter().map(|&i| documents[i].page_content.clone()).collect(),
+    )
+        .await;
+    let mut failed = HashSet::new();
+    for (i, embedding) in pending.into_iter().zip(embeddings) {
+        match embedding {
+            Ok(embedding) => documents[i].embedding_vector = Some(embedding),
+            Err(e) => {
+                println!("An error occurred while trying to embed chunk {}. Error: {}", i, e);
+                failed.insert(i);
+            }
+        }
+    }
+    let documents: Vec<Document> = documents
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !failed.contains(i))
+        .map(|(_, document)| document)
+        .collect();
+    if documents.is_empty() {
+        return Err(anyhow!("None of the chunks could be embedded"));
     }
+    Ok(EmbeddedChunks {
+        chunks: documents,
+        failed: failed.len(),
+    })
 }
@@ -0,0 +1,596 @@
+//! Syntax aware splitting of source code.
+//!
+//! Source files are cut at top level declarations (functions, classes, impl blocks...) so that each
+//! chunk holds whole definitions. This is a lightweight scanner rather than a full parser: it tracks
+//! brace depth (skipping strings and comments) for the C like languages and indentation for Python,
+//! and recognises declarations that start at the top level. Comments, attributes and decorators
+//! directly above a declaration are kept with it. Blocks that are still too large for the model are
+//! split further with the recursive splitter.
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::task;
+
+use crate::data::models::Document;
+use crate::data::recursive_splitting::{chunk_recursively, ChunkSize, RecursiveTextSplitter};
+use crate::llm::providers::{EmbeddingProvider, EmbeddingProviders};
+use crate::llm::tokens::TokenCounter;
+
+static RUST_IMPL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^\s*(?:unsafe\s+)?impl\b(?:\s*<[^{]*?>)?\s+([^{]+?)\s*(?:\bwhere\b.*)?\{?\s*$").unwrap()
+});
+static RUST_ITEM: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:(?:const|async|unsafe|default|extern(?:\s+"[^"]*")?)\s+)*(fn|struct|enum|trait|mod|union|type|static|const|macro_rules!)\s+([A-Za-z_][A-Za-z0-9_]*)"#).unwrap()
+});
+static PYTHON_ITEM: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(?:async\s+)?(def|class)\s+([A-Za-z_]\w*)").unwrap());
+static SCRIPT_ITEM: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^\s*(?:export\s+)?(?:default\s+)?(?:declare\s+)?(?:abstract\s+)?(?:async\s+)?(function\*?|class|interface|enum|type|namespace|module)\s+([A-Za-z_$][\w$]*)").unwrap()
+});
+static SCRIPT_FUNCTION_VARIABLE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^\s*(?:export\s+)?(?:const|let|var)\s+([A-Za-z_$][\w$]*)\s*(?::[^=]+)?=\s*(?:async\s+)?(?:function\b|\([^)]*\)\s*(?::[^=]+)?=>|[A-Za-z_$][\w$]*\s*=>)").unwrap()
+});
+static GO_FUNC: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^func\s+(?:\(\s*(?:\w+\s+)?\*?\s*([A-Za-z_]\w*)(?:\[[^\]]*\])?\s*\)\s*)?([A-Za-z_]\w*)").unwrap()
+});
+static GO_TYPE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^type\s+([A-Za-z_]\w*)").unwrap());
+static JAVA_ITEM: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^\s*(?:(?:public|protected|private|abstract|final|static|sealed|non-sealed|strictfp)\s+)*(class|interface|enum|record|@interface)\s+([A-Za-z_]\w*)").unwrap()
+});
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    Python,
+    TypeScript,
+    JavaScript,
+    Go,
+    Java,
+}
+
+impl Language {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_lowercase().as_str() {
+            "rs" => Some(Language::Rust),
+            "py" | "pyi" => Some(Language::Python),
+            "ts" | "tsx" | "mts" | "cts" => Some(Language::TypeScript),
+            "js" | "jsx" | "mjs" | "cjs" => Some(Language::JavaScript),
+            "go" => Some(Language::Go),
+            "java" => Some(Language::Java),
+            _ => None,
+        }
+    }
+
+    /// Accepts either a language name (as set on the datasource) or a file extension
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "rust" => Some(Language::Rust),
+            "python" => Some(Language::Python),
+            "typescript" => Some(Language::TypeScript),
+            "javascript" => Some(Language::JavaScript),
+            "golang" => Some(Language::Go),
+            other => Language::from_extension(other),
+        }
+    }
+
+    pub fn from_file_name(file_name: &str) -> Option<Self> {
+        Path::new(file_name.trim_matches('"'))
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(Language::from_extension)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Language::Rust => "rust",
+            Language::Python => "python",
+            Language::TypeScript => "typescript",
+            Language::JavaScript => "javascript",
+            Language::Go => "go",
+            Language::Java => "java",
+        }
+    }
+
+    // Single quotes are chars or lifetimes in Rust and strings in Python which are handled separately
+    fn has_char_literals(&self) -> bool {
+        matches!(self, Language::Java | Language::Go | Language::TypeScript | Language::JavaScript)
+    }
+
+    // Quotes that may span several lines
+    fn is_multiline_quote(&self, quote: char) -> bool {
+        match self {
+            Language::Rust => quote == '"',
+            Language::TypeScript | Language::JavaScript | Language::Go => quote == '`',
+            _ => false,
+        }
+    }
+}
+
+/// A top level declaration (or the code between declarations) along with where it is in the file
+#[derive(Clone, Debug)]
+pub struct CodeBlock {
+    pub symbol: Option<String>,
+    pub kind: String,
+    pub text: String,
+    pub start_index: usize,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+struct Declaration {
+    kind: String,
+    symbol: String,
+}
+
+fn match_declaration(language: Language, line: &str) -> Option<Declaration> {
+    let declaration = |kind: &str, symbol: &str| {
+        Some(Declaration {
+            kind: kind.to_string(),
+            symbol: symbol.to_string(),
+        })
+    };
+    match language {
+        Language::Rust => {
+            if let Some(captures) = RUST_IMPL.captures(line) {
+                return declaration("impl", captures[1].trim());
+            }
+            let captures = RUST_ITEM.captures(line)?;
+            declaration(captures[1].trim_end_matches('!'), &captures[2])
+        }
+        Language::Python => {
+            let captures = PYTHON_ITEM.captures(line)?;
+            declaration(&captures[1], &captures[2])
+        }
+        Language::TypeScript | Language::JavaScript => {
+            if let Some(captures) = SCRIPT_ITEM.captures(line) {
+                return declaration(captures[1].trim_end_matches('*'), &captures[2]);
+            }
+            let captures = SCRIPT_FUNCTION_VARIABLE.captures(line)?;
+            declaration("function", &captures[1])
+        }
+        Language::Go => {
+            if let Some(captures) = GO_FUNC.captures(line) {
+                return match captures.get(1) {
+                    Some(receiver) => declaration(
+                        "method",
+                        &format!("{}.{}", receiver.as_str(), &captures[2]),
+                    ),
+                    None => declaration("func", &captures[2]),
+                };
+            }
+            let captures = GO_TYPE.captures(line)?;
+            declaration("type", &captures[1])
+        }
+        Language::Java => {
+            let captures = JAVA_ITEM.captures(line)?;
+            declaration(&captures[1], &captures[2])
+        }
+    }
+}
+
+// Comments, attributes and decorators that belong to the declaration below them
+fn is_leading_line(language: Language, line: &str) -> bool {
+    let line = line.trim_start();
+    match language {
+        Language::Python => line.starts_with('#') || line.starts_with('@'),
+        Language::Rust => {
+            line.starts_with("//")
+                || line.starts_with("/*")
+                || line.starts_with('*')
+                || (line.starts_with("#[") && !line.starts_with("#!["))
+        }
+        _ => {
+            line.starts_with("//")
+                || line.starts_with("/*")
+                || line.starts_with('*')
+                || line.starts_with('@')
+        }
+    }
+}
+
+/// Tracks whether the scanner is inside a comment, a string or a block as it walks the file
+#[derive(Default)]
+struct ScanState {
+    depth: i64,
+    in_block_comment: bool,
+    string_quote: Option<char>,
+    // Rust only, the number of `#`s around the raw string we are in
+    raw_string_hashes: Option<usize>,
+    // Python only, the delimiter of the triple quoted string we are in
+    triple_quote: Option<&'static str>,
+}
+
+impl ScanState {
+    fn at_top_level(&self) -> bool {
+        self.depth <= 0
+            && !self.in_block_comment
+            && self.string_quote.is_none()
+            && self.raw_string_hashes.is_none()
+            && self.triple_quote.is_none()
+    }
+
+    fn scan_brace_line(&mut self, language: Language, line: &str) {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            if self.in_block_comment {
+                if c == '*' && next == Some('/') {
+                    self.in_block_comment = false;
+                    i += 1;
+                }
+            } else if let Some(quote) = self.string_quote {
+                if c == '\\' {
+                    i += 1;
+                } else if c == quote {
+                    self.string_quote = None;
+                }
+            } else if let Some(hashes) = self.raw_string_hashes {
+                if c == '"' && closes_raw_string(&chars[i + 1..], hashes) {
+                    self.raw_string_hashes = None;
+                    i += hashes;
+                }
+            } else if c == '/' && next == Some('/') {
+                break;
+            } else if c == '/' && next == Some('*') {
+                self.in_block_comment = true;
+                i += 1;
+            } else if language == Language::Rust && c == '\'' {
+                // `'{'` and `'\''` are chars, `'a` is a lifetime or a label and runs on as code
+                if let Some(end) = rust_char_literal_end(&chars, i) {
+                    i = end;
+                }
+            } else if let Some(hashes) = raw_string_opening(language, &chars, i) {
+                self.raw_string_hashes = Some(hashes);
+                // past the `r`, the `#`s and the opening quote
+                i += hashes + 1;
+            } else if c == '"' || c == '`' || (c == '\'' && language.has_char_literals()) {
+                self.string_quote = Some(c);
+            } else if c == '{' {
+                self.depth += 1;
+            } else if c == '}' {
+                self.depth -= 1;
+            }
+            i += 1;
+        }
+        if let Some(quote) = self.string_quote {
+            if !language.is_multiline_quote(quote) {
+                self.string_quote = None;
+            }
+        }
+    }
+
+    fn scan_python_line(&mut self, line: &str) {
+        let mut rest = line;
+        loop {
+            match self.triple_quote {
+                Some(delimiter) => match rest.find(delimiter) {
+                    Some(position) => {
+                        self.triple_quote = None;
+                        rest = &rest[position + 3..];
+                    }
+                    None => return,
+                },
+                None => {
+                    let next = ["\"\"\"", "'''"]
+                        .iter()
+                        .filter_map(|delimiter| rest.find(delimiter).map(|p| (p, *delimiter)))
+                        .min_by_key(|(position, _)| *position);
+                    match next {
+                        // a `#` before the quotes means they are in a comment
+                        Some((position, delimiter)) if !rest[..position].contains('#') => {
+                            self.triple_quote = Some(delimiter);
+                            rest = &rest[position + 3..];
+                        }
+                        _ => return,
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// The index of the closing quote of the Rust char literal opening at `start`, if it is one
+fn rust_char_literal_end(chars: &[char], start: usize) -> Option<usize> {
+    match chars.get(start + 1)? {
+        '\\' => {
+            // the escaped character may itself be a quote, `'\''`, so the closing quote comes after it
+            let closing = chars[(start + 3).min(chars.len())..].iter().position(|c| *c == '\'')?;
+            Some(start + 3 + closing)
+        }
+        '\'' => None,
+        _ if chars.get(start + 2) == Some(&'\'') => Some(start + 2),
+        _ => None,
+    }
+}
+
+// The number of `#`s of the Rust raw string (`r"…"`, `r#"…"#`, `br#"…"#`) opening at `start`
+fn raw_string_opening(language: Language, chars: &[char], start: usize) -> Option<usize> {
+    if language != Language::Rust || chars[start] != 'r' {
+        return None;
+    }
+    // the `r` must start a token, possibly after the `b` of a byte string
+    let before = match start.checked_sub(1).map(|i| chars[i]) {
+        Some('b') => start.checked_sub(2).map(|i| chars[i]),
+        before => before,
+    };
+    if before.is_some_and(is_identifier_char) {
+        return None;
+    }
+    let hashes = chars[start + 1..].iter().take_while(|c| **c == '#').count();
+    match chars.get(start + 1 + hashes) {
+        Some('"') => Some(hashes),
+        _ => None,
+    }
+}
+
+fn closes_raw_string(after_quote: &[char], hashes: usize) -> bool {
+    after_quote.len() >= hashes && after_quote[..hashes].iter().all(|c| *c == '#')
+}
+
+///
+///
+/// # Arguments
+///
+/// * `text`: The source code
+/// * `language`: The language the source is written in
+///
+/// Splits source into blocks at each top level declaration. Code before the first declaration
+/// (imports, module docs) is its own block without a symbol. Line numbers are 1 based and inclusive.
+///
+/// returns: Vec<CodeBlock>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn split_code(text: &str, language: Language) -> Vec<CodeBlock> {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut line_offsets = Vec::with_capacity(lines.len());
+    let mut offset = 0;
+    for line in &lines {
+        line_offsets.push(offset);
+        offset += line.len();
+    }
+
+    // (first line of the block, declaration that starts it)
+    let mut boundaries: Vec<(usize, Option<Declaration>)> = vec![(0, None)];
+    let mut state = ScanState::default();
+    for (line_number, line) in lines.iter().enumerate() {
+        let top_level = match language {
+            Language::Python => {
+                state.at_top_level() && !line.starts_with(char::is_whitespace)
+            }
+            _ => state.at_top_level(),
+        };
+        if top_level {
+            if let Some(declaration) = match_declaration(language, line) {
+                let mut start = line_number;
+                while start > 0 && is_leading_line(language, lines[start - 1]) {
+                    start -= 1;
+                }
+                if boundaries.len() == 1 && boundaries[0].1.is_none() && start == 0 {
+                    // nothing but comments before the first declaration, they belong to it
+                    boundaries[0] = (0, Some(declaration));
+                } else {
+                    // never reach back into the previous block
+                    let previous_start = boundaries.last().map_or(0, |(s, _)| *s);
+                    boundaries.push((start.max(previous_start + 1), Some(declaration)));
+                }
+            }
+        }
+        match language {
+            Language::Python => state.scan_python_line(line),
+            _ => state.scan_brace_line(language, line),
+        }
+    }
+
+    let mut blocks = vec![];
+    for (i, (start_line, declaration)) in boundaries.iter().enumerate() {
+        let end_line = boundaries.get(i + 1).map_or(lines.len(), |(s, _)| *s);
+        if *start_line >= end_line {
+            continue;
+        }
+        let start_index = line_offsets[*start_line];
+        let end_index = line_offsets.get(end_line).copied().unwrap_or(text.len());
+        let block_text = text[start_index..end_index].trim_end();
+        if block_text.trim().is_empty() {
+            continue;
+        }
+        let (kind, symbol) = match declaration {
+            Some(d) => (d.kind.clone(), Some(d.symbol.clone())),
+            None => ("module".to_string(), None),
+        };
+        blocks.push(CodeBlock {
+            symbol,
+            kind,
+            text: block_text.to_string(),
+            start_index,
+            start_line: start_line + 1,
+            end_line: *start_line + block_text.lines().count(),
+        });
+    }
+    blocks
+}
+
+// Splits blocks that are too large for the model along line and word boundaries
+fn split_oversized_blocks(
+    blocks: Vec<CodeBlock>,
+    splitter: &RecursiveTextSplitter,
+    max_tokens: usize,
+    token_counter: &TokenCounter,
+) -> Vec<CodeBlock> {
+    let mut results = vec![];
+    for block in blocks {
+        if token_counter.count(&block.text) <= max_tokens {
+            results.push(block);
+            continue;
+        }
+        for part in splitter.split_text(&block.text) {
+            let lines_before = block.text[..part.start_index].matches('\n').count();
+            results.push(CodeBlock {
+                symbol: block.symbol.clone(),
+                kind: block.kind.clone(),
+                start_index: block.start_index + part.start_index,
+                start_line: block.start_line + lines_before,
+                end_line: block.start_line + lines_before + part.text.matches('\n').count(),
+                text: part.text,
+            });
+        }
+    }
+    results
+}
+
+///
+///
+/// # Arguments
+///
+/// * `text`: The source code
+/// * `metadata`: Metadata copied onto every chunk. The language is taken from its `language` key if
+///   present, otherwise from the extension of the `document name`
+/// * `chunk_size`: Blocks larger than this are split further
+/// * `embedding_provider`: The provider used to size the chunks
+///
+/// Falls back to the recursive splitter when the language can not be determined.
+///
+/// returns: Result<Vec<Document>, Error> The chunks, not yet embedded
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn chunk_code(
+    text: String,
+    metadata: Option<HashMap<String, String>>,
+    chunk_size: ChunkSize,
+    embedding_provider: Arc<EmbeddingProviders>,
+) -> Result<Vec<Document>> {
+    let language = metadata.as_ref().and_then(|m| {
+        m.get("language")
+            .and_then(|l| Language::from_name(l))
+            .or_else(|| m.get("document name").and_then(|n| Language::from_file_name(n)))
+    });
+    let Some(language) = language else {
+        println!("Could not determine the language of the source file, falling back to recursive chunking");
+        return chunk_recursively(text, metadata, chunk_size, embedding_provider).await;
+    };
+    let token_counter = embedding_provider.token_counter().await?;
+    let max_tokens = chunk_size
+        .max_tokens
+        .min(embedding_provider.max_input_tokens());
+    let splitter = RecursiveTextSplitter::new(max_tokens, chunk_size.overlap_tokens, token_counter.clone());
+    let blocks = task::spawn_blocking(move || {
+        let blocks = split_code(&text, language);
+        split_oversized_blocks(blocks, &splitter, max_tokens, &token_counter)
+    })
+        .await?;
+    let documents = blocks
+        .into_iter()
+        .map(|block| {
+            let mut chunk_metadata = metadata.clone().unwrap_or_default();
+            chunk_metadata.insert("language".to_string(), language.as_str().to_string());
+            if let Some(symbol) = &block.symbol {
+                chunk_metadata.insert("symbol".to_string(), symbol.to_string());
+            }
+            chunk_metadata.insert("symbol_kind".to_string(), block.kind.clone());
+            chunk_metadata.insert("start_line".to_string(), block.start_line.to_string());
+            chunk_metadata.insert("end_line".to_string(), block.end_line.to_string());
+            chunk_metadata.insert("start_index".to_string(), block.start_index.to_string());
+            chunk_metadata.insert(
+                "end_index".to_string(),
+                (block.start_index + block.text.len()).to_string(),
+            );
+            chunk_metadata.insert("page_content".to_string(), block.text.clone());
+            Document::new(block.text, Some(chunk_metadata), None)
+        })
+        .collect();
+    Ok(documents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols(text: &str, language: Language) -> Vec<Option<String>> {
+        split_code(text, language)
+            .into_iter()
+            .map(|block| block.symbol)
+            .collect()
+    }
+
+    fn some(names: &[&str]) -> Vec<Option<String>> {
+        names.iter().map(|name| Some(name.to_string())).collect()
+    }
+
+    #[test]
+    fn rust_char_literals_do_not_open_blocks() {
+        let text = "fn a(c: char)->bool{ c=='{' }\n\nfn b(){}\n\nstruct C;";
+        assert_eq!(symbols(text, Language::Rust), some(&["a", "b", "C"]));
+    }
+
+    #[test]
+    fn rust_quote_chars_do_not_open_strings() {
+        let text = "fn a() -> char { '\"' }\n\nfn b() -> char { '\\'' }\n\nfn c() -> char { '\\u{7B}' }\n\nfn d() {}\n";
+        assert_eq!(symbols(text, Language::Rust), some(&["a", "b", "c", "d"]));
+    }
+
+    #[test]
+    fn rust_lifetimes_are_not_chars() {
+        let text = "fn a<'a>(x: &'a str) -> &'a str {\n    'outer: loop { break 'outer; }\n    x\n}\n\nfn b() {}\n";
+        assert_eq!(symbols(text, Language::Rust), some(&["a", "b"]));
+    }
+
+    #[test]
+    fn rust_raw_strings_are_skipped() {
+        let text = "fn a() -> &'static str {\n    r#\"{ \"quoted\" }\n}\n\"#\n}\n\nfn b() -> &'static [u8] {\n    br\"{\\\"\n}\n\nfn c() {}\n";
+        let blocks = split_code(text, Language::Rust);
+        let names: Vec<Option<String>> = blocks.iter().map(|block| block.symbol.clone()).collect();
+        assert_eq!(names, some(&["a", "b", "c"]));
+        assert_eq!(blocks[0].start_line, 1);
+        assert_eq!(blocks[0].end_line, 5);
+    }
+
+    #[test]
+    fn raw_identifiers_are_not_raw_strings() {
+        let text = "fn a() { let r#type = 1; }\n\nfn b() {}\n";
+        assert_eq!(symbols(text, Language::Rust), some(&["a", "b"]));
+    }
+
+    #[test]
+    fn multiline_strings_hide_braces() {
+        let text = "fn a() {\n    let s = \"{\n    \";\n}\n\nfn b() {}\n";
+        assert_eq!(symbols(text, Language::Rust), some(&["a", "b"]));
+    }
+
+    #[test]
+    fn attributes_and_comments_stay_with_their_declaration() {
+        let text = "use std::fmt;\n\n/// Docs\n#[derive(Debug)]\nstruct A;\n";
+        let blocks = split_code(text, Language::Rust);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].symbol, None);
+        assert_eq!(blocks[1].symbol.as_deref(), Some("A"));
+        assert!(blocks[1].text.starts_with("/// Docs"));
+    }
+
+    #[test]
+    fn java_char_literals_are_skipped() {
+        let text = "class A {\n    char c = '{';\n}\n\nclass B {}\n";
+        assert_eq!(symbols(text, Language::Java), some(&["A", "B"]));
+    }
+
+    #[test]
+    fn python_splits_at_top_level_definitions() {
+        let text = "def a():\n    \"\"\"\ndef not_a_function():\n\"\"\"\n    return 1\n\nclass B:\n    def c(self):\n        pass\n";
+        assert_eq!(symbols(text, Language::Python), some(&["a", "B"]));
+    }
+}
@@ -0,0 +1,333 @@
+//! Recording the progress of ingestion in Mongo.
+//!
+//! A job is recorded for every file, archive and crawl message and for every batch of Airbyte
+//! records, so that the webapp can show what is being ingested, how far along it is and why it
+//! failed. Records arrive as messages of their own, so the records of a datasource processed one
+//! after another make up a batch. A batch is done once none of its records are being processed,
+//! and it takes more records again if they arrive within the idle timeout. Failing to record
+//! progress is logged but never fails ingestion.
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bson::DateTime;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Document};
+use mongodb::Database;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::mongo::models::{IngestionJob, JobKind, JobState};
+use crate::mongo::queries::{get_embedding_model, insert_ingestion_job, update_ingestion_job};
+use crate::rabbitmq::delivery::Outcome;
+
+// Only the latest errors are kept so that a batch with many failing records stays small
+const MAX_JOB_ERRORS: i32 = 20;
+
+/// Records the progress of one ingestion job
+#[derive(Clone)]
+pub struct JobTracker {
+    mongo_conn: Arc<RwLock<Database>>,
+    id: ObjectId,
+}
+
+impl JobTracker {
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `mongo_conn`: The Mongo database jobs are recorded in
+    /// * `datasource_id`: The datasource being ingested into
+    /// * `kind`: What is being ingested, which may change once the message has been read
+    /// * `stream_offset`: The offset of the message in the stream, if it came from one
+    ///
+    /// Records a new job in the queued state.
+    ///
+    /// returns: JobTracker
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn create(
+        mongo_conn: Arc<RwLock<Database>>,
+        datasource_id: &str,
+        kind: JobKind,
+        stream_offset: Option<i64>,
+    ) -> Self {
+        let tracker = JobTracker {
+            mongo_conn,
+            id: ObjectId::new(),
+        };
+        let Ok(datasource_id) = ObjectId::from_str(datasource_id) else {
+            println!("Not recording an ingestion job for invalid datasource ID {}", datasource_id);
+            return tracker;
+        };
+        let job = IngestionJob {
+            _id: tracker.id,
+            datasourceId: datasource_id,
+            kind,
+            name: None,
+            state: JobState::Queued,
+            model: None,
+            streamOffset: stream_offset,
+            attempts: 0,
+            chunkCount: 0,
+            embeddedCount: 0,
+            failedCount: 0,
+            errors: vec![],
+            createdDate: DateTime::now(),
+            startedDate: None,
+            updatedDate: None,
+            finishedDate: None,
+        };
+        {
+            let mongodb_connection = tracker.mongo_conn.read().await;
+            if let Err(e) = insert_ingestion_job(&mongodb_connection, &job).await {
+                println!("{}", e);
+            }
+        }
+        tracker
+    }
+
+    pub fn id(&self) -> ObjectId {
+        self.id
+    }
+
+    /// Records what is being ingested and the model it is embedded with
+    pub async fn describe(&self, kind: JobKind, name: Option<&str>, model: &str) {
+        self.update(doc! {"$set": {"kind": kind.as_str(), "name": name, "model": model}})
+            .await;
+    }
+
+    /// Records the start of an attempt. The chunks counted by the attempt before, and those that
+    /// failed, are counted again.
+    pub async fn attempt(&self, attempt: u32) {
+        let mut set = doc! {
+            "state": JobState::Processing.as_str(),
+            "attempts": attempt as i32,
+            "chunkCount": 0_i64,
+            "failedCount": 0_i64,
+        };
+        if attempt <= 1 {
+            set.insert("startedDate", DateTime::now());
+        }
+        self.update(doc! {"$set": set}).await;
+    }
+
+    /// Records the chunks of a document, how many of them were embedded and uploaded and how many
+    /// could not be embedded
+    pub async fn add_chunks(&self, chunks: usize, embedded: usize, failed: usize) {
+        self.update(doc! {"$inc": {
+            "chunkCount": chunks as i64,
+            "embeddedCount": embedded as i64,
+            "failedCount": failed as i64,
+        }})
+            .await;
+    }
+
+    /// Records an attempt that failed and is going to be retried
+    pub async fn retrying(&self, reason: &str) {
+        let mut update = doc! {"$set": {"state": JobState::Retrying.as_str()}};
+        update.extend(push_error(reason));
+        self.update(update).await;
+    }
+
+    /// Records how the job ended, and why if it failed
+    pub async fn finish(&self, outcome: &Outcome) {
+        let state = match outcome {
+            Outcome::Done => JobState::Done,
+            Outcome::Retry(_) | Outcome::Reject(_) => JobState::Failed,
+        };
+        let mut update = doc! {"$set": {"state": state.as_str(), "finishedDate": DateTime::now()}};
+        if let Outcome::Retry(reason) | Outcome::Reject(reason) = outcome {
+            update.extend(push_error(reason));
+        }
+        self.update(update).await;
+    }
+
+    async fn update(&self, mut update: Document) {
+        match update.get_document_mut("$set") {
+            Ok(set) => {
+                set.insert("updatedDate", DateTime::now());
+            }
+            Err(_) => {
+                update.insert("$set", doc! {"updatedDate": DateTime::now()});
+            }
+        }
+        let mongodb_connection = self.mongo_conn.read().await;
+        if let Err(e) = update_ingestion_job(&mongodb_connection, self.id, update).await {
+            println!("{}", e);
+        }
+    }
+}
+
+/// A job being processed by a consumer. If the consumer is dropped before the job has finished,
+/// such as when the connection to rabbit is lost, the job is recorded as failed, as the message is
+/// then delivered again and ingested as a new job.
+pub struct UnfinishedJob(Option<JobTracker>);
+
+impl UnfinishedJob {
+    pub fn new(job: JobTracker) -> Self {
+        UnfinishedJob(Some(job))
+    }
+
+    /// Records how the job ended, and why if it failed
+    pub async fn finish(mut self, outcome: &Outcome) {
+        if let Some(job) = self.0.take() {
+            job.finish(outcome).await;
+        }
+    }
+}
+
+impl Drop for UnfinishedJob {
+    fn drop(&mut self) {
+        let Some(job) = self.0.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        runtime.spawn(async move {
+            let reason = "The job was interrupted before it finished, the message will be delivered again";
+            job.finish(&Outcome::Retry(reason.to_string())).await;
+        });
+    }
+}
+
+fn push_error(reason: &str) -> Document {
+    doc! {"$push": {"errors": {"$each": [reason], "$slice": -MAX_JOB_ERRORS}}}
+}
+
+/// The records of a datasource being processed one after another
+struct Batch {
+    job: JobTracker,
+    in_flight: usize,
+    ingested: u64,
+    failed: u64,
+    idle_since: Instant,
+}
+
+/// The open batches of Airbyte records, one per datasource
+pub struct RecordBatches {
+    mongo_conn: Arc<RwLock<Database>>,
+    idle_timeout: Duration,
+    open: Mutex<HashMap<String, Batch>>,
+}
+
+impl RecordBatches {
+    pub async fn new(mongo_conn: Arc<RwLock<Database>>) -> Self {
+        let idle_timeout = GLOBAL_DATA.read().await.record_batch_idle_timeout;
+        RecordBatches {
+            mongo_conn,
+            idle_timeout: Duration::from_secs(idle_timeout),
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `datasource_id`: The datasource the record was synced to
+    ///
+    /// Adds a record to the datasource's batch, starting a new batch if the last one has been idle
+    /// for longer than the idle timeout. Every record joined must be settled with `settled`.
+    ///
+    /// returns: ()
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn join(&self, datasource_id: &str) {
+        // held while a new job is created so that concurrent records don't start a batch each
+        let mut open = self.open.lock().await;
+        if let Some(batch) = open.get_mut(datasource_id) {
+            if batch.in_flight > 0 {
+                batch.in_flight += 1;
+                return;
+            }
+            if batch.idle_since.elapsed() < self.idle_timeout {
+                batch.in_flight = 1;
+                batch
+                    .job
+                    .update(doc! {"$set": {"state": JobState::Processing.as_str(), "finishedDate": null}})
+                    .await;
+                return;
+            }
+        }
+        let job = JobTracker::create(Arc::clone(&self.mongo_conn), datasource_id, JobKind::Records, None).await;
+        let model = {
+            let mongodb_connection = self.mongo_conn.read().await;
+            get_embedding_model(&mongodb_connection, datasource_id).await
+        };
+        match model {
+            Ok(Some(model)) => job.describe(JobKind::Records, None, model.model.as_str()).await,
+            Ok(None) => {}
+            Err(e) => println!("Could not look up the embedding model of the record batch. Error: {}", e),
+        }
+        job.attempt(1).await;
+        println!("Started ingestion job {} for the records of datasource {}", job.id(), datasource_id);
+        open.insert(
+            datasource_id.to_string(),
+            Batch {
+                job,
+                in_flight: 1,
+                ingested: 0,
+                failed: 0,
+                idle_since: Instant::now(),
+            },
+        );
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `datasource_id`: The datasource the record was synced to
+    /// * `outcome`: How processing the record ended
+    ///
+    /// Counts the record in its batch. Once no record of the batch is being processed the batch is
+    /// done, or failed if none of its records could be ingested.
+    ///
+    /// returns: ()
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn settled(&self, datasource_id: &str, outcome: &Outcome) {
+        let mut open = self.open.lock().await;
+        let Some(batch) = open.get_mut(datasource_id) else {
+            return;
+        };
+        let mut update = match outcome {
+            Outcome::Done => {
+                batch.ingested += 1;
+                doc! {"$inc": {"chunkCount": 1_i64, "embeddedCount": 1_i64}}
+            }
+            Outcome::Retry(reason) | Outcome::Reject(reason) => {
+                batch.failed += 1;
+                let mut update = doc! {"$inc": {"chunkCount": 1_i64, "failedCount": 1_i64}};
+                update.extend(push_error(reason));
+                update
+            }
+        };
+        batch.in_flight = batch.in_flight.saturating_sub(1);
+        if batch.in_flight == 0 {
+            batch.idle_since = Instant::now();
+            let state = if batch.ingested == 0 && batch.failed > 0 {
+                JobState::Failed
+            } else {
+                JobState::Done
+            };
+            update.insert("$set", doc! {"state": state.as_str(), "finishedDate": DateTime::now()});
+        }
+        batch.job.update(update).await;
+    }
+}
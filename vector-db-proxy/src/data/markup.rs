@@ -0,0 +1,583 @@
+//! Markdown and HTML extraction.
+//!
+//! Both formats are reduced to plain text in which headings are kept as Markdown ATX headings
+//! (`## Title`) and code as fenced blocks. Inline markup and page boilerplate (scripts, navigation,
+//! footers...) are dropped. The heading lines are what `sections` later uses to cut the document by
+//! heading hierarchy and build the `headings` breadcrumb for each chunk.
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// Metadata value marking extracted text as containing Markdown headings
+pub const MARKDOWN_CONTENT_FORMAT: &str = "markdown";
+
+static ATX_HEADING: Lazy<Regex> = Lazy::new(|| Regex::new(r"^ {0,3}(#{1,6})(?:[ \t]+(.*?))?(?:[ \t]+#+)?[ \t]*$").unwrap());
+static SETEXT_UNDERLINE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^ {0,3}(=+|-+)[ \t]*$").unwrap());
+static IMAGE: Lazy<Regex> = Lazy::new(|| Regex::new(r"!\[([^\]]*)\]\([^)]*\)").unwrap());
+static LINK: Lazy<Regex> = Lazy::new(|| Regex::new(r"\[([^\]]+)\](?:\([^)]*\)|\[[^\]]*\])").unwrap());
+static LINK_DEFINITION: Lazy<Regex> = Lazy::new(|| Regex::new(r"^ {0,3}\[[^\]]+\]:\s+\S+").unwrap());
+static STRONG: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\*\*|__)(\S(?:.*?\S)?)(\*\*|__)").unwrap());
+static EMPHASIS: Lazy<Regex> = Lazy::new(|| Regex::new(r"(^|[^\w*])\*(\S(?:[^*]*?\S)?)\*").unwrap());
+static STRIKETHROUGH: Lazy<Regex> = Lazy::new(|| Regex::new(r"~~(.+?)~~").unwrap());
+static INLINE_CODE: Lazy<Regex> = Lazy::new(|| Regex::new(r"`([^`]+)`").unwrap());
+static INLINE_HTML: Lazy<Regex> = Lazy::new(|| Regex::new(r"</?[A-Za-z][^>]*>").unwrap());
+static BLANK_LINES: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n{3,}").unwrap());
+static ANCHOR_HREF: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?i)<a\s[^>]*?\bhref\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#).unwrap());
+
+// Elements whose content is never part of the document text
+const SKIPPED_ELEMENTS: [&str; 10] = [
+    "script", "style", "noscript", "template", "svg", "iframe", "nav", "footer", "aside", "form",
+];
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+const BLOCK_ELEMENTS: [&str; 24] = [
+    "p", "div", "section", "article", "main", "header", "ul", "ol", "table", "thead", "tbody",
+    "tr", "blockquote", "dl", "dt", "dd", "figure", "figcaption", "details", "summary", "address",
+    "fieldset", "caption", "hr",
+];
+
+/// A part of a document under one heading
+#[derive(Clone, Debug)]
+pub struct Section {
+    /// The heading path leading to this section, outermost first
+    pub headings: Vec<String>,
+    pub text: String,
+    /// Byte offset of the section in the extracted text
+    pub start_index: usize,
+}
+
+fn is_fence(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+fn strip_inline_markdown(line: &str) -> String {
+    let line = IMAGE.replace_all(line, "$1");
+    let line = LINK.replace_all(&line, "$1");
+    let line = INLINE_CODE.replace_all(&line, "$1");
+    let line = STRONG.replace_all(&line, "$2");
+    let line = EMPHASIS.replace_all(&line, "$1$2");
+    let line = STRIKETHROUGH.replace_all(&line, "$1");
+    let line = INLINE_HTML.replace_all(&line, "");
+    decode_entities(&line)
+}
+
+///
+///
+/// # Arguments
+///
+/// * `markdown`: The Markdown source
+///
+/// Removes front matter, link definitions and inline markup. Setext headings are rewritten as ATX
+/// headings and fenced code is kept as is.
+///
+/// returns: (String, Option<String>) the text and the document title (from front matter or the first heading)
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn markdown_to_text(markdown: &str) -> (String, Option<String>) {
+    let mut title = None;
+    let mut lines: Vec<&str> = markdown.lines().collect();
+    // YAML front matter
+    if lines.first().is_some_and(|l| l.trim() == "---") {
+        if let Some(end) = lines.iter().skip(1).position(|l| l.trim() == "---" || l.trim() == "...") {
+            for line in &lines[1..=end] {
+                if let Some(value) = line.strip_prefix("title:") {
+                    title = Some(value.trim().trim_matches(|c| c == '"' || c == '\'').to_string());
+                }
+            }
+            lines.drain(..end + 2);
+        }
+    }
+    let mut output: Vec<String> = vec![];
+    let mut in_fence = false;
+    for (i, line) in lines.iter().enumerate() {
+        if is_fence(line) {
+            in_fence = !in_fence;
+            output.push(line.trim().to_string());
+            continue;
+        }
+        if in_fence {
+            output.push(line.to_string());
+            continue;
+        }
+        if LINK_DEFINITION.is_match(line) {
+            continue;
+        }
+        if let Some(captures) = ATX_HEADING.captures(line) {
+            let heading = strip_inline_markdown(captures.get(2).map_or("", |m| m.as_str()));
+            title.get_or_insert_with(|| heading.clone());
+            output.push(format!("{} {}", &captures[1], heading));
+            continue;
+        }
+        // a setext underline turns the previous paragraph line into a heading
+        if let Some(captures) = SETEXT_UNDERLINE.captures(line) {
+            let previous = if i > 0 { lines[i - 1].trim() } else { "" };
+            if !previous.is_empty() && output.last().is_some_and(|l| !l.starts_with('#')) {
+                let level = if captures[1].starts_with('=') { "#" } else { "##" };
+                let heading = output.pop().unwrap_or_default();
+                title.get_or_insert_with(|| heading.clone());
+                output.push(format!("{} {}", level, heading));
+                continue;
+            }
+        }
+        output.push(strip_inline_markdown(line.trim_end()));
+    }
+    let text = BLANK_LINES.replace_all(output.join("\n").trim(), "\n\n").to_string();
+    (text, title)
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(position) = rest.find('&') {
+        output.push_str(&rest[..position]);
+        rest = &rest[position..];
+        // entities are short, don't go looking for a `;` further than one could be
+        let Some(end) = rest.bytes().take(12).position(|b| b == b';') else {
+            output.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            "ndash" => Some('–'),
+            "mdash" => Some('—'),
+            "hellip" => Some('…'),
+            "copy" => Some('©'),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                output.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+struct HtmlTag<'a> {
+    name: String,
+    closing: bool,
+    self_closing: bool,
+    attributes: &'a str,
+}
+
+fn parse_tag(tag: &str) -> Option<HtmlTag<'_>> {
+    let (closing, inner) = match tag.strip_prefix('/') {
+        Some(inner) => (true, inner),
+        None => (false, tag),
+    };
+    let self_closing = inner.ends_with('/');
+    let inner = inner.trim_end_matches('/');
+    let name_end = inner
+        .find(|c: char| c.is_whitespace())
+        .unwrap_or(inner.len());
+    let name = inner[..name_end].to_lowercase();
+    if name.is_empty() || !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    Some(HtmlTag {
+        name,
+        closing,
+        self_closing,
+        attributes: &inner[name_end..],
+    })
+}
+
+fn heading_level(name: &str) -> Option<usize> {
+    match name {
+        "h1" => Some(1),
+        "h2" => Some(2),
+        "h3" => Some(3),
+        "h4" => Some(4),
+        "h5" => Some(5),
+        "h6" => Some(6),
+        _ => None,
+    }
+}
+
+/// Builds up the extracted text, collapsing whitespace outside of preformatted blocks
+#[derive(Default)]
+struct HtmlTextWriter {
+    output: String,
+    heading: Option<(usize, String)>,
+    title: Option<String>,
+    page_title: String,
+    in_title: bool,
+    pre_depth: usize,
+}
+
+impl HtmlTextWriter {
+    fn push_text(&mut self, text: &str) {
+        let text = decode_entities(text);
+        if self.in_title {
+            self.page_title.push_str(&text);
+            return;
+        }
+        if let Some((_, heading)) = self.heading.as_mut() {
+            heading.push_str(&text);
+            return;
+        }
+        if self.pre_depth > 0 {
+            self.output.push_str(&text);
+            return;
+        }
+        let collapsed = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+        if collapsed.is_empty() {
+            if !text.is_empty() && !self.output.ends_with([' ', '\n']) {
+                self.output.push(' ');
+            }
+            return;
+        }
+        if text.starts_with(char::is_whitespace) && !self.output.ends_with([' ', '\n']) {
+            self.output.push(' ');
+        }
+        self.output.push_str(&collapsed);
+        if text.ends_with(char::is_whitespace) {
+            self.output.push(' ');
+        }
+    }
+
+    fn new_line(&mut self, blank: bool) {
+        while self.output.ends_with(' ') {
+            self.output.pop();
+        }
+        let wanted = if blank { "\n\n" } else { "\n" };
+        if !self.output.is_empty() && !self.output.ends_with(wanted) {
+            if blank && self.output.ends_with('\n') {
+                self.output.push('\n');
+            } else {
+                self.output.push_str(wanted);
+            }
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `html`: The HTML document
+///
+/// Drops scripts, styles, navigation, footers and other boilerplate and turns headings into Markdown
+/// headings and `<pre>` blocks into fenced code.
+///
+/// returns: (String, Option<String>) the text and the document title (from `<title>` or the first heading)
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn html_to_text(html: &str) -> (String, Option<String>) {
+    let mut writer = HtmlTextWriter::default();
+    // (element, nesting depth) of the boilerplate element we are skipping
+    let mut skipping: Option<(String, usize)> = None;
+    let mut rest = html;
+    while !rest.is_empty() {
+        let Some(position) = rest.find('<') else {
+            if skipping.is_none() {
+                writer.push_text(rest);
+            }
+            break;
+        };
+        if skipping.is_none() {
+            writer.push_text(&rest[..position]);
+        }
+        rest = &rest[position..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            if skipping.is_none() {
+                writer.push_text(rest);
+            }
+            break;
+        };
+        let raw_tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        if raw_tag.starts_with('!') || raw_tag.starts_with('?') {
+            continue;
+        }
+        let Some(tag) = parse_tag(raw_tag) else {
+            if skipping.is_none() {
+                writer.push_text(&format!("<{}>", raw_tag));
+            }
+            continue;
+        };
+        if let Some((name, depth)) = skipping.as_mut() {
+            if *name == tag.name && !tag.self_closing {
+                if tag.closing {
+                    *depth -= 1;
+                } else {
+                    *depth += 1;
+                }
+                if *depth == 0 {
+                    skipping = None;
+                }
+            }
+            continue;
+        }
+        let navigation_role = tag.attributes.contains("role=\"navigation\"")
+            || tag.attributes.contains("role='navigation'");
+        if !tag.closing
+            && !tag.self_closing
+            && (SKIPPED_ELEMENTS.contains(&tag.name.as_str()) || navigation_role)
+        {
+            // script and style content is raw text, jump straight to the closing tag
+            if tag.name == "script" || tag.name == "style" {
+                let closing = format!("</{}", tag.name);
+                let close = rest
+                    .as_bytes()
+                    .windows(closing.len())
+                    .position(|window| window.eq_ignore_ascii_case(closing.as_bytes()));
+                match close {
+                    Some(close) => {
+                        rest = &rest[close..];
+                        rest = rest.find('>').map_or("", |e| &rest[e + 1..]);
+                    }
+                    None => rest = "",
+                }
+            } else {
+                skipping = Some((tag.name.clone(), 1));
+            }
+            continue;
+        }
+        if tag.name == "title" {
+            writer.in_title = !tag.closing;
+            continue;
+        }
+        if let Some(level) = heading_level(&tag.name) {
+            if tag.closing {
+                if let Some((level, heading)) = writer.heading.take() {
+                    let heading = heading.split_whitespace().collect::<Vec<&str>>().join(" ");
+                    if !heading.is_empty() {
+                        writer.title.get_or_insert_with(|| heading.clone());
+                        writer.new_line(true);
+                        writer.output.push_str(&format!("{} {}", "#".repeat(level), heading));
+                        writer.new_line(true);
+                    }
+                }
+            } else {
+                writer.heading = Some((level, String::new()));
+            }
+            continue;
+        }
+        match tag.name.as_str() {
+            "pre" => {
+                if tag.closing {
+                    writer.pre_depth = writer.pre_depth.saturating_sub(1);
+                    if !writer.output.ends_with('\n') {
+                        writer.output.push('\n');
+                    }
+                    writer.output.push_str("```");
+                    writer.new_line(true);
+                } else {
+                    writer.new_line(true);
+                    writer.pre_depth += 1;
+                    writer.output.push_str("```\n");
+                }
+            }
+            "br" => writer.new_line(false),
+            "li" if !tag.closing => {
+                writer.new_line(false);
+                writer.output.push_str("- ");
+            }
+            "td" | "th" if !tag.closing => writer.push_text(" "),
+            name if BLOCK_ELEMENTS.contains(&name) => writer.new_line(true),
+            name if VOID_ELEMENTS.contains(&name) => {}
+            _ => {}
+        }
+    }
+    let page_title = writer.page_title.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let title = if page_title.is_empty() { writer.title } else { Some(page_title) };
+    let text: Vec<&str> = writer.output.lines().map(|l| l.trim_end()).collect();
+    let text = BLANK_LINES.replace_all(text.join("\n").trim(), "\n\n").to_string();
+    (text, title)
+}
+
+/// The targets of the links in an HTML page, as written in the page and with entities decoded.
+/// Links inside comments are left out.
+pub fn html_links(html: &str) -> Vec<String> {
+    let mut links = vec![];
+    let mut rest = html;
+    while !rest.is_empty() {
+        let (content, next) = match rest.find("<!--") {
+            Some(start) => {
+                let after = &rest[start + 4..];
+                (&rest[..start], after.find("-->").map_or("", |end| &after[end + 3..]))
+            }
+            None => (rest, ""),
+        };
+        for captures in ANCHOR_HREF.captures_iter(content) {
+            if let Some(href) = captures.get(1).or(captures.get(2)).or(captures.get(3)) {
+                let href = decode_entities(href.as_str().trim());
+                if !href.is_empty() {
+                    links.push(href);
+                }
+            }
+        }
+        rest = next;
+    }
+    links
+}
+
+///
+///
+/// # Arguments
+///
+/// * `text`: Text with Markdown headings, as produced by `markdown_to_text` or `html_to_text`
+///
+/// Cuts the text at every heading. Each section carries the path of headings above it, so a `###`
+/// under a `##` under a `#` gets all three. Headings inside fenced code are ignored.
+///
+/// returns: Vec<Section>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn sections(text: &str) -> Vec<Section> {
+    let mut sections = vec![];
+    let mut heading_stack: Vec<(usize, String)> = vec![];
+    let mut section_start = 0;
+    let mut section_headings: Vec<String> = vec![];
+    let mut in_fence = false;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if is_fence(line) {
+            in_fence = !in_fence;
+        } else if !in_fence {
+            if let Some(captures) = ATX_HEADING.captures(line.trim_end_matches(['\n', '\r'])) {
+                push_section(&mut sections, text, section_start, offset, &section_headings);
+                let level = captures[1].len();
+                let heading = captures.get(2).map_or("", |m| m.as_str()).trim().to_string();
+                while heading_stack.last().is_some_and(|(l, _)| *l >= level) {
+                    heading_stack.pop();
+                }
+                if !heading.is_empty() {
+                    heading_stack.push((level, heading));
+                }
+                section_headings = heading_stack.iter().map(|(_, h)| h.clone()).collect();
+                section_start = offset;
+            }
+        }
+        offset += line.len();
+    }
+    push_section(&mut sections, text, section_start, text.len(), &section_headings);
+    sections
+}
+
+fn push_section(sections: &mut Vec<Section>, text: &str, start: usize, end: usize, headings: &[String]) {
+    let section_text = &text[start..end];
+    let trimmed = section_text.trim();
+    if trimmed.is_empty() {
+        return;
+    }
+    sections.push(Section {
+        headings: headings.to_vec(),
+        text: trimmed.to_string(),
+        start_index: start + (section_text.len() - section_text.trim_start().len()),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breadcrumbs(text: &str) -> Vec<String> {
+        sections(text).iter().map(|section| section.headings.join(" > ")).collect()
+    }
+
+    #[test]
+    fn sections_carry_the_path_of_headings_above_them() {
+        let text = "intro\n# A\na\n## B\nb\n### C\nc\n## D\nd";
+        assert_eq!(breadcrumbs(text), vec!["", "A", "A > B", "A > B > C", "A > D"]);
+        let c = &sections(text)[3];
+        assert_eq!(c.text, "### C\nc");
+        assert_eq!(&text[c.start_index..c.start_index + c.text.len()], c.text);
+    }
+
+    #[test]
+    fn headings_inside_fenced_code_are_ignored() {
+        let text = "# A\n```\n# not a heading\n```\n## B\nb";
+        assert_eq!(breadcrumbs(text), vec!["A", "A > B"]);
+    }
+
+    #[test]
+    fn front_matter_is_removed_and_gives_the_title() {
+        let (text, title) = markdown_to_text("---\ntitle: \"Guide\"\ntags: [a]\n---\n# Heading\nBody");
+        assert_eq!(text, "# Heading\nBody");
+        assert_eq!(title.as_deref(), Some("Guide"));
+    }
+
+    #[test]
+    fn setext_headings_become_atx_headings() {
+        let (text, title) = markdown_to_text("Title\n=====\n\nSub\n---\ntext");
+        assert_eq!(text, "# Title\n\n## Sub\ntext");
+        assert_eq!(title.as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn inline_markdown_is_stripped_outside_of_code() {
+        let (text, _) = markdown_to_text(
+            "Some **bold**, *em* and [a link](http://x) ![alt](i.png) &amp; `code`\n\n[ref]: http://x\n```\n**kept**\n```",
+        );
+        assert_eq!(text, "Some bold, em and a link alt & code\n\n```\n**kept**\n```");
+    }
+
+    #[test]
+    fn boilerplate_elements_are_dropped_from_html() {
+        let html = "<html><head><title>Page</title><script>var x = '<div>';</script><style>p {}</style></head>\
+            <body><nav><ul><li><nav>inner</nav></li><li>Home</li></ul></nav>\
+            <h1>Heading</h1><p>Body &amp; more&#33;</p><footer><div>Footer</div></footer></body></html>";
+        let (text, title) = html_to_text(html);
+        assert_eq!(text, "# Heading\n\nBody & more!");
+        assert_eq!(title.as_deref(), Some("Page"));
+    }
+
+    #[test]
+    fn html_headings_and_preformatted_text_are_kept() {
+        let (text, title) = html_to_text("<h2>Install</h2><pre>cargo  build\n</pre><h3>Then</h3><p>run</p>");
+        assert_eq!(text, "## Install\n\n```\ncargo  build\n```\n\n### Then\n\nrun");
+        assert_eq!(title.as_deref(), Some("Install"));
+    }
+
+    #[test]
+    fn entities_are_decoded() {
+        assert_eq!(decode_entities("a &lt;b&gt; &#x41;&#66; &unknown; & c"), "a <b> AB &unknown; & c");
+    }
+
+    #[test]
+    fn links_in_comments_are_left_out() {
+        let html = r#"<a href="/a?x=1&amp;y=2">a</a><!-- <a href="/hidden"> --><a href='/b'>b</a>"#;
+        assert_eq!(html_links(html), vec!["/a?x=1&y=2", "/b"]);
+    }
+}
@@ -1,5 +1,16 @@
+pub mod archives;
+pub mod bm25;
 pub mod chunking;
+pub mod code_splitting;
+pub mod jobs;
+pub mod markup;
 pub mod models;
+pub mod office;
+pub mod parts;
+pub mod pdf;
 pub mod processing_incoming_messages;
+pub mod recursive_splitting;
+pub mod segments;
+pub mod tabular;
 mod text_splitting;
 pub mod utils;
@@ -35,12 +35,19 @@ impl Hash for Document {
         self.page_content.hash(state);
     }
 }
-#[derive(Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug)]
 pub enum FileType {
     PDF,
     TXT,
     CSV,
+    TSV,
     DOCX,
+    MARKDOWN,
+    HTML,
+    SPREADSHEET,
+    PRESENTATION,
+    ARCHIVE,
     UNKNOWN,
 }
 impl From<String> for FileType {
@@ -48,9 +55,51 @@ impl From<String> for FileType {
         match value.as_str() {
             "pdf" => Self::PDF,
             "txt" => Self::TXT,
+            // source code is read as plain text and split by the CODE_SPLIT strategy
+            "rs" | "py" | "pyi" | "ts" | "tsx" | "mts" | "cts" | "js" | "jsx" | "mjs" | "cjs"
+            | "go" | "java" => Self::TXT,
             "csv" => Self::CSV,
-            "docx" | "pptx" | "xlsx" | "odt" | "ods" | "odp" => Self::DOCX,
+            "tsv" | "tab" => Self::TSV,
+            "md" | "markdown" | "mdx" => Self::MARKDOWN,
+            "html" | "htm" | "xhtml" => Self::HTML,
+            "docx" | "odt" => Self::DOCX,
+            "xlsx" | "ods" => Self::SPREADSHEET,
+            "pptx" | "odp" => Self::PRESENTATION,
+            "zip" | "tar" | "tgz" | "gz" => Self::ARCHIVE,
             _ => Self::UNKNOWN,
         }
     }
 }
+
+impl FileType {
+    /// Maps a MIME type to a file type. Returns `None` for types that say nothing about the
+    /// content, such as `application/octet-stream`, so that the content can be sniffed instead.
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let mime = mime.split(';').next().unwrap_or_default().trim().to_lowercase();
+        match mime.as_str() {
+            "application/pdf" => Some(Self::PDF),
+            "text/plain" => Some(Self::TXT),
+            "text/csv" | "application/csv" => Some(Self::CSV),
+            "text/tab-separated-values" => Some(Self::TSV),
+            "text/markdown" | "text/x-markdown" => Some(Self::MARKDOWN),
+            "text/html" | "application/xhtml+xml" => Some(Self::HTML),
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+            | "application/vnd.oasis.opendocument.text" => Some(Self::DOCX),
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            | "application/vnd.oasis.opendocument.spreadsheet" => Some(Self::SPREADSHEET),
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation"
+            | "application/vnd.oasis.opendocument.presentation" => Some(Self::PRESENTATION),
+            "application/zip" | "application/x-zip-compressed" | "application/x-tar" | "application/gzip"
+            | "application/x-gzip" | "application/x-compressed-tar" => Some(Self::ARCHIVE),
+            _ => None,
+        }
+    }
+
+    /// Whether the type is read as text, as opposed to a binary container format
+    pub fn is_text(&self) -> bool {
+        matches!(
+            self,
+            Self::TXT | Self::CSV | Self::TSV | Self::MARKDOWN | Self::HTML
+        )
+    }
+}
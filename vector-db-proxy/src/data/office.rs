@@ -0,0 +1,834 @@
+//! Spreadsheet (xlsx, ods) and presentation (pptx, odp) extraction.
+//!
+//! Both the Office Open XML and the OpenDocument formats are zip archives of XML parts. The parts
+//! are read into a small element tree from which the sheets and slides are taken, keeping their
+//! names, titles and speaker notes so that they can be stored alongside the chunks.
+use anyhow::{anyhow, Result};
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use zip::ZipArchive;
+
+use crate::data::segments::{segmented_document, Segment, SegmentContent};
+
+// Guards against sheets that mark a cell or row as repeated across the entire sheet
+const MAX_REPEATED_CELLS: usize = 1024;
+const MAX_REPEATED_ROWS: usize = 1024;
+// Placeholders holding the slide number, date, header and footer are the same on every slide
+const OOXML_SKIPPED_PLACEHOLDERS: [&str; 4] = ["sldNum", "dt", "ftr", "hdr"];
+const ODF_SKIPPED_CLASSES: [&str; 4] = ["page-number", "date-time", "footer", "header"];
+const CELL_SEPARATOR: &str = " | ";
+// The XML decompressed from a document is capped, as a part and in total, so that a small zip bomb
+// disguised as a spreadsheet or presentation can not exhaust memory
+const MAX_PART_BYTES: u64 = 64 * 1024 * 1024;
+const MAX_DOCUMENT_BYTES: u64 = 256 * 1024 * 1024;
+
+enum XmlNode {
+    Element(XmlElement),
+    Text(String),
+}
+
+struct XmlElement {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<XmlNode>,
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+impl XmlElement {
+    fn new(name: String, attributes: Vec<(String, String)>) -> Self {
+        XmlElement {
+            name,
+            attributes,
+            children: vec![],
+        }
+    }
+
+    fn is(&self, name: &str) -> bool {
+        local_name(&self.name) == name
+    }
+
+    /// Looks an attribute up by its name without the namespace prefix
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| local_name(key) == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Looks up the relationship ID (`r:id`), which clashes with a plain `id` on some elements
+    fn relationship_id(&self) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key.contains(':') && local_name(key) == "id")
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn children(&self) -> impl Iterator<Item = &XmlElement> {
+        self.children.iter().filter_map(|child| match child {
+            XmlNode::Element(element) => Some(element),
+            XmlNode::Text(_) => None,
+        })
+    }
+
+    fn child(&self, name: &str) -> Option<&XmlElement> {
+        self.children().find(|child| child.is(name))
+    }
+
+    fn descendants<'a>(&'a self, name: &str, found: &mut Vec<&'a XmlElement>) {
+        for child in self.children() {
+            if child.is(name) {
+                found.push(child);
+            } else {
+                child.descendants(name, found);
+            }
+        }
+    }
+
+    fn find_all(&self, name: &str) -> Vec<&XmlElement> {
+        let mut found = vec![];
+        self.descendants(name, &mut found);
+        found
+    }
+
+    /// The text of the element and everything in it, turning the elements that stand for
+    /// whitespace into the whitespace itself
+    fn text(&self) -> String {
+        let mut text = String::new();
+        self.collect_text(&mut text);
+        text
+    }
+
+    fn collect_text(&self, text: &mut String) {
+        for child in self.children.iter() {
+            match child {
+                XmlNode::Text(t) => text.push_str(t),
+                XmlNode::Element(element) => match local_name(&element.name) {
+                    // phonetic guides for East Asian text are not part of the text itself
+                    "rPh" => {}
+                    "s" => {
+                        let count = element.attribute("c").and_then(|c| c.parse().ok()).unwrap_or(1);
+                        text.push_str(&" ".repeat(count));
+                    }
+                    "tab" => text.push('\t'),
+                    "br" | "line-break" => text.push('\n'),
+                    _ => element.collect_text(text),
+                },
+            }
+        }
+    }
+}
+
+fn parse_xml(xml: &str) -> Result<XmlElement> {
+    let mut reader = Reader::from_str(xml);
+    reader.expand_empty_elements(true);
+    let mut stack = vec![XmlElement::new(String::new(), vec![])];
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => {
+                let attributes = e
+                    .attributes()
+                    .filter_map(|attribute| attribute.ok())
+                    .map(|attribute| {
+                        let value = attribute
+                            .unescaped_value()
+                            .map(|v| String::from_utf8_lossy(&v).into_owned())
+                            .unwrap_or_else(|_| String::from_utf8_lossy(attribute.value).into_owned());
+                        (String::from_utf8_lossy(attribute.key).into_owned(), value)
+                    })
+                    .collect();
+                stack.push(XmlElement::new(
+                    String::from_utf8_lossy(e.name()).into_owned(),
+                    attributes,
+                ));
+            }
+            // the root is never popped so that stray end tags can not empty the stack
+            Ok(Event::End(_)) if stack.len() > 1 => {
+                let element = stack.pop().unwrap();
+                if let Some(parent) = stack.last_mut() {
+                    parent.children.push(XmlNode::Element(element));
+                }
+            }
+            Ok(Event::Text(ref e)) => {
+                let text = e
+                    .unescape_and_decode(&reader)
+                    .unwrap_or_else(|_| String::from_utf8_lossy(e).into_owned());
+                if let Some(parent) = stack.last_mut() {
+                    parent.children.push(XmlNode::Text(text));
+                }
+            }
+            Ok(Event::CData(ref e)) => {
+                if let Some(parent) = stack.last_mut() {
+                    parent
+                        .children
+                        .push(XmlNode::Text(String::from_utf8_lossy(e).into_owned()));
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(anyhow!(
+                    "Could not parse XML at position {}: {}",
+                    reader.buffer_position(),
+                    e
+                ))
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+    stack
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("XML document was empty"))
+}
+
+/// A part of a document decompresses to more than is allowed, the document is rejected rather than
+/// ingested without it
+#[derive(Debug)]
+struct PartTooLarge {
+    name: String,
+    limit: u64,
+}
+
+impl std::fmt::Display for PartTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "'{}' decompresses to more than the {} bytes allowed, the document may be a zip bomb",
+            self.name, self.limit
+        )
+    }
+}
+
+impl std::error::Error for PartTooLarge {}
+
+struct OfficeArchive {
+    archive: ZipArchive<File>,
+    part_limit: u64,
+    // how many more bytes of XML may be decompressed from the document
+    remaining_bytes: u64,
+}
+
+impl OfficeArchive {
+    fn open(path: &str) -> Result<Self> {
+        let file = File::open(path).map_err(|e| anyhow!("Could not open file. Error: {}", e))?;
+        let archive = ZipArchive::new(file)
+            .map_err(|e| anyhow!("File is not a valid Office document. Error: {}", e))?;
+        Ok(OfficeArchive {
+            archive,
+            part_limit: MAX_PART_BYTES,
+            remaining_bytes: MAX_DOCUMENT_BYTES,
+        })
+    }
+
+    fn has(&mut self, name: &str) -> bool {
+        self.archive.by_name(name).is_ok()
+    }
+
+    fn read(&mut self, name: &str) -> Result<String> {
+        let entry = self
+            .archive
+            .by_name(name)
+            .map_err(|e| anyhow!("Could not find '{}' in document. Error: {}", name, e))?;
+        let limit = self.part_limit.min(self.remaining_bytes);
+        // the size in the entry's header is not trusted, one byte past the limit shows it was exceeded
+        let mut bytes = vec![];
+        entry
+            .take(limit + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|e| anyhow!("Could not read '{}' from document. Error: {}", name, e))?;
+        if bytes.len() as u64 > limit {
+            return Err(PartTooLarge {
+                name: name.to_string(),
+                limit,
+            }
+                .into());
+        }
+        self.remaining_bytes -= bytes.len() as u64;
+        String::from_utf8(bytes).map_err(|e| anyhow!("'{}' is not valid UTF-8. Error: {}", name, e))
+    }
+
+    fn xml(&mut self, name: &str) -> Result<XmlElement> {
+        parse_xml(&self.read(name)?)
+    }
+
+    /// Reads the relationships of a part, mapping each relationship ID to its type and to the
+    /// path of the part it points at
+    fn relationships(&mut self, part: &str) -> HashMap<String, (String, String)> {
+        let (directory, file_name) = part.rsplit_once('/').unwrap_or(("", part));
+        let rels_path = if directory.is_empty() {
+            format!("_rels/{}.rels", file_name)
+        } else {
+            format!("{}/_rels/{}.rels", directory, file_name)
+        };
+        let Ok(rels) = self.xml(&rels_path) else {
+            return HashMap::new();
+        };
+        rels.find_all("Relationship")
+            .into_iter()
+            .filter_map(|relationship| {
+                let id = relationship.attribute("Id")?;
+                let target = relationship.attribute("Target")?;
+                let kind = relationship.attribute("Type").unwrap_or_default();
+                Some((
+                    id.to_string(),
+                    (kind.to_string(), resolve_part_path(directory, target)),
+                ))
+            })
+            .collect()
+    }
+}
+
+// Relationship targets are relative to the directory of the part that owns them unless they start with '/'
+fn resolve_part_path(directory: &str, target: &str) -> String {
+    let mut parts: Vec<&str> = if target.starts_with('/') {
+        vec![]
+    } else {
+        directory.split('/').filter(|p| !p.is_empty()).collect()
+    };
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// A sheet along with its non empty rows and their (one based) row numbers
+struct Sheet {
+    name: String,
+    rows: Vec<(usize, Vec<String>)>,
+}
+
+struct Slide {
+    slide_number: usize,
+    title: Option<String>,
+    text: String,
+    notes: Option<String>,
+}
+
+fn non_empty(text: String) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+fn trim_trailing_empty_cells(cells: &mut Vec<String>) {
+    while cells.last().is_some_and(|cell| cell.trim().is_empty()) {
+        cells.pop();
+    }
+}
+
+// Converts a cell reference such as "AB12" to a zero based column index
+fn column_index(cell_reference: &str) -> Option<usize> {
+    let letters: String = cell_reference
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect();
+    if letters.is_empty() {
+        return None;
+    }
+    Some(
+        letters
+            .to_ascii_uppercase()
+            .bytes()
+            .fold(0, |index, letter| index * 26 + (letter - b'A' + 1) as usize)
+            - 1,
+    )
+}
+
+fn read_xlsx(archive: &mut OfficeArchive) -> Result<Vec<Sheet>> {
+    let shared_strings: Vec<String> = match archive.xml("xl/sharedStrings.xml") {
+        Ok(sst) => sst.find_all("si").into_iter().map(|si| si.text()).collect(),
+        Err(e) if e.is::<PartTooLarge>() => return Err(e),
+        // workbooks that only hold numbers or inline strings have no shared strings
+        Err(_) => vec![],
+    };
+    let relationships = archive.relationships("xl/workbook.xml");
+    let workbook = archive.xml("xl/workbook.xml")?;
+    let mut sheets = vec![];
+    for sheet in workbook.find_all("sheet") {
+        let name = sheet.attribute("name").unwrap_or_default().to_string();
+        let Some((_, part)) = sheet
+            .relationship_id()
+            .and_then(|id| relationships.get(id))
+        else {
+            println!("Could not find the worksheet for sheet '{}'", name);
+            continue;
+        };
+        let worksheet = match archive.xml(part) {
+            Ok(worksheet) => worksheet,
+            Err(e) if e.is::<PartTooLarge>() => return Err(e),
+            Err(e) => {
+                println!("Could not read sheet '{}'. Error: {}", name, e);
+                continue;
+            }
+        };
+        let mut rows = vec![];
+        for (i, row) in worksheet.find_all("row").into_iter().enumerate() {
+            let row_number = row
+                .attribute("r")
+                .and_then(|r| r.parse().ok())
+                .unwrap_or(i + 1);
+            let mut cells: Vec<String> = vec![];
+            for cell in row.children().filter(|c| c.is("c")) {
+                let value = cell.child("v").map(|v| v.text()).unwrap_or_default();
+                let value = match cell.attribute("t") {
+                    Some("s") => value
+                        .trim()
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|index| shared_strings.get(index).cloned())
+                        .unwrap_or_default(),
+                    Some("inlineStr") => cell.child("is").map(|is| is.text()).unwrap_or_default(),
+                    Some("b") => (value.trim() == "1").to_string(),
+                    _ => value,
+                };
+                let index = cell
+                    .attribute("r")
+                    .and_then(column_index)
+                    .unwrap_or(cells.len());
+                if index >= cells.len() {
+                    cells.resize(index + 1, String::new());
+                }
+                cells[index] = value.trim().to_string();
+            }
+            trim_trailing_empty_cells(&mut cells);
+            if !cells.is_empty() {
+                rows.push((row_number, cells));
+            }
+        }
+        sheets.push(Sheet { name, rows });
+    }
+    Ok(sheets)
+}
+
+fn read_ods(archive: &mut OfficeArchive) -> Result<Vec<Sheet>> {
+    let content = archive.xml("content.xml")?;
+    let mut sheets = vec![];
+    for table in content.find_all("table") {
+        let name = table.attribute("name").unwrap_or_default().to_string();
+        let mut rows = vec![];
+        let mut row_number = 0;
+        for row in table.find_all("table-row") {
+            let repeated_rows: usize = row
+                .attribute("number-rows-repeated")
+                .and_then(|r| r.parse().ok())
+                .unwrap_or(1);
+            let mut cells: Vec<String> = vec![];
+            for cell in row
+                .children()
+                .filter(|c| c.is("table-cell") || c.is("covered-table-cell"))
+            {
+                let repeated_cells: usize = cell
+                    .attribute("number-columns-repeated")
+                    .and_then(|r| r.parse().ok())
+                    .unwrap_or(1);
+                let value = cell
+                    .find_all("p")
+                    .iter()
+                    .map(|p| p.text())
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                let value = value.trim().to_string();
+                for _ in 0..repeated_cells.min(MAX_REPEATED_CELLS) {
+                    cells.push(value.clone());
+                }
+            }
+            trim_trailing_empty_cells(&mut cells);
+            if cells.is_empty() {
+                // empty rows are often repeated to the end of the sheet, skip them without expanding them
+                row_number += repeated_rows;
+                continue;
+            }
+            for _ in 0..repeated_rows.min(MAX_REPEATED_ROWS) {
+                row_number += 1;
+                rows.push((row_number, cells.clone()));
+            }
+        }
+        sheets.push(Sheet { name, rows });
+    }
+    Ok(sheets)
+}
+
+// Collects the paragraphs of a slide, skipping the placeholders that are the same on every slide
+fn ooxml_paragraphs(element: &XmlElement, paragraphs: &mut Vec<String>) {
+    for child in element.children() {
+        if child.is("sp") && ooxml_placeholder_type(child).is_some_and(|t| OOXML_SKIPPED_PLACEHOLDERS.contains(&t)) {
+            continue;
+        }
+        if child.is("p") {
+            if let Some(paragraph) = non_empty(child.text()) {
+                paragraphs.push(paragraph);
+            }
+        } else {
+            ooxml_paragraphs(child, paragraphs);
+        }
+    }
+}
+
+fn ooxml_placeholder_type(shape: &XmlElement) -> Option<&str> {
+    let placeholder = shape.find_all("ph").into_iter().next()?;
+    // a placeholder without a type is a body placeholder
+    Some(placeholder.attribute("type").unwrap_or("body"))
+}
+
+fn ooxml_shape_text(shape: &XmlElement) -> String {
+    let mut paragraphs = vec![];
+    ooxml_paragraphs(shape, &mut paragraphs);
+    paragraphs.join("\n")
+}
+
+fn read_pptx(archive: &mut OfficeArchive) -> Result<Vec<Slide>> {
+    let relationships = archive.relationships("ppt/presentation.xml");
+    let presentation = archive.xml("ppt/presentation.xml")?;
+    let mut slides = vec![];
+    for (i, slide_id) in presentation.find_all("sldId").into_iter().enumerate() {
+        let slide_number = i + 1;
+        let Some((_, part)) = slide_id
+            .relationship_id()
+            .and_then(|id| relationships.get(id))
+        else {
+            continue;
+        };
+        let slide = match archive.xml(part) {
+            Ok(slide) => slide,
+            Err(e) if e.is::<PartTooLarge>() => return Err(e),
+            Err(e) => {
+                println!("Could not read slide {}. Error: {}", slide_number, e);
+                continue;
+            }
+        };
+        let title = slide
+            .find_all("sp")
+            .into_iter()
+            .find(|shape| matches!(ooxml_placeholder_type(shape), Some("title" | "ctrTitle")))
+            .and_then(|shape| non_empty(ooxml_shape_text(shape)));
+        let notes = archive
+            .relationships(part)
+            .into_values()
+            .find(|(kind, _)| kind.ends_with("/notesSlide"))
+            .and_then(|(_, notes_part)| archive.xml(&notes_part).ok())
+            .and_then(|notes| {
+                // the notes page also shows an image of the slide and its number, only the body holds the notes
+                let text = notes
+                    .find_all("sp")
+                    .into_iter()
+                    .filter(|shape| ooxml_placeholder_type(shape) == Some("body"))
+                    .map(ooxml_shape_text)
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                non_empty(text)
+            });
+        slides.push(Slide {
+            slide_number,
+            title,
+            text: ooxml_shape_text(&slide),
+            notes,
+        });
+    }
+    Ok(slides)
+}
+
+fn odf_paragraphs(element: &XmlElement, paragraphs: &mut Vec<String>) {
+    for child in element.children() {
+        if child.is("notes")
+            || child
+                .attribute("class")
+                .is_some_and(|class| ODF_SKIPPED_CLASSES.contains(&class))
+        {
+            continue;
+        }
+        if child.is("p") || child.is("h") {
+            if let Some(paragraph) = non_empty(child.text()) {
+                paragraphs.push(paragraph);
+            }
+        } else {
+            odf_paragraphs(child, paragraphs);
+        }
+    }
+}
+
+fn odf_text(element: &XmlElement) -> String {
+    let mut paragraphs = vec![];
+    odf_paragraphs(element, &mut paragraphs);
+    paragraphs.join("\n")
+}
+
+fn read_odp(archive: &mut OfficeArchive) -> Result<Vec<Slide>> {
+    let content = archive.xml("content.xml")?;
+    let mut slides = vec![];
+    for (i, page) in content.find_all("page").into_iter().enumerate() {
+        let title = page
+            .find_all("frame")
+            .into_iter()
+            .find(|frame| frame.attribute("class") == Some("title"))
+            .and_then(|frame| non_empty(odf_text(frame)));
+        let notes = page.child("notes").and_then(|notes| {
+            let text = notes
+                .find_all("frame")
+                .into_iter()
+                .filter(|frame| frame.attribute("class") == Some("notes"))
+                .map(odf_text)
+                .collect::<Vec<String>>()
+                .join("\n");
+            non_empty(text)
+        });
+        slides.push(Slide {
+            slide_number: i + 1,
+            title,
+            text: odf_text(page),
+            notes,
+        });
+    }
+    Ok(slides)
+}
+
+///
+///
+/// # Arguments
+///
+/// * `path`: Path to an xlsx or ods file
+///
+/// Every sheet becomes a segment of rows. The first row of a sheet is taken to be its header and
+/// is repeated at the top of every chunk of that sheet.
+///
+/// returns: Result<(String, HashMap<String, String>), Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn extract_spreadsheet(path: &str) -> Result<(String, HashMap<String, String>)> {
+    let mut archive = OfficeArchive::open(path)?;
+    let sheets = if archive.has("xl/workbook.xml") {
+        read_xlsx(&mut archive)?
+    } else if archive.has("content.xml") {
+        read_ods(&mut archive)?
+    } else {
+        return Err(anyhow!("File is neither an xlsx nor an ods spreadsheet"));
+    };
+    let sheet_count = sheets.len();
+    let mut segments = vec![];
+    for sheet in sheets {
+        let mut rows = sheet.rows.into_iter();
+        let Some((_, headers)) = rows.next() else {
+            continue;
+        };
+        let rows: Vec<(usize, String)> = rows
+            .map(|(row_number, cells)| (row_number, cells.join(CELL_SEPARATOR)))
+            .collect();
+        let header = headers.join(CELL_SEPARATOR);
+        let mut metadata = HashMap::from([
+            ("sheet_name".to_string(), sheet.name),
+            (
+                "column_headers".to_string(),
+                headers
+                    .iter()
+                    .filter(|h| !h.is_empty())
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ),
+        ]);
+        let content = if rows.is_empty() {
+            // a sheet with a single row has no header, just the one row
+            metadata.remove("column_headers");
+            SegmentContent::Rows {
+                header: None,
+                rows: vec![(1, header)],
+            }
+        } else {
+            SegmentContent::Rows {
+                header: Some(header),
+                rows,
+            }
+        };
+        segments.push(Segment { metadata, content });
+    }
+    if segments.is_empty() {
+        return Err(anyhow!("Spreadsheet does not contain any data"));
+    }
+    let metadata = HashMap::from([("sheet count".to_string(), sheet_count.to_string())]);
+    Ok(segmented_document(segments, metadata))
+}
+
+///
+///
+/// # Arguments
+///
+/// * `path`: Path to a pptx or odp file
+///
+/// Every slide becomes a segment with its `slide_number`, `slide_title` and `speaker_notes`. A slide
+/// without any text of its own, such as one that is just an image, is embedded from its notes.
+///
+/// returns: Result<(String, HashMap<String, String>), Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn extract_presentation(path: &str) -> Result<(String, HashMap<String, String>)> {
+    let mut archive = OfficeArchive::open(path)?;
+    let slides = if archive.has("ppt/presentation.xml") {
+        read_pptx(&mut archive)?
+    } else if archive.has("content.xml") {
+        read_odp(&mut archive)?
+    } else {
+        return Err(anyhow!("File is neither a pptx nor an odp presentation"));
+    };
+    let slide_count = slides.len();
+    let mut segments = vec![];
+    for slide in slides {
+        let Some(text) = non_empty(slide.text).or(slide.notes.clone()) else {
+            continue;
+        };
+        let mut metadata = HashMap::from([(
+            "slide_number".to_string(),
+            slide.slide_number.to_string(),
+        )]);
+        if let Some(title) = slide.title {
+            metadata.insert("slide_title".to_string(), title);
+        }
+        if let Some(notes) = slide.notes {
+            metadata.insert("speaker_notes".to_string(), notes);
+        }
+        segments.push(Segment {
+            metadata,
+            content: SegmentContent::Text(text),
+        });
+    }
+    if segments.is_empty() {
+        return Err(anyhow!("Presentation does not contain any text"));
+    }
+    let metadata = HashMap::from([("slide count".to_string(), slide_count.to_string())]);
+    Ok(segmented_document(segments, metadata))
+}
+
+/// Whether the file is an OpenDocument text document rather than a Word document
+pub fn is_odt(path: &str) -> bool {
+    match OfficeArchive::open(path) {
+        Ok(mut archive) => archive.has("content.xml") && !archive.has("word/document.xml"),
+        Err(_) => false,
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `path`: Path to an odt file
+///
+/// The paragraphs and headings of the document, including those in lists and tables, each on a
+/// line of their own. Tracked changes that were deleted are left out.
+///
+/// returns: Result<(String, HashMap<String, String>), Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn extract_odt(path: &str) -> Result<(String, HashMap<String, String>)> {
+    let mut archive = OfficeArchive::open(path)?;
+    let content = archive.xml("content.xml")?;
+    let Some(body) = content.find_all("text").into_iter().next() else {
+        return Err(anyhow!("File is not an odt text document"));
+    };
+    let mut paragraphs = vec![];
+    collect_paragraphs(body, &mut paragraphs);
+    if paragraphs.is_empty() {
+        return Err(anyhow!("Document does not contain any text"));
+    }
+    Ok((paragraphs.join("\n"), HashMap::new()))
+}
+
+fn collect_paragraphs(element: &XmlElement, paragraphs: &mut Vec<String>) {
+    for child in element.children() {
+        match local_name(&child.name) {
+            "p" | "h" => {
+                if let Some(text) = non_empty(child.text()) {
+                    paragraphs.push(text);
+                }
+            }
+            "tracked-changes" => {}
+            _ => collect_paragraphs(child, paragraphs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+
+    fn write_document(parts: &[(&str, &str)]) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = zip::ZipWriter::new(file.reopen().unwrap());
+        for (name, content) in parts {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+        file
+    }
+
+    #[test]
+    fn odt_paragraphs_are_extracted_in_order() {
+        let content = concat!(
+            r#"<office:document-content xmlns:office="o" xmlns:text="t" xmlns:table="ta">"#,
+            r#"<office:body><office:text>"#,
+            r#"<text:tracked-changes><text:p>deleted</text:p></text:tracked-changes>"#,
+            r#"<text:h>Title</text:h><text:p>First<text:s text:c="2"/>line</text:p>"#,
+            r#"<text:list><text:list-item><text:p>Item</text:p></text:list-item></text:list>"#,
+            r#"<table:table><table:table-row><table:table-cell><text:p>Cell</text:p></table:table-cell></table:table-row></table:table>"#,
+            r#"<text:p/></office:text></office:body></office:document-content>"#,
+        );
+        let file = write_document(&[("mimetype", "application/vnd.oasis.opendocument.text"), ("content.xml", content)]);
+        let path = file.path().to_str().unwrap();
+        assert!(is_odt(path));
+        let (text, _) = extract_odt(path).unwrap();
+        assert_eq!(text, "Title\nFirst  line\nItem\nCell");
+    }
+
+    #[test]
+    fn parts_within_the_limits_are_read() {
+        let file = write_document(&[("a.xml", "<a>text</a>")]);
+        let mut archive = OfficeArchive::open(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(archive.xml("a.xml").unwrap().text(), "text");
+    }
+
+    #[test]
+    fn parts_over_the_part_limit_are_rejected() {
+        let file = write_document(&[("a.xml", &format!("<a>{}</a>", "x".repeat(100)))]);
+        let mut archive = OfficeArchive::open(file.path().to_str().unwrap()).unwrap();
+        archive.part_limit = 50;
+        assert!(archive.read("a.xml").unwrap_err().is::<PartTooLarge>());
+    }
+
+    #[test]
+    fn parts_over_the_document_limit_are_rejected() {
+        let content = format!("<a>{}</a>", "x".repeat(40));
+        let file = write_document(&[("a.xml", &content), ("b.xml", &content)]);
+        let mut archive = OfficeArchive::open(file.path().to_str().unwrap()).unwrap();
+        archive.remaining_bytes = 60;
+        assert!(archive.read("a.xml").is_ok());
+        assert!(archive.read("b.xml").unwrap_err().is::<PartTooLarge>());
+    }
+}
@@ -0,0 +1,181 @@
+//! Extraction of large documents in parts.
+//!
+//! The text of large text and PDF files is not held in memory whole. They are extracted a part at a
+//! time, each part being a run of whole lines or whole pages of about [`PART_BYTES`] of text, and
+//! each part is chunked, embedded and upserted while the next one is extracted. Chunks never
+//! straddle two parts. For text files memory use is then bounded by the part size rather than by the
+//! size of the file. PDF files are parsed whole before their pages are extracted, so their memory
+//! use is still bounded by the size of the file and only the extracted text is kept to a part.
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::{self, JoinHandle};
+
+use crate::data::models::{Document, FileType};
+use crate::data::pdf::extract_pdf_parts;
+
+/// The amount of text extracted before it is handed on to be chunked
+pub const PART_BYTES: usize = 1024 * 1024;
+/// Metadata key holding where a part starts in the text of the whole document
+pub const PART_OFFSET_KEY: &str = "part_offset";
+// Parts extracted ahead of the part being embedded, more would only use more memory
+const PART_QUEUE_LENGTH: usize = 1;
+
+pub struct DocumentPart {
+    pub text: String,
+    pub metadata: HashMap<String, String>,
+}
+
+/// The parts of a document as they are extracted
+pub struct Parts {
+    receiver: Receiver<Result<DocumentPart>>,
+    extraction: Option<JoinHandle<()>>,
+}
+
+impl Parts {
+    /// The next part. The parts only end once extraction has finished, if the extractor panicked
+    /// that is returned as an error instead.
+    pub async fn next(&mut self) -> Option<Result<DocumentPart>> {
+        if let Some(part) = self.receiver.recv().await {
+            return Some(part);
+        }
+        match self.extraction.take()?.await {
+            Ok(()) => None,
+            Err(e) => Some(Err(anyhow!("Extraction stopped before the end of the document. Error: {}", e))),
+        }
+    }
+}
+
+/// Whether files of a type can be extracted in parts
+pub fn supports_parts(file_type: FileType) -> bool {
+    matches!(file_type, FileType::TXT | FileType::PDF)
+}
+
+///
+///
+/// # Arguments
+///
+/// * `file_type`: The type of the file, one for which `supports_parts` is true
+/// * `path`: Path to the file
+///
+/// Extraction runs on the blocking thread pool and stops early if the parts are dropped. An
+/// error ends the parts.
+///
+/// returns: Parts The parts, in document order
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn extract_parts(file_type: FileType, path: String) -> Parts {
+    let (sender, receiver) = mpsc::channel(PART_QUEUE_LENGTH);
+    let extraction = task::spawn_blocking(move || {
+        let result = match file_type {
+            FileType::PDF => extract_pdf_parts(path.as_str(), PART_BYTES, &sender),
+            FileType::TXT => extract_text_parts(path.as_str(), PART_BYTES, &sender),
+            _ => Err(anyhow!("{:?} files can not be extracted in parts", file_type)),
+        };
+        if let Err(e) = result {
+            let _ = sender.blocking_send(Err(e));
+        }
+    });
+    Parts {
+        receiver,
+        extraction: Some(extraction),
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `path`: Path to the text file
+/// * `part_bytes`: The size parts are cut at, at the end of the line that reaches it
+/// * `sender`: Where the parts are sent
+///
+/// Invalid UTF-8 is replaced rather than failing the whole file, as `extract_text_from_txt` would.
+/// A line longer than a part is cut, keeping characters whole.
+///
+/// returns: Result<(), Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn extract_text_parts(path: &str, part_bytes: usize, sender: &Sender<Result<DocumentPart>>) -> Result<()> {
+    let file = File::open(path).map_err(|e| anyhow!("Could  not read file. Error: {}", e))?;
+    let mut reader = BufReader::new(file);
+    let mut text = String::new();
+    let mut offset = 0;
+    // bytes of a character cut in half by the end of a read
+    let mut pending: Vec<u8> = vec![];
+    loop {
+        let read = (&mut reader)
+            .take(part_bytes as u64)
+            .read_until(b'\n', &mut pending)?;
+        let complete = match std::str::from_utf8(&pending) {
+            Err(e) if read > 0 && e.error_len().is_none() => e.valid_up_to(),
+            _ => pending.len(),
+        };
+        let incomplete = pending.split_off(complete);
+        text.push_str(&String::from_utf8_lossy(&pending));
+        pending = incomplete;
+        if read == 0 || text.len() >= part_bytes {
+            if !text.is_empty() {
+                let length = text.len();
+                let part = DocumentPart {
+                    text: std::mem::take(&mut text),
+                    metadata: HashMap::from([(PART_OFFSET_KEY.to_string(), offset.to_string())]),
+                };
+                if sender.blocking_send(Ok(part)).is_err() {
+                    return Ok(());
+                }
+                offset += length;
+            }
+            if read == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `documents`: Chunks of a part of a document
+///
+/// Chunk offsets are relative to the part they were cut from. This moves `start_index` and
+/// `end_index` to be relative to the whole document using the part offset recorded at extraction,
+/// which is then removed from the chunk metadata. Chunks of documents that were not extracted in
+/// parts are left untouched.
+///
+/// returns: ()
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn apply_part_offsets(documents: &mut [Document]) {
+    for document in documents.iter_mut() {
+        let Some(metadata) = document.metadata.as_mut() else {
+            continue;
+        };
+        let Some(offset) = metadata
+            .remove(PART_OFFSET_KEY)
+            .and_then(|offset| offset.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        for key in ["start_index", "end_index"] {
+            if let Some(index) = metadata.get(key).and_then(|index| index.parse::<usize>().ok()) {
+                metadata.insert(key.to_string(), (index + offset).to_string());
+            }
+        }
+    }
+}
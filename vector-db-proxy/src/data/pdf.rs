@@ -0,0 +1,483 @@
+//! Page by page PDF text extraction.
+//!
+//! The text of every page is extracted on its own and the pages are joined into a single document.
+//! Where each page starts in that document is recorded in the metadata so that once the document
+//! has been chunked every chunk can be tagged with the pages it was taken from.
+//!
+//! Large documents are extracted in parts of consecutive pages instead, see [`extract_pdf_parts`].
+use anyhow::{anyhow, Result};
+use chrono::{FixedOffset, NaiveDate, TimeZone};
+use pdf_extract::{MediaBox, OutputDev, OutputError, Transform};
+use pdf_extract_lopdf::{Dictionary, Document as PdfDocument, Object};
+use std::collections::HashMap;
+use std::io;
+use tokio::sync::mpsc::Sender;
+
+use crate::data::models::Document;
+use crate::data::parts::{DocumentPart, PART_OFFSET_KEY};
+
+/// Metadata key holding the offset each page starts at in the extracted text, as `page:offset` pairs
+pub const PAGE_OFFSETS_KEY: &str = "page_offsets";
+const PAGE_SEPARATOR: &str = "\n\n";
+
+pub struct PdfPage {
+    pub page_number: u32,
+    pub text: String,
+}
+
+/// Called with the pages collected so far once they hold at least a part's worth of text. Returns
+/// whether extraction should go on.
+type PartSink<'a> = Box<dyn FnMut(Vec<PdfPage>) -> bool + 'a>;
+
+/// Collects the text of each page separately, spacing characters the same way as
+/// `pdf_extract::PlainTextOutput` does for the whole document
+struct PagedTextOutput<'a> {
+    pages: Vec<PdfPage>,
+    part_sink: Option<(usize, PartSink<'a>)>,
+    current: String,
+    page_number: u32,
+    page_height: f64,
+    first_char: bool,
+    last_end: f64,
+    last_y: f64,
+}
+
+impl<'a> PagedTextOutput<'a> {
+    fn new() -> Self {
+        PagedTextOutput {
+            pages: vec![],
+            part_sink: None,
+            current: String::new(),
+            page_number: 0,
+            page_height: 0.,
+            first_char: false,
+            last_end: 100000.,
+            last_y: 0.,
+        }
+    }
+
+    fn with_part_sink(part_bytes: usize, sink: PartSink<'a>) -> Self {
+        PagedTextOutput {
+            part_sink: Some((part_bytes, sink)),
+            ..PagedTextOutput::new()
+        }
+    }
+}
+
+impl OutputDev for PagedTextOutput<'_> {
+    fn begin_page(
+        &mut self,
+        page_num: u32,
+        media_box: &MediaBox,
+        _art_box: Option<(f64, f64, f64, f64)>,
+    ) -> Result<(), OutputError> {
+        self.page_number = page_num;
+        self.page_height = media_box.ury - media_box.lly;
+        self.current = String::new();
+        self.first_char = false;
+        self.last_end = 100000.;
+        self.last_y = 0.;
+        Ok(())
+    }
+
+    fn end_page(&mut self) -> Result<(), OutputError> {
+        self.pages.push(PdfPage {
+            page_number: self.page_number,
+            text: std::mem::take(&mut self.current),
+        });
+        if let Some((part_bytes, sink)) = self.part_sink.as_mut() {
+            let bytes: usize = self.pages.iter().map(|page| page.text.len()).sum();
+            if bytes >= *part_bytes && !sink(std::mem::take(&mut self.pages)) {
+                return Err(OutputError::IoError(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "No longer receiving parts",
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn output_character(
+        &mut self,
+        trm: &Transform,
+        width: f64,
+        _spacing: f64,
+        font_size: f64,
+        char: &str,
+    ) -> Result<(), OutputError> {
+        // flip the y axis so that y grows down the page, as PlainTextOutput does
+        let (x, y) = (trm.m31, self.page_height - trm.m32);
+        let font_width = font_size * trm.m11 + font_size * trm.m21;
+        let font_height = font_size * trm.m12 + font_size * trm.m22;
+        let transformed_font_size = (font_width * font_height).sqrt();
+        if self.first_char {
+            if (y - self.last_y).abs() > transformed_font_size * 1.5 {
+                self.current.push('\n');
+            }
+            // we've moved to the left and down
+            if x < self.last_end && (y - self.last_y).abs() > transformed_font_size * 0.5 {
+                self.current.push('\n');
+            }
+            if x > self.last_end + transformed_font_size * 0.1 {
+                self.current.push(' ');
+            }
+        }
+        self.current.push_str(char);
+        self.first_char = false;
+        self.last_y = y;
+        self.last_end = x + width * transformed_font_size;
+        Ok(())
+    }
+
+    fn begin_word(&mut self) -> Result<(), OutputError> {
+        self.first_char = true;
+        Ok(())
+    }
+
+    fn end_word(&mut self) -> Result<(), OutputError> {
+        Ok(())
+    }
+
+    fn end_line(&mut self) -> Result<(), OutputError> {
+        Ok(())
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `path`: Path to the PDF file
+///
+/// returns: Result<(String, HashMap<String, String>), Error> The text of all pages joined together
+/// and the document metadata, including the page offsets and the title, author and creation date
+/// from the Info dictionary when the document has them
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn extract_pdf(path: &str) -> Result<(String, HashMap<String, String>)> {
+    let doc = PdfDocument::load(path)
+        .map_err(|e| anyhow!("An error occurred while attempting to load PDF doc: {}", e))?;
+    let mut output = PagedTextOutput::new();
+    pdf_extract::output_doc(&doc, &mut output).map_err(|e| {
+        anyhow!("An error occurred while trying to extract text from pdf. Error: {}", e)
+    })?;
+    let page_count = output.pages.len();
+    let (text, page_offsets) = join_pages(output.pages);
+    if text.is_empty() {
+        return Err(anyhow!("Unable to extract text from PDF document: {}", path));
+    }
+    let mut metadata = document_info(&doc);
+    metadata.insert("character count".to_string(), text.len().to_string());
+    metadata.insert("page count".to_string(), page_count.to_string());
+    metadata.insert(PAGE_OFFSETS_KEY.to_string(), encode_page_offsets(&page_offsets));
+    Ok((text, metadata))
+}
+
+fn encode_page_offsets(page_offsets: &[(u32, usize)]) -> String {
+    page_offsets
+        .iter()
+        .map(|(page, offset)| format!("{}:{}", page, offset))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+///
+///
+/// # Arguments
+///
+/// * `path`: Path to the PDF file
+/// * `part_bytes`: The amount of text collected before the pages holding it are sent as a part
+/// * `sender`: Where the parts are sent
+///
+/// Each part carries the same metadata as `extract_pdf` gives the whole document, less the
+/// character count which is not known until the end, with page offsets relative to the part.
+/// The whole file is parsed into memory before the first page is extracted, as the cross reference
+/// table and page tree can be anywhere in it, so only the extracted text is bounded by the part size.
+///
+/// returns: Result<(), Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn extract_pdf_parts(path: &str, part_bytes: usize, sender: &Sender<Result<DocumentPart>>) -> Result<()> {
+    let doc = PdfDocument::load(path)
+        .map_err(|e| anyhow!("An error occurred while attempting to load PDF doc: {}", e))?;
+    let mut info = document_info(&doc);
+    info.insert("page count".to_string(), doc.get_pages().len().to_string());
+    let mut offset = 0;
+    let mut send_pages = |pages: Vec<PdfPage>| -> bool {
+        let (text, page_offsets) = join_pages(pages);
+        if text.is_empty() {
+            return true;
+        }
+        let mut metadata = info.clone();
+        metadata.insert(PAGE_OFFSETS_KEY.to_string(), encode_page_offsets(&page_offsets));
+        metadata.insert(PART_OFFSET_KEY.to_string(), offset.to_string());
+        offset += text.len() + PAGE_SEPARATOR.len();
+        sender.blocking_send(Ok(DocumentPart { text, metadata })).is_ok()
+    };
+    let remaining = {
+        let mut output = PagedTextOutput::with_part_sink(part_bytes, Box::new(&mut send_pages));
+        match pdf_extract::output_doc(&doc, &mut output) {
+            Ok(_) => {}
+            // the receiver has gone away, there is no point in extracting the rest
+            Err(OutputError::IoError(e)) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => {
+                return Err(anyhow!("An error occurred while trying to extract text from pdf. Error: {}", e));
+            }
+        }
+        std::mem::take(&mut output.pages)
+    };
+    if !remaining.is_empty() {
+        send_pages(remaining);
+    }
+    if offset == 0 {
+        return Err(anyhow!("Unable to extract text from PDF document: {}", path));
+    }
+    Ok(())
+}
+
+// Pages without any text are left out entirely so they can never be assigned to a chunk
+fn join_pages(pages: Vec<PdfPage>) -> (String, Vec<(u32, usize)>) {
+    let mut text = String::new();
+    let mut page_offsets = vec![];
+    for page in pages {
+        let page_text = page.text.trim();
+        if page_text.is_empty() {
+            continue;
+        }
+        if !text.is_empty() {
+            text.push_str(PAGE_SEPARATOR);
+        }
+        page_offsets.push((page.page_number, text.len()));
+        text.push_str(page_text);
+    }
+    (text, page_offsets)
+}
+
+fn document_info(doc: &PdfDocument) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    let info = doc
+        .trailer
+        .get(b"Info")
+        .and_then(|info| doc.dereference(info))
+        .and_then(|(_, info)| info.as_dict());
+    let Ok(info) = info else {
+        return metadata;
+    };
+    for (key, metadata_key) in [
+        (b"Title".as_slice(), "title"),
+        (b"Author".as_slice(), "author"),
+        (b"CreationDate".as_slice(), "creation_date"),
+    ] {
+        if let Some(value) = info_string(doc, info, key) {
+            let value = if metadata_key == "creation_date" {
+                // keep the raw value if it is not a well formed PDF date
+                parse_pdf_date(&value).unwrap_or(value)
+            } else {
+                value
+            };
+            metadata.insert(metadata_key.to_string(), value);
+        }
+    }
+    metadata
+}
+
+fn info_string(doc: &PdfDocument, info: &Dictionary, key: &[u8]) -> Option<String> {
+    let value = info.get(key).and_then(|value| doc.dereference(value)).ok()?.1;
+    match value {
+        Object::String(bytes, _) => {
+            let text = decode_text_string(bytes);
+            let text = text.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+            if text.is_empty() {
+                None
+            } else {
+                Some(text.to_string())
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Decodes a PDF text string, which is either UTF-16BE with a byte order mark or PDFDocEncoding.
+/// PDFDocEncoding is treated as Latin-1, which it only differs from in a few rarely used characters.
+fn decode_text_string(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        let units: Vec<u16> = utf16
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else if let Some(utf8) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        String::from_utf8_lossy(utf8).into_owned()
+    } else {
+        bytes.iter().map(|b| *b as char).collect()
+    }
+}
+
+/// Converts a PDF date such as `D:20230115093000+01'00'` to RFC 3339. Every part after the year is
+/// optional and a missing time zone is taken to be UTC.
+fn parse_pdf_date(date: &str) -> Option<String> {
+    let date = date.trim();
+    let date = date.strip_prefix("D:").unwrap_or(date);
+    let digits: String = date.chars().take_while(|c| c.is_ascii_digit()).collect();
+    if digits.len() < 4 {
+        return None;
+    }
+    let part = |start: usize, default: u32| -> Option<u32> {
+        match digits.get(start..start + 2) {
+            Some(value) => value.parse().ok(),
+            None => Some(default),
+        }
+    };
+    let year: i32 = digits[..4].parse().ok()?;
+    let naive = NaiveDate::from_ymd_opt(year, part(4, 1)?, part(6, 1)?)?
+        .and_hms_opt(part(8, 0)?, part(10, 0)?, part(12, 0)?)?;
+    let zone = &date[digits.len()..];
+    let offset_seconds = match zone.chars().next() {
+        Some(sign @ ('+' | '-')) => {
+            let zone_digits: String = zone[1..].chars().filter(|c| c.is_ascii_digit()).collect();
+            let hours: i32 = zone_digits.get(..2)?.parse().ok()?;
+            let minutes: i32 = zone_digits.get(2..4).map_or(Some(0), |m| m.parse().ok())?;
+            let seconds = hours * 3600 + minutes * 60;
+            if sign == '-' {
+                -seconds
+            } else {
+                seconds
+            }
+        }
+        _ => 0,
+    };
+    let offset = FixedOffset::east_opt(offset_seconds)?;
+    Some(offset.from_local_datetime(&naive).single()?.to_rfc3339())
+}
+
+fn page_at(page_offsets: &[(u32, usize)], index: usize) -> Option<u32> {
+    page_offsets
+        .iter()
+        .take_while(|(_, offset)| *offset <= index)
+        .last()
+        .or(page_offsets.first())
+        .map(|(page, _)| *page)
+}
+
+///
+///
+/// # Arguments
+///
+/// * `documents`: Chunks of a document extracted by `extract_pdf`
+///
+/// Uses the page offsets recorded at extraction and the offsets of each chunk to set `page_number`,
+/// `start_page` and `end_page` on every chunk. `page_number` is the page the chunk starts on. The
+/// page offsets themselves are removed from the chunk metadata. Chunks of documents that are not
+/// PDFs are left untouched.
+///
+/// returns: ()
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn assign_page_numbers(documents: &mut [Document]) {
+    for document in documents.iter_mut() {
+        let Some(metadata) = document.metadata.as_mut() else {
+            continue;
+        };
+        let Some(page_offsets) = metadata.remove(PAGE_OFFSETS_KEY) else {
+            continue;
+        };
+        let page_offsets: Vec<(u32, usize)> = page_offsets
+            .split(',')
+            .filter_map(|pair| {
+                let (page, offset) = pair.split_once(':')?;
+                Some((page.parse().ok()?, offset.parse().ok()?))
+            })
+            .collect();
+        let Some(start_index) = metadata
+            .get("start_index")
+            .and_then(|index| index.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        let end_index = metadata
+            .get("end_index")
+            .and_then(|index| index.parse::<usize>().ok())
+            .unwrap_or(start_index + document.page_content.len());
+        if let (Some(start_page), Some(end_page)) = (
+            page_at(&page_offsets, start_index),
+            page_at(&page_offsets, end_index.saturating_sub(1).max(start_index)),
+        ) {
+            metadata.insert("page_number".to_string(), start_page.to_string());
+            metadata.insert("start_page".to_string(), start_page.to_string());
+            metadata.insert("end_page".to_string(), end_page.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pdf_dates_are_converted_to_rfc3339() {
+        assert_eq!(
+            parse_pdf_date("D:20230102030405+05'30'").as_deref(),
+            Some("2023-01-02T03:04:05+05:30")
+        );
+        assert_eq!(
+            parse_pdf_date("D:20230102030405-08'00").as_deref(),
+            Some("2023-01-02T03:04:05-08:00")
+        );
+        assert_eq!(
+            parse_pdf_date("D:20230102030405Z").as_deref(),
+            Some("2023-01-02T03:04:05+00:00")
+        );
+        assert_eq!(parse_pdf_date("2023").as_deref(), Some("2023-01-01T00:00:00+00:00"));
+        assert_eq!(parse_pdf_date("D:20231302"), None);
+        assert_eq!(parse_pdf_date("D:20230102030405+5"), None);
+        assert_eq!(parse_pdf_date("yesterday"), None);
+    }
+
+    fn chunk(start_index: usize, end_index: usize) -> Document {
+        let metadata = HashMap::from([
+            (PAGE_OFFSETS_KEY.to_string(), "1:0,2:20,5:40".to_string()),
+            ("start_index".to_string(), start_index.to_string()),
+            ("end_index".to_string(), end_index.to_string()),
+        ]);
+        Document::new("text".to_string(), Some(metadata), None)
+    }
+
+    fn pages(document: &Document) -> (Option<&str>, Option<&str>, Option<&str>) {
+        let metadata = document.metadata.as_ref().unwrap();
+        (
+            metadata.get("page_number").map(String::as_str),
+            metadata.get("start_page").map(String::as_str),
+            metadata.get("end_page").map(String::as_str),
+        )
+    }
+
+    #[test]
+    fn chunks_are_tagged_with_the_pages_they_span() {
+        let mut documents = vec![chunk(0, 10), chunk(15, 45), chunk(40, 60)];
+        assign_page_numbers(&mut documents);
+        assert_eq!(pages(&documents[0]), (Some("1"), Some("1"), Some("1")));
+        assert_eq!(pages(&documents[1]), (Some("1"), Some("1"), Some("5")));
+        assert_eq!(pages(&documents[2]), (Some("5"), Some("5"), Some("5")));
+        assert!(!documents[0].metadata.as_ref().unwrap().contains_key(PAGE_OFFSETS_KEY));
+    }
+
+    #[test]
+    fn chunks_without_offsets_are_not_tagged() {
+        let metadata = HashMap::from([(PAGE_OFFSETS_KEY.to_string(), "1:0,2:20".to_string())]);
+        let mut documents = vec![Document::new("text".to_string(), Some(metadata), None)];
+        assign_page_numbers(&mut documents);
+        assert_eq!(pages(&documents[0]), (None, None, None));
+    }
+}
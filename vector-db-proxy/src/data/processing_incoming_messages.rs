@@ -1,21 +1,67 @@
+//! The stages an Airbyte record goes through on the embedding queue. Each stage either hands the
+//! record on to the next or ends with the record's outcome.
 use mongodb::Database;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::{RwLock};
 use qdrant_client::client::QdrantClient;
+use qdrant_client::prelude::PointStruct;
+use qdrant_client::qdrant::PointId;
 use serde_json::Value;
+use uuid::Uuid;
 
-use crate::llm::models::EmbeddingModels;
-use crate::mongo::queries::get_embedding_model_and_embedding_key;
+use crate::llm::providers::{get_embedding_provider, EmbeddingProviders};
+use crate::mongo::queries::{get_embedding_model_and_embedding_key, get_stream_primary_key};
+use crate::qdrant::dedup::{metadata_hash, record_point_id, RecordKey, METADATA_HASH_KEY};
 use crate::qdrant::helpers::embed_payload;
 use crate::qdrant::utils::Qdrant;
+use crate::rabbitmq::delivery::Outcome;
 use crate::utils::conversions::convert_serde_value_to_hashmap_string;
 
-pub async fn process_messages(
+/// A record that has been parsed and needs embedding
+pub struct PreparedRecord {
+    pub datasource_id: String,
+    pub point_id: Uuid,
+    pub metadata: HashMap<String, String>,
+    pub text: String,
+    pub vector_length: u64,
+    pub embedding_model_name: String,
+    pub embedding_provider: EmbeddingProviders,
+}
+
+/// A record that has been embedded and needs uploading
+pub struct EmbeddedRecord {
+    pub datasource_id: String,
+    pub point: PointStruct,
+    pub vector_length: u64,
+    pub embedding_model_name: String,
+}
+
+///
+///
+/// # Arguments
+///
+/// * `qdrant_conn`: The Qdrant client
+/// * `mongo_conn`: The Mongo database
+/// * `message`: An Airbyte record as JSON
+/// * `datasource_id`: The datasource the record was synced to
+/// * `stream`: The Airbyte stream the record came from
+///
+/// returns: Result<PreparedRecord, Outcome> The record, or its outcome if there is nothing more to
+/// do with it. A record that is already stored unchanged is `Outcome::Done`.
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn prepare_record(
     qdrant_conn: Arc<RwLock<QdrantClient>>,
     mongo_conn: Arc<RwLock<Database>>,
     message: String,
     datasource_id: String,
-) {
+    stream: String,
+) -> Result<PreparedRecord, Outcome> {
     // initiate variables
     let mongodb_connection = mongo_conn.read().await;
     // let redis_connection = redis_connection_pool.lock().await;
@@ -28,59 +74,166 @@ pub async fn process_messages(
                     Some(model_parameters) => {
                         let vector_length = model_parameters.embeddingLength as u64;
                         let embedding_model_name = model_parameters.model;
-                        let embedding_model_name_clone = embedding_model_name.clone();
-                        let ds_clone = datasource_id.clone();
-                        let qdrant = Qdrant::new(qdrant_conn, datasource_id);
-                        if let Value::Object(data_obj) = message_data {
-                            let mut metadata = convert_serde_value_to_hashmap_string(data_obj);
-                            if let Some(text_field) = embedding_field {
-                                println!("text field: {}", text_field.as_str());
-                                let text = metadata.remove(text_field.as_str()).unwrap();
-                                metadata.insert("page_content".to_string(), text.to_owned());
-                                let mongo_conn_clone = Arc::clone(&mongo_conn);
-                                match embed_payload(
-                                    mongo_conn_clone,
-                                    &metadata,
-                                    &text,
-                                    Some(ds_clone),
-                                    EmbeddingModels::from(embedding_model_name),
-                                )
-                                    .await
-                                {
-                                    Ok(point_struct) => {
-                                        if let Ok(_bulk_upload_result) =
-                                            qdrant
-                                                .upsert_data_point_blocking(
-                                                    point_struct,
-                                                    Some(vector_length),
-                                                    Some(embedding_model_name_clone),
-                                                )
-                                                .await
-                                        {
-                                            // let _ = redis_connection.increment_count(&"some_key".to_string(), 1);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        eprintln!("An error occurred while upserting  point structs to Qdrant: {}", e);
-                                    }
+                        let qdrant = Qdrant::new(qdrant_conn, datasource_id.clone());
+                        let primary_key = match get_stream_primary_key(&mongodb_connection, datasource_id.as_str(), stream.as_str()).await {
+                            Ok(primary_key) => primary_key,
+                            Err(e) => {
+                                return Err(Outcome::Retry(format!("Could not look up the primary key of stream {}: {}", stream, e)));
+                            }
+                        };
+                        let key = record_key(stream.as_str(), &primary_key, &message_data);
+                        let Value::Object(data_obj) = message_data else {
+                            return Err(Outcome::Reject("Record is not a JSON object".to_string()));
+                        };
+                        let mut metadata = convert_serde_value_to_hashmap_string(data_obj);
+                        let Some(text_field) = embedding_field else {
+                            return Err(Outcome::Reject(format!("Datasource {} has no embedding field", datasource_id)));
+                        };
+                        let Some(text) = metadata.remove(text_field.as_str()) else {
+                            return Err(Outcome::Reject(format!("Record has no embedding field '{}'", text_field)));
+                        };
+                        metadata.insert("page_content".to_string(), text.to_owned());
+                        let record_hash = metadata_hash(&metadata);
+                        metadata.insert(METADATA_HASH_KEY.to_string(), record_hash.clone());
+                        // records synced again unchanged already have a point, don't pay to embed them again
+                        let point_id = record_point_id(datasource_id.as_str(), &metadata, key.as_ref());
+                        match qdrant.get_existing_points(vec![PointId::from(point_id.to_string())]).await {
+                            Ok(existing) => {
+                                // points of records without a key are identified by their content
+                                let unchanged = match existing.get(&point_id.to_string()) {
+                                    Some(stored_hash) => key.is_none() || *stored_hash == record_hash,
+                                    None => false,
+                                };
+                                if unchanged {
+                                    println!("Record {} is unchanged since it was last synced, skipping it", point_id);
+                                    return Err(Outcome::Done);
                                 }
                             }
+                            Err(e) => println!("Could not check whether the record is already stored. Error: {}", e),
                         }
+                        let embedding_provider = match get_embedding_provider(&mongodb_connection, datasource_id.as_str()).await {
+                            Ok(provider) => provider,
+                            Err(e) => {
+                                return Err(Outcome::Retry(format!("Could not set up embedding provider: {}", e)));
+                            }
+                        };
+                        Ok(PreparedRecord {
+                            datasource_id,
+                            point_id,
+                            metadata,
+                            text,
+                            vector_length,
+                            embedding_model_name,
+                            embedding_provider,
+                        })
                     }
                     None => {
-                        eprintln!("Model mongo object returned None!");
+                        Err(Outcome::Reject(format!("There was no embedding model associated with datasource: {}", datasource_id)))
                     }
                 },
                 Err(e) => {
-                    println!("An error occurred: {}", e);
+                    Err(Outcome::Retry(format!("An error occurred: {}", e)))
                 }
             }
         }
         Err(e) => {
-            eprintln!(
+            Err(Outcome::Reject(format!(
                 "An error occurred while attempting to convert message to JSON: {}",
                 e
-            );
+            )))
         }
     }
 }
+
+// The values of the record's primary key fields. A record missing one of them is identified by its
+// content like the records of streams without a primary key.
+fn record_key(stream: &str, primary_key: &[Vec<String>], record: &Value) -> Option<RecordKey> {
+    if primary_key.is_empty() {
+        return None;
+    }
+    let values = primary_key
+        .iter()
+        .map(|path| {
+            path.iter()
+                .try_fold(record, |value, field| value.get(field))
+                .filter(|value| !value.is_null())
+        })
+        .collect::<Option<Vec<&Value>>>()?;
+    Some(RecordKey {
+        stream: stream.to_string(),
+        values: serde_json::to_string(&values).ok()?,
+    })
+}
+
+/// Embeds a prepared record into a point
+pub async fn embed_record(record: PreparedRecord) -> Result<EmbeddedRecord, Outcome> {
+    match embed_payload(
+        &record.embedding_provider,
+        &record.metadata,
+        &record.text,
+        record.point_id,
+    )
+        .await
+    {
+        Ok(point) => Ok(EmbeddedRecord {
+            datasource_id: record.datasource_id,
+            point,
+            vector_length: record.vector_length,
+            embedding_model_name: record.embedding_model_name,
+        }),
+        Err(e) => Err(Outcome::Retry(format!("An error occurred while embedding the record: {}", e))),
+    }
+}
+
+/// Uploads an embedded record to its datasource's collection
+pub async fn upsert_record(qdrant_conn: Arc<RwLock<QdrantClient>>, record: EmbeddedRecord) -> Outcome {
+    let qdrant = Qdrant::new(qdrant_conn, record.datasource_id);
+    match qdrant
+        .upsert_data_point_blocking(
+            record.point,
+            Some(record.vector_length),
+            Some(record.embedding_model_name),
+        )
+        .await
+    {
+        // let _ = redis_connection.increment_count(&"some_key".to_string(), 1);
+        Ok(true) => Outcome::Done,
+        Ok(false) => Outcome::Retry("Qdrant did not acknowledge the upsert".to_string()),
+        Err(e) => Outcome::Retry(format!("An error occurred while upserting the record to Qdrant: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn records_are_keyed_by_their_primary_key() {
+        let primary_key = vec![vec!["id".to_string()], vec!["owner".to_string(), "login".to_string()]];
+        let record = json!({"id": 7, "owner": {"login": "octocat"}, "title": "Old title"});
+        let key = record_key("issues", &primary_key, &record).unwrap();
+        assert_eq!(key.values, r#"[7,"octocat"]"#);
+
+        let changed = json!({"id": 7, "owner": {"login": "octocat"}, "title": "New title"});
+        assert_eq!(record_key("issues", &primary_key, &changed), Some(key.clone()));
+        let payload = HashMap::from([("title".to_string(), "Old title".to_string())]);
+        let changed_payload = HashMap::from([("title".to_string(), "New title".to_string())]);
+        assert_eq!(
+            record_point_id("datasource", &payload, Some(&key)),
+            record_point_id("datasource", &changed_payload, Some(&key))
+        );
+        assert_ne!(
+            record_point_id("datasource", &payload, None),
+            record_point_id("datasource", &changed_payload, None)
+        );
+    }
+
+    #[test]
+    fn records_missing_a_key_field_have_no_key() {
+        let primary_key = vec![vec!["id".to_string()]];
+        assert_eq!(record_key("issues", &primary_key, &json!({"title": "No id"})), None);
+        assert_eq!(record_key("issues", &primary_key, &json!({"id": null})), None);
+        assert_eq!(record_key("issues", &[], &json!({"id": 7})), None);
+    }
+}
@@ -0,0 +1,307 @@
+//! Recursive, token bounded text splitting.
+//!
+//! Text is cut on the coarsest separator that gives pieces within the token budget, trying
+//! paragraphs, then lines, then sentences, then words and finally single characters. The pieces are
+//! then merged back together greedily into chunks of up to `max_tokens`, repeating the tail of each
+//! chunk at the start of the next one as overlap.
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::task;
+
+use crate::data::models::Document;
+use crate::llm::providers::{EmbeddingProvider, EmbeddingProviders};
+use crate::llm::tokens::TokenCounter;
+use crate::mongo::models::DataSources;
+
+pub const DEFAULT_MAX_TOKENS: usize = 256;
+pub const DEFAULT_OVERLAP_TOKENS: usize = 32;
+
+static PARAGRAPH_BREAK: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n[ \t\r]*\n\s*").unwrap());
+static LINE_BREAK: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n\s*").unwrap());
+static SENTENCE_END: Lazy<Regex> = Lazy::new(|| Regex::new(r#"[.!?]+["')\]]*\s+"#).unwrap());
+static WHITESPACE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s+").unwrap());
+
+/// Chunk size limits, in model tokens
+#[derive(Copy, Clone, Debug)]
+pub struct ChunkSize {
+    pub max_tokens: usize,
+    pub overlap_tokens: usize,
+}
+
+impl Default for ChunkSize {
+    fn default() -> Self {
+        ChunkSize {
+            max_tokens: DEFAULT_MAX_TOKENS,
+            overlap_tokens: DEFAULT_OVERLAP_TOKENS,
+        }
+    }
+}
+
+impl From<&DataSources> for ChunkSize {
+    fn from(datasource: &DataSources) -> Self {
+        let default = ChunkSize::default();
+        ChunkSize {
+            max_tokens: datasource
+                .chunkSize
+                .filter(|size| *size > 0)
+                .map_or(default.max_tokens, |size| size as usize),
+            overlap_tokens: datasource
+                .chunkOverlap
+                .filter(|overlap| *overlap >= 0)
+                .map_or(default.overlap_tokens, |overlap| overlap as usize),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Separator {
+    Paragraph,
+    Line,
+    Sentence,
+    Word,
+    Character,
+}
+
+impl Separator {
+    fn next(self) -> Option<Separator> {
+        match self {
+            Separator::Paragraph => Some(Separator::Line),
+            Separator::Line => Some(Separator::Sentence),
+            Separator::Sentence => Some(Separator::Word),
+            Separator::Word => Some(Separator::Character),
+            Separator::Character => None,
+        }
+    }
+
+    /// Splits text into contiguous byte ranges. Separators stay attached to the end of the piece
+    /// before them so that the pieces cover the text exactly.
+    fn split(self, text: &str) -> Vec<(usize, usize)> {
+        let ends: Vec<usize> = match self {
+            Separator::Paragraph => PARAGRAPH_BREAK.find_iter(text).map(|m| m.end()).collect(),
+            Separator::Line => LINE_BREAK.find_iter(text).map(|m| m.end()).collect(),
+            Separator::Sentence => SENTENCE_END.find_iter(text).map(|m| m.end()).collect(),
+            Separator::Word => WHITESPACE.find_iter(text).map(|m| m.end()).collect(),
+            Separator::Character => text.char_indices().skip(1).map(|(i, _)| i).collect(),
+        };
+        let mut ranges = vec![];
+        let mut start = 0;
+        for end in ends {
+            if end > start {
+                ranges.push((start, end));
+                start = end;
+            }
+        }
+        if start < text.len() {
+            ranges.push((start, text.len()));
+        }
+        ranges
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Piece {
+    start: usize,
+    end: usize,
+    tokens: usize,
+}
+
+/// A chunk of the original text along with its byte offsets into that text
+#[derive(Clone, Debug)]
+pub struct TextChunk {
+    pub text: String,
+    pub start_index: usize,
+    pub end_index: usize,
+}
+
+pub struct RecursiveTextSplitter {
+    max_tokens: usize,
+    overlap_tokens: usize,
+    token_counter: TokenCounter,
+}
+
+impl RecursiveTextSplitter {
+    pub fn new(max_tokens: usize, overlap_tokens: usize, token_counter: TokenCounter) -> Self {
+        let max_tokens = max_tokens.max(1);
+        RecursiveTextSplitter {
+            max_tokens,
+            // an overlap as large as the chunk would never make progress
+            overlap_tokens: overlap_tokens.min(max_tokens / 2),
+            token_counter,
+        }
+    }
+
+    pub fn split_text(&self, text: &str) -> Vec<TextChunk> {
+        let mut pieces = vec![];
+        self.collect_pieces(text, 0, Separator::Paragraph, &mut pieces);
+        self.merge_pieces(text, pieces)
+    }
+
+    // Breaks the text down until every piece fits in a chunk on its own
+    fn collect_pieces(&self, text: &str, offset: usize, separator: Separator, pieces: &mut Vec<Piece>) {
+        for (start, end) in separator.split(text) {
+            let piece = &text[start..end];
+            let tokens = self.token_counter.count(piece);
+            if tokens > self.max_tokens {
+                if let Some(next_separator) = separator.next() {
+                    self.collect_pieces(piece, offset + start, next_separator, pieces);
+                    continue;
+                }
+            }
+            pieces.push(Piece {
+                start: offset + start,
+                end: offset + end,
+                tokens,
+            });
+        }
+    }
+
+    fn merge_pieces(&self, text: &str, pieces: Vec<Piece>) -> Vec<TextChunk> {
+        let mut chunks = vec![];
+        let mut current: Vec<Piece> = vec![];
+        let mut current_tokens = 0;
+        for piece in pieces {
+            if !current.is_empty() && current_tokens + piece.tokens > self.max_tokens {
+                chunks.extend(make_chunk(text, &current));
+                // keep the tail of the chunk we just finished as the start of the next one
+                let mut keep_from = 0;
+                while keep_from < current.len()
+                    && (current_tokens > self.overlap_tokens
+                    || current_tokens + piece.tokens > self.max_tokens)
+                {
+                    current_tokens -= current[keep_from].tokens;
+                    keep_from += 1;
+                }
+                current.drain(..keep_from);
+            }
+            current_tokens += piece.tokens;
+            current.push(piece);
+        }
+        if !current.is_empty() {
+            chunks.extend(make_chunk(text, &current));
+        }
+        chunks
+    }
+}
+
+fn make_chunk(text: &str, pieces: &[Piece]) -> Option<TextChunk> {
+    let (first, last) = (pieces.first()?, pieces.last()?);
+    let raw = &text[first.start..last.end];
+    let trimmed_start = raw.len() - raw.trim_start().len();
+    let trimmed = raw.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+    let start_index = first.start + trimmed_start;
+    Some(TextChunk {
+        text: trimmed.to_string(),
+        start_index,
+        end_index: start_index + trimmed.len(),
+    })
+}
+
+///
+///
+/// # Arguments
+///
+/// * `text`: The document text
+/// * `metadata`: Metadata copied onto every chunk
+/// * `chunk_size`: The chunk size and overlap in tokens. The chunk size is capped at the model's maximum input length
+/// * `embedding_provider`: The provider whose tokenizer sizes the chunks
+///
+/// returns: Result<Vec<Document>, Error> The chunks, not yet embedded
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn chunk_recursively(
+    text: String,
+    metadata: Option<HashMap<String, String>>,
+    chunk_size: ChunkSize,
+    embedding_provider: Arc<EmbeddingProviders>,
+) -> Result<Vec<Document>> {
+    let token_counter = embedding_provider.token_counter().await?;
+    let max_tokens = chunk_size
+        .max_tokens
+        .min(embedding_provider.max_input_tokens());
+    let splitter = RecursiveTextSplitter::new(max_tokens, chunk_size.overlap_tokens, token_counter);
+    // tokenizing the whole document is CPU bound so keep it off the async workers
+    let text_chunks = task::spawn_blocking(move || splitter.split_text(&text)).await?;
+    let documents = text_chunks
+        .into_iter()
+        .map(|chunk| {
+            let mut chunk_metadata = metadata.clone().unwrap_or_default();
+            chunk_metadata.insert("start_index".to_string(), chunk.start_index.to_string());
+            chunk_metadata.insert("end_index".to_string(), chunk.end_index.to_string());
+            chunk_metadata.insert("page_content".to_string(), chunk.text.clone());
+            Document::new(chunk.text, Some(chunk_metadata), None)
+        })
+        .collect();
+    Ok(documents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words() -> TokenCounter {
+        TokenCounter::new(|text| text.split_whitespace().count())
+    }
+
+    fn texts(chunks: &[TextChunk]) -> Vec<&str> {
+        chunks.iter().map(|chunk| chunk.text.as_str()).collect()
+    }
+
+    #[test]
+    fn paragraphs_are_kept_together_when_they_fit() {
+        let splitter = RecursiveTextSplitter::new(2, 0, words());
+        let chunks = splitter.split_text("one two\n\nthree four");
+        assert_eq!(texts(&chunks), vec!["one two", "three four"]);
+    }
+
+    #[test]
+    fn the_tail_of_each_chunk_starts_the_next() {
+        let splitter = RecursiveTextSplitter::new(4, 2, words());
+        let text = "a b c d e f g h i j";
+        let chunks = splitter.split_text(text);
+        assert_eq!(texts(&chunks), vec!["a b c d", "c d e f", "e f g h", "g h i j"]);
+        assert_eq!(chunks[1].start_index, text.find('c').unwrap());
+    }
+
+    #[test]
+    fn overlap_is_capped_at_half_a_chunk() {
+        let splitter = RecursiveTextSplitter::new(2, 10, words());
+        let chunks = splitter.split_text("a b c d");
+        assert_eq!(texts(&chunks), vec!["a b", "b c", "c d"]);
+    }
+
+    #[test]
+    fn words_larger_than_a_chunk_are_split_into_characters() {
+        let splitter = RecursiveTextSplitter::new(3, 0, TokenCounter::new(|text| text.chars().count()));
+        let chunks = splitter.split_text("abcdefgh");
+        assert_eq!(texts(&chunks), vec!["abc", "def", "gh"]);
+    }
+
+    #[test]
+    fn characters_larger_than_a_chunk_become_chunks_of_their_own() {
+        // every byte is a token, so the two byte characters can not fit in a chunk of one token
+        let splitter = RecursiveTextSplitter::new(1, 0, TokenCounter::new(|text| text.len()));
+        let chunks = splitter.split_text("aéb");
+        assert_eq!(texts(&chunks), vec!["a", "é", "b"]);
+    }
+
+    #[test]
+    fn offsets_are_byte_offsets_into_multibyte_text() {
+        let splitter = RecursiveTextSplitter::new(2, 0, words());
+        let text = "  héllo wörld. ünïcode tëxt,\nçà marche ";
+        let chunks = splitter.split_text(text);
+        assert_eq!(texts(&chunks), vec!["héllo wörld.", "ünïcode tëxt,", "çà marche"]);
+        for chunk in chunks {
+            assert_eq!(&text[chunk.start_index..chunk.end_index], chunk.text);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Documents made up of independently chunked segments.
+//!
+//! Some extractors know more about the structure of a document than plain text can carry, such as
+//! the slides of a presentation or the sheets of a workbook. They split the document into segments
+//! which are recorded in the metadata under [`SEGMENTS_KEY`]. Each segment is chunked on its own so
+//! that no chunk straddles two segments, and its metadata is copied onto every chunk taken from it.
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::task;
+
+use crate::data::models::Document;
+use crate::data::recursive_splitting::ChunkSize;
+use crate::llm::providers::{EmbeddingProvider, EmbeddingProviders};
+use crate::llm::tokens::TokenCounter;
+
+/// Metadata key holding the JSON encoded segments of a document
+pub const SEGMENTS_KEY: &str = "segments";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SegmentContent {
+    /// Free text, chunked with the datasource's chunking strategy
+    Text(String),
+    /// Table rows along with their row numbers, chunked into ranges of whole rows that each
+    /// start with the header row
+    Rows {
+        header: Option<String>,
+        rows: Vec<(usize, String)>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Segment {
+    pub metadata: HashMap<String, String>,
+    pub content: SegmentContent,
+}
+
+impl Segment {
+    pub fn text(&self) -> String {
+        match &self.content {
+            SegmentContent::Text(text) => text.clone(),
+            SegmentContent::Rows { header, rows } => header
+                .iter()
+                .chain(rows.iter().map(|(_, row)| row))
+                .cloned()
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `segments`: The segments of the document
+/// * `metadata`: Metadata describing the whole document
+///
+/// returns: (String, HashMap<String, String>) The text of the document, for anything that wants
+/// it in full, and the metadata with the encoded segments added to it
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn segmented_document(
+    segments: Vec<Segment>,
+    mut metadata: HashMap<String, String>,
+) -> (String, HashMap<String, String>) {
+    let text = segments
+        .iter()
+        .map(|segment| segment.text())
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    metadata.insert(
+        SEGMENTS_KEY.to_string(),
+        serde_json::to_string(&segments).unwrap_or_default(),
+    );
+    (text, metadata)
+}
+
+pub fn decode_segments(segments: &str) -> Result<Vec<Segment>> {
+    serde_json::from_str(segments).map_err(|e| anyhow!("Could not decode document segments: {}", e))
+}
+
+struct RowRange {
+    text: String,
+    start_row: usize,
+    end_row: usize,
+}
+
+// Rows are never split, a row that does not fit in a chunk on its own becomes a chunk by itself
+fn group_rows(
+    header: Option<String>,
+    rows: Vec<(usize, String)>,
+    max_tokens: usize,
+    token_counter: &TokenCounter,
+) -> Vec<RowRange> {
+    let header_tokens = header.as_deref().map_or(0, |h| token_counter.count(h) + 1);
+    let mut ranges: Vec<RowRange> = vec![];
+    let mut current: Vec<(usize, String)> = vec![];
+    let mut current_tokens = header_tokens;
+    let mut flush = |current: &mut Vec<(usize, String)>| {
+        if let (Some((start_row, _)), Some((end_row, _))) = (current.first(), current.last()) {
+            let text = header
+                .iter()
+                .chain(current.iter().map(|(_, row)| row))
+                .cloned()
+                .collect::<Vec<String>>()
+                .join("\n");
+            ranges.push(RowRange {
+                text,
+                start_row: *start_row,
+                end_row: *end_row,
+            });
+        }
+        current.clear();
+    };
+    for (row_number, row) in rows {
+        let row_tokens = token_counter.count(&row) + 1;
+        if !current.is_empty() && current_tokens + row_tokens > max_tokens {
+            flush(&mut current);
+            current_tokens = header_tokens;
+        }
+        current_tokens += row_tokens;
+        current.push((row_number, row));
+    }
+    flush(&mut current);
+    ranges
+}
+
+///
+///
+/// # Arguments
+///
+/// * `header`: The header row, repeated at the top of every chunk so each chunk can be read on its own
+/// * `rows`: The rows along with their row numbers
+/// * `metadata`: Metadata copied onto every chunk
+/// * `chunk_size`: The chunk size in tokens, capped at the model's maximum input length. Overlap does not apply to rows
+/// * `embedding_provider`: The provider whose tokenizer sizes the chunks
+///
+/// returns: Result<Vec<Document>, Error> Chunks of whole rows with `start_row` and `end_row` set, not
+/// yet embedded
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn chunk_rows(
+    header: Option<String>,
+    rows: Vec<(usize, String)>,
+    metadata: HashMap<String, String>,
+    chunk_size: ChunkSize,
+    embedding_provider: Arc<EmbeddingProviders>,
+) -> Result<Vec<Document>> {
+    let token_counter = embedding_provider.token_counter().await?;
+    let max_tokens = chunk_size
+        .max_tokens
+        .min(embedding_provider.max_input_tokens());
+    let ranges =
+        task::spawn_blocking(move || group_rows(header, rows, max_tokens, &token_counter)).await?;
+    let documents = ranges
+        .into_iter()
+        .map(|range| {
+            let mut chunk_metadata = metadata.clone();
+            chunk_metadata.insert("start_row".to_string(), range.start_row.to_string());
+            chunk_metadata.insert("end_row".to_string(), range.end_row.to_string());
+            chunk_metadata.insert("page_content".to_string(), range.text.clone());
+            Document::new(range.text, Some(chunk_metadata), None)
+        })
+        .collect();
+    Ok(documents)
+}
@@ -0,0 +1,365 @@
+//! Row by row ingestion of delimited text files (CSV and TSV).
+//!
+//! Every row becomes a single point. The text that is embedded is either taken from the
+//! datasource's embedding field or rendered from its embedding template, and the columns are stored
+//! in the payload with their numbers, booleans and dates kept as such so they can be filtered on.
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use csv::StringRecord;
+use once_cell::sync::Lazy;
+use qdrant_client::prelude::PointStruct;
+use regex::Regex;
+use serde_json::{Number, Value};
+use std::collections::HashMap;
+
+use crate::llm::providers::{EmbeddingProvider, EmbeddingProviders};
+use crate::llm::utils::embed_text_chunks_async;
+use crate::mongo::models::DataSources;
+use crate::qdrant::dedup::{metadata_hash, StoredDocument, CONTENT_HASH_KEY, DOCUMENT_ID_KEY, METADATA_HASH_KEY};
+use crate::qdrant::helpers::construct_point_struct;
+
+/// Number of rows embedded and upserted together
+pub const ROW_BATCH_SIZE: usize = 256;
+// Set on files fetched from a url
+const FETCHED_AT_KEY: &str = "fetched_at";
+
+static TEMPLATE_PLACEHOLDER: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{\s*([^{}]+?)\s*\}").unwrap());
+
+const DATE_FORMATS: [&str; 3] = ["%Y-%m-%d", "%Y/%m/%d", "%d.%m.%Y"];
+const DATETIME_FORMATS: [&str; 3] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"];
+
+/// Where the embedded text of a row comes from
+#[derive(Clone, Debug)]
+pub enum RowText {
+    /// A single column, which is then left out of the payload fields
+    Column(String),
+    /// A template such as `{title}: {description}` with placeholders naming columns
+    Template(String),
+    /// Every column, written as `header: value` lines
+    AllColumns,
+}
+
+impl From<&DataSources> for RowText {
+    fn from(datasource: &DataSources) -> Self {
+        let non_empty = |value: &Option<String>| value.clone().filter(|v| !v.trim().is_empty());
+        if let Some(template) = non_empty(&datasource.embeddingTemplate) {
+            RowText::Template(template)
+        } else if let Some(column) = non_empty(&datasource.embeddingField) {
+            RowText::Column(column)
+        } else {
+            RowText::AllColumns
+        }
+    }
+}
+
+impl RowText {
+    fn render(&self, row: &[(String, String)]) -> String {
+        match self {
+            RowText::Column(column) => row
+                .iter()
+                .find(|(header, _)| header == column)
+                .map(|(_, value)| value.trim().to_string())
+                .unwrap_or_default(),
+            RowText::Template(template) => TEMPLATE_PLACEHOLDER
+                .replace_all(template, |captures: &regex::Captures| {
+                    row.iter()
+                        .find(|(header, _)| header == &captures[1])
+                        .map(|(_, value)| value.trim().to_string())
+                        .unwrap_or_default()
+                })
+                .trim()
+                .to_string(),
+            RowText::AllColumns => row
+                .iter()
+                .filter(|(_, value)| !value.trim().is_empty())
+                .map(|(header, value)| format!("{}: {}", header, value.trim()))
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `value`: A raw cell value
+///
+/// Empty cells become null. Numbers with leading zeros, such as zip codes or IDs, are kept as text
+/// as turning them into numbers would lose the zeros. Dates and datetimes are normalised to RFC 3339.
+///
+/// returns: Value
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn infer_value(value: &str) -> Value {
+    let value = value.trim();
+    if value.is_empty() {
+        return Value::Null;
+    }
+    match value.to_lowercase().as_str() {
+        "true" => return Value::Bool(true),
+        "false" => return Value::Bool(false),
+        _ => {}
+    }
+    let digits = value.trim_start_matches(['-', '+']);
+    let has_leading_zero = digits.len() > 1 && digits.starts_with('0') && !digits.starts_with("0.");
+    if !has_leading_zero {
+        if let Ok(integer) = value.parse::<i64>() {
+            return Value::Number(integer.into());
+        }
+        if let Some(number) = value.parse::<f64>().ok().and_then(Number::from_f64) {
+            // keeps words such as "inf" and "NaN" as text
+            if value.chars().any(|c| c.is_ascii_digit()) {
+                return Value::Number(number);
+            }
+        }
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Value::String(datetime.to_rfc3339());
+    }
+    for format in DATETIME_FORMATS {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(value, format) {
+            return Value::String(datetime.and_utc().to_rfc3339());
+        }
+    }
+    for format in DATE_FORMATS {
+        if let Ok(date) = NaiveDate::parse_from_str(value, format) {
+            return Value::String(date.format("%Y-%m-%d").to_string());
+        }
+    }
+    Value::String(value.to_string())
+}
+
+// Blank or repeated headers would make columns overwrite each other in the payload
+fn unique_headers(headers: &StringRecord) -> Vec<String> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| {
+            let header = header.trim();
+            let header = if header.is_empty() {
+                format!("column_{}", i + 1)
+            } else {
+                header.to_string()
+            };
+            let count = seen.entry(header.clone()).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                format!("{}_{}", header, count)
+            } else {
+                header
+            }
+        })
+        .collect()
+}
+
+struct Row {
+    row_number: usize,
+    text: String,
+    content_hash: String,
+    payload: HashMap<String, Value>,
+}
+
+/// The rows of a file that were upserted and that could not be embedded
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RowCounts {
+    pub upserted: usize,
+    pub failed: usize,
+}
+
+impl RowCounts {
+    fn add(&mut self, other: RowCounts) {
+        self.upserted += other.upserted;
+        self.failed += other.failed;
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `path`: Path to the file
+/// * `delimiter`: The field delimiter, `b','` for CSV and `b'\t'` for TSV
+/// * `metadata`: Metadata stored on every row, the original name of the file and where it came from
+/// * `row_text`: Where each row's embedded text comes from
+/// * `embedding_provider`: The provider that embeds the rows
+/// * `vector_length`: The length of the embedding vectors, used if the collection has to be created
+/// * `stored`: The collection the rows are written to, with the rows of the file it already holds
+///
+/// Rows that are already stored are not embedded again and, once every row has been read and
+/// embedded, the stored rows the file no longer contains are deleted. Rows that fail to embed are
+/// counted rather than failing the rest of the file.
+///
+/// returns: Result<RowCounts, Error> How many rows were upserted and how many failed to embed
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn ingest_delimited_file(
+    path: &str,
+    delimiter: u8,
+    metadata: &HashMap<String, String>,
+    row_text: &RowText,
+    embedding_provider: &EmbeddingProviders,
+    vector_length: u64,
+    stored: &mut StoredDocument,
+) -> Result<RowCounts> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_path(path)
+        .map_err(|e| anyhow!("Could not open delimited file. Error: {}", e))?;
+    let headers = unique_headers(reader.headers()?);
+    if let RowText::Column(column) = row_text {
+        if !headers.contains(column) {
+            return Err(anyhow!(
+                "Embedding field '{}' is not one of the columns: {}",
+                column,
+                headers.join(", ")
+            ));
+        }
+    }
+    let mut counts = RowCounts::default();
+    let mut batch: Vec<Row> = vec![];
+    for (i, record) in reader.records().enumerate() {
+        let row_number = i + 1;
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                println!("Skipping row {} which could not be read. Error: {}", row_number, e);
+                continue;
+            }
+        };
+        let row: Vec<(String, String)> = headers
+            .iter()
+            .cloned()
+            .zip(record.iter().map(String::from))
+            .collect();
+        let text = row_text.render(&row);
+        if text.is_empty() {
+            println!("Skipping row {} which has no text to embed", row_number);
+            continue;
+        }
+        let mut payload: HashMap<String, Value> = row
+            .iter()
+            .filter(|(header, _)| !matches!(row_text, RowText::Column(column) if column == header))
+            .map(|(header, value)| (header.clone(), infer_value(value)))
+            .collect();
+        // when the file was fetched changes on every fetch, so it is left out of the row's identity
+        payload.extend(
+            metadata
+                .iter()
+                .filter(|(key, _)| key.as_str() != FETCHED_AT_KEY)
+                .map(|(key, value)| (key.clone(), Value::String(value.clone()))),
+        );
+        payload.insert("page_content".to_string(), Value::String(text.clone()));
+        payload.insert(DOCUMENT_ID_KEY.to_string(), Value::String(stored.document_id().to_string()));
+        // a row is identified by all of its columns, not just the embedded text, and not by where it
+        // is in the file so that inserting a row does not change the rows after it
+        let content_hash = metadata_hash(&payload);
+        payload.insert("row_number".to_string(), Value::Number(row_number.into()));
+        if let Some(fetched_at) = metadata.get(FETCHED_AT_KEY) {
+            payload.insert(FETCHED_AT_KEY.to_string(), Value::String(fetched_at.clone()));
+        }
+        payload.insert(CONTENT_HASH_KEY.to_string(), Value::String(content_hash.clone()));
+        payload.insert(METADATA_HASH_KEY.to_string(), Value::String(metadata_hash(&payload)));
+        batch.push(Row {
+            row_number,
+            text,
+            content_hash,
+            payload,
+        });
+        if batch.len() >= ROW_BATCH_SIZE {
+            counts.add(upsert_rows(std::mem::take(&mut batch), embedding_provider, vector_length, stored).await?);
+        }
+    }
+    if !batch.is_empty() {
+        counts.add(upsert_rows(batch, embedding_provider, vector_length, stored).await?);
+    }
+    // the old versions of rows that failed would otherwise be deleted before the new ones are stored
+    if counts.failed == 0 {
+        stored.delete_stale().await;
+    }
+    Ok(counts)
+}
+
+async fn upsert_rows(
+    rows: Vec<Row>,
+    embedding_provider: &EmbeddingProviders,
+    vector_length: u64,
+    stored: &mut StoredDocument,
+) -> Result<RowCounts> {
+    let mut rows_to_embed = vec![];
+    for row in rows {
+        stored.mark_seen(row.content_hash.as_str());
+        if !stored.is_stored(row.content_hash.as_str()) {
+            rows_to_embed.push(row);
+            continue;
+        }
+        // the row is unchanged but may have moved, in which case only its row number is out of date
+        let metadata_hash = row.payload.get(METADATA_HASH_KEY).and_then(|h| h.as_str()).unwrap_or_default();
+        if stored.metadata_changed(row.content_hash.as_str(), metadata_hash) {
+            if let Err(e) = stored.refresh_payload(row.content_hash.as_str(), &row.payload).await {
+                println!("An error occurred while updating row {}. Error: {}", row.row_number, e);
+            }
+        }
+    }
+    let rows = rows_to_embed;
+    let mut counts = RowCounts::default();
+    if rows.is_empty() {
+        return Ok(counts);
+    }
+    let embeddings = embed_text_chunks_async(
+        embedding_provider,
+        rows.iter().map(|row| row.text.clone()).collect(),
+    )
+        .await;
+    let mut points: Vec<PointStruct> = vec![];
+    for (row, embedding) in rows.into_iter().zip(embeddings) {
+        match embedding {
+            Ok(embedding) => {
+                if let Some(point) = construct_point_struct(
+                    stored.point_id(row.content_hash.as_str()),
+                    &embedding,
+                    row.text.as_str(),
+                    row.payload,
+                    embedding_provider.model_name(),
+                )
+                    .await
+                {
+                    points.push(point);
+                }
+            }
+            Err(e) => {
+                println!("An error occurred while embedding row {}. Error: {}", row.row_number, e);
+                counts.failed += 1;
+            }
+        }
+    }
+    if points.is_empty() {
+        return Ok(counts);
+    }
+    let count = points.len();
+    match stored
+        .collection()
+        .bulk_upsert_data(
+            points,
+            Some(vector_length),
+            Some(embedding_provider.model_name().to_string()),
+        )
+        .await?
+    {
+        true => {
+            counts.upserted = count;
+            Ok(counts)
+        }
+        false => Err(anyhow!("Qdrant did not acknowledge the upsert of {} rows", count)),
+    }
+}
@@ -1,14 +1,12 @@
 use crate::data::models::Document;
 use crate::data::utils::{cosine_similarity, percentile};
-use crate::llm::utils::embed_text;
-use crate::llm::{models::EmbeddingModels, utils::embed_text_chunks_async};
+use crate::llm::providers::EmbeddingProviders;
+use crate::llm::utils::embed_text_chunks_async;
 use crate::mongo::models::ChunkingStrategy;
 use anyhow::{anyhow, Result};
 use ndarray::Array1;
 use std::collections::HashMap;
-use std::sync::{Arc};
-use mongodb::Database;
-use tokio::sync::RwLock;
+use std::sync::Arc;
 
 // `Sentence` is a struct that holds the embedding and other metadata
 #[derive(Clone, Debug)]
@@ -16,6 +14,9 @@ struct Sentence {
     sentence_embedding: Array1<f32>,
     distance_to_next: Option<f32>,
     sentence: Option<String>,
+    // byte offsets of the sentence in the text it was split from
+    start_index: usize,
+    end_index: usize,
 }
 
 // a sentence should also have the associated text
@@ -25,10 +26,35 @@ impl Default for Sentence {
             sentence_embedding: Array1::from_vec(vec![]),
             distance_to_next: None,
             sentence: None,
+            start_index: 0,
+            end_index: 0,
         }
     }
 }
 
+// The pieces of `text` between the `delimiters`, given as (offset, length), each with the byte
+// offset it starts at
+fn pieces_between(text: &str, delimiters: impl Iterator<Item=(usize, usize)>) -> Vec<(usize, &str)> {
+    let mut pieces = vec![];
+    let mut start = 0;
+    for (offset, length) in delimiters {
+        pieces.push((start, &text[start..offset]));
+        start = offset + length;
+    }
+    pieces.push((start, &text[start..]));
+    pieces
+}
+
+// A chunk of the text with the byte offsets it spans in the text, which its own text may not be a
+// substring of when it joins several sentences
+fn chunk_spanning(text: String, embedding: Option<Vec<f32>>, start_index: usize, end_index: usize) -> Document {
+    let offsets = HashMap::from([
+        ("start_index".to_string(), start_index.to_string()),
+        ("end_index".to_string(), end_index.to_string()),
+    ]);
+    Document::new(text, Some(offsets), embedding)
+}
+
 
 fn calculate_cosine_distances(sentences: &mut Vec<Sentence>) -> Vec<f32> {
     let mut distances = Vec::new();
@@ -61,175 +87,177 @@ fn calculate_cosine_distances(sentences: &mut Vec<Sentence>) -> Vec<f32> {
 }
 
 pub struct Chunker {
-    embedding_model: EmbeddingModels,
+    embedding_provider: Arc<EmbeddingProviders>,
     add_start_index: bool,
     chunking_strategy: Option<ChunkingStrategy>,
     chunking_character: Option<String>,
-    mongo_conn: Arc<RwLock<Database>>,
-    datasource_id: String,
 }
 
 impl Chunker {
     pub fn new(
-        embedding_model: EmbeddingModels,
+        embedding_provider: Arc<EmbeddingProviders>,
         add_start_index: bool,
         chunking_strategy: Option<ChunkingStrategy>,
         chunking_character: Option<String>,
-        mongo_conn: Arc<RwLock<Database>>,
-        datasource_id: String,
     ) -> Self {
         Chunker {
-            embedding_model,
+            embedding_provider,
             add_start_index,
             chunking_strategy,
             chunking_character,
-            mongo_conn,
-            datasource_id,
         }
     }
 
     async fn form_sentences(&self, text: &str) -> Vec<HashMap<String, String>> {
-        let mut sentence_list: Vec<&str> = vec![];
+        let mut sentence_list: Vec<(usize, &str)> = vec![];
         match &self.chunking_strategy.as_ref().unwrap() {
             ChunkingStrategy::SEMANTIC_CHUNKING => {
-                sentence_list = text.split(&['.', '?', '!']).collect();
+                let delimiters = text.match_indices(&['.', '?', '!'][..]);
+                sentence_list = pieces_between(text, delimiters.map(|(i, d)| (i, d.len())));
             }
             ChunkingStrategy::CHARACTER_CHUNKING => {
-                sentence_list = text
-                    .split(&self.chunking_character.as_ref().unwrap().as_str())
-                    .collect();
+                let character = self.chunking_character.as_ref().unwrap().as_str();
+                let delimiters = text.match_indices(character);
+                sentence_list = pieces_between(text, delimiters.map(|(i, d)| (i, d.len())));
             }
             _ => {}
         }
         let sentences: Vec<HashMap<String, String>> = sentence_list
             .iter()
             .enumerate()
-            .map(|(i, &sentence)| {
+            .map(|(i, &(start_index, sentence))| {
                 let mut sentence_map = HashMap::new();
                 sentence_map.insert("sentence".to_string(), sentence.to_string());
                 sentence_map.insert("index".to_string(), i.to_string());
+                sentence_map.insert("start_index".to_string(), start_index.to_string());
                 sentence_map
             })
             .collect();
         sentences
     }
 
-    async fn split_text(&self, text: &str) -> Option<Vec<Document>> {
+    // Chunks are returned without an embedding wherever one is not needed to split the text, so
+    // that `embed_new_chunks` only embeds the chunks that are not stored already. Semantic chunking
+    // has to embed every sentence to find where to split, whether or not the chunks are stored.
+    // A sentence that fails to embed fails the whole text, as leaving it out would lose its text.
+    async fn split_text(&self, text: &str) -> Result<Option<Vec<Document>>> {
         // here we instantiate all the vectors that we will use later on
         let mut chunks = Vec::new();
         let mut vector_of_sentences: Vec<Sentence> = vec![];
         if !text.is_empty() {
             // we slice our text into sentences based on the chunking strategy that we are using
-            let sentences = &self.form_sentences(text).await;
+            let sentences = self.form_sentences(text).await;
+            let sentences: Vec<&HashMap<String, String>> =
+                sentences.iter().filter(|s| !s["sentence"].trim().is_empty()).collect();
+            if let Some(ChunkingStrategy::CHARACTER_CHUNKING) = self.chunking_strategy {
+                // every sentence is a chunk of its own, nothing needs embedding to split the text
+                for sentence in sentences {
+                    let start_index: usize = sentence["start_index"].parse().unwrap_or_default();
+                    chunks.push(chunk_spanning(
+                        sentence["sentence"].clone(),
+                        None,
+                        start_index,
+                        start_index + sentence["sentence"].len(),
+                    ));
+                }
+                return Ok(Some(chunks));
+            }
             // from those sentence hashmaps we extract the text and form a vector of strings which contain each sentence.
             let list_of_text: Vec<String> =
                 sentences.iter().map(|s| s["sentence"].clone()).collect();
 
-            // we embed each of those sentences
-            let mongo_conn_clone = Arc::clone(&self.mongo_conn);
-            let datasource_id_clone = self.datasource_id.clone();
-            match embed_text_chunks_async(mongo_conn_clone, datasource_id_clone, list_of_text, self.embedding_model).await {
-                Ok(embeddings) => {
-                    // we match the index with the embedding index and insert the embedding vector into the sentence hashmap
-                    for (i, sentence) in sentences.iter().enumerate() {
-                        if !embeddings.is_empty() && embeddings.len() > 0 {
-                            if i < embeddings.len() && !embeddings[i].is_empty() {
-                                vector_of_sentences.push(Sentence {
-                                    sentence_embedding: Array1::from_vec(embeddings[i].clone()),
-                                    distance_to_next: None,
-                                    sentence: Some(sentence["sentence"].clone()),
-                                });
-                            }
-                        }
+            // we embed each of those sentences, results come back in the same order as the sentences
+            let embeddings = embed_text_chunks_async(&self.embedding_provider, list_of_text).await;
+            for (sentence, embedding) in sentences.iter().zip(embeddings) {
+                match embedding {
+                    Ok(embedding) if !embedding.is_empty() => {
+                        let start_index = sentence["start_index"].parse().unwrap_or_default();
+                        vector_of_sentences.push(Sentence {
+                            sentence_embedding: Array1::from_vec(embedding),
+                            distance_to_next: None,
+                            sentence: Some(sentence["sentence"].clone()),
+                            start_index,
+                            end_index: start_index + sentence["sentence"].len(),
+                        });
                     }
-                    // here is where the divergence occurs depending on the chunking strategy chosen by the use
-                    match &self.chunking_strategy.as_ref().unwrap() {
-                        ChunkingStrategy::SEMANTIC_CHUNKING => {
-                            // in the semantic chunking we iterate through each of the sentences and calculate their relative cosine similarity scores
-                            let distances = calculate_cosine_distances(&mut vector_of_sentences);
-                            let breakpoint_percentile_threshold = 95;
-                            let breakpoint_distance_threshold =
-                                percentile(&distances, breakpoint_percentile_threshold);
-
-                            // Initialize accumulators for indices above and below the threshold
-                            let (indices_above_thresh, indices_below_threshold): (Vec<usize>, Vec<usize>) =
-                                distances
-                                    .iter()
-                                    .enumerate()
-                                    // Use fold to iterate once, separating indices based on the threshold
-                                    .fold((vec![], vec![]), |(mut above, mut below), (i, &d)| {
-                                        if d >= breakpoint_distance_threshold {
-                                            above.push(i);
-                                        } else if d < breakpoint_distance_threshold {
-                                            below.push(i);
-                                        }
-                                        (above, below)
-                                    });
+                    Ok(_) => {
+                        return Err(anyhow!("Sentence {} returned an empty embedding", sentence["index"]));
+                    }
+                    Err(e) => {
+                        return Err(anyhow!(
+                            "An error occurred while trying to embed sentence {}. Error: {}",
+                            sentence["index"], e
+                        ));
+                    }
+                }
+            }
+            if vector_of_sentences.is_empty() {
+                return Ok(Some(chunks));
+            }
+            // only semantic chunking combines sentences, character chunks were returned above
+            if let ChunkingStrategy::SEMANTIC_CHUNKING = self.chunking_strategy.as_ref().unwrap() {
+                // in the semantic chunking we iterate through each of the sentences and calculate their relative cosine similarity scores
+                let distances = calculate_cosine_distances(&mut vector_of_sentences);
+                let breakpoint_percentile_threshold = 95;
+                let breakpoint_distance_threshold =
+                    percentile(&distances, breakpoint_percentile_threshold);
 
-                            println!("Indices above threshold:  {:?}", &indices_above_thresh);
+                // Initialize accumulators for indices above and below the threshold
+                let (indices_above_thresh, indices_below_threshold): (Vec<usize>, Vec<usize>) =
+                    distances
+                        .iter()
+                        .enumerate()
+                        // Use fold to iterate once, separating indices based on the threshold
+                        .fold((vec![], vec![]), |(mut above, mut below), (i, &d)| {
+                            if d >= breakpoint_distance_threshold {
+                                above.push(i);
+                            } else if d < breakpoint_distance_threshold {
+                                below.push(i);
+                            }
+                            (above, below)
+                        });
 
-                            let mut start_index = 0;
-                            for &index in &indices_above_thresh {
-                                // Ensure the current index has not already been processed
-                                if index >= start_index {
-                                    // Create a chunk from start_index up to the current index
-                                    let group = &sentences[start_index..=index];
-                                    let combined_text = group
-                                        .iter()
-                                        .map(|d| d["sentence"].as_str())
-                                        .collect::<Vec<&str>>()
-                                        .join(". ");
-                                    // embed the new combined text and insert into document
-                                    let mongo_conn_clone = Arc::clone(&self.mongo_conn);
-                                    let datasource_id_clone = self.datasource_id.clone();
-                                    let new_embedding =
-                                        embed_text(mongo_conn_clone, datasource_id_clone, vec![&combined_text], &self.embedding_model)
-                                            .await
-                                            .unwrap();
-                                    let doc = Document::new(
-                                        combined_text,
-                                        None,
-                                        Some(new_embedding[0].to_owned()),
-                                    );
-                                    chunks.push(doc);
+                println!("Indices above threshold:  {:?}", &indices_above_thresh);
 
-                                    // Update start_index to the next sentence after the current chunk
-                                    start_index = index + 1;
-                                }
-                            }
+                let mut start_index = 0;
+                for &index in &indices_above_thresh {
+                    // Ensure the current index has not already been processed
+                    if index >= start_index {
+                        // Create a chunk from start_index up to the current index
+                        let group = &vector_of_sentences[start_index..=index];
+                        let combined_text = group
+                            .iter()
+                            .filter_map(|s| s.sentence.as_deref())
+                            .collect::<Vec<&str>>()
+                            .join(". ");
+                        // the combined text is embedded later on, unless it is already stored
+                        chunks.push(chunk_spanning(
+                            combined_text,
+                            None,
+                            group[0].start_index,
+                            group[group.len() - 1].end_index,
+                        ));
 
-                            // Ensure any remaining sentences are captured in a final chunk
-                            for sent in indices_below_threshold {
-                                let doc = Document::new(
-                                    sentences[sent]["sentence"].to_string(),
-                                    None,
-                                    Some(embeddings[sent].to_owned()),
-                                );
-                                chunks.push(doc);
-                            }
-                        }
-                        ChunkingStrategy::CHARACTER_CHUNKING => {
-                            for sentence in vector_of_sentences {
-                                chunks.push(Document::new(
-                                    sentence.sentence.unwrap(),
-                                    None,
-                                    Some(sentence.sentence_embedding.to_vec()),
-                                ))
-                            }
-                        }
-                        _ => {}
+                        // Update start_index to the next sentence after the current chunk
+                        start_index = index + 1;
                     }
                 }
-                Err(e) => {
-                    println!(
-                        "An error occurred while trying to embed text chunk. Error: {}",
-                        e
+
+                // Ensure any remaining sentences are captured in a final chunk
+                for sent in indices_below_threshold {
+                    let sentence = &vector_of_sentences[sent];
+                    let doc = chunk_spanning(
+                        sentence.sentence.clone().unwrap_or_default(),
+                        Some(sentence.sentence_embedding.to_vec()),
+                        sentence.start_index,
+                        sentence.end_index,
                     );
+                    chunks.push(doc);
                 }
             }
-            Some(chunks)
+            Ok(Some(chunks))
         } else {
-            None
+            Ok(None)
         }
     }
 
@@ -237,29 +265,16 @@ impl Chunker {
         &self,
         texts: Vec<String>,
         metadata: Vec<Option<HashMap<String, String>>>,
-    ) -> Vec<Document> {
+    ) -> Result<Vec<Document>> {
         let mut documents = Vec::new();
         for (i, text) in texts.into_iter().enumerate() {
-            let mut index: Option<usize> = None;
-            if let Some(chunks) = self.split_text(&text).await {
+            if let Some(chunks) = self.split_text(&text).await? {
                 for mut chunk in chunks {
-                    let mut metadata = metadata[i].clone().unwrap();
+                    let mut metadata = metadata[i].clone().unwrap_or_default();
+                    // the byte offsets the chunk spans in the text, recorded as it was split
+                    let offsets = chunk.metadata.take().unwrap_or_default();
                     if self.add_start_index {
-                        // Use char_indices to work with character boundaries
-                        let text_chars: Vec<(usize, char)> = text.char_indices().collect();
-                        index = match index {
-                            Some(idx) => {
-                                // Find the chunk's start position relative to idx, ensuring we're within character boundaries
-                                text_chars.iter().skip(idx + 1).position(|(_, c)| chunk.page_content.starts_with(*c)).map(|pos| text_chars[idx + 1 + pos].0)
-                            }
-                            None => {
-                                // Find the first occurrence of the chunk.page_content, still ensuring we're within character boundaries
-                                text_chars.iter().find(|(_, c)| chunk.page_content.starts_with(*c)).map(|&(pos, _)| pos)
-                            }
-                        };
-                        if let Some(idx) = index {
-                            metadata.insert("start_index".to_string(), idx.to_string());
-                        }
+                        metadata.extend(offsets);
                     }
                     metadata.insert("page_content".to_string(), chunk.page_content.to_string());
                     chunk.metadata = Some(metadata);
@@ -267,7 +282,7 @@ impl Chunker {
                 }
             }
         }
-        documents
+        Ok(documents)
     }
 
 
@@ -276,7 +291,7 @@ impl Chunker {
             .into_iter()
             .map(|doc| (doc.page_content, doc.metadata))
             .unzip();
-        let results = self.create_documents(texts, metadata).await;
+        let results = self.create_documents(texts, metadata).await?;
         if !results.is_empty() {
             Ok(results)
         } else {
@@ -284,3 +299,26 @@ impl Chunker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pieces_keep_their_byte_offsets() {
+        let text = "Première phrase. Deuxième? Fin";
+        let delimiters = text.match_indices(&['.', '?', '!'][..]).map(|(i, d)| (i, d.len()));
+        let pieces = pieces_between(text, delimiters);
+        assert_eq!(pieces.len(), 3);
+        for (start, piece) in pieces {
+            assert_eq!(&text[start..start + piece.len()], piece);
+        }
+    }
+
+    #[test]
+    fn multi_character_delimiters_are_skipped() {
+        let text = "a--bb--";
+        let delimiters = text.match_indices("--").map(|(i, d)| (i, d.len()));
+        assert_eq!(pieces_between(text, delimiters), vec![(0, "a"), (3, "bb"), (7, "")]);
+    }
+}
@@ -1,17 +1,15 @@
 use ndarray::Array1;
 use std::collections::HashMap;
-use std::fs;
 use std::sync::Arc;
+use tokio::task;
 use actix_web::dev::ResourcePath;
 use anyhow::anyhow;
-use mongodb::Database;
-use qdrant_client::client::QdrantClient;
-use tokio::sync::{RwLock};
-use crate::data::chunking::{Chunking, TextChunker};
-use crate::data::models::{Document as DocumentModel, FileType};
-use crate::llm::models::EmbeddingModels;
+use crate::data::chunking::{embed_new_chunks, Chunking, EmbeddedChunks, TextChunker};
+use crate::data::models::FileType;
+use crate::data::recursive_splitting::ChunkSize;
+use crate::llm::providers::EmbeddingProviders;
 use crate::mongo::models::ChunkingStrategy;
-use crate::queue::queuing::MyQueue;
+use crate::qdrant::dedup::StoredDocument;
 
 pub fn cosine_similarity(a: &Array1<f32>, b: &Array1<f32>) -> f32 {
     let dot_product = a.dot(b);
@@ -25,7 +23,8 @@ pub fn percentile(values: &Vec<f32>, percentile: usize) -> f32 {
     assert!(percentile <= 100, "Percentile must be between 0 and 100");
 
     let mut sorted_values = values.to_vec();
-    sorted_values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    // distances between zero vectors are NaN, which total_cmp sorts rather than panicking on
+    sorted_values.sort_by(|a, b| a.total_cmp(b));
 
     let k = (percentile as f64 / 100.0 * (values.len() as f64 - 1.0)).round() as usize;
     sorted_values[k]
@@ -35,61 +34,40 @@ pub async fn extract_text_from_file(
     file_type: FileType,
     file_path: &str,
     document_name: String,
-    datasource_id: String,
-    queue: Arc<RwLock<MyQueue<String>>>,
-    qdrant_conn: Arc<RwLock<QdrantClient>>,
-    mongo_conn: Arc<RwLock<Database>>,
     // redis_conn_pool: Arc<Mutex<RedisConnection>>,
-) -> Option<(String, Option<HashMap<String, String>>)> {
-    let mut document_text = String::new();
-    let mut metadata = HashMap::new();
+) -> anyhow::Result<(String, Option<HashMap<String, String>>)> {
     let path = file_path.trim_matches('"').path().to_string();
-    let chunker = TextChunker::default();
-    match file_type {
-        FileType::PDF => {
-            let path_clone = path.clone();
-            (document_text, metadata) = chunker
-                .extract_text_from_pdf(path_clone)
-                .expect("Could not extract text from PDF file");
-        }
-        FileType::TXT => {
-            let path_clone = path.clone();
-            (document_text, metadata) = chunker
-                .extract_text_from_txt(path_clone)
-                .expect("Could not extract text from TXT file");
+    // parsing is CPU bound and the parsers panic on some malformed files, so it runs on the
+    // blocking thread pool where a panic fails the file rather than the task
+    let extracted = task::spawn_blocking(move || {
+        let chunker = TextChunker::default();
+        match file_type {
+            FileType::PDF => chunker.extract_text_from_pdf(path),
+            FileType::TXT => chunker.extract_text_from_txt(path),
+            FileType::DOCX => chunker.extract_text_from_docx(path),
+            FileType::MARKDOWN => chunker.extract_text_from_markdown(path),
+            FileType::HTML => chunker.extract_text_from_html(path),
+            FileType::SPREADSHEET => chunker.extract_text_from_spreadsheet(path),
+            FileType::PRESENTATION => chunker.extract_text_from_presentation(path),
+            // rows are embedded and upserted as they are read, and archives are expanded and their
+            // entries extracted one by one, by the caller
+            FileType::CSV | FileType::TSV | FileType::ARCHIVE | FileType::UNKNOWN => {
+                Err(anyhow!("{:?} files are not extracted as text", file_type))
+            }
         }
-        FileType::DOCX => {
-            let path_clone = path.clone();
-            (document_text, metadata) = chunker
-                .extract_text_from_docx(path_clone)
-                .expect("Could not extract text from DOCX file");
+    })
+        .await
+        .unwrap_or_else(|e| Err(anyhow!("Extraction stopped before the end of the document. Error: {}", e)));
+    match extracted {
+        Ok((document_text, mut metadata)) => {
+            metadata.insert(String::from("document name"), document_name);
+            Ok((document_text, Some(metadata)))
         }
-        FileType::CSV => return {
-            let path_clone = path.clone();
-            _ = chunker.extract_text_from_csv(
-                path_clone,
-                datasource_id,
-                queue,
-                qdrant_conn,
-                mongo_conn,
-                // redis_conn_pool,
-            );
-            None
-        },
-        FileType::UNKNOWN => return None,
-    }
-    // Once we have extracted the text from the file we no longer need the file and there file we delete from disk
-    let path_clone = path.clone();
-    match fs::remove_file(path_clone) {
-        Ok(_) => println!("File: {:?} successfully deleted", file_path),
-        Err(e) => println!(
-            "An error occurred while trying to delete file: {}. Error: {:?}",
-            file_path, e
-        ),
+        Err(e) => Err(anyhow!(
+            "Could not extract text from {:?} file: {}. Error: {}",
+            file_type, file_path, e
+        )),
     }
-    metadata.insert(String::from("document name"), document_name);
-    let results = (document_text, Some(metadata));
-    Some(results)
 }
 
 pub async fn apply_chunking_strategy_to_document(
@@ -97,25 +75,23 @@ pub async fn apply_chunking_strategy_to_document(
     metadata: Option<HashMap<String, String>>,
     chunking_strategy: ChunkingStrategy,
     chunking_character: Option<String>,
-    embedding_models: Option<String>,
-    mongo_conn: Arc<RwLock<Database>>,
-    datasource_id: String,
-) -> anyhow::Result<Vec<DocumentModel>> {
+    chunk_size: ChunkSize,
+    embedding_provider: Arc<EmbeddingProviders>,
+    stored: &StoredDocument,
+) -> anyhow::Result<EmbeddedChunks> {
     let chunker = TextChunker::default();
-    let embedding_model_choice = EmbeddingModels::from(embedding_models.unwrap());
     match chunker
         .chunk(
             document_text,
             metadata,
             chunking_strategy,
             chunking_character,
-            embedding_model_choice,
-            mongo_conn,
-            datasource_id,
+            chunk_size,
+            Arc::clone(&embedding_provider),
         )
         .await
     {
-        Ok(c) => Ok(c),
+        Ok(c) => embed_new_chunks(c, stored, &embedding_provider).await,
         Err(e) => Err(anyhow!("An error occurred: {}", e)),
     }
 }
@@ -1,39 +1,74 @@
+use std::path::Path;
+
 use anyhow::{anyhow, Result};
+use futures_util::StreamExt;
 use google_cloud_storage::client::{Client, ClientConfig};
 use google_cloud_storage::http::objects::download::Range;
 use google_cloud_storage::http::objects::get::GetObjectRequest;
+use tokio::sync::OnceCell;
 
-pub async fn get_object_from_gcs(bucket: &str, object: &str) -> Result<Vec<u8>> {
-    let bucket_name = bucket.to_string();
-    let filename = object.to_string();
+use crate::utils::file_operations::DownloadWriter;
 
-    let client = match ClientConfig::default().with_auth().await {
-        Ok(config) => {
-            let client = Client::new(config);
-            Ok(client)
-        }
-        Err(e) => Err(anyhow!(
-            "An error occurred while authenticating to GCS. Error: {:?}",
-            e
-        )),
-    };
+// Authenticating is done once and the client shared, rather than for every file
+static GCS_CLIENT: OnceCell<Client> = OnceCell::const_new();
 
-    return match client
-        .unwrap()
-        .download_object(
+async fn gcs_client() -> Result<&'static Client> {
+    GCS_CLIENT
+        .get_or_try_init(|| async {
+            match ClientConfig::default().with_auth().await {
+                Ok(config) => Ok(Client::new(config)),
+                Err(e) => Err(anyhow!(
+                    "An error occurred while authenticating to GCS. Error: {:?}",
+                    e
+                )),
+            }
+        })
+        .await
+}
+
+///
+///
+/// # Arguments
+///
+/// * `bucket`: The bucket holding the object
+/// * `object`: The name of the object
+/// * `destination`: The file the object is written to
+///
+/// The object is written to disk as it is received rather than being held in memory, and the
+/// download fails if it is larger than the maximum file size.
+///
+/// returns: Result<u64, Error> The number of bytes written
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn download_object_from_gcs(bucket: &str, object: &str, destination: &Path) -> Result<u64> {
+    let client = gcs_client().await?;
+    let mut stream = match client
+        .download_streamed_object(
             &GetObjectRequest {
-                bucket: bucket_name,
-                object: filename,
+                bucket: bucket.to_string(),
+                object: object.to_string(),
                 ..Default::default()
             },
             &Range::default(),
         )
         .await
     {
-        Ok(data) => Ok(data),
-        Err(e) => Err(anyhow!(
-            "An error occurred while fetching data from GCS. Error: {}",
-            e
-        )),
+        Ok(stream) => stream,
+        Err(e) => {
+            return Err(anyhow!(
+                "An error occurred while fetching data from GCS. Error: {}",
+                e
+            ))
+        }
     };
+    let mut writer = DownloadWriter::create(destination, None).await?;
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes.map_err(|e| anyhow!("An error occurred while downloading {} from GCS. Error: {}", object, e))?;
+        writer.write(&bytes).await?;
+    }
+    writer.finish().await
 }
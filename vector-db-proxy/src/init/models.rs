@@ -1,3 +1,4 @@
+use ipnet::IpNet;
 use serde::Serialize;
 
 #[derive(Clone, Serialize, Debug, Default)]
@@ -11,6 +12,14 @@ pub struct GlobalData {
     pub rabbitmq_routing_key: String,
     pub rabbitmq_username: String,
     pub rabbitmq_password: String,
+    pub rabbitmq_stream_start: String,
+    pub stream_offset_store_interval: i64,
+    pub rabbitmq_reconnect_max_interval: u64,
+    pub rabbitmq_prefetch_count: u16,
+    pub rabbitmq_dead_letter_exchange: String,
+    pub rabbitmq_dead_letter_queue: String,
+    pub message_max_attempts: u32,
+    pub message_retry_max_interval: u64,
     pub mongo_uri: String,
     pub qdrant_uri: String,
     pub webapp_host: String,
@@ -18,7 +27,25 @@ pub struct GlobalData {
     pub redis_host: String,
     pub redis_port: String,
     pub thread_percentage_utilisation: f64,
+    pub embedding_queue_capacity: usize,
+    pub extract_workers: usize,
+    pub embed_workers: Option<usize>,
+    pub upsert_workers: usize,
+    pub record_batch_idle_timeout: u64,
     pub use_gpu: String,
+    pub max_resident_models: usize,
+    pub embedding_concurrency: usize,
+    pub archive_max_bytes: u64,
+    pub archive_max_entries: usize,
+    pub s3_endpoint: String,
+    pub s3_region: String,
+    pub s3_bucket: String,
+    pub s3_access_key_id: String,
+    pub s3_secret_access_key: String,
+    pub s3_force_path_style: bool,
+    pub crawl_max_pages: usize,
+    pub crawl_allowed_networks: Vec<IpNet>,
+    pub file_max_bytes: u64,
 }
 
 impl GlobalData {
@@ -36,6 +63,18 @@ impl GlobalData {
             rabbitmq_routing_key: dotenv::var("RABBITMQ_ROUTING_KEY").unwrap_or("key".to_string()),
             rabbitmq_username: dotenv::var("RABBITMQ_USERNAME").unwrap_or("guest".to_string()),
             rabbitmq_password: dotenv::var("RABBITMQ_PASSWORD").unwrap_or("guest".to_string()),
+            // where to start reading the stream when no offset has been stored: first, last, next, an offset or a timestamp
+            rabbitmq_stream_start: dotenv::var("RABBITMQ_STREAM_START").unwrap_or("next".to_string()),
+            stream_offset_store_interval: dotenv::var("STREAM_OFFSET_STORE_INTERVAL").unwrap_or("100".to_string()).parse().unwrap_or(100),
+            // seconds
+            rabbitmq_reconnect_max_interval: dotenv::var("RABBITMQ_RECONNECT_MAX_INTERVAL").unwrap_or("60".to_string()).parse().unwrap_or(60),
+            // how many messages can be delivered but not yet processed, holds back the broker when the embedding queue is full
+            rabbitmq_prefetch_count: dotenv::var("RABBITMQ_PREFETCH_COUNT").unwrap_or("100".to_string()).parse().unwrap_or(100),
+            rabbitmq_dead_letter_exchange: dotenv::var("RABBITMQ_DEAD_LETTER_EXCHANGE").unwrap_or("agentcloud-dead-letter".to_string()),
+            rabbitmq_dead_letter_queue: dotenv::var("RABBITMQ_DEAD_LETTER_QUEUE").unwrap_or("dead-letter".to_string()),
+            message_max_attempts: dotenv::var("MESSAGE_MAX_ATTEMPTS").unwrap_or("5".to_string()).parse().unwrap_or(5),
+            // seconds
+            message_retry_max_interval: dotenv::var("MESSAGE_RETRY_MAX_INTERVAL").unwrap_or("60".to_string()).parse().unwrap_or(60),
             mongo_uri: dotenv::var("MONGO_URI").unwrap_or("mongodb://localhost:27017".to_string()),
             qdrant_uri: dotenv::var("QDRANT_URI").unwrap_or("htttp://localhost:6334".to_string()),
             webapp_host: dotenv::var("WEBAPP_HOST").unwrap_or("localhost".to_string()),
@@ -43,7 +82,32 @@ impl GlobalData {
             redis_host: dotenv::var("REDIS_HOST").unwrap_or("localhost".to_string()),
             redis_port: dotenv::var("REDIS_PORT").unwrap_or("6379".to_string()),
             thread_percentage_utilisation: dotenv::var("THREAD_PERCENTAGE_UTILISATION").unwrap().parse().unwrap_or(0.8),
+            embedding_queue_capacity: dotenv::var("EMBEDDING_QUEUE_CAPACITY").unwrap_or("100".to_string()).parse().unwrap_or(100),
+            extract_workers: dotenv::var("EXTRACT_WORKERS").unwrap_or("4".to_string()).parse().unwrap_or(4),
+            // defaults to the share of the cores set by THREAD_PERCENTAGE_UTILISATION
+            embed_workers: dotenv::var("EMBED_WORKERS").ok().and_then(|workers| workers.parse().ok()),
+            upsert_workers: dotenv::var("UPSERT_WORKERS").unwrap_or("4".to_string()).parse().unwrap_or(4),
+            // seconds, how long after its last record an ingestion job of Airbyte records takes more records
+            record_batch_idle_timeout: dotenv::var("RECORD_BATCH_IDLE_TIMEOUT").unwrap_or("60".to_string()).parse().unwrap_or(60),
             use_gpu: dotenv::var("USE_GPU").unwrap_or("false".to_string()),
+            max_resident_models: dotenv::var("MAX_RESIDENT_EMBEDDING_MODELS").unwrap_or("2".to_string()).parse().unwrap_or(2),
+            embedding_concurrency: dotenv::var("EMBEDDING_CONCURRENCY").unwrap_or("4".to_string()).parse().unwrap_or(4),
+            archive_max_bytes: dotenv::var("ARCHIVE_MAX_BYTES").unwrap_or("536870912".to_string()).parse().unwrap_or(536870912),
+            archive_max_entries: dotenv::var("ARCHIVE_MAX_ENTRIES").unwrap_or("1000".to_string()).parse().unwrap_or(1000),
+            s3_endpoint: dotenv::var("S3_ENDPOINT").unwrap_or_default(),
+            s3_region: dotenv::var("S3_REGION").unwrap_or("us-east-1".to_string()),
+            s3_bucket: dotenv::var("S3_BUCKET").unwrap_or_default(),
+            s3_access_key_id: dotenv::var("S3_ACCESS_KEY_ID").unwrap_or_default(),
+            s3_secret_access_key: dotenv::var("S3_SECRET_ACCESS_KEY").unwrap_or_default(),
+            s3_force_path_style: dotenv::var("S3_FORCE_PATH_STYLE").unwrap_or("false".to_string()).parse().unwrap_or(false),
+            crawl_max_pages: dotenv::var("CRAWL_MAX_PAGES").unwrap_or("500".to_string()).parse().unwrap_or(500),
+            // comma separated networks, such as 10.0.0.0/8, whose loopback or private addresses may be fetched
+            crawl_allowed_networks: dotenv::var("CRAWL_ALLOWED_NETWORKS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|network| network.trim().parse().ok())
+                .collect(),
+            file_max_bytes: dotenv::var("FILE_MAX_BYTES").unwrap_or("1073741824".to_string()).parse().unwrap_or(1073741824),
         }
     }
 }
@@ -1,2 +1,5 @@
+pub mod model_registry;
+pub mod providers;
+pub mod tokens;
 pub mod utils;
 pub mod models;
\ No newline at end of file
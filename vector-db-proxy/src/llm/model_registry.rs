@@ -0,0 +1,232 @@
+//! Process wide registry of loaded fastembed models.
+//!
+//! Constructing a `FlagEmbedding` loads the ONNX model from disk (downloading it first if it is not
+//! cached) so models are loaded lazily, once, and then shared across tasks. The number of resident
+//! models is capped and the least recently used model is evicted when the cap is exceeded. Tasks that
+//! are still holding an evicted model keep it alive until they are done with it.
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use fastembed::{FlagEmbedding, InitOptions};
+use once_cell::sync::Lazy;
+use ort::{
+    CUDAExecutionProvider, CoreMLExecutionProvider, ExecutionProvider, ExecutionProviderDispatch,
+    ROCmExecutionProvider,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+use tokio::task;
+
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::llm::models::FastEmbedModels;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub enum ExecutionProviderKind {
+    Cpu,
+    CoreMl,
+    Cuda,
+    Rocm,
+}
+
+impl fmt::Display for ExecutionProviderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionProviderKind::Cpu => write!(f, "cpu"),
+            ExecutionProviderKind::CoreMl => write!(f, "coreml"),
+            ExecutionProviderKind::Cuda => write!(f, "cuda"),
+            ExecutionProviderKind::Rocm => write!(f, "rocm"),
+        }
+    }
+}
+
+impl ExecutionProviderKind {
+    fn dispatch(&self) -> Vec<ExecutionProviderDispatch> {
+        match self {
+            ExecutionProviderKind::Cpu => vec![],
+            ExecutionProviderKind::CoreMl => vec![ExecutionProviderDispatch::CoreML(
+                CoreMLExecutionProvider::default(),
+            )],
+            ExecutionProviderKind::Cuda => vec![ExecutionProviderDispatch::CUDA(
+                CUDAExecutionProvider::default(),
+            )],
+            ExecutionProviderKind::Rocm => vec![ExecutionProviderDispatch::ROCm(
+                ROCmExecutionProvider::default(),
+            )],
+        }
+    }
+}
+
+type ModelKey = (FastEmbedModels, ExecutionProviderKind);
+
+struct RegistryEntry {
+    model: Arc<OnceCell<Arc<FlagEmbedding>>>,
+    loaded_at: Option<DateTime<Utc>>,
+    last_used: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ResidentModel {
+    pub model: String,
+    pub execution_provider: ExecutionProviderKind,
+    pub loaded_at: Option<String>,
+    pub last_used: String,
+}
+
+static REGISTRY: Lazy<Mutex<HashMap<ModelKey, RegistryEntry>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Hardware detection only needs to happen once per process
+static EXECUTION_PROVIDER: OnceCell<ExecutionProviderKind> = OnceCell::const_new();
+
+/// Looks for hardware acceleration in the order CoreML, CUDA, ROCm, falling back to CPU.
+/// Detection is skipped entirely if `USE_GPU` is `false`.
+pub async fn execution_provider() -> Result<ExecutionProviderKind> {
+    EXECUTION_PROVIDER
+        .get_or_try_init(|| async {
+            let global_data = GLOBAL_DATA.read().await;
+            if global_data.use_gpu.as_str() == "false" {
+                return Ok(ExecutionProviderKind::Cpu);
+            }
+            println!("Checking for hardware acceleration...");
+            if CoreMLExecutionProvider::default().is_available().map_err(|e| {
+                anyhow!("An error occurred while looking for CoreML hardware: {}", e)
+            })? {
+                println!("Found CoreML...");
+                return Ok(ExecutionProviderKind::CoreMl);
+            }
+            if CUDAExecutionProvider::default()
+                .is_available()
+                .map_err(|e| anyhow!("Error occurred while looking for CUDA hardware: {}", e))?
+            {
+                println!("Found CUDA...");
+                return Ok(ExecutionProviderKind::Cuda);
+            }
+            if ROCmExecutionProvider::default()
+                .is_available()
+                .map_err(|e| anyhow!("Error occurred while looking for ROCm hardware: {}", e))?
+            {
+                println!("Found ROCm...");
+                return Ok(ExecutionProviderKind::Rocm);
+            }
+            println!("No hardware acceleration found...falling back to CPU");
+            Ok(ExecutionProviderKind::Cpu)
+        })
+        .await
+        .copied()
+}
+
+///
+///
+/// # Arguments
+///
+/// * `model`: The fastembed model to load
+/// * `provider`: The execution provider the model should run on
+///
+/// Returns the shared instance of the model, loading it first if it is not resident. Concurrent
+/// callers asking for the same model wait on the same load rather than loading it again.
+///
+/// returns: Result<Arc<FlagEmbedding>, Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn get_or_load_model(
+    model: FastEmbedModels,
+    provider: ExecutionProviderKind,
+) -> Result<Arc<FlagEmbedding>> {
+    let Some(model_name) = model.translate() else {
+        return Err(anyhow!(
+            "Model does not match any known fast embed model variants"
+        ));
+    };
+    let model_label = model_name.to_string();
+    let key = (model, provider);
+    let cell = {
+        let mut registry = REGISTRY.lock().unwrap();
+        let entry = registry.entry(key).or_insert_with(|| RegistryEntry {
+            model: Arc::new(OnceCell::new()),
+            loaded_at: None,
+            last_used: Utc::now(),
+        });
+        entry.last_used = Utc::now();
+        Arc::clone(&entry.model)
+    };
+    let loaded = match cell
+        .get_or_try_init(|| async {
+            println!("Loading embedding model {} on {}...", model_label, provider);
+            let loaded = task::spawn_blocking(move || {
+                FlagEmbedding::try_new(InitOptions {
+                    model_name,
+                    show_download_message: true,
+                    execution_providers: provider.dispatch(),
+                    ..Default::default()
+                })
+            })
+                .await??;
+            Ok::<Arc<FlagEmbedding>, anyhow::Error>(Arc::new(loaded))
+        })
+        .await
+    {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            // don't let a failed load occupy a slot, the next caller will try again
+            let mut registry = REGISTRY.lock().unwrap();
+            if registry.get(&key).is_some_and(|entry| entry.model.get().is_none()) {
+                registry.remove(&key);
+            }
+            return Err(anyhow!("Failed to load embedding model {}: {}", model_label, e));
+        }
+    };
+    let max_resident_models = GLOBAL_DATA.read().await.max_resident_models;
+    let mut registry = REGISTRY.lock().unwrap();
+    if let Some(entry) = registry.get_mut(&key) {
+        entry.loaded_at.get_or_insert_with(Utc::now);
+    }
+    evict_least_recently_used(&mut registry, key, max_resident_models);
+    Ok(Arc::clone(loaded))
+}
+
+fn evict_least_recently_used(
+    registry: &mut HashMap<ModelKey, RegistryEntry>,
+    keep: ModelKey,
+    max_resident_models: usize,
+) {
+    while registry.len() > max_resident_models.max(1) {
+        let least_recently_used = registry
+            .iter()
+            .filter(|(key, _)| **key != keep)
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| *key);
+        match least_recently_used {
+            Some(key) => {
+                println!("Evicting embedding model {:?} on {}", key.0, key.1);
+                registry.remove(&key);
+            }
+            None => break,
+        }
+    }
+}
+
+/// Lists the models that are currently loaded (or loading) along with when they were last used
+pub fn resident_models() -> Vec<ResidentModel> {
+    let registry = REGISTRY.lock().unwrap();
+    let mut models: Vec<ResidentModel> = registry
+        .iter()
+        .map(|((model, provider), entry)| ResidentModel {
+            model: model
+                .translate()
+                .map(|m| m.to_string())
+                .unwrap_or_default(),
+            execution_provider: *provider,
+            loaded_at: entry.loaded_at.map(|t| t.to_rfc3339()),
+            last_used: entry.last_used.to_rfc3339(),
+        })
+        .collect();
+    // RFC 3339 strings in UTC sort chronologically
+    models.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+    models
+}
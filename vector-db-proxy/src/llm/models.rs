@@ -48,6 +48,15 @@ impl EmbeddingModels {
     }
 }
 
+/// Whether text is being embedded for storage (passage) or for retrieval (query).
+/// Some fastembed models are trained with different prefixes for each.
+#[derive(Copy, Clone)]
+pub enum EmbeddingType {
+    Passage,
+    Query,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum FastEmbedModels {
     BAAI_BGE_SMALL_EN,
     BAAI_BGE_SMALL_EN_V1_5,
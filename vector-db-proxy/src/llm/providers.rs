@@ -0,0 +1,316 @@
+use anyhow::{anyhow, Result};
+use async_openai::config::{AzureConfig, Config, OpenAIConfig};
+use async_openai::types::CreateEmbeddingRequestArgs;
+use fastembed::{EmbeddingBase, FlagEmbedding};
+use mongodb::Database;
+use tokio::task;
+
+use crate::llm::model_registry::{execution_provider, get_or_load_model};
+use crate::llm::models::{EmbeddingType, FastEmbedModels};
+use crate::llm::tokens::TokenCounter;
+use crate::mongo::models::{Credentials, Model};
+use crate::mongo::queries::get_model_and_credentials;
+
+// Used when an Azure credential does not specify which API version to call
+const DEFAULT_AZURE_API_VERSION: &str = "2024-02-01";
+// Maximum number of inputs each backend accepts in a single embedding request
+const FASTEMBED_MAX_BATCH_SIZE: usize = 256;
+const OPENAI_MAX_BATCH_SIZE: usize = 2048;
+const AZURE_OPENAI_MAX_BATCH_SIZE: usize = 16;
+// Maximum number of tokens a single input can be
+const FASTEMBED_MAX_INPUT_TOKENS: usize = 512;
+const OPENAI_MAX_INPUT_TOKENS: usize = 8191;
+
+/// Something that can turn text into embedding vectors
+pub trait EmbeddingProvider {
+    /// The model name, which is also the name of the vector the embeddings are stored under in Qdrant
+    fn model_name(&self) -> &str;
+    /// The largest number of texts that should be sent in a single call to `embed`
+    fn max_batch_size(&self) -> usize;
+    /// The longest input, in tokens, the model accepts
+    fn max_input_tokens(&self) -> usize;
+    /// Counts tokens the way this model's tokenizer does
+    async fn token_counter(&self) -> Result<TokenCounter>;
+    async fn embed(&self, text: Vec<String>, embedding_type: EmbeddingType) -> Result<Vec<Vec<f32>>>;
+}
+
+/// Embeds locally with one of the supported fastembed models
+pub struct FastEmbedProvider {
+    model_name: String,
+    model: FastEmbedModels,
+}
+
+impl FastEmbedProvider {
+    pub fn new(model_name: String) -> Result<Self> {
+        let model = FastEmbedModels::from(model_name.clone());
+        if model == FastEmbedModels::UNKNOWN {
+            return Err(anyhow!("'{}' is not a known fast embed model", model_name));
+        }
+        Ok(FastEmbedProvider { model_name, model })
+    }
+}
+
+fn fastembed_embed(
+    model: &FlagEmbedding,
+    text: Vec<String>,
+    embedding_type: EmbeddingType,
+) -> Result<Vec<Vec<f32>>> {
+    match embedding_type {
+        EmbeddingType::Passage => model.passage_embed(text, None),
+        // fastembed only exposes single query embedding so we embed each query in turn
+        EmbeddingType::Query => text.into_iter().map(|t| model.query_embed(t)).collect(),
+    }
+}
+
+impl EmbeddingProvider for FastEmbedProvider {
+    fn model_name(&self) -> &str {
+        self.model_name.as_str()
+    }
+
+    fn max_batch_size(&self) -> usize {
+        FASTEMBED_MAX_BATCH_SIZE
+    }
+
+    fn max_input_tokens(&self) -> usize {
+        FASTEMBED_MAX_INPUT_TOKENS
+    }
+
+    async fn token_counter(&self) -> Result<TokenCounter> {
+        let provider = execution_provider().await?;
+        let loaded_model = get_or_load_model(self.model, provider).await?;
+        let mut tokenizer = loaded_model.tokenizer.clone();
+        // the model's tokenizer truncates to its max length which would cap every count we take
+        tokenizer
+            .with_truncation(None)
+            .map_err(|e| anyhow!("Could not disable tokenizer truncation: {}", e))?;
+        // the model sees the special tokens ([CLS], [SEP]...) too, they count towards its limit
+        Ok(TokenCounter::new(move |text| {
+            tokenizer
+                .encode(text, true)
+                .map(|encoding| encoding.len())
+                .unwrap_or(text.len())
+        }))
+    }
+
+    async fn embed(&self, text: Vec<String>, embedding_type: EmbeddingType) -> Result<Vec<Vec<f32>>> {
+        let provider = execution_provider().await?;
+        let loaded_model = get_or_load_model(self.model, provider).await?;
+        // inference is CPU bound so keep it off the async workers
+        task::spawn_blocking(move || fastembed_embed(&loaded_model, text, embedding_type)).await?
+    }
+}
+
+/// Embeds through the OpenAI embeddings API. Azure OpenAI and any OpenAI compatible server
+/// (Ollama, vLLM, TEI, LocalAI...) speak the same protocol so they share this implementation
+/// and only differ in configuration.
+pub struct OpenAIProvider<C: Config> {
+    model_name: String,
+    max_batch_size: usize,
+    client: async_openai::Client<C>,
+}
+
+impl OpenAIProvider<OpenAIConfig> {
+    /// `api_base` points the client at an OpenAI compatible server instead of api.openai.com
+    pub fn new(
+        model_name: String,
+        key: Option<String>,
+        org: Option<String>,
+        api_base: Option<String>,
+    ) -> Self {
+        // local servers often do not need a key so an empty one is sent rather than falling back to OPENAI_API_KEY
+        let mut config = OpenAIConfig::new().with_api_key(key.unwrap_or_default());
+        if let Some(org) = org {
+            config = config.with_org_id(org);
+        }
+        if let Some(base) = api_base {
+            config = config.with_api_base(base.trim_end_matches('/'));
+        }
+        OpenAIProvider {
+            model_name,
+            max_batch_size: OPENAI_MAX_BATCH_SIZE,
+            client: with_backoff(async_openai::Client::with_config(config)),
+        }
+    }
+}
+
+impl OpenAIProvider<AzureConfig> {
+    pub fn azure(
+        model_name: String,
+        key: String,
+        api_base: String,
+        deployment: String,
+        api_version: String,
+    ) -> Self {
+        let config = AzureConfig::new()
+            .with_api_key(key)
+            .with_api_base(api_base.trim_end_matches('/'))
+            .with_deployment_id(deployment)
+            .with_api_version(api_version);
+        OpenAIProvider {
+            model_name,
+            max_batch_size: AZURE_OPENAI_MAX_BATCH_SIZE,
+            client: with_backoff(async_openai::Client::with_config(config)),
+        }
+    }
+}
+
+fn with_backoff<C: Config>(client: async_openai::Client<C>) -> async_openai::Client<C> {
+    let backoff = backoff::ExponentialBackoffBuilder::new()
+        .with_max_elapsed_time(Some(std::time::Duration::from_secs(60)))
+        .build();
+    client.with_backoff(backoff)
+}
+
+impl<C: Config> EmbeddingProvider for OpenAIProvider<C> {
+    fn model_name(&self) -> &str {
+        self.model_name.as_str()
+    }
+
+    fn max_batch_size(&self) -> usize {
+        self.max_batch_size
+    }
+
+    fn max_input_tokens(&self) -> usize {
+        OPENAI_MAX_INPUT_TOKENS
+    }
+
+    async fn token_counter(&self) -> Result<TokenCounter> {
+        Ok(TokenCounter::cl100k_base())
+    }
+
+    // OpenAI style APIs do not distinguish between query and passage embeddings
+    async fn embed(&self, text: Vec<String>, _embedding_type: EmbeddingType) -> Result<Vec<Vec<f32>>> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(self.model_name.as_str())
+            .input(text)
+            .build()?;
+        let mut response = self.client.embeddings().create(request).await?;
+        // the API returns an index per input, don't rely on the response order
+        response.data.sort_by_key(|data| data.index);
+        Ok(response.data.into_iter().map(|data| data.embedding).collect())
+    }
+}
+
+pub enum EmbeddingProviders {
+    FastEmbed(FastEmbedProvider),
+    OpenAI(OpenAIProvider<OpenAIConfig>),
+    AzureOpenAI(OpenAIProvider<AzureConfig>),
+}
+
+impl EmbeddingProviders {
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `model`: The model document from the `models` collection
+    /// * `credentials`: The credentials document the model references, if any
+    ///
+    /// The backend is chosen from the credential type. Anything that is not fastembed or Azure is
+    /// treated as OpenAI, pointed at the credential's endpoint if it has one. This means new models
+    /// only need to be added to the `models` collection, not to this code.
+    ///
+    /// returns: Result<EmbeddingProviders, Error>
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub fn from_model(model: Model, credentials: Option<Credentials>) -> Result<Self> {
+        let credential_type = credentials
+            .as_ref()
+            .and_then(|c| c.r#type.clone())
+            .or(model.r#type.clone());
+        let creds = credentials.and_then(|c| c.credentials).unwrap_or_default();
+        match credential_type.as_deref() {
+            Some("fastembed") => Ok(EmbeddingProviders::FastEmbed(FastEmbedProvider::new(model.model)?)),
+            Some("azure") => {
+                let key = creds
+                    .key
+                    .ok_or_else(|| anyhow!("Azure OpenAI credentials are missing a key"))?;
+                let endpoint = creds
+                    .endpoint
+                    .ok_or_else(|| anyhow!("Azure OpenAI credentials are missing an endpoint"))?;
+                // deployments are commonly named after the model they serve
+                let deployment = creds.deployment.unwrap_or(model.model.clone());
+                let api_version = creds
+                    .apiVersion
+                    .unwrap_or(DEFAULT_AZURE_API_VERSION.to_string());
+                Ok(EmbeddingProviders::AzureOpenAI(OpenAIProvider::azure(
+                    model.model,
+                    key,
+                    endpoint,
+                    deployment,
+                    api_version,
+                )))
+            }
+            None if FastEmbedModels::from(model.model.clone()) != FastEmbedModels::UNKNOWN => {
+                Ok(EmbeddingProviders::FastEmbed(FastEmbedProvider::new(model.model)?))
+            }
+            _ => {
+                if creds.key.is_none() && creds.endpoint.is_none() {
+                    return Err(anyhow!("Credentials key was empty"));
+                }
+                Ok(EmbeddingProviders::OpenAI(OpenAIProvider::new(
+                    model.model,
+                    creds.key,
+                    creds.org,
+                    creds.endpoint,
+                )))
+            }
+        }
+    }
+}
+
+impl EmbeddingProvider for EmbeddingProviders {
+    fn model_name(&self) -> &str {
+        match self {
+            EmbeddingProviders::FastEmbed(p) => p.model_name(),
+            EmbeddingProviders::OpenAI(p) => p.model_name(),
+            EmbeddingProviders::AzureOpenAI(p) => p.model_name(),
+        }
+    }
+
+    fn max_batch_size(&self) -> usize {
+        match self {
+            EmbeddingProviders::FastEmbed(p) => p.max_batch_size(),
+            EmbeddingProviders::OpenAI(p) => p.max_batch_size(),
+            EmbeddingProviders::AzureOpenAI(p) => p.max_batch_size(),
+        }
+    }
+
+    fn max_input_tokens(&self) -> usize {
+        match self {
+            EmbeddingProviders::FastEmbed(p) => p.max_input_tokens(),
+            EmbeddingProviders::OpenAI(p) => p.max_input_tokens(),
+            EmbeddingProviders::AzureOpenAI(p) => p.max_input_tokens(),
+        }
+    }
+
+    async fn token_counter(&self) -> Result<TokenCounter> {
+        match self {
+            EmbeddingProviders::FastEmbed(p) => p.token_counter().await,
+            EmbeddingProviders::OpenAI(p) => p.token_counter().await,
+            EmbeddingProviders::AzureOpenAI(p) => p.token_counter().await,
+        }
+    }
+
+    async fn embed(&self, text: Vec<String>, embedding_type: EmbeddingType) -> Result<Vec<Vec<f32>>> {
+        match self {
+            EmbeddingProviders::FastEmbed(p) => p.embed(text, embedding_type).await,
+            EmbeddingProviders::OpenAI(p) => p.embed(text, embedding_type).await,
+            EmbeddingProviders::AzureOpenAI(p) => p.embed(text, embedding_type).await,
+        }
+    }
+}
+
+/// Builds the embedding provider for the model associated with a datasource
+pub async fn get_embedding_provider(db: &Database, datasource_id: &str) -> Result<EmbeddingProviders> {
+    match get_model_and_credentials(db, datasource_id).await? {
+        Some((model, credentials)) => EmbeddingProviders::from_model(model, credentials),
+        None => Err(anyhow!(
+            "There was no embedding model associated with datasource: {}",
+            datasource_id
+        )),
+    }
+}
@@ -0,0 +1,43 @@
+//! Token counting, used to keep chunks within what the embedding model can take in one input.
+use once_cell::sync::Lazy;
+use std::sync::Arc;
+use tiktoken_rs::CoreBPE;
+
+// All of the OpenAI embedding models use the cl100k_base encoding
+static CL100K_BASE: Lazy<Option<Arc<CoreBPE>>> =
+    Lazy::new(|| tiktoken_rs::cl100k_base().ok().map(Arc::new));
+
+/// Counts how many tokens a piece of text is for a particular model
+#[derive(Clone)]
+pub struct TokenCounter {
+    count: Arc<dyn Fn(&str) -> usize + Send + Sync>,
+}
+
+impl TokenCounter {
+    pub fn new(count: impl Fn(&str) -> usize + Send + Sync + 'static) -> Self {
+        TokenCounter {
+            count: Arc::new(count),
+        }
+    }
+
+    /// The OpenAI tokenizer. OpenAI compatible servers also use this as we have no way of knowing
+    /// their tokenizer, it is close enough for sizing chunks.
+    pub fn cl100k_base() -> Self {
+        match CL100K_BASE.clone() {
+            Some(bpe) => TokenCounter::new(move |text| bpe.encode_ordinary(text).len()),
+            None => {
+                println!("Could not load the cl100k_base tokenizer, falling back to approximate token counts");
+                TokenCounter::approximate()
+            }
+        }
+    }
+
+    /// Roughly four characters per token, which holds for most English text
+    pub fn approximate() -> Self {
+        TokenCounter::new(|text| text.chars().count().div_ceil(4))
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        (self.count)(text)
+    }
+}
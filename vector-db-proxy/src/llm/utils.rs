@@ -1,214 +1,117 @@
 use anyhow::{anyhow, Result};
-use async_openai::types::CreateEmbeddingRequestArgs;
-use fastembed::{EmbeddingBase, FlagEmbedding, InitOptions};
-use std::sync::Arc as arc;
-use std::sync::Arc;
-use async_openai::config::OpenAIConfig;
-use mongodb::Database;
-use ort::{CoreMLExecutionProvider, CUDAExecutionProvider, ExecutionProvider, ExecutionProviderDispatch, ROCmExecutionProvider};
-use tokio::sync::mpsc;
-use tokio::sync::RwLock;
-use tokio::task;
-use crate::init::env_variables::GLOBAL_DATA;
+use async_openai::error::OpenAIError;
+use futures::stream::{self, StreamExt};
 
-use crate::llm::models::{EmbeddingModels, FastEmbedModels};
-use crate::mongo::queries::get_model_credentials;
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::llm::models::EmbeddingType;
+use crate::llm::providers::{EmbeddingProvider, EmbeddingProviders};
 
 pub async fn embed_text(
-    mongo_conn: Arc<RwLock<Database>>,
-    datasource_id: String,
+    embedding_provider: &EmbeddingProviders,
     text: Vec<&String>,
-    model: &EmbeddingModels,
+    embedding_type: EmbeddingType,
 ) -> Result<Vec<Vec<f32>>> {
-    match model {
-        EmbeddingModels::UNKNOWN => Err(anyhow!("This is an unknown model type!")),
-        // Group all fast embed models together
-        EmbeddingModels::BAAI_BGE_SMALL_EN
-        | EmbeddingModels::BAAI_BGE_SMALL_EN_V1_5
-        | EmbeddingModels::BAAI_BGE_BASE_EN
-        | EmbeddingModels::BAAI_BGE_BASE_EN_V1_5
-        | EmbeddingModels::ENTENCE_TRANSFORMERS_ALL_MINILM_L6_V2
-        | EmbeddingModels::XENOVA_FAST_MULTILINGUAL_E5_LARGE => match model.to_str() {
-            Some(m) => {
-                let global_data = GLOBAL_DATA.read().await;
-                let model = FastEmbedModels::from(m.to_string());
-                match FastEmbedModels::translate(&model) {
-                    Some(translation) => {
-                        match global_data.use_gpu.as_str() {
-                            "false" => {
-                                let model: FlagEmbedding = FlagEmbedding::try_new(InitOptions {
-                                    model_name: translation,
-                                    show_download_message: true,
-                                    ..Default::default()
-                                })?;
-                                let embeddings = model.passage_embed(text, None)?;
-                                Ok(embeddings)
-                            }
-                            _ => {
-                                println!("Checking for hardware acceleration...");
-                                println!("Checking for CoreML...");
-                                let coreml = CoreMLExecutionProvider::default();
-                                match coreml.is_available() {
-                                    Ok(acceleration) => {
-                                        if acceleration {
-                                            println!("Found CoreML...");
-                                            let model: FlagEmbedding = FlagEmbedding::try_new(InitOptions {
-                                                model_name: translation,
-                                                show_download_message: true,
-                                                execution_providers: vec![ExecutionProviderDispatch::CoreML(coreml)],
-                                                ..Default::default()
-                                            })?;
-                                            let embeddings = model.passage_embed(text, None)?;
-                                            Ok(embeddings)
-                                        } else {
-                                            println!("CoreML was not available");
-                                            println!("Looking for CUDA hardware...");
-                                            let cuda = CUDAExecutionProvider::default();
-                                            match cuda.is_available() {
-                                                Ok(acceleration) => {
-                                                    if acceleration {
-                                                        println!("Found CUDA...");
-                                                        let model: FlagEmbedding = FlagEmbedding::try_new(InitOptions {
-                                                            model_name: translation,
-                                                            show_download_message: true,
-                                                            execution_providers: vec![ExecutionProviderDispatch::CUDA(cuda)],
-                                                            ..Default::default()
-                                                        })?;
-                                                        let embeddings = model.passage_embed(text, None)?;
-                                                        Ok(embeddings)
-                                                    } else {
-                                                        println!("CUDA was  not available");
-                                                        println!("Checking for ROCm...");
-                                                        let roc = ROCmExecutionProvider::default();
-                                                        match roc.is_available() {
-                                                            Ok(acceleration) => {
-                                                                if acceleration {
-                                                                    println!("Found ROCm...");
-                                                                    let model: FlagEmbedding = FlagEmbedding::try_new(InitOptions {
-                                                                        model_name: translation,
-                                                                        show_download_message: true,
-                                                                        execution_providers: vec![ExecutionProviderDispatch::ROCm(roc)],
-                                                                        ..Default::default()
-                                                                    })?;
-                                                                    let embeddings = model.passage_embed(text, None)?;
-                                                                    Ok(embeddings)
-                                                                } else {
-                                                                    println!("No hardware acceleration found...falling back to CPU");
-                                                                    let model: FlagEmbedding = FlagEmbedding::try_new(InitOptions {
-                                                                        model_name: translation,
-                                                                        show_download_message: true,
-                                                                        ..Default::default()
-                                                                    })?;
-                                                                    let embeddings = model.passage_embed(text, None)?;
-                                                                    Ok(embeddings)
-                                                                }
-                                                            }
-                                                            Err(e) => Err(anyhow!("Error occurred while looking for ROCm hardware: {}",e))
-                                                        }
-                                                    }
-                                                }
-                                                Err(e) => Err(anyhow!("Error occurred while looking for CUDA hardware: {}",e))
-                                            }
-                                        }
-                                    }
-                                    Err(e) =>
-                                        Err(anyhow!("An error occurred while looking for CoreML hardware: {}", e))
-                                }
-                            }
-                        }
-                    }
-                    None => Err(anyhow!(
-                        "Model does not match any known fast embed model variants"
-                    )),
-                }
-            }
-            None => Err(anyhow!("Model type unknown")),
-        },
-
-        // Group all OAI models
-        _ => match model.to_str() {
-            Some(m) => {
-                // initiate variables
-                let mongodb_connection = mongo_conn.read().await;
-                match get_model_credentials(&mongodb_connection, datasource_id.as_str()).await {
-                    Ok(Some(creds_obj)) => {
-                        match creds_obj.key {
-                            Some(k) => {
-                                let backoff = backoff::ExponentialBackoffBuilder::new()
-                                    .with_max_elapsed_time(Some(std::time::Duration::from_secs(60)))
-                                    .build();
-                                let mut config = OpenAIConfig::new()
-                                    .with_api_key(k);
-                                if let Some(org) = creds_obj.org {
-                                    config = config.with_org_id(org);
-                                }
-                                let client = async_openai::Client::with_config(config).with_backoff(backoff);
-                                let request = CreateEmbeddingRequestArgs::default()
-                                    .model(m)
-                                    .input(text)
-                                    .build()?;
-                                let response = client.embeddings().create(request).await?;
-                                let embedding: Vec<Vec<f32>> = response
-                                    .data
-                                    .iter()
-                                    .map(|data| data.clone().embedding)
-                                    .collect();
-                                Ok(embedding)
-                            }
-                            None => {
-                                Err(anyhow!("Credentials key was empty"))
-                            }
-                        }
-                    }
-                    Ok(None) => Err(anyhow!("Model credentials returned NONE")),
-                    Err(e) => {
-                        Err(anyhow!("Could not get OPEN AI model credentials, {:?}",e))
-                    }
-                }
-            }
-            None => Err(anyhow!("Model type is unknown")),
-        },
-    }
+    let text: Vec<String> = text.into_iter().cloned().collect();
+    embedding_provider.embed(text, embedding_type).await
 }
 
-
+///
+///
+/// # Arguments
+///
+/// * `embedding_provider`: The provider to embed the chunks with
+/// * `table_chunks`: The texts to embed
+///
+/// Chunks are sent in batches sized for the provider with at most `EMBEDDING_CONCURRENCY` batches
+/// in flight at once. The result is aligned with the input, element `i` is the embedding (or the
+/// error) for `table_chunks[i]`.
+///
+/// returns: Vec<Result<Vec<f32>, Error>>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
 pub async fn embed_text_chunks_async(
-    mongo_conn: Arc<RwLock<Database>>,
-    datasource_id: String,
+    embedding_provider: &EmbeddingProviders,
     table_chunks: Vec<String>,
-    model: EmbeddingModels,
-) -> Result<Vec<Vec<f32>>> {
-    let mut list_of_embeddings: Vec<Vec<f32>> = vec![];
-
-    let (tx, mut rx) = mpsc::channel(table_chunks.len()); // Channel with enough capacity
+) -> Vec<Result<Vec<f32>>> {
+    let batch_size = embedding_provider.max_batch_size().max(1);
+    let concurrency = GLOBAL_DATA.read().await.embedding_concurrency.max(1);
+    let batches: Vec<Vec<String>> = table_chunks
+        .chunks(batch_size)
+        .map(|batch| batch.to_vec())
+        .collect();
+    // `buffered` (unlike `buffer_unordered`) yields the batches in the order they were submitted
+    let results: Vec<Vec<Result<Vec<f32>>>> = stream::iter(batches)
+        .map(|batch| embed_batch(embedding_provider, batch))
+        .buffered(concurrency)
+        .collect()
+        .await;
+    results.into_iter().flatten().collect()
+}
 
-    for item in table_chunks {
-        let tx = tx.clone(); // Clone the transmitter for each task
-        let item = arc::new(item); // Use Arc to share ownership across tasks, avoiding cloning large data
-        let mongo_conn_clone = Arc::clone(&mongo_conn);
-        let datasource_clone = datasource_id.clone();
-        task::spawn(async move {
-            let processed_item = embed_text(
-                mongo_conn_clone,
-                datasource_clone,
-                vec![&item],
-                &model).await; // Process item asynchronously
-            tx.send(processed_item)
-                .await
-                .expect("Failed to send processed item"); // Send back the result
-        });
+async fn embed_batch(
+    embedding_provider: &EmbeddingProviders,
+    batch: Vec<String>,
+) -> Vec<Result<Vec<f32>>> {
+    let batch_len = batch.len();
+    match embedding_provider
+        .embed(batch.clone(), EmbeddingType::Passage)
+        .await
+    {
+        Ok(embeddings) if embeddings.len() == batch_len => embeddings.into_iter().map(Ok).collect(),
+        Ok(embeddings) => batch_error(
+            anyhow!("Expected {} embeddings but the provider returned {}", batch_len, embeddings.len()),
+            batch_len,
+        ),
+        Err(e) if batch_len > 1 && is_input_error(&e) => {
+            // one bad input fails the whole request, retry one by one so only that input fails
+            println!("Embedding batch was rejected, retrying each input on its own. Error: {}", e);
+            embed_individually(embedding_provider, batch).await
+        }
+        // rate limits, server errors and timeouts would only be made worse by a request per input
+        Err(e) => batch_error(e, batch_len),
     }
+}
 
-    // Drop the original transmitter so the receiver knows when all tasks are done
-    drop(tx);
-
-    // Collect the results
-    while let Some(processed_item) = rx.recv().await {
-        if let Ok(embed) = processed_item {
-            list_of_embeddings.push(embed[0].clone())
+/// Whether the provider rejected the request because of what was in it, rather than because it
+/// is overloaded or could not be reached
+fn is_input_error(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<OpenAIError>() {
+        Some(OpenAIError::ApiError(api_error)) => {
+            api_error.r#type.as_deref() == Some("invalid_request_error")
         }
+        Some(OpenAIError::InvalidArgument(_)) => true,
+        _ => false,
     }
+}
 
-    Ok(list_of_embeddings)
+/// The same error for every input of a batch
+fn batch_error(error: anyhow::Error, batch_len: usize) -> Vec<Result<Vec<f32>>> {
+    let message = error.to_string();
+    (0..batch_len).map(|_| Err(anyhow!("{}", message))).collect()
 }
 
+async fn embed_individually(
+    embedding_provider: &EmbeddingProviders,
+    batch: Vec<String>,
+) -> Vec<Result<Vec<f32>>> {
+    let mut results = Vec::with_capacity(batch.len());
+    for text in batch {
+        let result = match embedding_provider
+            .embed(vec![text], EmbeddingType::Passage)
+            .await
+        {
+            Ok(mut embeddings) if embeddings.len() == 1 => Ok(embeddings.remove(0)),
+            Ok(embeddings) => Err(anyhow!(
+                "Expected 1 embedding but the provider returned {}",
+                embeddings.len()
+            )),
+            Err(e) => Err(e),
+        };
+        results.push(result);
+    }
+    results
+}
@@ -4,6 +4,8 @@
 #![allow(unused_assignments)]
 
 
+mod aws;
+mod crawl;
 mod data;
 mod errors;
 mod gcp;
@@ -31,18 +33,19 @@ use tokio::join;
 use tokio::signal::unix::{signal, SignalKind};
 #[cfg(windows)]
 use tokio::signal::windows::ctrl_c;
-use tokio::sync::{RwLock};
+use tokio::sync::{mpsc, RwLock};
 
 use crate::init::env_variables::set_all_env_vars;
-use crate::rabbitmq::consume::subscribe_to_queue;
-use crate::rabbitmq::models::RabbitConnect;
+use crate::rabbitmq::models::{RabbitConnect, RabbitState};
+use crate::rabbitmq::supervisor::ConsumerSupervisor;
 use routes::api_routes::{
-    bulk_upsert_data_to_collection, create_collection, delete_collection, health_check,
-    list_collections, lookup_data_point, scroll_data, upsert_data_point_to_collection,
+    bulk_upsert_data_to_collection, create_collection, delete_collection, get_job, health_check,
+    json_error_handler, query_error_handler, list_collections, list_datasource_jobs, list_resident_models, lookup_data_point, recommend_data_points, replay_dead_letter_messages,
+    queue_metrics, replay_stream, scroll_data, search_data_point, upsert_data_point_to_collection,
 };
 use crate::mongo::client::start_mongo_connection;
-use crate::queue::queuing::{MyQueue, Control};
-use crate::rabbitmq::client::{bind_queue_to_exchange, channel_rabbitmq, connect_rabbitmq};
+use crate::mongo::queries::create_ingestion_job_indexes;
+use crate::queue::queuing::{EmbeddingQueue, QueueConfig};
 
 pub fn init(config: &mut web::ServiceConfig) {
     let webapp_url =
@@ -63,7 +66,15 @@ pub fn init(config: &mut web::ServiceConfig) {
             .service(upsert_data_point_to_collection)
             .service(bulk_upsert_data_to_collection)
             .service(lookup_data_point)
-            .service(scroll_data),
+            .service(search_data_point)
+            .service(recommend_data_points)
+            .service(list_resident_models)
+            .service(scroll_data)
+            .service(replay_dead_letter_messages)
+            .service(replay_stream)
+            .service(queue_metrics)
+            .service(get_job)
+            .service(list_datasource_jobs),
     );
 }
 
@@ -83,38 +94,40 @@ async fn main() -> std::io::Result<()> {
         }
     };
     let mongo_connection = start_mongo_connection().await.unwrap();
+    if let Err(e) = create_ingestion_job_indexes(&mongo_connection).await {
+        println!("{}", e);
+    }
     let app_qdrant_client = Arc::new(RwLock::new(qdrant_client));
     let qdrant_connection_for_rabbitmq = Arc::clone(&app_qdrant_client);
-    let queue: Arc<RwLock<MyQueue<String>>> = Arc::new(RwLock::new(Control::optimised(global_data.thread_percentage_utilisation)));
     // let redis_connection_pool: Arc<Mutex<RedisConnection>> = Arc::new(Mutex::new(redis_pool));
     let mongo_client_clone = Arc::new(RwLock::new(mongo_connection));
+    let queue = Arc::new(EmbeddingQueue::start(
+        Arc::clone(&app_qdrant_client),
+        Arc::clone(&mongo_client_clone),
+        QueueConfig::from_global_data().await,
+    ));
+    let app_queue = Arc::clone(&queue);
+    let app_mongo_client = Arc::clone(&mongo_client_clone);
     let rabbitmq_connection_details = RabbitConnect {
         host: global_data.rabbitmq_host.clone(),
         port: global_data.rabbitmq_port.clone(),
         username: global_data.rabbitmq_username.clone(),
         password: global_data.rabbitmq_password.clone(),
     };
-    let mut connection = connect_rabbitmq(&rabbitmq_connection_details).await;
-    let mut channel = channel_rabbitmq(&connection).await;
-    bind_queue_to_exchange(
-        &mut connection,
-        &mut channel,
-        &rabbitmq_connection_details,
-        &global_data.rabbitmq_exchange,
-        &global_data.rabbitmq_stream,
-        &global_data.rabbitmq_routing_key,
-    )
-        .await;
+    let rabbitmq_state = Arc::new(RwLock::new(RabbitState::default()));
+    let app_rabbitmq_state = Arc::clone(&rabbitmq_state);
+    let (replay_sender, replay_receiver) = mpsc::channel(1);
+    // connects to rabbit and keeps the stream consumer running, reconnecting whenever the connection is lost
     let rabbitmq_stream = tokio::spawn(async move {
-        let _ = subscribe_to_queue(
-            Arc::clone(&qdrant_connection_for_rabbitmq),
-            Arc::clone(&queue),
-            Arc::clone(&mongo_client_clone),
+        let supervisor = ConsumerSupervisor {
+            connection_details: rabbitmq_connection_details,
+            qdrant_conn: Arc::clone(&qdrant_connection_for_rabbitmq),
+            queue: Arc::clone(&queue),
+            mongo_conn: Arc::clone(&mongo_client_clone),
             // Arc::clone(&redis_connection_pool),
-            &channel,
-            &global_data.rabbitmq_stream,
-        )
-            .await;
+            state: rabbitmq_state,
+        };
+        supervisor.run(replay_receiver).await;
     });
     env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
     let web_task = tokio::spawn(async move {
@@ -123,6 +136,13 @@ async fn main() -> std::io::Result<()> {
             App::new()
                 .wrap(Logger::default())
                 .app_data(Data::new(Arc::clone(&app_qdrant_client)))
+                .app_data(Data::new(Arc::clone(&app_mongo_client)))
+                .app_data(Data::new(Arc::clone(&app_rabbitmq_state)))
+                .app_data(Data::new(Arc::clone(&app_queue)))
+                .app_data(Data::new(replay_sender.clone()))
+                // malformed bodies and query strings are answered with a ResponseBody like every other error
+                .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+                .app_data(web::QueryConfig::default().error_handler(query_error_handler))
                 .configure(init)
         })
             .bind(format!("{}:{}", host, port))?
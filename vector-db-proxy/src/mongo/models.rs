@@ -1,7 +1,7 @@
 use bson::DateTime;
 use mongodb::bson::{doc, oid::ObjectId};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DatasourceConnectionSettings {
@@ -17,6 +17,43 @@ pub struct DatasourceConnectionSettings {
     pub status: String,
 }
 
+/// The streams of a datasource's Airbyte connection, the only part of its connection settings read
+#[derive(Deserialize, Clone, Debug)]
+pub struct DatasourceSyncCatalog {
+    pub connectionSettings: Option<SyncCatalogSettings>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SyncCatalogSettings {
+    pub syncCatalog: SyncCatalog,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SyncCatalog {
+    #[serde(default)]
+    pub streams: Vec<SyncCatalogStream>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SyncCatalogStream {
+    pub stream: AirbyteStream,
+    pub config: Option<AirbyteStreamConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AirbyteStream {
+    pub name: String,
+    // each key field is a path into the record
+    #[serde(default)]
+    pub sourceDefinedPrimaryKey: Vec<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AirbyteStreamConfig {
+    #[serde(default)]
+    pub primaryKey: Vec<Vec<String>>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DataSources {
     pub _id: ObjectId,
@@ -36,16 +73,22 @@ pub struct DataSources {
     pub connectionId: Option<String>,
     pub chunkStrategy: Option<String>,
     pub chunkCharacter: Option<String>,
+    pub chunkSize: Option<i32>,
+    pub chunkOverlap: Option<i32>,
+    pub codeLanguage: Option<String>,
     pub lastSyncedDate: Option<DateTime>,
     pub embeddingField: Option<String>,
+    // e.g. "{title}: {description}", renders the embedded text of CSV rows from several columns
+    pub embeddingTemplate: Option<String>,
     pub createdDate: Option<DateTime>,
     pub status: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Copy, Clone)]
 pub enum ChunkingStrategy {
     SEMANTIC_CHUNKING,
     CHARACTER_CHUNKING,
+    RECURSIVE_CHUNKING,
     CODE_SPLIT,
     UNKNOWN,
 }
@@ -55,6 +98,7 @@ impl From<String> for ChunkingStrategy {
         match value.as_str() {
             "semantic" => ChunkingStrategy::SEMANTIC_CHUNKING,
             "character" => ChunkingStrategy::CHARACTER_CHUNKING,
+            "recursive" => ChunkingStrategy::RECURSIVE_CHUNKING,
             "code" => ChunkingStrategy::CODE_SPLIT,
             _ => ChunkingStrategy::UNKNOWN,
         }
@@ -71,14 +115,18 @@ pub struct Model {
     pub model: String,
     pub embeddingLength: i32,
     pub modelType: String,
+    pub r#type: Option<String>,
 }
 
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct CredentialsObj {
     pub key: Option<String>,
+    #[serde(alias = "endpointURL")]
     pub endpoint: Option<String>,
     pub org: Option<String>,
+    pub deployment: Option<String>,
+    pub apiVersion: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -88,5 +136,113 @@ pub struct Credentials {
     pub teamId: ObjectId,
     pub name: String,
     pub createdDate: Option<DateTime>,
+    pub r#type: Option<String>,
     pub credentials: Option<CredentialsObj>,
 }
+
+/// The last offset of a stream whose message, and every message before it, has been processed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StreamOffset {
+    pub stream: String,
+    pub offset: i64,
+    pub updatedDate: Option<DateTime>,
+}
+
+/// What an ingestion job ingests
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    File,
+    Archive,
+    Crawl,
+    /// Airbyte records synced to a datasource one after another
+    Records,
+}
+
+impl JobKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::File => "file",
+            JobKind::Archive => "archive",
+            JobKind::Crawl => "crawl",
+            JobKind::Records => "records",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Processing,
+    /// An attempt failed and the next one is waiting for its backoff
+    Retrying,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Processing => "processing",
+            JobState::Retrying => "retrying",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+        }
+    }
+}
+
+/// The progress of ingesting a file, an archive, a crawl or a batch of Airbyte records into a
+/// datasource
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IngestionJob {
+    pub _id: ObjectId,
+    pub datasourceId: ObjectId,
+    pub kind: JobKind,
+    /// The file name, or the URL crawled from
+    pub name: Option<String>,
+    pub state: JobState,
+    /// The embedding model the chunks were embedded with
+    pub model: Option<String>,
+    /// The offset of the message in the stream, for files
+    pub streamOffset: Option<i64>,
+    pub attempts: i32,
+    /// Chunks of the documents, or records received for batches, in the last attempt
+    pub chunkCount: i64,
+    /// Chunks or records embedded and uploaded over every attempt. Chunks already stored unchanged
+    /// are not embedded again and not counted.
+    pub embeddedCount: i64,
+    /// Records that could not be ingested, for batches
+    pub failedCount: i64,
+    pub errors: Vec<String>,
+    pub createdDate: DateTime,
+    pub startedDate: Option<DateTime>,
+    pub updatedDate: Option<DateTime>,
+    pub finishedDate: Option<DateTime>,
+}
+
+impl IngestionJob {
+    /// The job as the webapp reads it, with plain string IDs and RFC 3339 dates
+    pub fn to_json(&self) -> Value {
+        let date = |date: Option<DateTime>| date.and_then(|d| d.try_to_rfc3339_string().ok());
+        json!({
+            "id": self._id.to_hex(),
+            "datasource_id": self.datasourceId.to_hex(),
+            "kind": self.kind,
+            "name": self.name,
+            "state": self.state,
+            "model": self.model,
+            "stream_offset": self.streamOffset,
+            "attempts": self.attempts,
+            "chunk_count": self.chunkCount,
+            "embedded_count": self.embeddedCount,
+            "failed_count": self.failedCount,
+            "errors": self.errors,
+            "created_date": date(Some(self.createdDate)),
+            "started_date": date(self.startedDate),
+            "updated_date": date(self.updatedDate),
+            "finished_date": date(self.finishedDate),
+        })
+    }
+}
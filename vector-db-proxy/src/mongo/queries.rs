@@ -1,11 +1,13 @@
 use anyhow::{anyhow, Result};
 use mongodb::bson::doc;
 use mongodb::bson::oid::ObjectId;
-use mongodb::{Collection, Database};
+use mongodb::{Collection, Database, IndexModel};
 use std::str::FromStr;
-use mongodb::options::{FindOneOptions};
+use futures::TryStreamExt;
+use mongodb::bson::Document;
+use mongodb::options::{FindOneOptions, FindOptions, UpdateOptions};
 
-use crate::mongo::models::{DataSources, Model, Credentials, CredentialsObj};
+use crate::mongo::models::{DataSources, DatasourceSyncCatalog, Model, Credentials, CredentialsObj, IngestionJob, StreamOffset};
 
 pub async fn get_datasource(db: &Database, datasource_id: &str) -> Result<Option<DataSources>> {
     let datasources_collection: Collection<DataSources> = db.collection("datasources");
@@ -143,3 +145,160 @@ pub async fn get_model_credentials(
         }
     }
 }
+
+pub async fn get_model_and_credentials(
+    db: &Database,
+    datasource_id: &str,
+) -> Result<Option<(Model, Option<Credentials>)>> {
+    let credentials_collection = db.collection::<Credentials>("credentials");
+    match get_embedding_model(db, datasource_id).await? {
+        Some(model) => match model.credentialId {
+            Some(credential_id) => {
+                match credentials_collection
+                    .find_one(doc! {"_id": credential_id}, None)
+                    .await
+                {
+                    Ok(credentials) => Ok(Some((model, credentials))),
+                    Err(e) => Err(anyhow!("Failed to find a Credentials object: {}", e)),
+                }
+            }
+            // fast embed models run locally and don't need credentials
+            None => Ok(Some((model, None))),
+        },
+        None => Ok(None),
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `db`: The Mongo database
+/// * `datasource_id`: The datasource of the Airbyte connection
+/// * `stream`: The name of the stream
+///
+/// The primary key configured for the stream, or else the one the source defines for it.
+///
+/// returns: Result<Vec<Vec<String>>, Error> The path of each field of the key into the record.
+/// Empty if the stream has no primary key or is not in the connection's catalog.
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn get_stream_primary_key(db: &Database, datasource_id: &str, stream: &str) -> Result<Vec<Vec<String>>> {
+    let datasources_collection: Collection<DatasourceSyncCatalog> = db.collection("datasources");
+    let options = FindOneOptions::builder()
+        .projection(doc! {"connectionSettings.syncCatalog.streams": 1})
+        .build();
+    let datasource_id = ObjectId::from_str(datasource_id).map_err(|e| anyhow!("Invalid datasource ID: {}", e))?;
+    let datasource = datasources_collection
+        .find_one(doc! {"_id": datasource_id}, options)
+        .await
+        .map_err(|e| anyhow!("Failed to find the sync catalog of datasource {}: {}", datasource_id, e))?;
+    let Some(settings) = datasource.and_then(|datasource| datasource.connectionSettings) else {
+        return Ok(vec![]);
+    };
+    Ok(settings
+        .syncCatalog
+        .streams
+        .into_iter()
+        .find(|s| s.stream.name == stream)
+        .map(|s| match s.config {
+            Some(config) if !config.primaryKey.is_empty() => config.primaryKey,
+            _ => s.stream.sourceDefinedPrimaryKey,
+        })
+        .unwrap_or_default())
+}
+
+pub async fn get_stream_offset(db: &Database, stream: &str) -> Result<Option<i64>> {
+    let stream_offsets_collection = db.collection::<StreamOffset>("streamoffsets");
+    match stream_offsets_collection
+        .find_one(doc! {"stream": stream}, None)
+        .await
+    {
+        Ok(stream_offset) => Ok(stream_offset.map(|s| s.offset)),
+        Err(e) => Err(anyhow!("Failed to find the offset of stream {}: {}", stream, e)),
+    }
+}
+
+pub async fn set_stream_offset(db: &Database, stream: &str, offset: i64) -> Result<()> {
+    let stream_offsets_collection = db.collection::<StreamOffset>("streamoffsets");
+    let options = UpdateOptions::builder().upsert(true).build();
+    match stream_offsets_collection
+        .update_one(
+            doc! {"stream": stream},
+            doc! {"$set": {"offset": offset, "updatedDate": bson::DateTime::now()}},
+            options,
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(anyhow!("Failed to store the offset of stream {}: {}", stream, e)),
+    }
+}
+
+pub async fn create_ingestion_job_indexes(db: &Database) -> Result<()> {
+    let ingestion_jobs_collection = db.collection::<IngestionJob>("ingestionjobs");
+    // backs the listing of a datasource's jobs, newest first
+    let index = IndexModel::builder()
+        .keys(doc! {"datasourceId": 1, "createdDate": -1})
+        .build();
+    match ingestion_jobs_collection.create_index(index, None).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(anyhow!("Failed to create the ingestion job indexes: {}", e)),
+    }
+}
+
+pub async fn insert_ingestion_job(db: &Database, job: &IngestionJob) -> Result<()> {
+    let ingestion_jobs_collection = db.collection::<IngestionJob>("ingestionjobs");
+    match ingestion_jobs_collection.insert_one(job, None).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(anyhow!("Failed to create ingestion job {}: {}", job._id, e)),
+    }
+}
+
+pub async fn update_ingestion_job(db: &Database, job_id: ObjectId, update: Document) -> Result<()> {
+    let ingestion_jobs_collection = db.collection::<IngestionJob>("ingestionjobs");
+    match ingestion_jobs_collection
+        .update_one(doc! {"_id": job_id}, update, None)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(anyhow!("Failed to update ingestion job {}: {}", job_id, e)),
+    }
+}
+
+pub async fn get_ingestion_job(db: &Database, job_id: &str) -> Result<Option<IngestionJob>> {
+    let ingestion_jobs_collection = db.collection::<IngestionJob>("ingestionjobs");
+    let job_id = ObjectId::from_str(job_id).map_err(|e| anyhow!("Invalid job ID {}: {}", job_id, e))?;
+    match ingestion_jobs_collection
+        .find_one(doc! {"_id": job_id}, None)
+        .await
+    {
+        Ok(job) => Ok(job),
+        Err(e) => Err(anyhow!("Failed to find ingestion job {}: {}", job_id, e)),
+    }
+}
+
+/// The most recently created ingestion jobs of a datasource, newest first
+pub async fn get_ingestion_jobs(db: &Database, datasource_id: &str, limit: i64) -> Result<Vec<IngestionJob>> {
+    let ingestion_jobs_collection = db.collection::<IngestionJob>("ingestionjobs");
+    let datasource_id = ObjectId::from_str(datasource_id)
+        .map_err(|e| anyhow!("Invalid datasource ID {}: {}", datasource_id, e))?;
+    let options = FindOptions::builder()
+        .sort(doc! {"createdDate": -1})
+        .limit(limit)
+        .build();
+    match ingestion_jobs_collection
+        .find(doc! {"datasourceId": datasource_id}, options)
+        .await
+    {
+        Ok(cursor) => cursor
+            .try_collect()
+            .await
+            .map_err(|e| anyhow!("Failed to read the ingestion jobs of datasource {}: {}", datasource_id, e)),
+        Err(e) => Err(anyhow!("Failed to find the ingestion jobs of datasource {}: {}", datasource_id, e)),
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+use qdrant_client::prelude::Payload;
+use qdrant_client::qdrant::PointId;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::qdrant::utils::Qdrant;
+
+/// Payload key holding the identity of the document a chunk was cut from
+pub const DOCUMENT_ID_KEY: &str = "document_id";
+/// Payload key holding the hash of the text a chunk's vector was embedded from
+pub const CONTENT_HASH_KEY: &str = "content_hash";
+/// Payload key holding the hash of the rest of a chunk's payload, used to spot chunks whose text is
+/// unchanged but whose position in the document moved
+pub const METADATA_HASH_KEY: &str = "metadata_hash";
+
+// Namespace for the version 5 point ids, changing it changes the id of every point
+const POINT_ID_NAMESPACE: Uuid = Uuid::from_u128(0x6c1e_2f0a_94d3_4b7e_9a51_3d0c_8e27_f4b6);
+
+/// Hex encoded SHA-256 of the text
+pub fn content_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+/// Hash of a payload with its keys in sorted order so that the same payload always hashes the same.
+/// The metadata hash itself is left out.
+pub fn metadata_hash<V: Serialize>(metadata: &HashMap<String, V>) -> String {
+    let sorted: BTreeMap<&String, &V> = metadata
+        .iter()
+        .filter(|(key, _)| key.as_str() != METADATA_HASH_KEY)
+        .collect();
+    content_hash(serde_json::to_string(&sorted).unwrap_or_default().as_str())
+}
+
+///
+///
+/// # Arguments
+///
+/// * `datasource_id`: The datasource the point belongs to
+/// * `document_id`: The identity of the document within the datasource
+/// * `content_hash`: The hash of the point's text
+///
+/// The same chunk of the same document always gets the same id, so uploading it again overwrites
+/// the point instead of adding a duplicate.
+///
+/// returns: Uuid
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn point_id(datasource_id: &str, document_id: &str, content_hash: &str) -> Uuid {
+    let name = format!("{}\n{}\n{}", datasource_id, document_id, content_hash);
+    Uuid::new_v5(&POINT_ID_NAMESPACE, name.as_bytes())
+}
+
+/// The identity of an Airbyte record in a stream that has a primary key
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordKey {
+    pub stream: String,
+    /// The values of the primary key's fields, as JSON
+    pub values: String,
+}
+
+///
+///
+/// # Arguments
+///
+/// * `datasource_id`: The datasource the record was synced to
+/// * `record`: The payload of the record
+/// * `key`: The record's primary key, if its stream has one
+///
+/// A record with a primary key is identified by it, so a changed record replaces its old point.
+/// Other records carry no identity of their own and are identified by their content, so a record
+/// that is synced again unchanged gets the same id and a changed record gets a new point.
+///
+/// returns: Uuid
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn record_point_id(datasource_id: &str, record: &HashMap<String, String>, key: Option<&RecordKey>) -> Uuid {
+    match key {
+        Some(key) => point_id(datasource_id, key.stream.as_str(), content_hash(key.values.as_str()).as_str()),
+        None => point_id(datasource_id, "", metadata_hash(record).as_str()),
+    }
+}
+
+/// A document in its datasource's collection along with the chunks it already has there. Chunks
+/// whose text is stored are not embedded again, and once the new version of the document has been
+/// uploaded the chunks it no longer contains are stale and can be deleted.
+pub struct StoredDocument {
+    qdrant: Qdrant,
+    datasource_id: String,
+    document_id: String,
+    // content hash to metadata hash of every chunk in the collection
+    stored: HashMap<String, String>,
+    seen: HashSet<String>,
+}
+
+impl StoredDocument {
+    /// A document that has nothing stored, for when the stored chunks could not be looked up
+    pub fn new(qdrant: Qdrant, datasource_id: &str, document_id: String) -> Self {
+        StoredDocument {
+            qdrant,
+            datasource_id: datasource_id.to_string(),
+            document_id,
+            stored: HashMap::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Looks up the chunks of the document in the collection. Failing to do so is not fatal, every
+    /// chunk is then treated as new.
+    pub async fn load(qdrant: Qdrant, datasource_id: &str, document_id: String) -> Self {
+        let mut document = StoredDocument::new(qdrant, datasource_id, document_id);
+        match document.qdrant.get_document_hashes(document.document_id.as_str()).await {
+            Ok(stored) => document.stored = stored,
+            Err(e) => println!(
+                "Could not look up the stored chunks of document {}, all chunks will be embedded. Error: {}",
+                document.document_id, e
+            ),
+        }
+        document
+    }
+
+    /// The collection the document is stored in
+    pub fn collection(&self) -> &Qdrant {
+        &self.qdrant
+    }
+
+    pub fn document_id(&self) -> &str {
+        self.document_id.as_str()
+    }
+
+    pub fn point_id(&self, content_hash: &str) -> Uuid {
+        point_id(self.datasource_id.as_str(), self.document_id.as_str(), content_hash)
+    }
+
+    /// Whether a chunk with this text is already in the collection
+    pub fn is_stored(&self, content_hash: &str) -> bool {
+        self.stored.contains_key(content_hash)
+    }
+
+    /// Whether a stored chunk's payload differs from the given metadata hash
+    pub fn metadata_changed(&self, content_hash: &str, metadata_hash: &str) -> bool {
+        self.stored
+            .get(content_hash)
+            .is_some_and(|stored| stored != metadata_hash)
+    }
+
+    /// Records that the new version of the document contains a chunk
+    pub fn mark_seen(&mut self, content_hash: &str) {
+        self.seen.insert(content_hash.to_string());
+    }
+
+    /// How many distinct chunks the new version of the document has been seen to contain
+    pub fn seen_count(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// Writes the payload of a stored chunk, for when its text is unchanged but its metadata is not.
+    /// The vector is left as it is.
+    pub async fn refresh_payload<V: Serialize>(
+        &self,
+        content_hash: &str,
+        payload: &HashMap<String, V>,
+    ) -> Result<()> {
+        let payload: Payload = json!(payload)
+            .try_into()
+            .map_err(|e| anyhow!("Could not convert payload. Error: {}", e))?;
+        self.qdrant
+            .overwrite_payload(PointId::from(self.point_id(content_hash).to_string()), payload)
+            .await
+    }
+
+    /// Chunks that are stored but were not seen in the new version of the document
+    pub fn stale_points(&self) -> Vec<PointId> {
+        self.stored
+            .keys()
+            .filter(|hash| !self.seen.contains(*hash))
+            .map(|hash| PointId::from(self.point_id(hash).to_string()))
+            .collect()
+    }
+
+    /// Deletes the stale chunks. Only call this once every chunk of the new version has been seen.
+    pub async fn delete_stale(&self) {
+        let stale = self.stale_points();
+        if stale.is_empty() {
+            return;
+        }
+        let count = stale.len();
+        match self.qdrant.delete_points(stale).await {
+            Ok(_) => println!("Deleted {} stale chunks of document {}", count, self.document_id),
+            Err(e) => println!(
+                "Could not delete {} stale chunks of document {}. Error: {}",
+                count, self.document_id, e
+            ),
+        }
+    }
+}
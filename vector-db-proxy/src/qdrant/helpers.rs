@@ -4,18 +4,25 @@ use qdrant_client::client::QdrantClient;
 use qdrant_client::prelude::Value;
 use qdrant_client::qdrant::point_id::PointIdOptions;
 use qdrant_client::qdrant::vectors::VectorsOptions;
-use qdrant_client::qdrant::{PointStruct, ScrollPoints, ScrollResponse};
+use qdrant_client::qdrant::{
+    PointId, PointStruct, ScoredPoint, ScrollPoints, ScrollResponse, Vector, Vectors,
+};
+use serde::Serialize;
 use serde_json::json;
 use std::collections::HashMap;
 use std::sync::Arc;
-use mongodb::Database;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
+use crate::data::bm25::{document_sparse_vector, SPARSE_VECTOR_NAME};
 use crate::hash_map_values_as_serde_values;
-use crate::llm::models::EmbeddingModels;
+use crate::llm::models::EmbeddingType;
+use crate::llm::providers::{EmbeddingProvider, EmbeddingProviders};
 use crate::llm::utils::embed_text;
-use crate::qdrant::models::ScrollResults;
+use crate::qdrant::models::{HybridSearchResults, ScrollResults};
+
+// Rank constant from the original reciprocal rank fusion paper
+const RRF_K: f32 = 60.0;
 
 ///
 ///
@@ -48,6 +55,93 @@ pub async fn get_next_page(
     Ok((result, offset))
 }
 
+pub fn point_id_to_string(point_id: Option<PointId>) -> String {
+    match point_id.and_then(|p| p.point_id_options) {
+        Some(PointIdOptions::Num(num)) => num.to_string(),
+        Some(PointIdOptions::Uuid(uuid)) => uuid,
+        None => String::new(),
+    }
+}
+
+/// Point IDs in Qdrant are either unsigned integers or UUIDs
+pub fn parse_point_id(id: &str) -> Result<PointId> {
+    if let Ok(num) = id.parse::<u64>() {
+        return Ok(PointId::from(num));
+    }
+    match Uuid::parse_str(id) {
+        Ok(uuid) => Ok(PointId::from(uuid.to_string())),
+        Err(_) => Err(anyhow!(
+            "Point ID '{}' is neither an unsigned integer nor a UUID",
+            id
+        )),
+    }
+}
+
+/// Drops the BM25 sparse vector from a point so it can be written to a collection that only has the dense vector
+pub fn remove_sparse_vector(point: &mut PointStruct) {
+    if let Some(Vectors {
+        vectors_options: Some(VectorsOptions::Vectors(named_vectors)),
+    }) = point.vectors.as_mut()
+    {
+        named_vectors.vectors.remove(SPARSE_VECTOR_NAME);
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `dense_results`: Ranked results of the dense vector search
+/// * `sparse_results`: Ranked results of the sparse (BM25) search
+/// * `limit`: The number of fused results to return
+///
+/// Reciprocal rank fusion scores each point as the sum of `1 / (k + rank)` over the searches that
+/// returned it. It only looks at ranks, so the very different dense and sparse score scales do not
+/// need to be normalised against each other.
+///
+/// returns: Vec<HybridSearchResults, Global>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn reciprocal_rank_fusion(
+    dense_results: Vec<ScoredPoint>,
+    sparse_results: Vec<ScoredPoint>,
+    limit: usize,
+) -> Vec<HybridSearchResults> {
+    let mut fused: HashMap<String, HybridSearchResults> = HashMap::new();
+    for (rank, point) in dense_results.into_iter().enumerate() {
+        let id = point_id_to_string(point.id);
+        let result = fused.entry(id.clone()).or_insert(HybridSearchResults {
+            id,
+            dense_score: None,
+            sparse_score: None,
+            fused_score: 0.0,
+            payload: point.payload,
+        });
+        result.dense_score = Some(point.score);
+        result.fused_score += 1.0 / (RRF_K + rank as f32 + 1.0);
+    }
+    for (rank, point) in sparse_results.into_iter().enumerate() {
+        let id = point_id_to_string(point.id);
+        let result = fused.entry(id.clone()).or_insert(HybridSearchResults {
+            id,
+            dense_score: None,
+            sparse_score: None,
+            fused_score: 0.0,
+            payload: point.payload,
+        });
+        result.sparse_score = Some(point.score);
+        result.fused_score += 1.0 / (RRF_K + rank as f32 + 1.0);
+    }
+    let mut results: Vec<HybridSearchResults> = fused.into_values().collect();
+    results.sort_by(|a, b| b.fused_score.total_cmp(&a.fused_score));
+    results.truncate(limit);
+    results
+}
+
 pub fn get_scroll_results(result: ScrollResponse) -> Result<Vec<ScrollResults>> {
     let mut response: Vec<ScrollResults> = vec![];
     for result in result.result {
@@ -91,7 +185,11 @@ pub fn get_scroll_results(result: ScrollResponse) -> Result<Vec<ScrollResults>>
 ///
 /// # Arguments
 ///
-/// * `row`:
+/// * `embedding_provider`: The provider to embed the text with
+/// * `data`: The payload of the point
+/// * `text`: The text to embed
+/// * `point_id`: The id of the point, derived from the record so that syncing the same record
+///   again overwrites it
 ///
 /// returns: Result<PointStruct, Error>
 ///
@@ -101,41 +199,40 @@ pub fn get_scroll_results(result: ScrollResponse) -> Result<Vec<ScrollResults>>
 ///
 /// ```
 pub async fn embed_payload(
-    mongo_conn: Arc<RwLock<Database>>,
+    embedding_provider: &EmbeddingProviders,
     data: &HashMap<String, String>,
     text: &String,
-    datasource_id: Option<String>,
-    embedding_model: EmbeddingModels,
+    point_id: Uuid,
 ) -> Result<PointStruct, anyhow::Error> {
     if !data.is_empty() {
-        if let Some(_id) = datasource_id {
-            let payload: HashMap<String, serde_json::Value> =
-                hash_map_values_as_serde_values!(data);
-            if let Ok(metadata) = json!(payload).try_into() {
-                // Embedding sentences using OpenAI ADA2
-                let embedding_vec = embed_text(mongo_conn, _id, vec![text], &embedding_model).await?;
-                // Construct PointStruct to insert into DB
-                if !embedding_vec.is_empty() {
-                    if let Some(embedding) = embedding_vec.into_iter().next() {
-                        let point = PointStruct::new(
-                            Uuid::new_v4().to_string(),
-                            HashMap::from([(
-                                String::from(embedding_model.to_str().unwrap()),
-                                embedding.to_owned(),
-                            )]),
-                            metadata,
-                        );
-                        return Ok(point);
-                    }
+        let payload: HashMap<String, serde_json::Value> =
+            hash_map_values_as_serde_values!(data);
+        if let Ok(metadata) = json!(payload).try_into() {
+            // Embedding sentences using OpenAI ADA2
+            let embedding_vec = embed_text(embedding_provider, vec![text], EmbeddingType::Passage).await?;
+            // Construct PointStruct to insert into DB
+            if !embedding_vec.is_empty() {
+                if let Some(embedding) = embedding_vec.into_iter().next() {
+                    let point = PointStruct::new(
+                        point_id.to_string(),
+                        HashMap::from([
+                            (
+                                String::from(embedding_provider.model_name()),
+                                Vector::from(embedding),
+                            ),
+                            (
+                                String::from(SPARSE_VECTOR_NAME),
+                                Vector::from(document_sparse_vector(text)),
+                            ),
+                        ]),
+                        metadata,
+                    );
+                    return Ok(point);
                 }
-            } else {
-                return Err(anyhow!(
-                    "Could not convert payload to JSON type. Aborting embedding!"
-                ));
             }
         } else {
             return Err(anyhow!(
-                "Could not find an stream ID for this payload. Aborting embedding!"
+                "Could not convert payload to JSON type. Aborting embedding!"
             ));
         }
     }
@@ -154,28 +251,75 @@ pub async fn reverse_embed_payload(payload: &HashMap<String, Value>) -> Result<V
     }
 }
 
-pub async fn construct_point_struct(
+///
+///
+/// # Arguments
+///
+/// * `point_id`: The id of the point, see `dedup::point_id`
+/// * `vector`: The embedding of the text
+/// * `text`: The text the BM25 sparse vector is computed from
+/// * `payload`: The payload of the point, no point is built if it is empty
+/// * `vector_name`: The name of the dense vector, the embedding model
+///
+/// returns: Option<PointStruct>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn construct_point_struct<V: Serialize>(
+    point_id: Uuid,
     vector: &Vec<f32>,
-    payload: HashMap<String, String>,
-    embedding_models: Option<EmbeddingModels>,
+    text: &str,
+    payload: HashMap<String, V>,
+    vector_name: &str,
 ) -> Option<PointStruct> {
     if !payload.is_empty() {
-        return if let Some(model_name) = embedding_models {
-            if let Some(model) = model_name.to_str() {
-                let qdrant_point_struct = PointStruct::new(
-                    Uuid::new_v4().to_string(),
-                    HashMap::from([(String::from(model), vector.to_owned())]),
-                    json!(payload).try_into().unwrap(),
-                );
-                Some(qdrant_point_struct)
-            } else {
-                eprintln!("Could not convert model to a string slice");
-                None
-            }
-        } else {
-            println!("Embedding Model name is None");
-            None
-        };
+        let qdrant_point_struct = PointStruct::new(
+            point_id.to_string(),
+            HashMap::from([
+                (String::from(vector_name), Vector::from(vector.to_owned())),
+                (
+                    String::from(SPARSE_VECTOR_NAME),
+                    Vector::from(document_sparse_vector(text)),
+                ),
+            ]),
+            json!(payload).try_into().unwrap(),
+        );
+        return Some(qdrant_point_struct);
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(ids: &[u64]) -> Vec<ScoredPoint> {
+        ids.iter()
+            .map(|&id| ScoredPoint {
+                id: Some(PointId::from(id)),
+                score: 1.0 / (id as f32 + 1.0),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn points_found_by_both_searches_rank_first() {
+        let results = reciprocal_rank_fusion(points(&[1, 2, 3]), points(&[3]), 10);
+        let ids: Vec<&str> = results.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["3", "1", "2"]);
+        assert_eq!(results[0].dense_score, Some(0.25));
+        assert_eq!(results[0].sparse_score, Some(0.25));
+        assert!((results[0].fused_score - (1.0 / 63.0 + 1.0 / 61.0)).abs() < 1e-6);
+        assert_eq!(results[2].sparse_score, None);
+    }
+
+    #[test]
+    fn fused_results_are_limited() {
+        let results = reciprocal_rank_fusion(points(&[1, 2, 3]), points(&[4, 5, 6]), 2);
+        assert_eq!(results.len(), 2);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod client;
 pub mod models;
 pub mod utils;
-pub mod helpers;
\ No newline at end of file
+pub mod helpers;
+pub mod dedup;
\ No newline at end of file
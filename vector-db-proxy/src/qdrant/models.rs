@@ -1,3 +1,4 @@
+use qdrant_client::qdrant::PointId;
 use serde_json::Value;
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
@@ -17,6 +18,44 @@ pub struct PointSearchResults {
     pub payload: HashMap<String, qdrant_client::prelude::Value>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HybridSearchResults {
+    pub id: String,
+    pub dense_score: Option<f32>,
+    pub sparse_score: Option<f32>,
+    pub fused_score: f32,
+    pub payload: HashMap<String, qdrant_client::prelude::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecommendationResults {
+    pub id: String,
+    pub score: f32,
+    pub payload: HashMap<String, qdrant_client::prelude::Value>,
+}
+
+/// Points (or raw vectors) that results should be similar to (positive) or steered away from (negative)
+#[derive(Debug, Default)]
+pub struct RecommendationExamples {
+    pub positive: Vec<PointId>,
+    pub negative: Vec<PointId>,
+    pub positive_vectors: Vec<Vec<f32>>,
+    pub negative_vectors: Vec<Vec<f32>>,
+}
+
+impl RecommendationExamples {
+    pub fn is_empty(&self) -> bool {
+        self.positive.is_empty()
+            && self.negative.is_empty()
+            && self.positive_vectors.is_empty()
+            && self.negative_vectors.is_empty()
+    }
+
+    pub fn has_positive(&self) -> bool {
+        !self.positive.is_empty() || !self.positive_vectors.is_empty()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetCollectionsResults {
     pub collection_name: String,
@@ -1,21 +1,40 @@
 use anyhow::{anyhow, Result};
 
-use crate::qdrant::models::{CreateDisposition, PointSearchResults};
-use crate::routes::models::FilterConditions;
-use crate::utils::conversions::convert_hashmap_to_filters;
+use crate::data::bm25::SPARSE_VECTOR_NAME;
+use crate::qdrant::dedup::{CONTENT_HASH_KEY, DOCUMENT_ID_KEY, METADATA_HASH_KEY};
+use crate::qdrant::helpers::{point_id_to_string, reciprocal_rank_fusion, remove_sparse_vector};
+use crate::qdrant::models::{
+    CreateDisposition, HybridSearchResults, PointSearchResults, RecommendationExamples,
+};
 use qdrant_client::client::QdrantClient;
 use qdrant_client::prelude::*;
 use qdrant_client::qdrant::vectors_config::Config;
 use qdrant_client::qdrant::{
-    CreateCollection, Filter, PointId, PointStruct, RecommendPoints, ScoredPoint, VectorParams,
-    VectorParamsMap, VectorsConfig,
+    Condition, CreateCollection, Filter, PointId, PointStruct, RecommendPoints, RecommendStrategy,
+    ScoredPoint, ScrollPoints, SearchBatchPoints, SparseIndices, SparseVectorConfig,
+    SparseVectorParams, Vector, VectorParams, VectorParamsMap, VectorsConfig,
 };
-use std::sync::Arc;
-use std::time::Duration;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use backoff::{ExponentialBackoff};
 use backoff::backoff::Backoff;
 
+// How many candidates each search of a hybrid query fetches relative to the requested limit
+const HYBRID_CANDIDATE_MULTIPLIER: u64 = 4;
+// How many points a page of a document's chunks holds when looking them up
+const DOCUMENT_SCROLL_LIMIT: u32 = 1000;
+
+// Whether each collection holds the BM25 sparse vector, and when that was looked up. A collection's
+// vectors don't change once it has been created, but other services may drop a collection and
+// create it again, so entries expire and are dropped whenever an upsert or search fails.
+static SPARSE_VECTOR_COLLECTIONS: Lazy<Mutex<HashMap<String, (bool, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// How long whether a collection holds the sparse vector is cached for
+const SPARSE_VECTOR_CACHE_TTL: Duration = Duration::from_secs(60);
+
 pub struct Qdrant {
     client: Arc<RwLock<QdrantClient>>,
     collection_name: String,
@@ -50,7 +69,11 @@ impl Qdrant {
             Ok(r) => match r {
                 true => match qdrant_conn.delete_collection(&self.collection_name).await {
                     Ok(result) => match result.result {
-                        true => Ok(()),
+                        true => {
+                            // a collection created again under the same name is looked up afresh
+                            self.forget_sparse_vector();
+                            Ok(())
+                        }
                         false => Err(anyhow!("Collection could not be deleted!")),
                     },
                     Err(e) => Err(anyhow!(
@@ -113,6 +136,7 @@ impl Qdrant {
             );
             let vector_size = vector_length.unwrap_or(512); // Default to fastembed embedding size if none is given;
             let mut config: Option<VectorsConfig> = Some(VectorsConfig::default());
+            let mut sparse_config: Option<SparseVectorConfig> = None;
             match create_disposition {
                 CreateDisposition::CreateIfNeeded => {
                     // check if vector name is a value or None
@@ -133,7 +157,15 @@ impl Qdrant {
                                     )]
                                         .into(),
                                 })),
-                            })
+                            });
+                            // named vector collections also get a BM25 sparse vector for keyword matching
+                            sparse_config = Some(SparseVectorConfig {
+                                map: [(
+                                    String::from(SPARSE_VECTOR_NAME),
+                                    SparseVectorParams::default(),
+                                )]
+                                    .into(),
+                            });
                         }
                         None => {
                             config = Some(VectorsConfig {
@@ -149,6 +181,7 @@ impl Qdrant {
                         .create_collection(&CreateCollection {
                             collection_name: self.collection_name.to_owned(),
                             vectors_config: config,
+                            sparse_vectors_config: sparse_config,
                             ..Default::default()
                         })
                         .await
@@ -183,6 +216,156 @@ impl Qdrant {
         }
     }
 
+    /// Collections created before hybrid search was introduced only hold the dense model vector,
+    /// so we check for the BM25 sparse vector before sending points that carry one.
+    pub async fn has_sparse_vector(&self) -> Result<bool> {
+        if let Some((has_sparse_vector, looked_up)) = SPARSE_VECTOR_COLLECTIONS.lock().unwrap().get(&self.collection_name) {
+            if looked_up.elapsed() < SPARSE_VECTOR_CACHE_TTL {
+                return Ok(*has_sparse_vector);
+            }
+        }
+        let qdrant_conn = &self.client.read().await;
+        let collection_info = qdrant_conn.collection_info(&self.collection_name).await?;
+        let has_sparse_vector = collection_info
+            .result
+            .and_then(|info| info.config)
+            .and_then(|config| config.params)
+            .and_then(|params| params.sparse_vectors_config)
+            .is_some_and(|sparse| sparse.map.contains_key(SPARSE_VECTOR_NAME));
+        SPARSE_VECTOR_COLLECTIONS
+            .lock()
+            .unwrap()
+            .insert(self.collection_name.clone(), (has_sparse_vector, Instant::now()));
+        Ok(has_sparse_vector)
+    }
+
+    /// Looks up whether the collection holds the sparse vector again the next time it is needed, in
+    /// case the collection was created again with other vectors
+    pub fn forget_sparse_vector(&self) {
+        SPARSE_VECTOR_COLLECTIONS.lock().unwrap().remove(&self.collection_name);
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `document_id`: The identity of the document whose chunks to look up
+    ///
+    /// returns: Result<HashMap<String, String>, Error> The content hash of every chunk of the
+    /// document mapped to the hash of its metadata. Empty if the collection does not exist yet.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn get_document_hashes(&self, document_id: &str) -> Result<HashMap<String, String>> {
+        let mut hashes = HashMap::new();
+        if !self
+            .check_collection_exists(CreateDisposition::CreateNever, None, None)
+            .await?
+        {
+            return Ok(hashes);
+        }
+        let qdrant_conn = &self.client.read().await;
+        let mut scroll = ScrollPoints {
+            collection_name: self.collection_name.to_owned(),
+            filter: Some(Filter::must([Condition::matches(
+                DOCUMENT_ID_KEY,
+                document_id.to_string(),
+            )])),
+            limit: Some(DOCUMENT_SCROLL_LIMIT),
+            with_payload: Some(vec![CONTENT_HASH_KEY, METADATA_HASH_KEY].into()),
+            with_vectors: Some(false.into()),
+            ..Default::default()
+        };
+        loop {
+            let response = qdrant_conn.scroll(&scroll).await?;
+            for point in response.result {
+                if let Some(content_hash) = point.payload.get(CONTENT_HASH_KEY).and_then(|h| h.as_str()) {
+                    let metadata_hash = point
+                        .payload
+                        .get(METADATA_HASH_KEY)
+                        .and_then(|h| h.as_str())
+                        .cloned()
+                        .unwrap_or_default();
+                    hashes.insert(content_hash.to_string(), metadata_hash);
+                }
+            }
+            match response.next_page_offset {
+                Some(offset) => scroll.offset = Some(offset),
+                None => break,
+            }
+        }
+        Ok(hashes)
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `ids`: The points to look for
+    ///
+    /// returns: Result<HashMap<String, String>, Error> The ids of the points that exist in the
+    /// collection, with the metadata hash of their payload or an empty string if they have none.
+    /// Empty if the collection does not exist yet.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn get_existing_points(&self, ids: Vec<PointId>) -> Result<HashMap<String, String>> {
+        if ids.is_empty()
+            || !self
+            .check_collection_exists(CreateDisposition::CreateNever, None, None)
+            .await?
+        {
+            return Ok(HashMap::new());
+        }
+        let qdrant_conn = &self.client.read().await;
+        let response = qdrant_conn
+            .get_points(
+                &self.collection_name,
+                None,
+                &ids,
+                Some(false),
+                Some(vec![METADATA_HASH_KEY]),
+                None,
+            )
+            .await?;
+        Ok(response
+            .result
+            .into_iter()
+            .map(|point| {
+                let metadata_hash = point
+                    .payload
+                    .get(METADATA_HASH_KEY)
+                    .and_then(|h| h.as_str())
+                    .cloned()
+                    .unwrap_or_default();
+                (point_id_to_string(point.id), metadata_hash)
+            })
+            .collect())
+    }
+
+    pub async fn delete_points(&self, ids: Vec<PointId>) -> Result<()> {
+        let qdrant_conn = &self.client.read().await;
+        qdrant_conn
+            .delete_points_blocking(&self.collection_name, None, &ids.into(), None)
+            .await?;
+        Ok(())
+    }
+
+    /// Replaces the payload of a point without touching its vectors
+    pub async fn overwrite_payload(&self, id: PointId, payload: Payload) -> Result<()> {
+        let qdrant_conn = &self.client.read().await;
+        qdrant_conn
+            .overwrite_payload_blocking(&self.collection_name, None, &vec![id].into(), payload, None, None)
+            .await?;
+        Ok(())
+    }
+
     ///
     ///
     /// # Arguments
@@ -232,6 +415,10 @@ impl Qdrant {
         {
             Ok(result) => match result {
                 true => {
+                    let mut point = point;
+                    if !self.has_sparse_vector().await? {
+                        remove_sparse_vector(&mut point);
+                    }
                     let mut backoff = ExponentialBackoff {
                         current_interval: Duration::from_millis(50),
                         initial_interval: Duration::from_millis(50),
@@ -264,7 +451,10 @@ impl Qdrant {
                                     },
                                     None => return Err(anyhow!("Results returned None")),
                                 },
-                                Err(e) => println!("Error upserting to Qdrant: {}, retrying...", e),
+                                Err(e) => {
+                                    println!("Error upserting to Qdrant: {}, retrying...", e);
+                                    self.forget_sparse_vector();
+                                }
                             }
 
                             if backoff.next_backoff().is_none() {
@@ -327,6 +517,10 @@ impl Qdrant {
         {
             Ok(result) => match result {
                 true => {
+                    let mut points = points;
+                    if !self.has_sparse_vector().await? {
+                        points.iter_mut().for_each(remove_sparse_vector);
+                    }
                     match qdrant_conn
                         .upsert_points_batch_blocking(
                             &self.collection_name,
@@ -350,7 +544,10 @@ impl Qdrant {
                             },
                             None => Err(anyhow!("Results returned None")),
                         },
-                        Err(e) => Err(anyhow!("There was an error upserting to qdrant: {}", e)),
+                        Err(e) => {
+                            self.forget_sparse_vector();
+                            Err(anyhow!("There was an error upserting to qdrant: {}", e))
+                        }
                     }
                 }
                 false => {
@@ -373,8 +570,9 @@ impl Qdrant {
     /// # Arguments
     ///
     /// * `vector`: A list of float 32
-    /// * `filters`: Hashmap comprised of the key value pairs to filter on
+    /// * `filter`: Validated filter built from the client's filter conditions
     /// * `limit`: The number of results to return from search
+    /// * `vector_name`: The named vector to search against (the embedding model name)
     ///
     /// returns: Result<Vec<PointSearchResults, Global>, Error>
     ///
@@ -386,24 +584,20 @@ impl Qdrant {
     pub async fn return_similar_results(
         &self,
         vector: Vec<f32>,
-        filters: Option<FilterConditions>,
+        filter: Option<Filter>,
         limit: Option<u64>,
+        vector_name: Option<String>,
     ) -> Result<Vec<PointSearchResults>> {
         let qdrant_conn = &self.client.read().await;
-        let (must, must_not, should) = convert_hashmap_to_filters(&filters);
         let mut response_data: Vec<PointSearchResults> = vec![];
         let search_result = qdrant_conn
             .search_points(&SearchPoints {
                 collection_name: self.collection_name.clone(),
                 vector: vector.to_owned(),
-                filter: Some(Filter {
-                    must,
-                    must_not,
-                    should,
-                    ..Default::default()
-                }),
+                filter,
                 limit: limit.unwrap_or(5),
                 with_payload: Some(true.into()),
+                vector_name,
                 ..Default::default()
             })
             .await?;
@@ -420,9 +614,81 @@ impl Qdrant {
     ///
     /// # Arguments
     ///
-    /// * `id`: Vector ID
-    /// * `filters`: list of filters
+    /// * `vector`: Dense query vector
+    /// * `sparse_vector`: BM25 query term vector
+    /// * `filter`: Validated filter built from the client's filter conditions
+    /// * `limit`: The number of results to return from search
+    /// * `vector_name`: The named dense vector to search against (the embedding model name)
+    ///
+    /// Runs the dense and sparse searches in a single batch and fuses both rankings with
+    /// reciprocal rank fusion. Each result carries the score it got from each search.
+    ///
+    /// returns: Result<Vec<HybridSearchResults, Global>, Error>
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn return_hybrid_results(
+        &self,
+        vector: Vec<f32>,
+        sparse_vector: Vec<(u32, f32)>,
+        filter: Option<Filter>,
+        limit: Option<u64>,
+        vector_name: Option<String>,
+    ) -> Result<Vec<HybridSearchResults>> {
+        let limit = limit.unwrap_or(5);
+        let qdrant_conn = &self.client.read().await;
+        // fetch more than requested from each search so that fusion has overlapping candidates to work with
+        let candidates = limit * HYBRID_CANDIDATE_MULTIPLIER;
+        let (indices, values): (Vec<u32>, Vec<f32>) = sparse_vector.into_iter().unzip();
+        let search_results = qdrant_conn
+            .search_batch_points(&SearchBatchPoints {
+                collection_name: self.collection_name.clone(),
+                search_points: vec![
+                    SearchPoints {
+                        collection_name: self.collection_name.clone(),
+                        vector,
+                        filter: filter.clone(),
+                        limit: candidates,
+                        with_payload: Some(true.into()),
+                        vector_name,
+                        ..Default::default()
+                    },
+                    SearchPoints {
+                        collection_name: self.collection_name.clone(),
+                        vector: values,
+                        sparse_indices: Some(SparseIndices { data: indices }),
+                        filter,
+                        limit: candidates,
+                        with_payload: Some(true.into()),
+                        vector_name: Some(String::from(SPARSE_VECTOR_NAME)),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            })
+            .await
+            .inspect_err(|_| self.forget_sparse_vector())?;
+        let mut batches = search_results.result.into_iter().map(|batch| batch.result);
+        let dense_results = batches.next().unwrap_or_default();
+        let sparse_results = batches.next().unwrap_or_default();
+        Ok(reciprocal_rank_fusion(dense_results, sparse_results, limit as usize))
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `examples`: Point IDs and/or raw vectors to recommend towards (positive) and away from (negative)
+    /// * `filter`: Validated filter built from the client's filter conditions
     /// * `limit`: limit the number of returned results
+    /// * `score_threshold`: Results scoring worse than this are cut off
+    /// * `vector_name`: The named vector to recommend against (the embedding model name)
+    ///
+    /// When only negative examples are given the best score strategy is used, as the default
+    /// average vector strategy requires at least one positive example.
     ///
     /// returns: Result<Vec<ScoredPoint, Global>, Error>
     ///
@@ -433,27 +699,29 @@ impl Qdrant {
     /// ```
     pub async fn return_recommendations(
         &self,
-        id: String,
-        filters: Option<FilterConditions>,
+        examples: RecommendationExamples,
+        filter: Option<Filter>,
         limit: u64,
+        score_threshold: Option<f32>,
+        vector_name: Option<String>,
     ) -> Result<Vec<ScoredPoint>> {
-        let (must, must_not, should) = convert_hashmap_to_filters(&filters);
-        let point_id = PointId {
-            point_id_options: Some(point_id::PointIdOptions::Uuid(id)),
+        let strategy = match examples.has_positive() {
+            true => RecommendStrategy::AverageVector,
+            false => RecommendStrategy::BestScore,
         };
         let qdrant = &self.client.read().await;
         let recommend = RecommendPoints {
             collection_name: self.collection_name.to_owned(),
-            positive: vec![point_id],
-            negative: vec![],
+            positive: examples.positive,
+            negative: examples.negative,
+            positive_vectors: examples.positive_vectors.into_iter().map(Vector::from).collect(),
+            negative_vectors: examples.negative_vectors.into_iter().map(Vector::from).collect(),
             limit,
-            filter: Some(Filter {
-                must,
-                must_not,
-                should,
-                ..Default::default()
-            }),
-            score_threshold: Some(0.9),
+            filter,
+            score_threshold,
+            using: vector_name,
+            strategy: Some(strategy.into()),
+            with_payload: Some(true.into()),
             ..Default::default()
         };
         match qdrant.recommend(&recommend).await {
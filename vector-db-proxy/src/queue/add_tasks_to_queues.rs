@@ -1,21 +1,13 @@
-use crate::queue::queuing::{Control, MyQueue};
-use mongodb::Database;
-use qdrant_client::client::QdrantClient;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use crate::queue::queuing::EmbeddingQueue;
+use crate::rabbitmq::delivery::Outcome;
+use tokio::sync::oneshot;
 
-/// Adds the incoming task to the execution Queue to be processes when threads are available
+/// Adds the incoming record to the embedding queue to be processed when a worker is free. Waits
+/// while the queue is full. The returned receiver resolves once the record has been processed.
 pub async fn add_message_to_embedding_queue(
-    queue: Arc<RwLock<MyQueue<String>>>,
-    qdrant_conn: Arc<RwLock<QdrantClient>>,
-    mongo_conn: Arc<RwLock<Database>>,
-    params: (String, String),
-) {
-    let (dataset_id, table_name) = params;
-    // Instantiate a new instance of the MyQueue
-    let mut q_guard = queue.write().await;
-    // Add task to queue
-    q_guard.enqueue(dataset_id);
-    // Call associated function to being processing tasks in the queue
-    q_guard.embed_message(qdrant_conn, mongo_conn, table_name);
-}
\ No newline at end of file
+    queue: &EmbeddingQueue,
+    params: (String, String, String),
+) -> oneshot::Receiver<Outcome> {
+    let (datasource_id, stream, message) = params;
+    queue.enqueue(datasource_id, stream, message).await
+}
@@ -1,128 +1,373 @@
-//! This is queueing module that provides app wide capability to add tasks to a queue
-use mongodb::Database;
-use std::fmt::Debug;
-use std::marker::Send;
+//! This is queueing module that provides app wide capability to embed Airbyte records.
+//!
+//! Records go through three stages, each with its own bounded queue and pool of workers running
+//! on the main runtime: extract parses the record and looks up its datasource, embed embeds it and
+//! upsert uploads it. When a stage's queue is full the stage before it waits, and ultimately the
+//! records waiting to be queued hold their deliveries unacked, so the broker stops delivering
+//! once the prefetch count is reached.
+//!
+//! Every job runs as a task of its own, so a stage that panics on a record rejects that record
+//! without losing the worker.
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::thread::available_parallelism;
-use tokio::sync::RwLock;
-
-use queues::Queue;
-use queues::*;
-use threadpool::ThreadPool;
+use std::thread::{self, available_parallelism};
 
+use mongodb::Database;
 use qdrant_client::client::QdrantClient;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
 
-use crate::data::processing_incoming_messages::process_messages;
+use crate::data::processing_incoming_messages::{
+    embed_record, prepare_record, upsert_record, EmbeddedRecord, PreparedRecord,
+};
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::rabbitmq::delivery::Outcome;
 
-// This is essentially the Class
-// The requirement for T to be Clone is a constraint of the queues crate
-pub struct MyQueue<T: Clone> {
-    q: Queue<T>,
-    pool: ThreadPool,
+/// How many workers each stage has and how many records each stage's queue holds
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub extract_workers: usize,
+    pub embed_workers: usize,
+    pub upsert_workers: usize,
 }
 
-// This list all the methods that are available in this class
-pub trait Control<T>
-    where
-        T: Debug,
-{
-    fn new(pool_size: usize) -> Self;
-    fn optimised(thread_utilisation_percentage: f64) -> Self;
-    fn default() -> Self;
-    fn enqueue(&mut self, task: T);
-    fn embed_message(
-        &mut self,
-        qdrant_conn: Arc<RwLock<QdrantClient>>,
-        mongo_conn: Arc<RwLock<Database>>,
-        message: String,
-    ) -> bool;
+impl QueueConfig {
+    pub async fn from_global_data() -> Self {
+        let global_data = GLOBAL_DATA.read().await;
+        // embedding is the CPU heavy stage when models run locally, by default it gets the share of
+        // the cores the proxy is configured to use
+        let threads = available_parallelism().map(|t| t.get()).unwrap_or(1);
+        let default_embed_workers = (threads as f64 * global_data.thread_percentage_utilisation) as usize;
+        QueueConfig {
+            capacity: global_data.embedding_queue_capacity.max(1),
+            extract_workers: global_data.extract_workers.max(1),
+            embed_workers: global_data.embed_workers.unwrap_or(default_embed_workers).max(1),
+            upsert_workers: global_data.upsert_workers.max(1),
+        }
+    }
 }
 
-// This defines implementations of each of the methods in the class
-// This implementation is generic for all types that are both Send and Clone
-// T must be Send in order to be sent safely across threads
-impl<T: Clone + Send> Control<T> for MyQueue<T>
-    where
-        T: Debug,
-        String: From<T>,
-{
-    // This is similar to the __init__ method in python. That instantiates an instance of the class
-    fn new(pool_size: usize) -> Self {
-        //Here, Self is used to mean "the type that this trait is implemented for."
-        MyQueue {
-            q: Queue::new(),
-            pool: ThreadPool::new(pool_size), // need to make this a dynamic number
+/// A record on its way through the stages along with where to send its outcome
+struct Job<T> {
+    item: T,
+    outcome: OutcomeSender,
+}
+
+impl<T> Job<T> {
+    fn finish(self, outcome: Outcome) {
+        self.outcome.send(outcome);
+    }
+}
+
+/// Where the outcome of a record is sent once it has been processed. A record dropped while the
+/// stage working on it panics is rejected, as it would most likely panic again if it were retried.
+struct OutcomeSender {
+    sender: Option<oneshot::Sender<Outcome>>,
+    outcomes: Arc<OutcomeMetrics>,
+}
+
+impl OutcomeSender {
+    fn send(mut self, outcome: Outcome) {
+        if let Some(sender) = self.sender.take() {
+            self.outcomes.count(&outcome);
+            let _ = sender.send(outcome);
         }
     }
+}
 
-    fn optimised(thread_utilisation_percentage: f64) -> Self {
-        match available_parallelism() {
-            Ok(t) => {
-                println!("Threads Available: {} ", t.get());
-                let threads_utilised = (t.get() as f64 * thread_utilisation_percentage) as usize;
-                println!("Threads used: {}", threads_utilised);
-                MyQueue {
-                    q: Queue::new(),
-                    pool: ThreadPool::new(threads_utilised),
+impl Drop for OutcomeSender {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        if let Some(sender) = self.sender.take() {
+            let outcome = Outcome::Reject("Processing the record stopped unexpectedly".to_string());
+            self.outcomes.count(&outcome);
+            let _ = sender.send(outcome);
+        }
+    }
+}
+
+struct RecordMessage {
+    datasource_id: String,
+    stream: String,
+    message: String,
+}
+
+/// Counts of a stage's workers and the records they have processed
+struct StageMetrics {
+    workers: usize,
+    in_progress: AtomicUsize,
+    processed: AtomicU64,
+}
+
+impl StageMetrics {
+    fn new(workers: usize) -> Arc<Self> {
+        Arc::new(StageMetrics {
+            workers,
+            in_progress: AtomicUsize::new(0),
+            processed: AtomicU64::new(0),
+        })
+    }
+
+    fn report<T>(&self, queue: &mpsc::Sender<Job<T>>) -> Value {
+        json!({
+            "queued": queue.max_capacity() - queue.capacity(),
+            "capacity": queue.max_capacity(),
+            "workers": self.workers,
+            "in_progress": self.in_progress.load(Ordering::Relaxed),
+            "processed": self.processed.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Counts of how records have ended
+#[derive(Default)]
+struct OutcomeMetrics {
+    done: AtomicU64,
+    retry: AtomicU64,
+    reject: AtomicU64,
+}
+
+pub struct EmbeddingQueue {
+    extract: mpsc::Sender<Job<RecordMessage>>,
+    embed: mpsc::Sender<Job<PreparedRecord>>,
+    upsert: mpsc::Sender<Job<EmbeddedRecord>>,
+    extract_metrics: Arc<StageMetrics>,
+    embed_metrics: Arc<StageMetrics>,
+    upsert_metrics: Arc<StageMetrics>,
+    outcomes: Arc<OutcomeMetrics>,
+}
+
+impl EmbeddingQueue {
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `qdrant_conn`: The Qdrant client records are uploaded with
+    /// * `mongo_conn`: The Mongo database datasources are looked up in
+    /// * `config`: How many workers each stage has and how many records each stage's queue holds
+    ///
+    /// Starts the workers of every stage. They run for as long as the queue exists.
+    ///
+    /// returns: EmbeddingQueue
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub fn start(
+        qdrant_conn: Arc<RwLock<QdrantClient>>,
+        mongo_conn: Arc<RwLock<Database>>,
+        config: QueueConfig,
+    ) -> Self {
+        println!("Starting embedding queue: {:?}", config);
+        let (extract, extract_rx) = mpsc::channel::<Job<RecordMessage>>(config.capacity);
+        let (embed, embed_rx) = mpsc::channel::<Job<PreparedRecord>>(config.capacity);
+        let (upsert, upsert_rx) = mpsc::channel::<Job<EmbeddedRecord>>(config.capacity);
+        let outcomes = Arc::new(OutcomeMetrics::default());
+        let extract_metrics = StageMetrics::new(config.extract_workers);
+        let embed_metrics = StageMetrics::new(config.embed_workers);
+        let upsert_metrics = StageMetrics::new(config.upsert_workers);
+
+        let (next, qdrant, mongo) = (embed.clone(), Arc::clone(&qdrant_conn), mongo_conn);
+        spawn_workers(Arc::clone(&extract_metrics), extract_rx, move |job: Job<RecordMessage>| {
+            let (next, qdrant, mongo) = (next.clone(), Arc::clone(&qdrant), Arc::clone(&mongo));
+            async move {
+                let Job { item, outcome } = job;
+                match prepare_record(qdrant, mongo, item.message, item.datasource_id, item.stream).await {
+                    Ok(prepared) => forward(&next, Job { item: prepared, outcome }).await,
+                    Err(result) => outcome.send(result),
                 }
             }
-            Err(_) => MyQueue {
-                q: Queue::new(),
-                pool: ThreadPool::new(1),
-            },
+        });
+
+        let next = upsert.clone();
+        spawn_workers(Arc::clone(&embed_metrics), embed_rx, move |job: Job<PreparedRecord>| {
+            let next = next.clone();
+            async move {
+                let Job { item, outcome } = job;
+                match embed_record(item).await {
+                    Ok(embedded) => forward(&next, Job { item: embedded, outcome }).await,
+                    Err(result) => outcome.send(result),
+                }
+            }
+        });
+
+        spawn_workers(Arc::clone(&upsert_metrics), upsert_rx, move |job: Job<EmbeddedRecord>| {
+            let qdrant = Arc::clone(&qdrant_conn);
+            async move {
+                let Job { item, outcome } = job;
+                let result = upsert_record(qdrant, item).await;
+                outcome.send(result);
+            }
+        });
+
+        EmbeddingQueue {
+            extract,
+            embed,
+            upsert,
+            extract_metrics,
+            embed_metrics,
+            upsert_metrics,
+            outcomes,
         }
     }
 
-    fn default() -> Self {
-        match available_parallelism() {
-            Ok(t) => MyQueue {
-                q: Queue::new(),
-                pool: ThreadPool::new(t.get()),
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `datasource_id`: The datasource the record was synced to
+    /// * `stream`: The Airbyte stream the record came from
+    /// * `message`: The record as JSON
+    ///
+    /// Waits for room on the extract queue if it is full.
+    ///
+    /// returns: Receiver<Outcome> Resolves once the record has been processed
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn enqueue(&self, datasource_id: String, stream: String, message: String) -> oneshot::Receiver<Outcome> {
+        let (sender, receiver) = oneshot::channel();
+        let job = Job {
+            item: RecordMessage {
+                datasource_id,
+                stream,
+                message,
             },
-            Err(_) => MyQueue {
-                q: Queue::new(),
-                pool: ThreadPool::new(1),
+            outcome: OutcomeSender {
+                sender: Some(sender),
+                outcomes: Arc::clone(&self.outcomes),
             },
-        }
+        };
+        forward(&self.extract, job).await;
+        receiver
     }
 
-    fn enqueue(&mut self, task: T) {
-        match self.q.add(task) {
-            Ok(_) => {}
-            Err(_) => println!("Could not add task to queue"),
+    /// Queue depths, workers and throughput of every stage
+    pub fn metrics(&self) -> Value {
+        json!({
+            "stages": {
+                "extract": self.extract_metrics.report(&self.extract),
+                "embed": self.embed_metrics.report(&self.embed),
+                "upsert": self.upsert_metrics.report(&self.upsert),
+            },
+            "outcomes": {
+                "done": self.outcomes.done.load(Ordering::Relaxed),
+                "retry": self.outcomes.retry.load(Ordering::Relaxed),
+                "reject": self.outcomes.reject.load(Ordering::Relaxed),
+            },
+        })
+    }
+}
+
+impl OutcomeMetrics {
+    fn count(&self, outcome: &Outcome) {
+        let counter = match outcome {
+            Outcome::Done => &self.done,
+            Outcome::Retry(_) => &self.retry,
+            Outcome::Reject(_) => &self.reject,
         };
+        counter.fetch_add(1, Ordering::Relaxed);
     }
+}
 
-    /// The reason this works is that you're cloning the Arc references before they're moved into the closure, thereby satisfying Rust's lifetime requirements.
-    /// The closure now has ownership of the cloned Arcs, which guarantees their existence for the entire lifetime of the closure.
-    /// The original Arcs are still owned by the app_data object and will be dropped when app_data goes out of scope, but this won't affect the cloned Arcs.
+/// Hands a job to a stage, waiting for room on its queue
+async fn forward<T>(queue: &mpsc::Sender<Job<T>>, job: Job<T>) {
+    if let Err(mpsc::error::SendError(job)) = queue.send(job).await {
+        job.finish(Outcome::Retry("The embedding queue has stopped".to_string()));
+    }
+}
 
-    fn embed_message(
-        &mut self,
-        qdrant_conn: Arc<RwLock<QdrantClient>>,
-        mongo_conn: Arc<RwLock<Database>>,
-        message: String,
-    ) -> bool {
-        while self.q.size() > 0 {
-            let task = match self.q.remove() {
-                Ok(t) => t,
-                _ => continue, //Because ! (continue) can never have a value, Rust decides that the type of guess is u32.
-            };
-            // We try and coerce T into String type, if it can't we handle the error
-            let id = match String::try_from(task) {
-                Ok(i) => i,
-                Err(_) => continue,
-            };
-            let data = message.clone();
-            let qdrant_client = Arc::clone(&qdrant_conn);
-            let mongo_client = Arc::clone(&mongo_conn);
-            self.pool.execute(move || {
-                let rt = tokio::runtime::Runtime::new().unwrap();
-                rt.block_on(async {
-                    process_messages(qdrant_client, mongo_client, data, id).await;
-                })
+/// Counts a job as in progress for as long as it exists, including when its task panics
+struct InProgress(Arc<StageMetrics>);
+
+impl InProgress {
+    fn start(metrics: &Arc<StageMetrics>) -> Self {
+        metrics.in_progress.fetch_add(1, Ordering::Relaxed);
+        InProgress(Arc::clone(metrics))
+    }
+}
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        self.0.in_progress.fetch_sub(1, Ordering::Relaxed);
+        self.0.processed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The workers of a stage take turns to take the next job off the stage's queue. Each job runs as
+/// a task of its own which the worker waits for, so a job that panics only takes its task down.
+fn spawn_workers<T, F, Fut>(metrics: Arc<StageMetrics>, queue: mpsc::Receiver<Job<T>>, work: F)
+    where
+        T: Send + 'static,
+        F: Fn(Job<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output=()> + Send + 'static,
+{
+    let queue = Arc::new(Mutex::new(queue));
+    let work = Arc::new(work);
+    for _ in 0..metrics.workers {
+        let (queue, work, metrics) = (Arc::clone(&queue), Arc::clone(&work), Arc::clone(&metrics));
+        tokio::spawn(async move {
+            loop {
+                let job = queue.lock().await.recv().await;
+                let Some(job) = job else {
+                    break;
+                };
+                let in_progress = InProgress::start(&metrics);
+                let job = work(job);
+                let task = tokio::spawn(async move {
+                    let _in_progress = in_progress;
+                    job.await;
+                });
+                if let Err(e) = task.await {
+                    println!("A job of the embedding queue stopped unexpectedly. Error: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_panicking_job_is_rejected_without_losing_its_worker() {
+        // on a single thread a job's task has finished by the time its outcome is received
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let outcomes = Arc::new(OutcomeMetrics::default());
+            let metrics = StageMetrics::new(1);
+            let (queue, queue_rx) = mpsc::channel::<Job<bool>>(2);
+            spawn_workers(Arc::clone(&metrics), queue_rx, |job: Job<bool>| async move {
+                if job.item {
+                    panic!("the record could not be processed");
+                }
+                job.finish(Outcome::Done);
             });
-        }
-        true
+            let mut receivers = vec![];
+            for item in [true, false] {
+                let (sender, receiver) = oneshot::channel();
+                let outcome = OutcomeSender {
+                    sender: Some(sender),
+                    outcomes: Arc::clone(&outcomes),
+                };
+                forward(&queue, Job { item, outcome }).await;
+                receivers.push(receiver);
+            }
+            assert!(matches!(receivers.remove(0).await, Ok(Outcome::Reject(_))));
+            assert!(matches!(receivers.remove(0).await, Ok(Outcome::Done)));
+            assert_eq!(metrics.in_progress.load(Ordering::Relaxed), 0);
+            assert_eq!(metrics.processed.load(Ordering::Relaxed), 2);
+            assert_eq!(outcomes.reject.load(Ordering::Relaxed), 1);
+        });
     }
 }
@@ -1,15 +1,33 @@
 use crate::rabbitmq::models::RabbitConnect;
 use amqp_serde::types::{FieldTable, FieldValue, ShortStr};
+use amqprs::callbacks::{ChannelCallback, ConnectionCallback};
 use amqprs::channel::{Channel, ExchangeDeclareArguments};
+use amqprs::error::Error as AmqpError;
 use amqprs::{
-    callbacks::{DefaultChannelCallback, DefaultConnectionCallback},
     channel::{BasicQosArguments, QueueBindArguments, QueueDeclareArguments},
     connection::{Connection, OpenConnectionArguments},
+    Ack, BasicProperties, Cancel, Close, CloseChannel, Nack, Return,
 };
-use tokio::time::{sleep, Duration};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::sync::mpsc::UnboundedSender;
 
-pub async fn connect_rabbitmq(connection_details: &RabbitConnect) -> Connection {
-    let mut res = Connection::open(
+///
+///
+/// # Arguments
+///
+/// * `connection_details`: Where the broker is and how to log in
+/// * `closed`: Where to send the reason when the broker closes the connection or one of its channels
+///
+/// returns: Result<Connection, Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn connect_rabbitmq(connection_details: &RabbitConnect, closed: &UnboundedSender<String>) -> Result<Connection> {
+    let connection = Connection::open(
         OpenConnectionArguments::new(
             &connection_details.host,
             connection_details.port,
@@ -18,67 +36,120 @@ pub async fn connect_rabbitmq(connection_details: &RabbitConnect) -> Connection
         )
             .virtual_host("/"),
     )
-        .await;
-
-    while res.is_err() {
-        println!("trying to connect after error");
-        sleep(Duration::from_millis(2000)).await;
-        res = Connection::open(&OpenConnectionArguments::new(
-            &connection_details.host,
-            connection_details.port,
-            &connection_details.username,
-            &connection_details.password,
-        ))
-            .await;
-    }
-
-    let connection = res.unwrap();
-    connection
-        .register_callback(DefaultConnectionCallback)
         .await
-        .unwrap();
+        .map_err(|e| anyhow!("Could not connect to rabbit. Error: {}", e))?;
     connection
+        .register_callback(ClosedCallback { closed: closed.clone() })
+        .await
+        .map_err(|e| anyhow!("Could not register the connection callback. Error: {}", e))?;
+    Ok(connection)
 }
 
-pub async fn channel_rabbitmq(connection: &Connection) -> Channel {
-    let channel = connection.open_channel(None).await.unwrap();
-    channel
-        .register_callback(DefaultChannelCallback)
+pub async fn channel_rabbitmq(connection: &Connection, closed: &UnboundedSender<String>) -> Result<Channel> {
+    let channel = connection
+        .open_channel(None)
         .await
-        .unwrap();
+        .map_err(|e| anyhow!("Could not open a channel. Error: {}", e))?;
     channel
+        .register_callback(ClosedCallback { closed: closed.clone() })
+        .await
+        .map_err(|e| anyhow!("Could not register the channel callback. Error: {}", e))?;
+    Ok(channel)
+}
+
+/// Reports the broker closing the connection or a channel, so that the consumer can reconnect
+struct ClosedCallback {
+    closed: UnboundedSender<String>,
+}
+
+#[async_trait]
+impl ConnectionCallback for ClosedCallback {
+    async fn close(&mut self, _connection: &Connection, close: Close) -> std::result::Result<(), AmqpError> {
+        let _ = self.closed.send(format!("The broker closed the connection: {}", close));
+        Ok(())
+    }
+
+    async fn blocked(&mut self, _connection: &Connection, reason: String) {
+        println!("The broker blocked the connection: {}", reason);
+    }
+
+    async fn unblocked(&mut self, _connection: &Connection) {
+        println!("The broker unblocked the connection");
+    }
 }
 
+#[async_trait]
+impl ChannelCallback for ClosedCallback {
+    async fn close(&mut self, _channel: &Channel, close: CloseChannel) -> std::result::Result<(), AmqpError> {
+        let _ = self.closed.send(format!("The broker closed the channel: {}", close));
+        Ok(())
+    }
+
+    async fn cancel(&mut self, _channel: &Channel, cancel: Cancel) -> std::result::Result<(), AmqpError> {
+        let _ = self.closed.send(format!("The broker cancelled consumer {}", cancel.consumer_tag()));
+        Ok(())
+    }
+
+    async fn flow(&mut self, _channel: &Channel, active: bool) -> std::result::Result<bool, AmqpError> {
+        Ok(active)
+    }
+
+    async fn publish_ack(&mut self, _channel: &Channel, _ack: Ack) {}
+
+    async fn publish_nack(&mut self, _channel: &Channel, _nack: Nack) {}
+
+    async fn publish_return(
+        &mut self,
+        _channel: &Channel,
+        ret: Return,
+        _basic_properties: BasicProperties,
+        _content: Vec<u8>,
+    ) {
+        println!("The broker returned a published message: {}", ret);
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `channel`: The channel to declare the exchange and stream on
+/// * `exchange`: The exchange messages are published to
+/// * `queue`: The stream messages are consumed from
+/// * `routing_key`: The routing key the stream is bound with
+/// * `prefetch_count`: How many messages can be delivered to the consumer before it acks them
+///
+/// Safe to call on every connection, declaring what already exists with the same settings does
+/// nothing.
+///
+/// returns: Result<(), Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
 pub async fn bind_queue_to_exchange(
-    connection: &mut Connection,
-    channel: &mut Channel,
-    connection_details: &RabbitConnect,
+    channel: &Channel,
     exchange: &str,
     queue: &str,
     routing_key: &str,
-) {
-    if !connection.is_open() {
-        println!("Connection not open");
-        *connection = connect_rabbitmq(connection_details).await;
-        *channel = channel_rabbitmq(connection).await;
-        println!("{}", connection);
-    }
+    prefetch_count: u16,
+) -> Result<()> {
     // Declaring the exchange on startup
     channel
         .exchange_declare(ExchangeDeclareArguments::new(exchange, "direct"))
         .await
-        .unwrap();
+        .map_err(|e| anyhow!("An error occurred while declaring exchange {}: {}", exchange, e))?;
     // Setting up basic quality-of-service parameters for the channel to enable streaming queue
-    match channel
+    channel
         .basic_qos(BasicQosArguments {
-            prefetch_count: 10000,
+            prefetch_count,
             prefetch_size: 0,
             global: false,
         })
-        .await {
-        Ok(_) => {}
-        Err(e) => { println!("An error occurred while setting up the channel:{}", e) }
-    }
+        .await
+        .map_err(|e| anyhow!("An error occurred while setting up the channel:{}", e))?;
     // adding queue type as custom arguments to the queue declaration
     let mut args: FieldTable = FieldTable::new();
     let queue_type_x: ShortStr = "x-queue-type".try_into().unwrap();
@@ -94,29 +165,47 @@ pub async fn bind_queue_to_exchange(
                 .finish(),
         )
         .await {
-        Ok(queue_option) => {
-            match queue_option {
-                Some((queue, _, _)) => {
-                    //check if the channel is open, if not then open it
-                    if !channel.is_open() {
-                        println!(
-                            "Channel is not open, does exchange {} exist on rabbitMQ?",
-                            exchange
-                        );
-                        *channel = channel_rabbitmq(connection).await;
-                    }
-
-                    // bind the queue to the exchange using this channel
-                    channel
-                        .queue_bind(QueueBindArguments::new(&queue, exchange, routing_key))
-                        .await
-                        .unwrap();
-                }
-                None => {}
-            }
-        }
-        Err(e) => {
-            println!("An error occurred while setting up the queue: {}", e)
+        Ok(Some((queue, _, _))) => {
+            // bind the queue to the exchange using this channel
+            channel
+                .queue_bind(QueueBindArguments::new(&queue, exchange, routing_key))
+                .await
+                .map_err(|e| anyhow!("An error occurred while binding the queue: {}", e))
         }
+        Ok(None) => Ok(()),
+        Err(e) => Err(anyhow!("An error occurred while setting up the queue: {}", e)),
     }
 }
+
+///
+///
+/// # Arguments
+///
+/// * `channel`: The channel to declare the exchange and queue on
+/// * `exchange`: The dead-letter exchange failed messages are published to
+/// * `queue`: The queue that holds dead-lettered messages until they are replayed
+///
+/// The queue is a durable classic queue rather than a stream so that replayed messages can be
+/// taken off it one at a time.
+///
+/// returns: Result<(), Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn declare_dead_letter_queue(channel: &Channel, exchange: &str, queue: &str) -> Result<()> {
+    channel
+        .exchange_declare(ExchangeDeclareArguments::new(exchange, "fanout").durable(true).finish())
+        .await
+        .map_err(|e| anyhow!("An error occurred while declaring the dead-letter exchange: {}", e))?;
+    channel
+        .queue_declare(QueueDeclareArguments::durable_client_named(queue))
+        .await
+        .map_err(|e| anyhow!("An error occurred while declaring the dead-letter queue: {}", e))?;
+    channel
+        .queue_bind(QueueBindArguments::new(queue, exchange, ""))
+        .await
+        .map_err(|e| anyhow!("An error occurred while binding the dead-letter queue: {}", e))
+}
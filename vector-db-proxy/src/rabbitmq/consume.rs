@@ -1,144 +1,740 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 
 use amqp_serde::types::ShortStr;
-use amqprs::channel::{BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, Channel};
+use amqprs::channel::{BasicCancelArguments, Channel};
 use mongodb::Database;
 use qdrant_client::client::QdrantClient;
 use qdrant_client::prelude::PointStruct;
 use serde_json::Value;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::task;
+use url::Url;
 
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::data::archives::{expand_archive, ArchiveLimits};
+use crate::data::chunking::{Chunking, EmbeddedChunks, TextChunker};
+use crate::data::jobs::{JobTracker, RecordBatches, UnfinishedJob};
+use crate::data::models::FileType;
+use crate::data::parts::{extract_parts, supports_parts, PART_BYTES};
+use crate::data::recursive_splitting::ChunkSize;
 use crate::data::utils::{apply_chunking_strategy_to_document, extract_text_from_file};
-use crate::llm::models::EmbeddingModels;
-use crate::mongo::{models::ChunkingStrategy, queries::get_embedding_model};
+use crate::llm::providers::{get_embedding_provider, EmbeddingProviders};
+use crate::mongo::models::{ChunkingStrategy, DataSources, JobKind, Model};
+use crate::mongo::queries::get_embedding_model;
 use crate::mongo::queries::get_datasource;
+use crate::qdrant::dedup::{metadata_hash, StoredDocument, CONTENT_HASH_KEY, DOCUMENT_ID_KEY, METADATA_HASH_KEY};
 use crate::qdrant::{helpers::construct_point_struct, utils::Qdrant};
 use crate::queue::add_tasks_to_queues::add_message_to_embedding_queue;
-use crate::queue::queuing::MyQueue;
+use crate::queue::queuing::EmbeddingQueue;
+use crate::rabbitmq::delivery::{settle, with_retries, Delivery, Outcome, RetryPolicy};
+use crate::rabbitmq::offsets::{stream_offset, OffsetStore, OffsetTracker, StartFrom};
 use crate::utils::file_operations;
-use crate::utils::file_operations::save_file_to_disk;
-use crate::utils::webhook::send_webapp_embed_ready;
+use crate::utils::file_operations::{determine_file_type, FileRefused};
+use crate::crawl::crawler::{CrawlOptions, Crawler};
+use crate::utils::webhook::{send_webapp_embed_failed, send_webapp_embed_ready};
 
+///
+///
+/// # Arguments
+///
+/// * `qdrant_clone`: The Qdrant client
+/// * `queue`: The queue Airbyte records are embedded on
+/// * `mongo_client`: The Mongo database
+/// * `channel`: The channel to consume on
+/// * `queue_name`: The stream to consume
+/// * `replays`: Where to consume the stream from instead, sent by the replay endpoint
+///
+/// Keeps a single consumer on the stream, starting after the last offset processed before the
+/// proxy last stopped. When a replay is requested the consumer is replaced with one reading from
+/// the requested place in the stream.
+///
+/// returns: ()
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
 pub async fn subscribe_to_queue(
     // redis_connection_pool: Arc<Mutex<RedisConnection>>,
     qdrant_clone: Arc<RwLock<QdrantClient>>,
-    queue: Arc<RwLock<MyQueue<String>>>,
+    queue: Arc<EmbeddingQueue>,
     mongo_client: Arc<RwLock<Database>>,
     channel: &Channel,
     queue_name: &String,
+    replays: &mut mpsc::Receiver<StartFrom>,
 ) {
-    let mongodb_connection = mongo_client.read().await;
-    let args = BasicConsumeArguments::new(queue_name, "");
-    match channel.basic_consume_rx(args.clone()).await {
-        Ok((ctag, mut messages_rx)) => {
-            loop {
-                while let Some(message) = messages_rx.recv().await {
-                    let args = BasicAckArguments::new(message.deliver.unwrap().delivery_tag(), false);
-                    let _ = channel.basic_ack(args).await;
-                    let headers = message.basic_properties.unwrap().headers().unwrap().clone();
-                    if let Some(stream) = headers.get(&ShortStr::try_from("stream").unwrap()) {
-                        let stream_string: String = stream.to_string();
-                        let stream_split: Vec<&str> = stream_string.split('_').collect();
-                        let datasource_id = stream_split.to_vec()[0];
-                        if let Some(msg) = message.content {
-                            // if the header 'type' is present then assume that it is a file upload. pull from gcs
-                            if let Ok(message_string) = String::from_utf8(msg.clone().to_vec()) {
-                                match get_datasource(&mongodb_connection, datasource_id).await {
-                                    Ok(datasource) => {
-                                        if let Some(ds) = datasource {
-                                            if let Ok(Some(model_parameters)) = get_embedding_model(&mongodb_connection, datasource_id).await {
-                                                if headers.get(&ShortStr::try_from("type").unwrap()).is_some() {
-                                                    if let Ok(_json) = serde_json::from_str(message_string.as_str()) {
-                                                        let message_data: Value = _json; // this is necessary because  you can not do type annotation inside a if let Ok() expression
-                                                        match file_operations::read_file_from_source(headers, message_data).await {
-                                                            Some((file_type, file, file_path)) => {
-                                                                save_file_to_disk(file, file_path.as_str()).await.unwrap();
-                                                                let message_queue = Arc::clone(&queue);
-                                                                let qdrant_conn = Arc::clone(&qdrant_clone);
-                                                                let mongo_conn = Arc::clone(&mongo_client);
-                                                                // let redis_conn = Arc::clone(&redis_connection_pool);
-                                                                let datasource_clone = ds.clone();
-                                                                let (document_text, metadata) =
-                                                                    extract_text_from_file(file_type, file_path.as_str(), ds.originalName, datasource_id.to_string(), message_queue, qdrant_conn, mongo_conn).await.unwrap();
-                                                                // dynamically get user's chunking strategy of choice from the database
-                                                                let model_obj_clone = model_parameters.clone();
-                                                                let model_name = model_obj_clone.model;
-                                                                let chunking_character = datasource_clone.chunkCharacter;
-                                                                let chunking_method = datasource_clone.chunkStrategy.unwrap();
-                                                                let chunking_strategy = ChunkingStrategy::from(chunking_method);
-                                                                let mongo_conn_clone = Arc::clone(&mongo_client);
-                                                                match apply_chunking_strategy_to_document(document_text, metadata, chunking_strategy, chunking_character, Some(model_parameters.model), mongo_conn_clone, datasource_id.to_string())
-                                                                    .await {
-                                                                    Ok(chunks) => {
-                                                                        let mut points_to_upload: Vec<PointStruct> = vec![];
-                                                                        for element in chunks.iter() {
-                                                                            let embedding_vector =
-                                                                                &element.embedding_vector;
-                                                                            match embedding_vector {
-                                                                                Some(val) => {
-                                                                                    let model = EmbeddingModels::from(model_name.clone());
-                                                                                    if let Some(point_struct) = construct_point_struct(val, element.metadata.clone().unwrap(), Some(model)).await {
-                                                                                        points_to_upload.push(point_struct)
-                                                                                    }
-                                                                                }
-                                                                                None => {
-                                                                                    println!("Embedding vector was empty!")
-                                                                                }
-                                                                            }
-                                                                        }
-                                                                        let vector_length = model_parameters.embeddingLength as u64;
-                                                                        let qdrant_conn_clone = Arc::clone(&qdrant_clone);
-                                                                        let qdrant = Qdrant::new(qdrant_conn_clone, datasource_id.to_string());
-                                                                        match qdrant.bulk_upsert_data(points_to_upload, Some(vector_length), Some(model_name)).await {
-                                                                            Ok(_) => {
-                                                                                println!("points uploaded successfully!");
-                                                                                if let Err(e) = send_webapp_embed_ready(&datasource_id).await {
-                                                                                    println!("Error notifying webapp: {}", e);
-                                                                                } else {
-                                                                                    println!("Webapp notified successfully!");
-                                                                                }
-                                                                            }
-                                                                            Err(e) => {
-                                                                                println!("An error occurred while attempting upload to qdrant. Error: {:?}", e);
-                                                                            }
-                                                                        }
-                                                                    }
-                                                                    Err(e) => println!("Error: {}", e),
-                                                                }
-                                                            }
-                                                            None => {
-                                                                println!("Could not read file from source...source returned NONE!")
-                                                            }
-                                                        }
-                                                    }
-                                                } else {
-                                                    // This is where data is coming from airbyte rather than a direct file upload
-                                                    let message_queue = Arc::clone(&queue);
-                                                    let qdrant_conn = Arc::clone(&qdrant_clone);
-                                                    let mongo_conn = Arc::clone(&mongo_client);
-                                                    let _ = add_message_to_embedding_queue(message_queue, qdrant_conn, mongo_conn, (datasource_id.to_string(), message_string)).await;
-                                                }
-                                            }
-                                        } else {
-                                            eprintln!(
-                                                "There was no embedding model associated with datasource: {}",
-                                                datasource_id
-                                            )
-                                        }
-                                    }
-                                    Err(e) => { println!("Could not find associated datasource: {}", e) }
+    let retry_policy = RetryPolicy::from_global_data().await;
+    let store_every = GLOBAL_DATA.read().await.stream_offset_store_interval;
+    let offsets = OffsetStore::new(Arc::clone(&mongo_client), queue_name);
+    let batches = Arc::new(RecordBatches::new(Arc::clone(&mongo_client)).await);
+    let mut start = offsets.start_from().await;
+    loop {
+        let args = start.consume_arguments(queue_name);
+        let (ctag, mut messages_rx) = match channel.basic_consume_rx(args).await {
+            Ok(consumer) => consumer,
+            Err(e) => {
+                println!("Error consuming message from rabbit: {}", e);
+                return;
+            }
+        };
+        println!("Consuming stream {} from {}", queue_name, start);
+        let tracker = Arc::new(Mutex::new(OffsetTracker::new(store_every)));
+        let replay_from = loop {
+            tokio::select! {
+                message = messages_rx.recv() => match message {
+                    Some(message) => {
+                        let (Some(deliver), Some(properties), Some(content)) =
+                            (message.deliver, message.basic_properties, message.content)
+                        else {
+                            println!("Received an incomplete message from rabbit");
+                            continue;
+                        };
+                        // messages are only acked once they have been processed, see `settle`
+                        let delivery = Delivery {
+                            delivery_tag: deliver.delivery_tag(),
+                            exchange: deliver.exchange().to_string(),
+                            routing_key: deliver.routing_key().to_string(),
+                            properties,
+                            content,
+                        };
+                        let offset = stream_offset(&delivery.headers());
+                        if let Some(offset) = offset {
+                            tracker.lock().await.delivered(offset);
+                        }
+                        let handler = MessageHandler {
+                            qdrant_conn: Arc::clone(&qdrant_clone),
+                            queue: Arc::clone(&queue),
+                            mongo_conn: Arc::clone(&mongo_client),
+                            channel: channel.clone(),
+                            retry_policy,
+                            tracker: Arc::clone(&tracker),
+                            offsets: offsets.clone(),
+                            batches: Arc::clone(&batches),
+                        };
+                        handler.handle(delivery, offset).await;
+                    }
+                    None => {
+                        println!("The consumer of stream {} was closed", queue_name);
+                        // the next consumer starts from the stored offset, messages of this one
+                        // still being processed must not move it
+                        tracker.lock().await.retire();
+                        return;
+                    }
+                },
+                Some(replay_from) = replays.recv() => break replay_from,
+            }
+        };
+        // messages of the old consumer still being processed must not move the stored offset
+        tracker.lock().await.retire();
+        if let Err(e) = channel.basic_cancel(BasicCancelArguments::new(&ctag)).await {
+            println!("error {}", e);
+        };
+        println!("Replaying stream {} from {}", queue_name, replay_from);
+        start = replay_from;
+    }
+}
+
+/// Everything needed to process a message and settle it once it has been
+struct MessageHandler {
+    qdrant_conn: Arc<RwLock<QdrantClient>>,
+    queue: Arc<EmbeddingQueue>,
+    mongo_conn: Arc<RwLock<Database>>,
+    channel: Channel,
+    retry_policy: RetryPolicy,
+    tracker: Arc<Mutex<OffsetTracker>>,
+    offsets: OffsetStore,
+    batches: Arc<RecordBatches>,
+}
+
+impl MessageHandler {
+    /// File messages are processed before the next message is taken off the stream, Airbyte
+    /// records are processed on the queue while the consumer moves on
+    async fn handle(self, delivery: Delivery, offset: Option<i64>) {
+        let headers = delivery.headers();
+        let Some(stream) = headers.get(&ShortStr::try_from("stream").unwrap()) else {
+            let reason = "There was no stream_id in message... can not upload data!".to_string();
+            self.settle(&delivery, offset, 1, Outcome::Reject(reason)).await;
+            return;
+        };
+        let stream_string: String = stream.to_string();
+        let stream_split: Vec<&str> = stream_string.split('_').collect();
+        let datasource_id = stream_split.to_vec()[0].to_string();
+        // the name of the Airbyte stream follows the datasource ID and may itself contain underscores
+        let stream_name = stream_string.split_once('_').map(|(_, name)| name.to_string()).unwrap_or_default();
+        // if the header 'type' is present then assume that it is a file upload. pull from gcs
+        if headers.get(&ShortStr::try_from("type").unwrap()).is_some() {
+            let job = JobTracker::create(Arc::clone(&self.mongo_conn), datasource_id.as_str(), JobKind::File, offset).await;
+            println!("Ingesting file message as job {}", job.id());
+            // the supervisor drops the consumer, and this with it, when the connection to rabbit is lost
+            let unfinished = UnfinishedJob::new(job.clone());
+            let attempt = AtomicU32::new(0);
+            let (outcome, attempts) = with_retries(&self.retry_policy, || {
+                let attempt = attempt.fetch_add(1, Ordering::Relaxed) + 1;
+                self.attempt_file(&job, attempt, datasource_id.as_str(), &delivery)
+            })
+                .await;
+            unfinished.finish(&outcome).await;
+            file_operations::remove_local_file(&headers, &delivery.content);
+            match &outcome {
+                Outcome::Done => notify_embed_ready(datasource_id.as_str()).await,
+                Outcome::Retry(reason) | Outcome::Reject(reason) => {
+                    notify_embed_failed(datasource_id.as_str(), reason.clone()).await
+                }
+            }
+            self.settle(&delivery, offset, attempts, outcome).await;
+        } else {
+            // This is where data is coming from airbyte rather than a direct file upload
+            self.batches.join(datasource_id.as_str()).await;
+            tokio::spawn(async move {
+                let (outcome, attempts) = with_retries(&self.retry_policy, || {
+                    embed_record(&self.queue, datasource_id.as_str(), stream_name.as_str(), &delivery)
+                })
+                    .await;
+                self.batches.settled(datasource_id.as_str(), &outcome).await;
+                self.settle(&delivery, offset, attempts, outcome).await;
+            });
+        }
+    }
+
+    /// Attempts to ingest a file once, recording the attempt on its job. The attempt runs as a task
+    /// of its own so that a panic while ingesting, such as in a parser given a malformed file,
+    /// rejects the message instead of bringing down the consumer. The task is aborted if the
+    /// consumer is dropped, so that it does not race the message being delivered again.
+    async fn attempt_file(&self, job: &JobTracker, attempt: u32, datasource_id: &str, delivery: &Delivery) -> Outcome {
+        job.attempt(attempt).await;
+        let (qdrant_conn, mongo_conn) = (Arc::clone(&self.qdrant_conn), Arc::clone(&self.mongo_conn));
+        let (task_job, datasource_id, delivery) = (job.clone(), datasource_id.to_string(), delivery.clone());
+        let attempt_task = tokio::spawn(async move {
+            handle_file_message(&qdrant_conn, &mongo_conn, datasource_id.as_str(), &delivery, &task_job).await
+        });
+        let _abort = AbortOnDrop(attempt_task.abort_handle());
+        let outcome = match attempt_task.await {
+            Ok(outcome) => outcome,
+            Err(e) => Outcome::Reject(format!("Ingesting the file stopped unexpectedly. Error: {}", e)),
+        };
+        match &outcome {
+            // the reason the last attempt failed is recorded once the job has finished
+            Outcome::Retry(reason) if attempt < self.retry_policy.max_attempts => job.retrying(reason).await,
+            _ => {}
+        }
+        outcome
+    }
+
+    async fn settle(&self, delivery: &Delivery, offset: Option<i64>, attempts: u32, outcome: Outcome) {
+        // a message that was neither acked nor dead-lettered stays in flight, so the stored offset
+        // never moves past it and it is processed again once the consumer restarts
+        if !settle(&self.channel, delivery, attempts, outcome).await {
+            return;
+        }
+        if let Some(offset) = offset {
+            // the lock is held while storing so that offsets are stored in order
+            let mut tracker = self.tracker.lock().await;
+            if let Some(committed) = tracker.settled(offset) {
+                self.offsets.store(committed).await;
+            }
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `qdrant_conn`: The Qdrant client
+/// * `mongo_conn`: The Mongo database
+/// * `datasource_id`: The datasource the file was uploaded to
+/// * `delivery`: The message, describing where to get the file from or what to crawl
+/// * `job`: The job the progress of the file is recorded on
+///
+/// returns: Outcome Whether the file was ingested, or why it was not
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+async fn handle_file_message(
+    qdrant_conn: &Arc<RwLock<QdrantClient>>,
+    mongo_conn: &Arc<RwLock<Database>>,
+    datasource_id: &str,
+    delivery: &Delivery,
+    job: &JobTracker,
+) -> Outcome {
+    let Ok(message_string) = String::from_utf8(delivery.content.clone()) else {
+        return Outcome::Reject("Message body is not valid UTF-8".to_string());
+    };
+    let message_data: Value = match serde_json::from_str(message_string.as_str()) {
+        Ok(message_data) => message_data,
+        Err(e) => return Outcome::Reject(format!("Message body is not valid JSON. Error: {}", e)),
+    };
+    let (ds, model_parameters) = {
+        let mongodb_connection = mongo_conn.read().await;
+        let ds = match get_datasource(&mongodb_connection, datasource_id).await {
+            Ok(Some(ds)) => ds,
+            Ok(None) => return Outcome::Reject(format!("Could not find associated datasource: {}", datasource_id)),
+            Err(e) => return Outcome::Retry(format!("Could not look up datasource {}. Error: {}", datasource_id, e)),
+        };
+        match get_embedding_model(&mongodb_connection, datasource_id).await {
+            Ok(Some(model_parameters)) => (ds, model_parameters),
+            Ok(None) => {
+                return Outcome::Reject(format!(
+                    "There was no embedding model associated with datasource: {}",
+                    datasource_id
+                ))
+            }
+            Err(e) => return Outcome::Retry(format!("Could not look up the embedding model. Error: {}", e)),
+        }
+    };
+    let ingestion = FileIngestion {
+        datasource_id,
+        datasource: &ds,
+        model_parameters: &model_parameters,
+        qdrant_conn: Arc::clone(qdrant_conn),
+        mongo_conn: Arc::clone(mongo_conn),
+        job,
+    };
+    let model_name = model_parameters.model.as_str();
+    let headers = delivery.headers();
+    if file_operations::is_crawl(&headers, &message_data) {
+        let url = message_data.get("url").and_then(|u| u.as_str());
+        job.describe(JobKind::Crawl, url, model_name).await;
+        return ingest_crawl(&ingestion, &message_data).await;
+    }
+    job.describe(JobKind::File, Some(ds.originalName.as_str()), model_name).await;
+    // the file is deleted when `source_file` goes out of scope, however ingestion ends
+    match file_operations::read_file_from_source(headers, message_data).await {
+        Ok(source_file) => match source_file.file_type {
+            FileType::UNKNOWN => Outcome::Reject(format!(
+                "Could not determine a supported file type for file: {}",
+                source_file.metadata["file_name"]
+            )),
+            FileType::ARCHIVE => {
+                job.describe(JobKind::Archive, Some(ds.originalName.as_str()), model_name).await;
+                ingest_archive(&ingestion, source_file.path_string().as_str(), source_file.metadata.clone()).await
+            }
+            file_type => {
+                ingestion
+                    .ingest(file_type, source_file.path_string().as_str(), ds.originalName.clone(), source_file.metadata.clone())
+                    .await
+            }
+        },
+        Err(e) if e.is::<FileRefused>() => Outcome::Reject(format!("Could not read file from source. Error: {}", e)),
+        Err(e) => Outcome::Retry(format!("Could not read file from source. Error: {}", e)),
+    }
+}
+
+/// Queues an Airbyte record to be embedded and waits for it to be processed
+async fn embed_record(queue: &EmbeddingQueue, datasource_id: &str, stream: &str, delivery: &Delivery) -> Outcome {
+    let Ok(message_string) = String::from_utf8(delivery.content.clone()) else {
+        return Outcome::Reject("Message body is not valid UTF-8".to_string());
+    };
+    let processed =
+        add_message_to_embedding_queue(queue, (datasource_id.to_string(), stream.to_string(), message_string)).await;
+    processed
+        .await
+        .unwrap_or_else(|_| Outcome::Retry("The record was dropped before it was processed".to_string()))
+}
+
+
+/// Everything needed to ingest a file into a datasource's collection
+struct FileIngestion<'a> {
+    datasource_id: &'a str,
+    datasource: &'a DataSources,
+    model_parameters: &'a Model,
+    qdrant_conn: Arc<RwLock<QdrantClient>>,
+    mongo_conn: Arc<RwLock<Database>>,
+    job: &'a JobTracker,
+}
+
+impl FileIngestion<'_> {
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `file_type`: The type of the file
+    /// * `file_path`: Where the file has been saved to on disk
+    /// * `document_name`: The name stored on every chunk of the file
+    /// * `extra_metadata`: Metadata added to every chunk on top of what is extracted from the file
+    ///
+    /// Text and PDF files larger than a part are extracted, chunked and uploaded a part at a time.
+    /// CSV and TSV files are embedded and uploaded a batch of rows at a time. Chunks the document
+    /// already has in the collection are not embedded again, so ingesting the file again after a
+    /// failure only embeds what is missing, and once the whole file has been uploaded the chunks it
+    /// no longer contains are deleted.
+    ///
+    /// returns: Outcome Whether the whole file was uploaded, or why it was not
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    async fn ingest(
+        &self,
+        file_type: FileType,
+        file_path: &str,
+        document_name: String,
+        mut extra_metadata: HashMap<String, String>,
+    ) -> Outcome {
+        let document_id = document_id(document_name.as_str(), &extra_metadata);
+        let mut stored = StoredDocument::load(self.qdrant(), self.datasource_id, document_id.clone()).await;
+        extra_metadata.insert(DOCUMENT_ID_KEY.to_string(), document_id);
+        let path = file_path.trim_matches('"').to_string();
+        if matches!(file_type, FileType::CSV | FileType::TSV) {
+            return self.ingest_rows(file_type, path, document_name, extra_metadata, &mut stored).await;
+        }
+        let file_size = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or_default();
+        let outcome = if supports_parts(file_type) && file_size > PART_BYTES as u64 {
+            self.ingest_in_parts(file_type, path, document_name, extra_metadata, &mut stored)
+                .await
+        } else {
+            let (document_text, metadata) = match extract_text_from_file(file_type, file_path, document_name).await {
+                Ok(extracted) => extracted,
+                Err(e) => return Outcome::Reject(e.to_string()),
+            };
+            let embedding_provider = match self.embedding_provider().await {
+                Ok(embedding_provider) => embedding_provider,
+                Err(e) => return Outcome::Retry(e.to_string()),
+            };
+            let metadata = metadata.map(|metadata| self.add_metadata(metadata, extra_metadata));
+            self.chunk_and_upload(document_text, metadata, embedding_provider, &mut stored)
+                .await
+        };
+        // the chunks of parts that failed would otherwise look stale
+        if let Outcome::Done = outcome {
+            stored.delete_stale().await;
+        }
+        outcome
+    }
+
+    async fn ingest_in_parts(
+        &self,
+        file_type: FileType,
+        path: String,
+        document_name: String,
+        extra_metadata: HashMap<String, String>,
+        stored: &mut StoredDocument,
+    ) -> Outcome {
+        let embedding_provider = match self.embedding_provider().await {
+            Ok(embedding_provider) => embedding_provider,
+            Err(e) => return Outcome::Retry(e.to_string()),
+        };
+        let mut outcome = Outcome::Done;
+        let mut parts = extract_parts(file_type, path.clone());
+        let mut part_count = 0;
+        // the parts only end once extraction has finished, so stale chunks are only deleted after
+        // every part has been seen
+        while let Some(part) = parts.next().await {
+            match part {
+                Ok(part) => {
+                    part_count += 1;
+                    let mut metadata = part.metadata;
+                    metadata.insert(String::from("document name"), document_name.clone());
+                    let metadata = self.add_metadata(metadata, extra_metadata.clone());
+                    let part_outcome = self.chunk_and_upload(part.text, Some(metadata), Arc::clone(&embedding_provider), stored).await;
+                    outcome = outcome.and(part_outcome);
+                }
+                Err(e) => {
+                    outcome = outcome.and(Outcome::Reject(format!(
+                        "An error occurred while extracting text from {}. Error: {}",
+                        path, e
+                    )));
+                }
+            }
+        }
+        println!("Extracted {} parts from {}", part_count, path);
+        outcome
+    }
+
+    /// CSV and TSV rows are embedded and uploaded as they are read so there is nothing left to chunk
+    async fn ingest_rows(
+        &self,
+        file_type: FileType,
+        path: String,
+        document_name: String,
+        extra_metadata: HashMap<String, String>,
+        stored: &mut StoredDocument,
+    ) -> Outcome {
+        let delimiter = match file_type {
+            FileType::TSV => b'\t',
+            _ => b',',
+        };
+        match TextChunker::default()
+            .extract_text_from_csv(
+                path,
+                delimiter,
+                document_name,
+                extra_metadata,
+                self.datasource_id.to_string(),
+                Arc::clone(&self.mongo_conn),
+                stored,
+            )
+            .await
+        {
+            Ok(rows) => {
+                println!("{} rows uploaded successfully!", rows.upserted);
+                self.job.add_chunks(stored.seen_count(), rows.upserted, rows.failed).await;
+                if rows.failed > 0 {
+                    return Outcome::Retry(format!("{} rows could not be embedded", rows.failed));
+                }
+                Outcome::Done
+            }
+            Err(e) => {
+                self.job.add_chunks(stored.seen_count(), 0, 0).await;
+                Outcome::Retry(format!("An error occurred while ingesting rows: {}", e))
+            }
+        }
+    }
+
+    fn qdrant(&self) -> Qdrant {
+        Qdrant::new(Arc::clone(&self.qdrant_conn), self.datasource_id.to_string())
+    }
+
+    async fn embedding_provider(&self) -> anyhow::Result<Arc<EmbeddingProviders>> {
+        let mongodb_connection = self.mongo_conn.read().await;
+        let embedding_provider = get_embedding_provider(&mongodb_connection, self.datasource_id).await?;
+        Ok(Arc::new(embedding_provider))
+    }
+
+    fn add_metadata(&self, mut metadata: HashMap<String, String>, extra_metadata: HashMap<String, String>) -> HashMap<String, String> {
+        metadata.extend(extra_metadata);
+        // an explicit language on the datasource overrides detection from the file extension
+        if let Some(language) = self.datasource.codeLanguage.clone() {
+            metadata.insert("language".to_string(), language);
+        }
+        metadata
+    }
+
+    async fn chunk_and_upload(
+        &self,
+        document_text: String,
+        metadata: Option<HashMap<String, String>>,
+        embedding_provider: Arc<EmbeddingProviders>,
+        stored: &mut StoredDocument,
+    ) -> Outcome {
+        let datasource = self.datasource.clone();
+        // dynamically get user's chunking strategy of choice from the database
+        let model_name = self.model_parameters.model.clone();
+        let chunk_size = ChunkSize::from(&datasource);
+        let chunking_character = datasource.chunkCharacter;
+        let chunking_method = datasource.chunkStrategy.unwrap();
+        let chunking_strategy = ChunkingStrategy::from(chunking_method);
+        let chunking_result = apply_chunking_strategy_to_document(document_text, metadata, chunking_strategy, chunking_character, chunk_size, embedding_provider, stored).await;
+        match chunking_result {
+            Ok(EmbeddedChunks { chunks, failed }) => {
+                let mut points_to_upload: Vec<PointStruct> = vec![];
+                for element in chunks.iter() {
+                    let mut metadata = element.metadata.clone().unwrap_or_default();
+                    let content_hash = metadata.get(CONTENT_HASH_KEY).cloned().unwrap_or_default();
+                    metadata.insert(METADATA_HASH_KEY.to_string(), metadata_hash(&metadata));
+                    stored.mark_seen(content_hash.as_str());
+                    match &element.embedding_vector {
+                        Some(val) => {
+                            if let Some(point_struct) = construct_point_struct(stored.point_id(content_hash.as_str()), val, element.page_content.as_str(), metadata, model_name.as_str()).await {
+                                points_to_upload.push(point_struct)
+                            }
+                        }
+                        // unchanged chunks keep their vector, but may have moved within the document
+                        None if stored.is_stored(content_hash.as_str()) => {
+                            if stored.metadata_changed(content_hash.as_str(), metadata[METADATA_HASH_KEY].as_str()) {
+                                if let Err(e) = stored.refresh_payload(content_hash.as_str(), &metadata).await {
+                                    println!("An error occurred while updating an unchanged chunk. Error: {}", e);
                                 }
                             }
-                        } else {
-                            println!("There was no stream_id in message... can not upload data!");
+                        }
+                        None => {
+                            println!("Embedding vector was empty!")
                         }
                     }
-                    if let Err(e) = channel.basic_cancel(BasicCancelArguments::new(&ctag)).await {
-                        println!("error {}", e);
-                    };
                 }
+                let (chunk_count, point_count) = (chunks.len() + failed, points_to_upload.len());
+                // the chunks that did embed are still uploaded so that the next attempt only embeds the rest
+                let embedding_outcome = match failed {
+                    0 => Outcome::Done,
+                    failed => Outcome::Retry(format!(
+                        "{} chunks of document {} could not be embedded",
+                        failed,
+                        stored.document_id()
+                    )),
+                };
+                if points_to_upload.is_empty() {
+                    println!("Every chunk of document {} is unchanged, nothing to upload", stored.document_id());
+                    self.job.add_chunks(chunk_count, 0, failed).await;
+                    return embedding_outcome;
+                }
+                let vector_length = self.model_parameters.embeddingLength as u64;
+                let outcome = match stored.collection().bulk_upsert_data(points_to_upload, Some(vector_length), Some(model_name)).await {
+                    Ok(true) => {
+                        println!("points uploaded successfully!");
+                        Outcome::Done
+                    }
+                    Ok(false) => Outcome::Retry("Qdrant did not acknowledge the upload".to_string()),
+                    Err(e) => Outcome::Retry(format!("An error occurred while attempting upload to qdrant. Error: {:?}", e)),
+                };
+                let embedded = match outcome {
+                    Outcome::Done => point_count,
+                    _ => 0,
+                };
+                self.job.add_chunks(chunk_count, embedded, failed).await;
+                outcome.and(embedding_outcome)
             }
+            Err(e) => Outcome::Retry(format!("An error occurred while chunking the document. Error: {}", e)),
         }
-        Err(e) => { println!("Error consuming message from rabbit: {}", e) }
+    }
+}
+
+/// Aborts a task once whatever is waiting for it is dropped
+struct AbortOnDrop(task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Identifies a document within its datasource so that ingesting it again replaces its chunks: the
+/// URL it was fetched from, its path inside the archive it came in, or otherwise its name
+fn document_id(document_name: &str, extra_metadata: &HashMap<String, String>) -> String {
+    if let Some(url) = extra_metadata.get("source_url") {
+        return url.clone();
+    }
+    match (extra_metadata.get("archive_name"), extra_metadata.get("archive_path")) {
+        (Some(archive_name), Some(archive_path)) => format!("{}/{}", archive_name, archive_path),
+        _ => document_name.to_string(),
+    }
+}
+
+async fn notify_embed_ready(datasource_id: &str) {
+    if let Err(e) = send_webapp_embed_ready(datasource_id).await {
+        println!("Error notifying webapp: {}", e);
+    } else {
+        println!("Webapp notified successfully!");
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `ingestion`: The datasource the archive was uploaded to
+/// * `file_path`: Where the archive has been saved to on disk
+/// * `source_metadata`: Metadata describing where the archive came from, added to every file in it
+///
+/// Every supported file in the archive is ingested as a document of its own with the name of the
+/// archive and its path inside the archive added to its metadata. Archives inside the archive and
+/// files of unsupported types are skipped.
+///
+/// returns: Outcome Done if every file that failed can never be ingested and at least one file was
+/// ingested, so that the archive is retried if any file may be ingested on another attempt
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+async fn ingest_archive(ingestion: &FileIngestion<'_>, file_path: &str, source_metadata: HashMap<String, String>) -> Outcome {
+    let limits = ArchiveLimits::from_global_data().await;
+    let archive_path = file_path.trim_matches('"').to_string();
+    let expanded = task::spawn_blocking(move || expand_archive(archive_path.as_str(), limits)).await;
+    let expanded = match expanded {
+        Ok(Ok(expanded)) => expanded,
+        Ok(Err(e)) => return Outcome::Reject(format!("Could not expand archive {}. Error: {}", file_path, e)),
+        Err(e) => return Outcome::Retry(format!("Expanding archive {} did not complete. Error: {}", file_path, e)),
+    };
+    let archive_name = ingestion.datasource.originalName.clone();
+    let (mut ingested, mut retry) = (0, None);
+    for entry in expanded.entries.iter() {
+        let entry_path = entry.file_path.to_string_lossy().to_string();
+        let file_type = determine_file_type(entry.file_name().as_str(), &entry.file_path, None);
+        if matches!(file_type, FileType::ARCHIVE | FileType::UNKNOWN) {
+            println!("Skipping archive entry {} of type {:?}", entry.archive_path, file_type);
+            continue;
+        }
+        let mut extra_metadata = source_metadata.clone();
+        extra_metadata.insert("archive_name".to_string(), archive_name.clone());
+        extra_metadata.insert("archive_path".to_string(), entry.archive_path.clone());
+        match ingestion.ingest(file_type, entry_path.as_str(), entry.file_name(), extra_metadata).await {
+            Outcome::Done => ingested += 1,
+            Outcome::Retry(reason) => retry = Some(reason),
+            Outcome::Reject(reason) => println!("Skipping archive entry {}. Reason: {}", entry.archive_path, reason),
+        }
+    }
+    println!("Ingested {} of {} files in archive {}", ingested, expanded.entries.len(), archive_name);
+    match retry {
+        Some(reason) => Outcome::Retry(reason),
+        None if ingested > 0 => Outcome::Done,
+        None => Outcome::Reject(format!("No files could be ingested from archive {}", archive_name)),
+    }
+}
+
+async fn notify_embed_failed(datasource_id: &str, reason: String) {
+    println!("{}", reason);
+    if let Err(e) = send_webapp_embed_failed(datasource_id, reason.as_str()).await {
+        println!("Error notifying webapp: {}", e);
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `ingestion`: The datasource the pages are ingested into
+/// * `message_data`: The message body, with the first page in `url` and the crawl options in `crawl`
+///
+/// Every HTML page fetched is ingested as a document of its own, named after its URL, as soon as
+/// it has been fetched.
+///
+/// returns: Outcome Done if at least one page was ingested and no page may be ingested on another
+/// attempt
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+async fn ingest_crawl(ingestion: &FileIngestion<'_>, message_data: &Value) -> Outcome {
+    let start = match message_data.get("url").and_then(|u| u.as_str()).map(Url::parse) {
+        Some(Ok(url)) => url,
+        Some(Err(e)) => return Outcome::Reject(format!("Could not parse the url to crawl. Error: {}", e)),
+        None => return Outcome::Reject("No url to crawl in message data".to_string()),
+    };
+    // `"crawl": true` crawls with the default options
+    let options = match message_data.get("crawl") {
+        Some(Value::Bool(true)) => Ok(CrawlOptions::default()),
+        Some(options) => serde_json::from_value::<CrawlOptions>(options.clone()),
+        None => Ok(CrawlOptions::default()),
+    };
+    let options = match options {
+        Ok(options) => options,
+        Err(e) => return Outcome::Reject(format!("Invalid crawl options. Error: {}", e)),
+    };
+    let max_pages_cap = GLOBAL_DATA.read().await.crawl_max_pages;
+    let mut crawler = match Crawler::new(start.clone(), options, max_pages_cap).await {
+        Ok(crawler) => crawler,
+        Err(e) => return Outcome::Retry(format!("Could not crawl {}. Error: {}", start, e)),
+    };
+    let (mut crawled, mut ingested, mut retry) = (0, 0, None);
+    while let Some(page) = crawler.next_page().await {
+        crawled += 1;
+        let source_metadata = HashMap::from([
+            ("source_url".to_string(), page.url.to_string()),
+            ("fetched_at".to_string(), page.fetched_at),
+        ]);
+        let page_path = page.file_path.to_string_lossy().to_string();
+        match ingestion.ingest(FileType::HTML, page_path.as_str(), page.url.to_string(), source_metadata).await {
+            Outcome::Done => ingested += 1,
+            Outcome::Retry(reason) => retry = Some(reason),
+            Outcome::Reject(reason) => println!("Skipping page {}. Reason: {}", page.url, reason),
+        }
+    }
+    println!("Ingested {} of {} pages crawled from {}", ingested, crawled, start);
+    match retry {
+        Some(reason) => Outcome::Retry(reason),
+        None if ingested > 0 => Outcome::Done,
+        None => Outcome::Reject(format!("No pages could be ingested from {}", start)),
     }
 }
@@ -0,0 +1,312 @@
+//! Settling messages taken off the queue.
+//!
+//! A message is only acked once it has been processed, so that a crash or an outage while it is
+//! being processed does not lose it. Failures that may pass on another attempt are retried with
+//! exponential backoff. Messages that fail every attempt, or that can never be processed, are
+//! published to the dead-letter exchange with the reason in their headers and then acked. The
+//! stream queue the proxy consumes from does not support dead-lettering by the broker, so this is
+//! done by the proxy itself. Dead-lettered messages can be replayed onto the exchange they were
+//! originally published to once whatever made them fail has been fixed.
+use std::future::Future;
+use std::time::Duration;
+
+use amqp_serde::types::{FieldTable, FieldValue, ShortStr};
+use amqprs::channel::{
+    BasicAckArguments, BasicGetArguments, BasicNackArguments, BasicPublishArguments, Channel,
+};
+use amqprs::BasicProperties;
+use anyhow::{anyhow, Result};
+use backoff::backoff::Backoff;
+use backoff::ExponentialBackoff;
+use chrono::Utc;
+
+use crate::init::env_variables::GLOBAL_DATA;
+
+pub const FAILURE_REASON_HEADER: &str = "x-failure-reason";
+pub const FAILURE_ATTEMPTS_HEADER: &str = "x-failure-attempts";
+pub const FAILED_AT_HEADER: &str = "x-failed-at";
+pub const ORIGINAL_EXCHANGE_HEADER: &str = "x-original-exchange";
+pub const ORIGINAL_ROUTING_KEY_HEADER: &str = "x-original-routing-key";
+
+// Long reasons are cut so the headers stay well within the frame size
+const MAX_REASON_BYTES: usize = 4096;
+
+/// How processing a message ended
+#[derive(Clone, Debug)]
+pub enum Outcome {
+    /// The message was processed, or there was nothing to do
+    Done,
+    /// Processing failed in a way that may pass on another attempt, such as Qdrant or the
+    /// embedding API being unavailable
+    Retry(String),
+    /// The message can never be processed, such as a malformed message or an unsupported file
+    Reject(String),
+}
+
+impl Outcome {
+    /// Combines the outcomes of the parts of a message. Anything worth retrying makes the whole
+    /// message worth retrying.
+    pub fn and(self, other: Outcome) -> Outcome {
+        match (self, other) {
+            (Outcome::Retry(reason), _) | (_, Outcome::Retry(reason)) => Outcome::Retry(reason),
+            (Outcome::Reject(reason), _) | (_, Outcome::Reject(reason)) => Outcome::Reject(reason),
+            (Outcome::Done, Outcome::Done) => Outcome::Done,
+        }
+    }
+}
+
+/// A message taken off the queue, kept until it has been settled
+#[derive(Clone)]
+pub struct Delivery {
+    pub delivery_tag: u64,
+    pub exchange: String,
+    pub routing_key: String,
+    pub properties: BasicProperties,
+    pub content: Vec<u8>,
+}
+
+impl Delivery {
+    pub fn headers(&self) -> FieldTable {
+        self.properties.headers().cloned().unwrap_or_default()
+    }
+}
+
+/// How often and how far apart a message is retried
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_interval: Duration,
+    pub max_interval: Duration,
+}
+
+impl RetryPolicy {
+    pub async fn from_global_data() -> Self {
+        let global_data = GLOBAL_DATA.read().await;
+        RetryPolicy {
+            max_attempts: global_data.message_max_attempts.max(1),
+            initial_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(global_data.message_retry_max_interval.max(1)),
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `policy`: How often and how far apart to retry
+/// * `attempt`: Processes the message once
+///
+/// Rejections are not retried.
+///
+/// returns: (Outcome, u32) The outcome of the last attempt and the number of attempts made
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn with_retries<F, Fut>(policy: &RetryPolicy, mut attempt: F) -> (Outcome, u32)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output=Outcome>,
+{
+    let mut backoff = ExponentialBackoff {
+        current_interval: policy.initial_interval,
+        initial_interval: policy.initial_interval,
+        max_interval: policy.max_interval,
+        max_elapsed_time: None,
+        multiplier: 2.0,
+        randomization_factor: 0.5,
+        ..ExponentialBackoff::default()
+    };
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let outcome = attempt().await;
+        match &outcome {
+            Outcome::Retry(reason) if attempts < policy.max_attempts => {
+                let wait = backoff.next_backoff().unwrap_or(policy.max_interval);
+                println!(
+                    "Attempt {} of {} failed, retrying in {:?}. Error: {}",
+                    attempts, policy.max_attempts, wait, reason
+                );
+                tokio::time::sleep(wait).await;
+            }
+            _ => return (outcome, attempts),
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `channel`: The channel the message was delivered on
+/// * `delivery`: The message
+/// * `attempts`: How many times processing the message was attempted
+/// * `outcome`: How the last attempt ended
+///
+/// Processed messages are acked. Failed messages are dead-lettered and then acked, unless the
+/// dead-letter exchange could not take them in which case they are nacked and requeued. Streams
+/// can not requeue, so a message that was neither acked nor dead-lettered is only processed again
+/// if the consumer restarts from before it.
+///
+/// returns: bool Whether the message was acked or dead-lettered, and so is done with
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn settle(channel: &Channel, delivery: &Delivery, attempts: u32, outcome: Outcome) -> bool {
+    let reason = match outcome {
+        Outcome::Done => return ack(channel, delivery.delivery_tag).await,
+        Outcome::Retry(reason) | Outcome::Reject(reason) => reason,
+    };
+    match dead_letter(channel, delivery, attempts, reason.as_str()).await {
+        Ok(_) => {
+            println!(
+                "Message {} was dead-lettered after {} attempts. Reason: {}",
+                delivery.delivery_tag, attempts, reason
+            );
+            // the dead-lettered copy is what matters, failing to ack only means it may be redelivered
+            ack(channel, delivery.delivery_tag).await;
+            true
+        }
+        Err(e) => {
+            println!("Could not dead-letter message {}, requeueing it. Error: {}", delivery.delivery_tag, e);
+            let args = BasicNackArguments::new(delivery.delivery_tag, false, true);
+            if let Err(e) = channel.basic_nack(args).await {
+                println!("Could not nack message {}. Error: {}", delivery.delivery_tag, e);
+            }
+            false
+        }
+    }
+}
+
+async fn ack(channel: &Channel, delivery_tag: u64) -> bool {
+    match channel
+        .basic_ack(BasicAckArguments::new(delivery_tag, false))
+        .await
+    {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Could not ack message {}. Error: {}", delivery_tag, e);
+            false
+        }
+    }
+}
+
+async fn dead_letter(channel: &Channel, delivery: &Delivery, attempts: u32, reason: &str) -> Result<()> {
+    let dead_letter_exchange = GLOBAL_DATA.read().await.rabbitmq_dead_letter_exchange.clone();
+    let mut headers = delivery.headers();
+    insert_header(&mut headers, FAILURE_REASON_HEADER, truncate(reason, MAX_REASON_BYTES).into());
+    insert_header(&mut headers, FAILURE_ATTEMPTS_HEADER, FieldValue::I(attempts as i32));
+    insert_header(&mut headers, FAILED_AT_HEADER, Utc::now().to_rfc3339().into());
+    insert_header(&mut headers, ORIGINAL_EXCHANGE_HEADER, delivery.exchange.as_str().into());
+    insert_header(&mut headers, ORIGINAL_ROUTING_KEY_HEADER, delivery.routing_key.as_str().into());
+    let mut properties = delivery.properties.clone();
+    properties.with_headers(headers).with_persistence(true);
+    channel
+        .basic_publish(
+            properties,
+            delivery.content.clone(),
+            BasicPublishArguments::new(dead_letter_exchange.as_str(), ""),
+        )
+        .await?;
+    Ok(())
+}
+
+///
+///
+/// # Arguments
+///
+/// * `channel`: The channel to move the messages on
+/// * `limit`: The most messages to replay
+///
+/// Takes messages off the dead-letter queue and publishes them again, without their failure
+/// headers, to the exchange and routing key they were originally published with.
+///
+/// returns: Result<usize, Error> The number of messages replayed
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn replay_dead_letters(channel: &Channel, limit: usize) -> Result<usize> {
+    let (dead_letter_queue, default_exchange, default_routing_key) = {
+        let global_data = GLOBAL_DATA.read().await;
+        (
+            global_data.rabbitmq_dead_letter_queue.clone(),
+            global_data.rabbitmq_exchange.clone(),
+            global_data.rabbitmq_routing_key.clone(),
+        )
+    };
+    let mut replayed = 0;
+    while replayed < limit {
+        let Some((get_ok, mut properties, content)) = channel
+            .basic_get(BasicGetArguments::new(dead_letter_queue.as_str()))
+            .await?
+        else {
+            break;
+        };
+        let mut headers = properties.headers().cloned().unwrap_or_default();
+        let exchange = header_string(&headers, ORIGINAL_EXCHANGE_HEADER).unwrap_or(default_exchange.clone());
+        let routing_key = header_string(&headers, ORIGINAL_ROUTING_KEY_HEADER).unwrap_or(default_routing_key.clone());
+        for key in [
+            FAILURE_REASON_HEADER,
+            FAILURE_ATTEMPTS_HEADER,
+            FAILED_AT_HEADER,
+            ORIGINAL_EXCHANGE_HEADER,
+            ORIGINAL_ROUTING_KEY_HEADER,
+        ] {
+            if let Ok(key) = ShortStr::try_from(key) {
+                headers.remove(&key);
+            }
+        }
+        properties.with_headers(headers);
+        if let Err(e) = channel
+            .basic_publish(
+                properties,
+                content,
+                BasicPublishArguments::new(exchange.as_str(), routing_key.as_str()),
+            )
+            .await
+        {
+            let _ = channel
+                .basic_nack(BasicNackArguments::new(get_ok.delivery_tag(), false, true))
+                .await;
+            return Err(anyhow!("Could not replay dead-lettered message. Error: {}", e));
+        }
+        channel
+            .basic_ack(BasicAckArguments::new(get_ok.delivery_tag(), false))
+            .await?;
+        replayed += 1;
+    }
+    println!("Replayed {} dead-lettered messages", replayed);
+    Ok(replayed)
+}
+
+fn insert_header(headers: &mut FieldTable, key: &str, value: FieldValue) {
+    if let Ok(key) = ShortStr::try_from(key) {
+        headers.insert(key, value);
+    }
+}
+
+fn header_string(headers: &FieldTable, key: &str) -> Option<String> {
+    let key = ShortStr::try_from(key).ok()?;
+    headers.get(&key).map(|value| value.to_string())
+}
+
+fn truncate(text: &str, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text.to_string();
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
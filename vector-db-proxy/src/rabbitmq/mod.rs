@@ -1,3 +1,6 @@
 pub mod client;
 pub mod consume;
+pub mod delivery;
+pub mod offsets;
+pub mod supervisor;
 pub mod models;
\ No newline at end of file
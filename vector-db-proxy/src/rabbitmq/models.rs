@@ -1,6 +1,74 @@
+use amqprs::channel::Channel;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+
 pub struct RabbitConnect {
     pub host: String,
     pub port: u16,
     pub username: String,
     pub password: String,
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+/// The consumer's connection to rabbit, shared with the routes that need it
+pub struct RabbitState {
+    pub status: ConnectionStatus,
+    pub since: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub reconnects: u32,
+    /// The channel of the current connection, if there is one
+    pub channel: Option<Channel>,
+}
+
+impl Default for RabbitState {
+    fn default() -> Self {
+        RabbitState {
+            status: ConnectionStatus::Connecting,
+            since: Utc::now(),
+            last_error: None,
+            reconnects: 0,
+            channel: None,
+        }
+    }
+}
+
+impl RabbitState {
+    pub fn connecting(&mut self) {
+        self.status = ConnectionStatus::Connecting;
+        self.since = Utc::now();
+    }
+
+    pub fn connected(&mut self, channel: Channel) {
+        if self.last_error.is_some() {
+            self.reconnects += 1;
+        }
+        self.status = ConnectionStatus::Connected;
+        self.since = Utc::now();
+        self.channel = Some(channel);
+    }
+
+    pub fn disconnected(&mut self, reason: String) {
+        self.status = ConnectionStatus::Disconnected;
+        self.since = Utc::now();
+        self.last_error = Some(reason);
+        self.channel = None;
+    }
+
+    /// What the health check reports
+    pub fn health(&self) -> Value {
+        json!({
+            "status": self.status,
+            "since": self.since.to_rfc3339(),
+            "last_error": self.last_error,
+            "reconnects": self.reconnects,
+        })
+    }
+}
@@ -0,0 +1,252 @@
+//! Where the consumer reads the stream from.
+//!
+//! The offset of every message is tracked from the moment it is delivered until it has been
+//! settled. Airbyte records are settled out of order, so the offset stored is the last one below
+//! which every message has been settled. On startup the consumer resumes from the message after
+//! it, which means a message may be processed twice after a crash but never skipped.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+
+use amqp_serde::types::{FieldTable, FieldValue, ShortStr};
+use amqprs::channel::BasicConsumeArguments;
+use chrono::DateTime;
+use mongodb::Database;
+use tokio::sync::RwLock;
+
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::mongo::queries::{get_stream_offset, set_stream_offset};
+
+pub const STREAM_OFFSET_HEADER: &str = "x-stream-offset";
+
+/// Where in the stream to start consuming
+#[derive(Clone, Debug, PartialEq)]
+pub enum StartFrom {
+    First,
+    Last,
+    Next,
+    Offset(i64),
+    /// Seconds since the epoch, the broker starts at the first chunk of messages published then
+    Timestamp(u64),
+}
+
+impl StartFrom {
+    /// Parses `first`, `last`, `next`, an offset or an RFC 3339 timestamp
+    pub fn parse(value: &str) -> Option<StartFrom> {
+        match value.trim() {
+            "first" => Some(StartFrom::First),
+            "last" => Some(StartFrom::Last),
+            "next" => Some(StartFrom::Next),
+            value => match value.parse::<i64>() {
+                Ok(offset) if offset >= 0 => Some(StartFrom::Offset(offset)),
+                Ok(_) => None,
+                Err(_) => DateTime::parse_from_rfc3339(value)
+                    .ok()
+                    .and_then(|timestamp| u64::try_from(timestamp.timestamp()).ok())
+                    .map(StartFrom::Timestamp),
+            },
+        }
+    }
+
+    fn to_field_value(&self) -> FieldValue {
+        match self {
+            StartFrom::First => "first".into(),
+            StartFrom::Last => "last".into(),
+            StartFrom::Next => "next".into(),
+            StartFrom::Offset(offset) => FieldValue::l(*offset),
+            StartFrom::Timestamp(seconds) => FieldValue::T(*seconds),
+        }
+    }
+
+    /// Arguments to consume the stream from here
+    pub fn consume_arguments(&self, queue_name: &str) -> BasicConsumeArguments {
+        let mut arguments = FieldTable::new();
+        arguments.insert(
+            ShortStr::try_from(STREAM_OFFSET_HEADER).unwrap(),
+            self.to_field_value(),
+        );
+        BasicConsumeArguments::new(queue_name, "")
+            .arguments(arguments)
+            .finish()
+    }
+}
+
+impl fmt::Display for StartFrom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartFrom::First => write!(f, "the first message"),
+            StartFrom::Last => write!(f, "the last chunk of messages"),
+            StartFrom::Next => write!(f, "the next message published"),
+            StartFrom::Offset(offset) => write!(f, "offset {}", offset),
+            StartFrom::Timestamp(seconds) => write!(f, "timestamp {}", seconds),
+        }
+    }
+}
+
+/// The offset of a message delivered from a stream
+pub fn stream_offset(headers: &FieldTable) -> Option<i64> {
+    match headers.get(&ShortStr::try_from(STREAM_OFFSET_HEADER).ok()?)? {
+        FieldValue::l(offset) => Some(*offset),
+        FieldValue::I(offset) => Some(*offset as i64),
+        _ => None,
+    }
+}
+
+/// The offsets of one consumer's messages that have been delivered but not settled
+#[derive(Debug, Default)]
+pub struct OffsetTracker {
+    in_flight: BTreeSet<i64>,
+    last_delivered: Option<i64>,
+    committed: Option<i64>,
+    stored: Option<i64>,
+    // how far the committed offset moves before it is stored, unless nothing is in flight
+    store_every: i64,
+    retired: bool,
+}
+
+impl OffsetTracker {
+    pub fn new(store_every: i64) -> Self {
+        OffsetTracker {
+            store_every: store_every.max(1),
+            ..OffsetTracker::default()
+        }
+    }
+
+    pub fn delivered(&mut self, offset: i64) {
+        self.in_flight.insert(offset);
+        self.last_delivered = Some(offset);
+    }
+
+    /// Records that a message has been settled. Returns the offset to store, if it is time to.
+    pub fn settled(&mut self, offset: i64) -> Option<i64> {
+        self.in_flight.remove(&offset);
+        if self.retired {
+            return None;
+        }
+        let committed = match self.in_flight.first() {
+            Some(first_in_flight) => first_in_flight - 1,
+            None => self.last_delivered?,
+        };
+        if self.committed.is_some_and(|c| c >= committed) {
+            return None;
+        }
+        self.committed = Some(committed);
+        let due = match self.stored {
+            Some(stored) => committed - stored >= self.store_every || self.in_flight.is_empty(),
+            None => true,
+        };
+        if due {
+            self.stored = Some(committed);
+            return Some(committed);
+        }
+        None
+    }
+
+    /// Stops storing offsets, for when the consumer has been replaced by one reading from
+    /// elsewhere in the stream while some of its messages are still being processed
+    pub fn retire(&mut self) {
+        self.retired = true;
+    }
+}
+
+/// The offsets consumers of a stream have stored in Mongo
+#[derive(Clone)]
+pub struct OffsetStore {
+    mongo_conn: Arc<RwLock<Database>>,
+    stream: String,
+}
+
+impl OffsetStore {
+    pub fn new(mongo_conn: Arc<RwLock<Database>>, stream: &str) -> Self {
+        OffsetStore {
+            mongo_conn,
+            stream: stream.to_string(),
+        }
+    }
+
+    /// The message after the stored offset, or where the stream is configured to be read from
+    /// if no offset has been stored yet
+    pub async fn start_from(&self) -> StartFrom {
+        let stored = {
+            let mongodb_connection = self.mongo_conn.read().await;
+            get_stream_offset(&mongodb_connection, self.stream.as_str()).await
+        };
+        match stored {
+            Ok(Some(offset)) => StartFrom::Offset(offset + 1),
+            Ok(None) => Self::configured_start().await,
+            Err(e) => {
+                println!("Could not look up the stored offset, starting from the configured offset. Error: {}", e);
+                Self::configured_start().await
+            }
+        }
+    }
+
+    async fn configured_start() -> StartFrom {
+        let configured = GLOBAL_DATA.read().await.rabbitmq_stream_start.clone();
+        StartFrom::parse(configured.as_str()).unwrap_or_else(|| {
+            println!("Invalid stream start offset '{}', starting from the next message", configured);
+            StartFrom::Next
+        })
+    }
+
+    pub async fn store(&self, offset: i64) {
+        let mongodb_connection = self.mongo_conn.read().await;
+        if let Err(e) = set_stream_offset(&mongodb_connection, self.stream.as_str(), offset).await {
+            println!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_from_is_parsed() {
+        assert_eq!(StartFrom::parse(" first "), Some(StartFrom::First));
+        assert_eq!(StartFrom::parse("next"), Some(StartFrom::Next));
+        assert_eq!(StartFrom::parse("42"), Some(StartFrom::Offset(42)));
+        assert_eq!(StartFrom::parse("-1"), None);
+        assert_eq!(
+            StartFrom::parse("1970-01-01T00:01:00Z"),
+            Some(StartFrom::Timestamp(60))
+        );
+        assert_eq!(StartFrom::parse("yesterday"), None);
+    }
+
+    #[test]
+    fn committed_offset_stops_below_the_first_in_flight() {
+        let mut tracker = OffsetTracker::new(1);
+        for offset in 10..=13 {
+            tracker.delivered(offset);
+        }
+        assert_eq!(tracker.settled(12), Some(9));
+        assert_eq!(tracker.settled(11), None);
+        assert_eq!(tracker.settled(10), Some(12));
+        assert_eq!(tracker.settled(13), Some(13));
+    }
+
+    #[test]
+    fn offsets_are_stored_every_so_often_and_when_idle() {
+        let mut tracker = OffsetTracker::new(10);
+        for offset in 0..5 {
+            tracker.delivered(offset);
+        }
+        assert_eq!(tracker.settled(0), Some(0));
+        assert_eq!(tracker.settled(1), None);
+        assert_eq!(tracker.settled(2), None);
+        assert_eq!(tracker.settled(4), None);
+        // nothing left in flight, so the offset is stored even though it moved less than 10
+        assert_eq!(tracker.settled(3), Some(4));
+    }
+
+    #[test]
+    fn retired_trackers_store_nothing() {
+        let mut tracker = OffsetTracker::new(1);
+        tracker.delivered(0);
+        tracker.delivered(1);
+        tracker.retire();
+        assert_eq!(tracker.settled(0), None);
+        assert_eq!(tracker.settled(1), None);
+    }
+}
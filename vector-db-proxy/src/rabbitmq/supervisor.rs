@@ -0,0 +1,124 @@
+//! Keeping the stream consumer connected.
+//!
+//! The connection is watched through the callbacks the broker closes connections and channels
+//! with, and for network failures. When the connection is lost, or the consumer stops for any
+//! other reason, the supervisor reconnects with exponential backoff, declares the exchanges and
+//! queues again in case the broker lost them, and consumes the stream from the stored offset.
+use std::sync::Arc;
+use std::time::Duration;
+
+use amqprs::channel::Channel;
+use amqprs::connection::Connection;
+use anyhow::Result;
+use backoff::backoff::Backoff;
+use backoff::ExponentialBackoff;
+use mongodb::Database;
+use qdrant_client::client::QdrantClient;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::queue::queuing::EmbeddingQueue;
+use crate::rabbitmq::client::{
+    bind_queue_to_exchange, channel_rabbitmq, connect_rabbitmq, declare_dead_letter_queue,
+};
+use crate::rabbitmq::consume::subscribe_to_queue;
+use crate::rabbitmq::models::{RabbitConnect, RabbitState};
+use crate::rabbitmq::offsets::StartFrom;
+
+/// Everything the stream consumer needs, kept across reconnections
+pub struct ConsumerSupervisor {
+    pub connection_details: RabbitConnect,
+    pub qdrant_conn: Arc<RwLock<QdrantClient>>,
+    pub queue: Arc<EmbeddingQueue>,
+    pub mongo_conn: Arc<RwLock<Database>>,
+    pub state: Arc<RwLock<RabbitState>>,
+}
+
+impl ConsumerSupervisor {
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `replays`: Where to consume the stream from instead, sent by the replay endpoint
+    ///
+    /// Runs the consumer for as long as the proxy runs, reconnecting whenever the connection is
+    /// lost.
+    ///
+    /// returns: ()
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn run(self, mut replays: mpsc::Receiver<StartFrom>) {
+        let (stream, max_interval) = {
+            let global_data = GLOBAL_DATA.read().await;
+            (
+                global_data.rabbitmq_stream.clone(),
+                Duration::from_secs(global_data.rabbitmq_reconnect_max_interval.max(1)),
+            )
+        };
+        let mut backoff = ExponentialBackoff {
+            current_interval: Duration::from_secs(1),
+            initial_interval: Duration::from_secs(1),
+            max_interval,
+            max_elapsed_time: None,
+            ..ExponentialBackoff::default()
+        };
+        loop {
+            self.state.write().await.connecting();
+            let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+            let reason = match self.connect(&closed_tx).await {
+                Ok((connection, channel)) => {
+                    backoff.reset();
+                    println!("Connected to rabbit");
+                    self.state.write().await.connected(channel.clone());
+                    let reason = tokio::select! {
+                        _ = subscribe_to_queue(
+                            Arc::clone(&self.qdrant_conn),
+                            Arc::clone(&self.queue),
+                            Arc::clone(&self.mongo_conn),
+                            &channel,
+                            &stream,
+                            &mut replays,
+                        ) => "The consumer stopped".to_string(),
+                        Some(reason) = closed_rx.recv() => reason,
+                        true = connection.listen_network_io_failure() => {
+                            "The network connection to rabbit failed".to_string()
+                        }
+                    };
+                    // only the channel or the consumer may have failed, don't leave the connection open
+                    let _ = connection.close().await;
+                    reason
+                }
+                Err(e) => e.to_string(),
+            };
+            let wait = backoff.next_backoff().unwrap_or(max_interval);
+            println!("Disconnected from rabbit, reconnecting in {:?}. Reason: {}", wait, reason);
+            self.state.write().await.disconnected(reason);
+            sleep(wait).await;
+        }
+    }
+
+    async fn connect(&self, closed: &UnboundedSender<String>) -> Result<(Connection, Channel)> {
+        let connection = connect_rabbitmq(&self.connection_details, closed).await?;
+        let channel = channel_rabbitmq(&connection, closed).await?;
+        let (exchange, stream, routing_key, prefetch_count, dead_letter_exchange, dead_letter_queue) = {
+            let global_data = GLOBAL_DATA.read().await;
+            (
+                global_data.rabbitmq_exchange.clone(),
+                global_data.rabbitmq_stream.clone(),
+                global_data.rabbitmq_routing_key.clone(),
+                global_data.rabbitmq_prefetch_count,
+                global_data.rabbitmq_dead_letter_exchange.clone(),
+                global_data.rabbitmq_dead_letter_queue.clone(),
+            )
+        };
+        bind_queue_to_exchange(&channel, &exchange, &stream, &routing_key, prefetch_count).await?;
+        declare_dead_letter_queue(&channel, &dead_letter_exchange, &dead_letter_queue).await?;
+        Ok((connection, channel))
+    }
+}
@@ -5,37 +5,60 @@ use actix_web::*;
 use actix_web_lab::extract::Path;
 use std::sync::Arc;
 
+use crate::data::bm25::query_sparse_vector;
 use crate::errors::types::Result;
-use crate::qdrant::helpers::{get_next_page, get_scroll_results};
-use crate::qdrant::models::{MyPoint, PointSearchResults, ScrollResults};
+use crate::qdrant::helpers::{get_next_page, get_scroll_results, parse_point_id, point_id_to_string};
+use crate::qdrant::models::{
+    MyPoint, PointSearchResults, RecommendationExamples, RecommendationResults, ScrollResults,
+};
 use crate::qdrant::utils::Qdrant;
+use crate::queue::queuing::EmbeddingQueue;
+use crate::rabbitmq::delivery::replay_dead_letters;
+use crate::rabbitmq::models::RabbitState;
+use crate::rabbitmq::offsets::StartFrom;
 use crate::routes;
-use crate::utils::conversions::convert_hashmap_to_filters;
+use crate::utils::conversions::convert_filter_conditions_to_filter;
 
 use qdrant_client::client::QdrantClient;
 use qdrant_client::prelude::*;
 use qdrant_client::qdrant::vectors_config::Config;
 use qdrant_client::qdrant::{
-    CreateCollection, Filter, PointId, PointStruct, ScrollPoints, VectorParams, VectorsConfig,
+    CreateCollection, PointId, PointStruct, ScrollPoints, VectorParams, VectorsConfig,
     WithVectorsSelector,
 };
 
+use crate::llm::models::EmbeddingType;
+use crate::llm::providers::EmbeddingProviders;
+use crate::llm::model_registry::resident_models;
+use crate::llm::utils::embed_text;
 use crate::mongo::client::start_mongo_connection;
 use crate::mongo::models::Model;
-use crate::mongo::queries::get_embedding_model;
+use crate::mongo::queries::{
+    get_embedding_model, get_ingestion_job, get_ingestion_jobs, get_model_and_credentials,
+};
+use anyhow::anyhow;
+use mongodb::bson::oid::ObjectId;
+use mongodb::Database;
 use qdrant_client::qdrant::point_id::PointIdOptions;
 use qdrant_client::qdrant::with_vectors_selector::SelectorOptions;
-use routes::models::{ResponseBody, SearchRequest, Status};
+use routes::models::{
+    JobsRequest, QueryRequest, RecommendRequest, ReplayRequest, ResponseBody, SearchMode,
+    SearchRequest, Status, StreamReplayRequest,
+};
 use serde_json::json;
+use std::str::FromStr;
 use std::vec;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use wherr::wherr;
 
+// Messages replayed per request when no limit is given
+const DEFAULT_REPLAY_LIMIT: usize = 100;
+
 ///
 ///
 /// # Arguments
 ///
-/// Simple health check API for ingress
+/// Simple health check API for ingress, along with the state of the connection to rabbit
 ///
 /// returns: Result<impl Responder<Body=<unknown>>+Sized, MyError>
 ///
@@ -46,8 +69,52 @@ use wherr::wherr;
 /// ```
 #[wherr]
 #[get("/")]
-pub async fn health_check() -> Result<impl Responder> {
-    Ok(HttpResponse::Ok().finish())
+pub async fn health_check(rabbitmq_state: Data<Arc<RwLock<RabbitState>>>) -> Result<impl Responder> {
+    let rabbitmq = rabbitmq_state.read().await.health();
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(json!(ResponseBody {
+            status: Status::Success,
+            data: Some(json!({"rabbitmq": rabbitmq})),
+            error_message: None
+        })))
+}
+
+///
+///
+/// # Arguments
+///
+/// Lists the fastembed models currently resident in memory and which execution provider they run on
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, CustomErrorType>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[get("/embedding-models/resident")]
+pub async fn list_resident_models() -> Result<impl Responder> {
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(json!(ResponseBody {
+            status: Status::Success,
+            data: Some(json!({"resident_models": resident_models()})),
+            error_message: None
+        })))
+}
+
+fn invalid_filters_response(e: anyhow::Error) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type(ContentType::json())
+        .json(json!(ResponseBody {
+            status: Status::Failure,
+            data: None,
+            error_message: Some(json!({
+                "errorMessage": format!("Invalid filters: {}", e)
+            }))
+        }))
 }
 
 ///
@@ -270,18 +337,16 @@ pub async fn lookup_data_point(
     let qdrant_conn = app_data.get_ref().clone();
     let qdrant_conn_lock = qdrant_conn.read().await;
     let vector = data.clone().vector.unwrap_or(vec![]).to_vec();
-    let (must, must_not, should) = convert_hashmap_to_filters(&data.filters);
+    let filter = match convert_filter_conditions_to_filter(&data.filters) {
+        Ok(f) => f,
+        Err(e) => return Ok(invalid_filters_response(e)),
+    };
     let limit = data.limit.unwrap_or(3) as u64;
     let search_result = qdrant_conn_lock
         .search_points(&SearchPoints {
             collection_name,
             vector: vector.to_owned(),
-            filter: Some(Filter {
-                must,
-                must_not,
-                should,
-                ..Default::default()
-            }),
+            filter,
             limit,
             with_payload: Some(true.into()),
             ..Default::default()
@@ -303,6 +368,215 @@ pub async fn lookup_data_point(
         })))
 }
 
+///
+///
+/// # Arguments
+///
+/// * `app_data`: Data<Arc<RwLock<QdrantClient>>>
+/// * `mongo_data`: Data<Arc<RwLock<Database>>>
+/// * `Path(datasource_id)`:
+/// * `data`: Plain text query which is embedded server side using the datasource's embedding model.
+///   `search_mode` can be set to `hybrid` to fuse the dense search with a BM25 keyword search
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, CustomErrorType>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[post("/search/{datasource_id}")]
+pub async fn search_data_point(
+    app_data: Data<Arc<RwLock<QdrantClient>>>,
+    mongo_data: Data<Arc<RwLock<Database>>>,
+    Path(datasource_id): Path<String>,
+    data: web::Json<QueryRequest>,
+) -> Result<impl Responder> {
+    if data.query.trim().is_empty() || ObjectId::from_str(datasource_id.as_str()).is_err() {
+        return Ok(HttpResponse::BadRequest()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!({
+                    "errorMessage": "A non empty query and a valid datasource ID are required"
+                }))
+            })));
+    }
+    let filter = match convert_filter_conditions_to_filter(&data.filters) {
+        Ok(f) => f,
+        Err(e) => return Ok(invalid_filters_response(e)),
+    };
+    let mongo_conn = mongo_data.get_ref().clone();
+    let model_and_credentials = {
+        let mongodb_connection = mongo_conn.read().await;
+        get_model_and_credentials(&mongodb_connection, datasource_id.as_str()).await?
+    };
+    let Some((model_parameters, credentials)) = model_and_credentials else {
+        return Ok(HttpResponse::NotFound()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::NotFound,
+                data: None,
+                error_message: Some(json!({
+                    "errorMessage": format!("No embedding model found for datasource: '{}'", datasource_id)
+                }))
+            })));
+    };
+    // The query must be embedded with the same model the datasource was indexed with
+    let embedding_provider = EmbeddingProviders::from_model(model_parameters.clone(), credentials)?;
+    let embeddings = embed_text(&embedding_provider, vec![&data.query], EmbeddingType::Query).await?;
+    let Some(vector) = embeddings.into_iter().next() else {
+        return Err(anyhow!("Embedding the query returned no vectors").into());
+    };
+    let qdrant_conn = app_data.get_ref().clone();
+    let qdrant = Qdrant::new(qdrant_conn, datasource_id.clone());
+    let response_data = match data.search_mode.unwrap_or_default() {
+        SearchMode::Dense => json!(
+            qdrant
+                .return_similar_results(
+                    vector,
+                    filter,
+                    data.limit,
+                    Some(model_parameters.model),
+                )
+                .await?
+        ),
+        SearchMode::Hybrid => {
+            if !qdrant.has_sparse_vector().await? {
+                return Ok(HttpResponse::BadRequest()
+                    .content_type(ContentType::json())
+                    .json(json!(ResponseBody {
+                        status: Status::Failure,
+                        data: None,
+                        error_message: Some(json!({
+                            "errorMessage": format!("Collection: '{}' was created without a sparse vector and does not support hybrid search", datasource_id)
+                        }))
+                    })));
+            }
+            json!(
+                qdrant
+                    .return_hybrid_results(
+                        vector,
+                        query_sparse_vector(data.query.as_str()),
+                        filter,
+                        data.limit,
+                        Some(model_parameters.model),
+                    )
+                    .await?
+            )
+        }
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(json!(ResponseBody {
+            status: Status::Success,
+            data: Some(json!(response_data)),
+            error_message: None
+        })))
+}
+
+///
+///
+/// # Arguments
+///
+/// * `app_data`: Data<Arc<RwLock<QdrantClient>>>
+/// * `mongo_data`: Data<Arc<RwLock<Database>>>
+/// * `Path(collection_name)`:
+/// * `data`: Positive and negative examples (point IDs and/or raw vectors), filters, limit and score threshold
+///
+/// If no `vector_name` is given and the collection belongs to a datasource, the datasource's
+/// embedding model is used as the named vector.
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, CustomErrorType>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[post("/recommend/{collection_name}")]
+pub async fn recommend_data_points(
+    app_data: Data<Arc<RwLock<QdrantClient>>>,
+    mongo_data: Data<Arc<RwLock<Database>>>,
+    Path(collection_name): Path<String>,
+    data: web::Json<RecommendRequest>,
+) -> Result<impl Responder> {
+    let filter = match convert_filter_conditions_to_filter(&data.filters) {
+        Ok(f) => f,
+        Err(e) => return Ok(invalid_filters_response(e)),
+    };
+    let parse_ids = |ids: &Vec<String>| ids.iter().map(|id| parse_point_id(id)).collect::<anyhow::Result<Vec<PointId>>>();
+    let examples = match (parse_ids(&data.positive), parse_ids(&data.negative)) {
+        (Ok(positive), Ok(negative)) => RecommendationExamples {
+            positive,
+            negative,
+            positive_vectors: data.positive_vectors.clone(),
+            negative_vectors: data.negative_vectors.clone(),
+        },
+        (Err(e), _) | (_, Err(e)) => {
+            return Ok(HttpResponse::BadRequest()
+                .content_type(ContentType::json())
+                .json(json!(ResponseBody {
+                    status: Status::Failure,
+                    data: None,
+                    error_message: Some(json!({
+                        "errorMessage": e.to_string()
+                    }))
+                })));
+        }
+    };
+    if examples.is_empty() {
+        return Ok(HttpResponse::BadRequest()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!({
+                    "errorMessage": "At least one positive or negative example is required"
+                }))
+            })));
+    }
+    let vector_name = match &data.vector_name {
+        Some(name) => Some(name.to_string()),
+        None if ObjectId::from_str(collection_name.as_str()).is_ok() => {
+            let mongodb_connection = mongo_data.get_ref().read().await;
+            get_embedding_model(&mongodb_connection, collection_name.as_str())
+                .await?
+                .map(|model| model.model)
+        }
+        None => None,
+    };
+    let qdrant_conn = app_data.get_ref().clone();
+    let qdrant = Qdrant::new(qdrant_conn, collection_name);
+    let recommendations = qdrant
+        .return_recommendations(
+            examples,
+            filter,
+            data.limit.unwrap_or(5),
+            data.score_threshold,
+            vector_name,
+        )
+        .await?;
+    let response_data: Vec<RecommendationResults> = recommendations
+        .into_iter()
+        .map(|point| RecommendationResults {
+            id: point_id_to_string(point.id),
+            score: point.score,
+            payload: point.payload,
+        })
+        .collect();
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(json!(ResponseBody {
+            status: Status::Success,
+            data: Some(json!(response_data)),
+            error_message: None
+        })))
+}
+
 ///
 ///
 /// # Arguments
@@ -328,8 +602,11 @@ pub async fn scroll_data(
     let qdrant_conn = app_data.get_ref();
     // Initialise lists
     let mut response: Vec<ScrollResults> = vec![];
-    // Create a hash map of all filters provided by the client
-    let (must, must_not, should) = convert_hashmap_to_filters(&data.filters);
+    // Validate and convert the filters provided by the client
+    let filter = match convert_filter_conditions_to_filter(&data.filters) {
+        Ok(f) => f,
+        Err(e) => return Ok(invalid_filters_response(e)),
+    };
     if qdrant_conn
         .read()
         .await
@@ -352,12 +629,7 @@ pub async fn scroll_data(
     // Initial scroll point query to be sent to qdrant
     let mut scroll_points = ScrollPoints {
         collection_name: dataset_id,
-        filter: Some(Filter {
-            must,
-            must_not,
-            should,
-            ..Default::default()
-        }),
+        filter,
         limit: data.limit,
         with_vectors: Some(WithVectorsSelector {
             selector_options: Some(SelectorOptions::Enable(true)),
@@ -424,3 +696,307 @@ pub async fn delete_collection(
             }))),
     }
 }
+
+///
+///
+/// # Arguments
+///
+/// * `rabbitmq_state`: Data<Arc<RwLock<RabbitState>>> The connection to rabbit
+/// * `data`: Query string parameters based on the `ReplayRequest` struct
+///
+/// Moves dead-lettered messages back onto the exchange they were originally published to so that
+/// they are processed again, for use once whatever made them fail has been fixed.
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, CustomErrorType>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[post("/dead-letters/replay")]
+pub async fn replay_dead_letter_messages(
+    rabbitmq_state: Data<Arc<RwLock<RabbitState>>>,
+    data: web::Query<ReplayRequest>,
+) -> Result<impl Responder> {
+    let limit = data.limit.unwrap_or(DEFAULT_REPLAY_LIMIT);
+    let Some(channel) = rabbitmq_state.read().await.channel.clone() else {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!("Not connected to rabbit, try again once the connection is back"))
+            })));
+    };
+    match replay_dead_letters(&channel, limit).await {
+        Ok(replayed) => Ok(HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Success,
+                data: Some(json!({"replayed": replayed})),
+                error_message: None
+            }))),
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!(format!("Could not replay dead-lettered messages. Error: {}", e)))
+            }))),
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `replays`: Data<mpsc::Sender<StartFrom>> Where the stream consumer takes replay requests from
+/// * `data`: Query string parameters based on the `StreamReplayRequest` struct
+///
+/// Restarts the stream consumer from the given place in the stream. Messages from there on are
+/// processed again, and the stored offset moves back with them.
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, CustomErrorType>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[post("/stream/replay")]
+pub async fn replay_stream(
+    replays: Data<mpsc::Sender<StartFrom>>,
+    data: web::Query<StreamReplayRequest>,
+) -> Result<impl Responder> {
+    let Some(start) = StartFrom::parse(data.from.as_str()) else {
+        return Ok(HttpResponse::BadRequest()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!(format!(
+                    "Can not replay the stream from '{}', expected first, last, next, an offset or an RFC 3339 timestamp",
+                    data.from
+                )))
+            })));
+    };
+    let description = start.to_string();
+    // the consumer takes one replay at a time, don't hold the request while it is reconnecting
+    match replays.try_send(start) {
+        Ok(()) => Ok(HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Success,
+                data: Some(json!({"replaying_from": description})),
+                error_message: None
+            }))),
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!(format!("The stream consumer can not take a replay now. Error: {}", e)))
+            }))),
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `queue`: Data<Arc<EmbeddingQueue>> The queue Airbyte records are embedded on
+///
+/// Reports how many records are waiting in and being worked on by each stage of the embedding
+/// queue, and how the records processed so far have ended.
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, CustomErrorType>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[get("/metrics/queues")]
+pub async fn queue_metrics(queue: Data<Arc<EmbeddingQueue>>) -> Result<impl Responder> {
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(json!(ResponseBody {
+            status: Status::Success,
+            data: Some(queue.metrics()),
+            error_message: None
+        })))
+}
+
+const DEFAULT_JOBS_LIMIT: i64 = 50;
+// Most jobs returned per request
+const MAX_JOBS_LIMIT: i64 = 500;
+
+///
+///
+/// # Arguments
+///
+/// * `mongo_data`: Data<Arc<RwLock<Database>>>
+/// * `job_id`: The ID of the ingestion job
+///
+/// Reports the state of an ingestion job, how many chunks it has embedded so far and the errors
+/// it has run into.
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, CustomErrorType>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[get("/jobs/{job_id}")]
+pub async fn get_job(
+    mongo_data: Data<Arc<RwLock<Database>>>,
+    Path(job_id): Path<String>,
+) -> Result<impl Responder> {
+    if ObjectId::from_str(job_id.as_str()).is_err() {
+        return Ok(HttpResponse::BadRequest()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!(format!("Invalid job ID: {}", job_id)))
+            })));
+    }
+    let mongodb_connection = mongo_data.read().await;
+    match get_ingestion_job(&mongodb_connection, job_id.as_str()).await {
+        Ok(Some(job)) => Ok(HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Success,
+                data: Some(job.to_json()),
+                error_message: None
+            }))),
+        Ok(None) => Ok(HttpResponse::NotFound()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!(format!("No ingestion job with ID: {}", job_id)))
+            }))),
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!(e.to_string()))
+            }))),
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `mongo_data`: Data<Arc<RwLock<Database>>>
+/// * `datasource_id`: The ID of the datasource
+/// * `data`: Query string parameters based on the `JobsRequest` struct
+///
+/// Lists the most recent ingestion jobs of a datasource, newest first, at most 500 per request.
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, CustomErrorType>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[get("/datasources/{datasource_id}/jobs")]
+pub async fn list_datasource_jobs(
+    mongo_data: Data<Arc<RwLock<Database>>>,
+    Path(datasource_id): Path<String>,
+    data: web::Query<JobsRequest>,
+) -> Result<impl Responder> {
+    if ObjectId::from_str(datasource_id.as_str()).is_err() {
+        return Ok(HttpResponse::BadRequest()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!(format!("Invalid datasource ID: {}", datasource_id)))
+            })));
+    }
+    let limit = data.limit.unwrap_or(DEFAULT_JOBS_LIMIT).clamp(1, MAX_JOBS_LIMIT);
+    let mongodb_connection = mongo_data.read().await;
+    match get_ingestion_jobs(&mongodb_connection, datasource_id.as_str(), limit).await {
+        Ok(jobs) => Ok(HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Success,
+                data: Some(json!(jobs.iter().map(|job| job.to_json()).collect::<Vec<_>>())),
+                error_message: None
+            }))),
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .content_type(ContentType::json())
+            .json(json!(ResponseBody {
+                status: Status::Failure,
+                data: None,
+                error_message: Some(json!(e.to_string()))
+            }))),
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `err`: Why the request body could not be deserialised, such as a malformed filter
+/// * `_req`: The request
+///
+/// Answers the request with the reason in a `ResponseBody` rather than actix's plain text response.
+///
+/// returns: Error
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn json_error_handler(err: error::JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    bad_request_error(err)
+}
+
+/// The same as `json_error_handler`, for query strings
+pub fn query_error_handler(err: error::QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    bad_request_error(err)
+}
+
+fn bad_request_error<E: std::fmt::Debug + std::fmt::Display + 'static>(err: E) -> actix_web::Error {
+    let response = HttpResponse::BadRequest()
+        .content_type(ContentType::json())
+        .json(json!(ResponseBody {
+            status: Status::Failure,
+            data: None,
+            error_message: Some(json!(err.to_string()))
+        }));
+    error::InternalError::from_response(err, response).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn malformed_bodies_are_answered_with_a_response_body() {
+        let request = actix_web::test::TestRequest::default().to_http_request();
+        let Err(serde_error) = serde_json::from_str::<SearchRequest>(r#"{"filters": {"must": [{"key": "a", "equals": 1}]}}"#) else {
+            panic!("the filter should not deserialise");
+        };
+        let response = json_error_handler(error::JsonPayloadError::Deserialize(serde_error), &request).error_response();
+        assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+    }
+}
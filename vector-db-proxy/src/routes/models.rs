@@ -1,5 +1,4 @@
-use std::collections::HashMap;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 
 
@@ -18,22 +17,174 @@ pub struct ResponseBody{
     pub error_message: Option<Value>
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// A boolean group of conditions. Groups can be nested inside one another
+/// e.g. a `should` group inside of a `must` list.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct FilterConditions{
-    pub must: Vec<HashMap<String, String>>,
-    pub must_not: Vec<HashMap<String, String>>,
-    pub should: Vec<HashMap<String, String>>,
+    #[serde(default)]
+    pub must: Vec<FilterCondition>,
+    #[serde(default)]
+    pub must_not: Vec<FilterCondition>,
+    #[serde(default)]
+    pub should: Vec<FilterCondition>,
+}
+
+/// Either a condition on a single payload field (anything with a `key`) or a nested group
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum FilterCondition{
+    Field(Box<FieldCondition>),
+    Group(FilterConditions),
+}
+
+// Deserialised by hand rather than as an untagged enum so that a malformed condition reports
+// what was actually wrong with it instead of "data did not match any variant"
+impl<'de> Deserialize<'de> for FilterCondition {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+    {
+        let value = Value::deserialize(deserializer)?;
+        if value.get("key").is_some() {
+            serde_json::from_value(value)
+                .map(|field| FilterCondition::Field(Box::new(field)))
+                .map_err(de::Error::custom)
+        } else {
+            serde_json::from_value(value)
+                .map(FilterCondition::Group)
+                .map_err(de::Error::custom)
+        }
+    }
+}
+
+/// A condition on a single payload field. Exactly one operator should be set, with the exception
+/// of `gt`, `gte`, `lt` and `lte` which are shorthand for `range` and can be combined.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FieldCondition{
+    pub key: String,
+    pub eq: Option<Value>,
+    #[serde(rename = "in")]
+    pub any: Option<Vec<Value>>,
+    pub range: Option<RangeCondition>,
+    pub gt: Option<f64>,
+    pub gte: Option<f64>,
+    pub lt: Option<f64>,
+    pub lte: Option<f64>,
+    pub datetime_range: Option<DatetimeRangeCondition>,
+    pub text: Option<String>,
+    pub is_empty: Option<bool>,
+    pub is_null: Option<bool>,
+    pub geo_radius: Option<GeoRadiusCondition>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RangeCondition{
+    pub gt: Option<f64>,
+    pub gte: Option<f64>,
+    pub lt: Option<f64>,
+    pub lte: Option<f64>,
+}
+
+/// Bounds are RFC 3339 timestamps or plain `YYYY-MM-DD` dates (taken as midnight UTC)
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DatetimeRangeCondition{
+    pub gt: Option<String>,
+    pub gte: Option<String>,
+    pub lt: Option<String>,
+    pub lte: Option<String>,
+}
+
+/// `radius` is in meters
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GeoRadiusCondition{
+    pub lat: f64,
+    pub lon: f64,
+    pub radius: f32,
+}
+
+// Filters arrive as a JSON object in request bodies but can only be a (JSON encoded) string in a query string
+fn deserialize_filters<'de, D>(deserializer: D) -> Result<Option<FilterConditions>, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::String(filters)) => serde_json::from_str(filters.as_str())
+            .map(Some)
+            .map_err(de::Error::custom),
+        Some(Value::Null) | None => Ok(None),
+        Some(filters) => serde_json::from_value(filters)
+            .map(Some)
+            .map_err(de::Error::custom),
+    }
 }
 
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SearchRequest{
     pub vector: Option<Vec<f32>>,
+    #[serde(default, deserialize_with = "deserialize_filters")]
     pub filters: Option<FilterConditions>,
     pub limit: Option<u32>,
     pub get_all_pages: Option<bool>
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode{
+    #[default]
+    Dense,
+    Hybrid
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QueryRequest{
+    pub query: String,
+    pub filters: Option<FilterConditions>,
+    pub limit: Option<u64>,
+    pub search_mode: Option<SearchMode>
+}
+
+/// How many dead-lettered messages to replay, all of them up to a default if not given
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReplayRequest{
+    pub limit: Option<usize>
+}
+
+/// How many of a datasource's most recent ingestion jobs to list
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JobsRequest{
+    pub limit: Option<i64>
+}
+
+/// Where to replay the stream from: `first`, `last`, `next`, an offset or an RFC 3339 timestamp
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StreamReplayRequest{
+    pub from: String
+}
+
+/// Point IDs are either UUIDs or unsigned integers, as in Qdrant
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RecommendRequest{
+    #[serde(default)]
+    pub positive: Vec<String>,
+    #[serde(default)]
+    pub negative: Vec<String>,
+    #[serde(default)]
+    pub positive_vectors: Vec<Vec<f32>>,
+    #[serde(default)]
+    pub negative_vectors: Vec<Vec<f32>>,
+    #[serde(default, deserialize_with = "deserialize_filters")]
+    pub filters: Option<FilterConditions>,
+    pub limit: Option<u64>,
+    pub score_threshold: Option<f32>,
+    pub vector_name: Option<String>
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Prompt{
     pub prompt: Vec<String>,
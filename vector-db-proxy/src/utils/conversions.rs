@@ -1,5 +1,8 @@
-use crate::routes::models::FilterConditions;
-use qdrant_client::qdrant::Condition;
+use crate::routes::models::{FieldCondition, FilterCondition, FilterConditions, RangeCondition};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use qdrant_client::qdrant::{Condition, DatetimeRange, Filter, GeoPoint, GeoRadius, Range};
+use qdrant_client::Timestamp;
 use serde_json::{Map, Value};
 use std::collections::HashMap;
 
@@ -13,26 +16,320 @@ pub fn convert_serde_value_to_hashmap_string(
     return hashmap_serde;
 }
 
-pub fn convert_hashmap_to_filters(
+/// Maximum depth of nested groups, a guard against pathological (or malicious) filters
+const MAX_FILTER_DEPTH: usize = 8;
+
+///
+///
+/// # Arguments
+///
+/// * `filters`: The filter conditions provided by the client
+///
+/// Validates the filter conditions and converts them into a Qdrant filter. Errors name the
+/// offending condition by its path, e.g. `must[1].should[0]`, so they can be returned to the client as is.
+///
+/// returns: Result<Option<Filter>, Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn convert_filter_conditions_to_filter(
     filters: &Option<FilterConditions>,
-) -> (Vec<Condition>, Vec<Condition>, Vec<Condition>) {
-    let mut must_vec: Vec<Condition> = vec![];
-    let mut must_not_vec: Vec<Condition> = vec![];
-    let mut should_vec: Vec<Condition> = vec![];
-
-    fn process_filters(filters: Vec<HashMap<String, String>>, target_vec: &mut Vec<Condition>) {
-        for f in filters {
-            for (k, v) in f {
-                target_vec.push(Condition::matches(k.to_string(), v.to_string()));
+) -> Result<Option<Filter>> {
+    match filters {
+        Some(f) => Ok(Some(convert_group(f, "filters", 0)?)),
+        None => Ok(None),
+    }
+}
+
+fn convert_group(group: &FilterConditions, path: &str, depth: usize) -> Result<Filter> {
+    if depth > MAX_FILTER_DEPTH {
+        return Err(anyhow!(
+            "{}: filters can not be nested more than {} levels deep",
+            path,
+            MAX_FILTER_DEPTH
+        ));
+    }
+    let convert_list = |conditions: &Vec<FilterCondition>, name: &str| -> Result<Vec<Condition>> {
+        conditions
+            .iter()
+            .enumerate()
+            .map(|(i, condition)| {
+                let condition_path = format!("{}.{}[{}]", path, name, i);
+                match condition {
+                    FilterCondition::Field(field) => convert_field_condition(field, &condition_path),
+                    FilterCondition::Group(nested) => {
+                        Ok(convert_group(nested, &condition_path, depth + 1)?.into())
+                    }
+                }
+            })
+            .collect()
+    };
+    Ok(Filter {
+        must: convert_list(&group.must, "must")?,
+        must_not: convert_list(&group.must_not, "must_not")?,
+        should: convert_list(&group.should, "should")?,
+        ..Default::default()
+    })
+}
+
+fn convert_field_condition(field: &FieldCondition, path: &str) -> Result<Condition> {
+    let key = field.key.trim();
+    if key.is_empty() {
+        return Err(anyhow!("{}: 'key' can not be empty", path));
+    }
+    let has_range_shorthand =
+        field.gt.is_some() || field.gte.is_some() || field.lt.is_some() || field.lte.is_some();
+    let operators = [
+        field.eq.is_some(),
+        field.any.is_some(),
+        field.range.is_some(),
+        has_range_shorthand,
+        field.datetime_range.is_some(),
+        field.text.is_some(),
+        field.is_empty.is_some(),
+        field.is_null.is_some(),
+        field.geo_radius.is_some(),
+    ]
+        .iter()
+        .filter(|set| **set)
+        .count();
+    if operators != 1 {
+        return Err(anyhow!(
+            "{}: condition on '{}' must have exactly one of eq, in, range (or gt/gte/lt/lte), datetime_range, text, is_empty, is_null or geo_radius",
+            path,
+            key
+        ));
+    }
+
+    if let Some(value) = &field.eq {
+        return match value {
+            Value::String(s) => Ok(Condition::matches(key, s.to_string())),
+            Value::Bool(b) => Ok(Condition::matches(key, *b)),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => Ok(Condition::matches(key, i)),
+                None => Err(anyhow!(
+                    "{}: 'eq' only supports integers, use a range to match decimal numbers",
+                    path
+                )),
+            },
+            _ => Err(anyhow!(
+                "{}: 'eq' must be a string, integer or boolean",
+                path
+            )),
+        };
+    }
+    if let Some(values) = &field.any {
+        if values.is_empty() {
+            return Err(anyhow!("{}: 'in' must contain at least one value", path));
+        }
+        if let Some(strings) = values
+            .iter()
+            .map(|v| v.as_str().map(String::from))
+            .collect::<Option<Vec<String>>>()
+        {
+            return Ok(Condition::matches(key, strings));
+        }
+        if let Some(integers) = values.iter().map(|v| v.as_i64()).collect::<Option<Vec<i64>>>() {
+            return Ok(Condition::matches(key, integers));
+        }
+        return Err(anyhow!(
+            "{}: 'in' must be a list of only strings or only integers",
+            path
+        ));
+    }
+    if let Some(range) = &field.range {
+        return Ok(Condition::range(key, convert_range(range, path)?));
+    }
+    if has_range_shorthand {
+        let range = RangeCondition {
+            gt: field.gt,
+            gte: field.gte,
+            lt: field.lt,
+            lte: field.lte,
+        };
+        return Ok(Condition::range(key, convert_range(&range, path)?));
+    }
+    if let Some(range) = &field.datetime_range {
+        let parse = |bound: &Option<String>, name: &str| -> Result<Option<Timestamp>> {
+            match bound {
+                Some(b) => Ok(Some(parse_timestamp(b).ok_or_else(|| {
+                    anyhow!(
+                        "{}: datetime_range '{}' value '{}' is not an RFC 3339 timestamp or YYYY-MM-DD date",
+                        path,
+                        name,
+                        b
+                    )
+                })?)),
+                None => Ok(None),
             }
+        };
+        let datetime_range = DatetimeRange {
+            gt: parse(&range.gt, "gt")?,
+            gte: parse(&range.gte, "gte")?,
+            lt: parse(&range.lt, "lt")?,
+            lte: parse(&range.lte, "lte")?,
+        };
+        if datetime_range.gt.is_none()
+            && datetime_range.gte.is_none()
+            && datetime_range.lt.is_none()
+            && datetime_range.lte.is_none()
+        {
+            return Err(anyhow!("{}: datetime_range needs at least one bound", path));
+        }
+        return Ok(Condition::datetime_range(key, datetime_range));
+    }
+    if let Some(text) = &field.text {
+        if text.trim().is_empty() {
+            return Err(anyhow!("{}: 'text' can not be empty", path));
+        }
+        return Ok(Condition::matches_text(key, text.to_string()));
+    }
+    if let Some(is_empty) = field.is_empty {
+        return Ok(negate_if_false(Condition::is_empty(key), is_empty));
+    }
+    if let Some(is_null) = field.is_null {
+        return Ok(negate_if_false(Condition::is_null(key), is_null));
+    }
+    if let Some(geo) = &field.geo_radius {
+        if !(-90.0..=90.0).contains(&geo.lat) || !(-180.0..=180.0).contains(&geo.lon) {
+            return Err(anyhow!(
+                "{}: geo_radius lat must be between -90 and 90 and lon between -180 and 180",
+                path
+            ));
         }
+        if geo.radius <= 0.0 {
+            return Err(anyhow!("{}: geo_radius radius must be greater than 0", path));
+        }
+        return Ok(Condition::geo_radius(
+            key,
+            GeoRadius {
+                center: Some(GeoPoint {
+                    lat: geo.lat,
+                    lon: geo.lon,
+                }),
+                radius: geo.radius,
+            },
+        ));
+    }
+    Err(anyhow!("{}: condition on '{}' has no operator", path, key))
+}
+
+fn convert_range(range: &RangeCondition, path: &str) -> Result<Range> {
+    let lower = range.gt.or(range.gte);
+    let upper = range.lt.or(range.lte);
+    if lower.is_none() && upper.is_none() {
+        return Err(anyhow!("{}: range needs at least one bound", path));
+    }
+    if range.gt.is_some() && range.gte.is_some() || range.lt.is_some() && range.lte.is_some() {
+        return Err(anyhow!(
+            "{}: range can not have both gt and gte (or lt and lte)",
+            path
+        ));
+    }
+    if let (Some(l), Some(u)) = (lower, upper) {
+        if l > u {
+            return Err(anyhow!(
+                "{}: range lower bound {} is greater than the upper bound {}",
+                path,
+                l,
+                u
+            ));
+        }
+    }
+    Ok(Range {
+        gt: range.gt,
+        gte: range.gte,
+        lt: range.lt,
+        lte: range.lte,
+    })
+}
+
+fn parse_timestamp(value: &str) -> Option<Timestamp> {
+    let datetime = match DateTime::parse_from_rfc3339(value) {
+        Ok(d) => d.with_timezone(&Utc),
+        Err(_) => NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .ok()?
+            .and_hms_opt(0, 0, 0)?
+            .and_utc(),
+    };
+    Some(Timestamp {
+        seconds: datetime.timestamp(),
+        nanos: datetime.timestamp_subsec_nanos() as i32,
+    })
+}
+
+// Qdrant has no negated is_empty/is_null conditions so `false` wraps the condition in a must_not group
+fn negate_if_false(condition: Condition, value: bool) -> Condition {
+    if value {
+        condition
+    } else {
+        Filter::must_not([condition]).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn convert(filters: Value) -> Result<Option<Filter>> {
+        let filters: FilterConditions = serde_json::from_value(filters)?;
+        convert_filter_conditions_to_filter(&Some(filters))
+    }
+
+    #[test]
+    fn valid_conditions_are_converted() {
+        let filter = convert(json!({
+            "must": [
+                {"key": "author", "eq": "ada"},
+                {"key": "year", "gte": 1990, "lt": 2000},
+                {"key": "tags", "in": ["a", "b"]},
+            ],
+            "must_not": [{"key": "draft", "is_null": true}],
+        }))
+            .unwrap()
+            .unwrap();
+        assert_eq!(filter.must.len(), 3);
+        assert_eq!(filter.must_not.len(), 1);
+        assert!(filter.should.is_empty());
     }
 
-    if let Some(filters) = &filters {
-        process_filters(filters.must.to_vec(), &mut must_vec);
-        process_filters(filters.must_not.to_vec(), &mut must_not_vec);
-        process_filters(filters.should.to_vec(), &mut should_vec);
+    #[test]
+    fn nested_groups_are_converted() {
+        let filter = convert(json!({
+            "must": [{"should": [{"key": "a", "eq": 1}, {"key": "b", "eq": true}]}],
+        }))
+            .unwrap()
+            .unwrap();
+        assert_eq!(filter.must.len(), 1);
     }
 
-    (must_vec, must_not_vec, should_vec)
+    #[test]
+    fn filters_nested_too_deep_are_refused() {
+        let mut filters = json!({"must": [{"key": "a", "eq": 1}]});
+        for _ in 0..=MAX_FILTER_DEPTH {
+            filters = json!({"must": [filters]});
+        }
+        let error = convert(filters).unwrap_err().to_string();
+        assert!(error.contains("nested more than"), "{}", error);
+    }
+
+    #[test]
+    fn conditions_with_more_than_one_operator_are_refused() {
+        let error = convert(json!({"should": [{"key": "a", "eq": 1, "text": "b"}]}))
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("filters.should[0]: condition on 'a'"), "{}", error);
+        // the range shorthand counts as a single range
+        assert!(convert(json!({"must": [{"key": "a", "gt": 1, "range": {"lt": 2}}]})).is_err());
+    }
+
+    #[test]
+    fn unknown_operators_are_refused_when_deserialising() {
+        let error = convert(json!({"must": [{"key": "a", "equals": 1}]})).unwrap_err().to_string();
+        assert!(error.contains("unknown field `equals`"), "{}", error);
+    }
 }
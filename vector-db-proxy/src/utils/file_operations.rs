@@ -1,90 +1,424 @@
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Seek};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use amqp_serde::types::{FieldTable, ShortStr};
+use anyhow::{anyhow, Result};
 use serde_json::Value;
-use tokio::fs;
+use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
+use url::Url;
+use zip::ZipArchive;
+use crate::aws::s3::download_object_from_s3;
+use crate::data::archives::ArchiveKind;
 use crate::data::models::FileType;
-use crate::gcp::gcs::get_object_from_gcs;
+use crate::gcp::gcs::download_object_from_gcs;
+use crate::init::env_variables::GLOBAL_DATA;
 use crate::utils::models::FileSources;
+use crate::crawl::fetch::fetch_to_file;
 
-pub async fn save_file_to_disk(content: Vec<u8>, file_name: &str) -> anyhow::Result<()> {
-    let file_path = file_name.trim_matches('"');
-    println!("File path : {}", file_path);
-    let mut file = File::create(file_path)?;
-    file.write_all(&content)?; // handle errors
-    Ok(())
-}
-
-pub async fn determine_file_type(file_path: &str) -> FileType {
-    let file_path =
-        format!("{}", file_path);
-    let file_path_split: Vec<&str> =
-        file_path.split(".").collect();
-    let file_extension =
-        file_path_split.to_vec()[1]
-            .to_string()
-            .trim_end_matches('"')
-            .to_string();
-    let file_type =
-        FileType::from(file_extension);
-    file_type
-}
-
-pub async fn read_file_from_source(headers: FieldTable, message_data: Value) -> Option<(FileType, Vec<u8>, String)> {
+// Only the start of a file is looked at when deciding whether it is text
+const SNIFF_LENGTH: usize = 8192;
+
+/// What the first bytes of a file say about its type
+enum Sniffed {
+    Pdf,
+    Zip,
+    Archive,
+    Html,
+    Text,
+    Binary,
+}
+
+fn sniff(content: &[u8]) -> Option<Sniffed> {
+    if content.is_empty() {
+        return None;
+    }
+    let head = &content[..content.len().min(SNIFF_LENGTH)];
+    // PDF readers accept the header anywhere in the first kilobyte
+    if head[..head.len().min(1024)].windows(5).any(|w| w == b"%PDF-") {
+        return Some(Sniffed::Pdf);
+    }
+    match ArchiveKind::sniff(head) {
+        Some(ArchiveKind::Zip) => return Some(Sniffed::Zip),
+        Some(_) => return Some(Sniffed::Archive),
+        None => {}
+    }
+    if head.contains(&0) {
+        return Some(Sniffed::Binary);
+    }
+    let text = String::from_utf8_lossy(head);
+    let start = text
+        .trim_start_matches('\u{feff}')
+        .trim_start()
+        .chars()
+        .take(15)
+        .collect::<String>()
+        .to_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        return Some(Sniffed::Html);
+    }
+    Some(Sniffed::Text)
+}
+
+// Office documents are zip archives, the entries they contain say which kind of document it is.
+// Any other zip is an archive of documents.
+fn zip_file_type<R: Read + Seek>(reader: R) -> FileType {
+    let Ok(mut archive) = ZipArchive::new(reader) else {
+        return FileType::UNKNOWN;
+    };
+    if archive.by_name("word/document.xml").is_ok() {
+        return FileType::DOCX;
+    }
+    if archive.by_name("xl/workbook.xml").is_ok() {
+        return FileType::SPREADSHEET;
+    }
+    if archive.by_name("ppt/presentation.xml").is_ok() {
+        return FileType::PRESENTATION;
+    }
+    // OpenDocument files store their MIME type, uncompressed, in the first entry
+    let mut mime = String::new();
+    if let Ok(mut entry) = archive.by_name("mimetype") {
+        let _ = entry.read_to_string(&mut mime);
+    }
+    if mime.starts_with("application/vnd.oasis.opendocument") {
+        // OpenDocument drawings, formulas and the like are not archives of documents
+        return FileType::from_mime(mime.as_str()).unwrap_or(FileType::UNKNOWN);
+    }
+    FileType::ARCHIVE
+}
+
+fn file_extension(file_name: &str) -> Option<String> {
+    Path::new(file_name.trim_matches('"'))
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+}
+
+///
+///
+/// # Arguments
+///
+/// * `file_name`: Name of the file, only its last extension is looked at
+/// * `path`: Where the file is on disk, its first bytes are sniffed
+/// * `content_type`: A MIME type given explicitly by the sender, which takes precedence over everything else
+///
+/// The content decides between the binary formats (PDF and Office documents) and text. As text
+/// formats can not be told apart reliably from their content, text files are typed from their
+/// extension, and read as plain text if the extension is not that of a text format.
+///
+/// returns: FileType
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn determine_file_type(file_name: &str, path: &Path, content_type: Option<&str>) -> FileType {
+    if let Some(file_type) = content_type.and_then(FileType::from_mime) {
+        return file_type;
+    }
+    let extension_type = file_extension(file_name)
+        .map(FileType::from)
+        .unwrap_or(FileType::UNKNOWN);
+    let Ok(file) = File::open(path) else {
+        return FileType::UNKNOWN;
+    };
+    let mut head = vec![];
+    if file.take(SNIFF_LENGTH as u64).read_to_end(&mut head).is_err() {
+        return FileType::UNKNOWN;
+    }
+    match sniff(&head) {
+        Some(Sniffed::Pdf) => FileType::PDF,
+        // the zip directory is at the end of the file so the whole file is needed
+        Some(Sniffed::Zip) => File::open(path).map_or(FileType::UNKNOWN, zip_file_type),
+        Some(Sniffed::Archive) => FileType::ARCHIVE,
+        Some(Sniffed::Html) => FileType::HTML,
+        Some(Sniffed::Text) if extension_type.is_text() => extension_type,
+        Some(Sniffed::Text) => FileType::TXT,
+        Some(Sniffed::Binary) => FileType::UNKNOWN,
+        None => extension_type,
+    }
+}
+
+// The name a download is saved under. Only the extension of the original name is kept, so that
+// nothing in a name sent to us can decide where the file is written.
+fn generated_file_name(original_name: &str) -> String {
+    match file_extension(original_name) {
+        Some(extension) if !extension.is_empty() && extension.len() <= 10 && extension.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            format!("download.{}", extension)
+        }
+        _ => "download".to_string(),
+    }
+}
+
+// The last part of a path or object name, which is what is kept as the file name in the metadata
+fn original_file_name(name: &str) -> String {
+    let name = name.trim_matches('"');
+    name.rsplit(['/', '\\']).next().unwrap_or(name).to_string()
+}
+
+/// The message, or the file it names, can never be ingested however often it is tried again, such
+/// as when it does not say where the file is or the file is too large. Other errors reading a file
+/// are taken to be transient.
+#[derive(Debug)]
+pub struct FileRefused(pub String);
+
+impl std::fmt::Display for FileRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for FileRefused {}
+
+fn refuse(reason: String) -> anyhow::Error {
+    anyhow!(FileRefused(reason))
+}
+
+// Says which source a file could not be read from, keeping a refusal a refusal
+fn reading_from(source: &str, e: anyhow::Error) -> anyhow::Error {
+    let reason = format!("An error occurred while reading file from {}: {}", source, e);
+    if e.is::<FileRefused>() {
+        refuse(reason)
+    } else {
+        anyhow!(reason)
+    }
+}
+
+/// Writes a download to disk as it arrives, failing as soon as it grows past the maximum file size
+pub struct DownloadWriter {
+    file: tokio::fs::File,
+    written: u64,
+    max_bytes: u64,
+}
+
+impl DownloadWriter {
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The file to write to
+    /// * `expected_length`: The length announced by the source, if any. A file known to be too large is refused before anything is downloaded
+    ///
+    /// returns: Result<DownloadWriter, Error>
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn create(path: &Path, expected_length: Option<u64>) -> Result<Self> {
+        let max_bytes = GLOBAL_DATA.read().await.file_max_bytes;
+        if let Some(length) = expected_length.filter(|length| *length > max_bytes) {
+            return Err(refuse(format!("File of {} bytes is larger than the maximum of {} bytes", length, max_bytes)));
+        }
+        Ok(DownloadWriter {
+            file: tokio::fs::File::create(path).await?,
+            written: 0,
+            max_bytes,
+        })
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.written += bytes.len() as u64;
+        if self.written > self.max_bytes {
+            return Err(refuse(format!("File is larger than the maximum of {} bytes", self.max_bytes)));
+        }
+        self.file.write_all(bytes).await?;
+        Ok(())
+    }
+
+    /// Flushes the file, returning the number of bytes written
+    pub async fn finish(mut self) -> Result<u64> {
+        self.file.flush().await?;
+        Ok(self.written)
+    }
+}
+
+fn file_source(headers: &FieldTable) -> Option<FileSources> {
+    headers
+        .get(&ShortStr::try_from("type").unwrap())
+        .map(|t| FileSources::from(t.to_string()))
+}
+
+/// Deletes the local file a file message names, if it names one. Called once the message has been
+/// settled for good, however ingestion ended, as a local file is only ever ingested once.
+pub fn remove_local_file(headers: &FieldTable, content: &[u8]) {
+    if !matches!(file_source(headers), Some(FileSources::LOCAL)) {
+        return;
+    }
+    let Ok(message_data) = serde_json::from_slice::<Value>(content) else {
+        return;
+    };
+    let Some(file_path) = message_data.get("file").and_then(|f| f.as_str()) else {
+        return;
+    };
+    match std::fs::remove_file(file_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => println!("Could not delete local file {}. Error: {}", file_path, e),
+    }
+}
+
+/// Whether the message asks for a site to be crawled from a URL, rather than a single file to be fetched
+pub fn is_crawl(headers: &FieldTable, message_data: &Value) -> bool {
+    matches!(file_source(headers), Some(FileSources::URL))
+        && message_data
+            .get("crawl")
+            .is_some_and(|crawl| !crawl.is_null() && crawl != &Value::Bool(false))
+}
+
+/// Removes a file to be ingested once it is no longer needed
+enum FileGuard {
+    /// The private directory a download was written into
+    Directory(TempDir),
+    /// A local file, which is used where it is and deleted by `remove_local_file` once the message
+    /// has been settled, so that every attempt can read it
+    Local,
+}
+
+/// A file to be ingested and where it came from. A downloaded file, along with the private directory
+/// it was downloaded into, is deleted when this is dropped, whether ingestion succeeded, failed or
+/// panicked.
+pub struct SourceFile {
+    pub file_type: FileType,
+    pub path: PathBuf,
+    /// Describes where the file came from, including its original name, and is added to every chunk of the file
+    pub metadata: HashMap<String, String>,
+    _guard: FileGuard,
+}
+
+impl SourceFile {
+    pub fn path_string(&self) -> String {
+        self.path.to_string_lossy().to_string()
+    }
+}
+
+// Runs a download into a new private directory that is deleted if the download fails
+async fn download<F, Fut>(original_name: &str, download: F) -> Result<(TempDir, PathBuf)>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: Future<Output = Result<u64>>,
+{
+    let directory = tempfile::Builder::new()
+        .prefix("job-")
+        .tempdir()
+        .map_err(|e| anyhow!("Could not create a directory to download into: {}", e))?;
+    let file_path = directory.path().join(generated_file_name(original_name));
+    let bytes = download(file_path.clone()).await?;
+    println!("Downloaded {} bytes to {:?}", bytes, file_path);
+    Ok((directory, file_path))
+}
+
+///
+///
+/// # Arguments
+///
+/// * `headers`: The message headers, whose `type` names the source of the file
+/// * `message_data`: The message body, saying where the file is in that source
+///
+/// Files in object storage or at a URL are streamed into a private directory made for the job,
+/// under a generated name. Local files are used where they are and left for `remove_local_file`.
+/// Files larger than the maximum file size are refused.
+///
+/// returns: Result<SourceFile, Error> The error is a `FileRefused` if reading the file again can not succeed
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn read_file_from_source(headers: FieldTable, message_data: Value) -> Result<SourceFile> {
+    let content_type = message_data.get("contentType").and_then(|c| c.as_str());
+    let bucket_name = message_data.get("bucket").and_then(|b| b.as_str());
+    let file_name = message_data.get("filename").and_then(|f| f.as_str());
     // If the type field is present in the headers then we assume it is a file of sorts
-    match headers.get(&ShortStr::try_from("type").unwrap()) {
-        Some(t) => {
-            let file_source = FileSources::from(t.to_string());
-            return match file_source {
-                FileSources::GCS => {
-                    if let Some(bucket_name) =
-                        message_data.get("bucket")
-                    {
-                        if let Some(file_name) =
-                            message_data.get("filename") {
-                            match get_object_from_gcs(bucket_name.as_str().unwrap(), file_name.as_str().unwrap()).await {
-                                Ok(file) => {
-                                    let file_type = determine_file_type(file_name.as_str().unwrap()).await;
-                                    let result = (file_type, file, file_name.to_string());
-                                    Some(result)
-                                }
-                                Err(e) => {
-                                    println!("An error occurred while reading file from GCS: {}", e);
-                                    None
-                                }
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                }
-                FileSources::LOCAL => {
-                    if let Some(file_path) = message_data.get("file") {
-                        match fs::read(file_path.as_str().unwrap()).await {
-                            Ok(file) => {
-                                let file_type = determine_file_type(file_path.as_str().unwrap()).await;
-                                let results = (file_type, file, file_path.to_string());
-                                Some(results)
-                            }
-                            Err(e) => {
-                                println!("An error occurred while reading file from DISK, {}", e);
-                                None
-                            }
-                        }
-                    } else {
-                        println!("No file path in message data");
-                        None
-                    }
-                }
-                FileSources::UNKNOWN => {
-                    None
+    let (guard, path, original_name, mut metadata, content_type) = match file_source(&headers) {
+        Some(FileSources::GCS) => {
+            let (Some(bucket_name), Some(file_name)) = (bucket_name, file_name) else {
+                return Err(refuse("No bucket or file name in message data".to_string()));
+            };
+            let (directory, path) = download(file_name, |path| async move {
+                download_object_from_gcs(bucket_name, file_name, &path).await
+            })
+                .await
+                .map_err(|e| reading_from("GCS", e))?;
+            (FileGuard::Directory(directory), path, original_file_name(file_name), HashMap::new(), content_type.map(String::from))
+        }
+        Some(FileSources::S3) => {
+            let Some(file_name) = file_name else {
+                return Err(refuse("No file name in message data".to_string()));
+            };
+            // the bucket can be left out of the message, the configured bucket is then used
+            let (directory, path) = download(file_name, |path| async move {
+                download_object_from_s3(bucket_name.unwrap_or_default(), file_name, &path).await
+            })
+                .await
+                .map_err(|e| reading_from("S3", e))?;
+            (FileGuard::Directory(directory), path, original_file_name(file_name), HashMap::new(), content_type.map(String::from))
+        }
+        Some(FileSources::URL) => {
+            let Some(url) = message_data.get("url").and_then(|u| u.as_str()) else {
+                return Err(refuse("No url in message data".to_string()));
+            };
+            let url = Url::parse(url).map_err(|e| refuse(format!("Could not parse url {}: {}", url, e)))?;
+            let file_name = url
+                .path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .filter(|segment| !segment.is_empty())
+                .unwrap_or("index")
+                .to_string();
+            let mut fetched = None;
+            let (directory, path) = download(file_name.as_str(), |path| {
+                let fetched = &mut fetched;
+                let url = &url;
+                async move {
+                    let file = fetch_to_file(url, &path).await?;
+                    let bytes = file.bytes;
+                    *fetched = Some(file);
+                    Ok(bytes)
                 }
-            }
+            })
+                .await
+                .map_err(|e| reading_from("URL", e))?;
+            let Some(fetched) = fetched else {
+                return Err(anyhow!("An error occurred while reading file from URL: {}", url));
+            };
+            let metadata = HashMap::from([
+                ("source_url".to_string(), fetched.url.to_string()),
+                ("fetched_at".to_string(), fetched.fetched_at),
+            ]);
+            // a type given in the message still takes precedence over what the server says
+            let content_type = content_type.map(String::from).or(fetched.content_type);
+            (FileGuard::Directory(directory), path, file_name, metadata, content_type)
         }
-        None => {
-            None
+        Some(FileSources::LOCAL) => {
+            let Some(file_path) = message_data.get("file").and_then(|f| f.as_str()) else {
+                return Err(refuse("No file path in message data".to_string()));
+            };
+            let size = std::fs::metadata(file_path)
+                .ok()
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+                .ok_or_else(|| refuse(format!("An error occurred while reading file from DISK, {} is not a file", file_path)))?;
+            // the file is deleted once the message has been settled, as it has always been, even if it is refused
+            let guard = FileGuard::Local;
+            let max_bytes = GLOBAL_DATA.read().await.file_max_bytes;
+            if size > max_bytes {
+                return Err(refuse(format!("File of {} bytes is larger than the maximum of {} bytes", size, max_bytes)));
+            }
+            (guard, PathBuf::from(file_path), original_file_name(file_path), HashMap::new(), content_type.map(String::from))
         }
-    }
+        Some(FileSources::UNKNOWN) => return Err(refuse("Unknown file source in message type header".to_string())),
+        None => return Err(refuse("No file source in message headers".to_string())),
+    };
+    let file_type = determine_file_type(original_name.as_str(), &path, content_type.as_deref());
+    metadata.insert("file_name".to_string(), original_name);
+    Ok(SourceFile {
+        file_type,
+        path,
+        metadata,
+        _guard: guard,
+    })
 }
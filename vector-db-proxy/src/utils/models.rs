@@ -1,5 +1,8 @@
+#[allow(clippy::upper_case_acronyms)]
 pub enum FileSources {
     GCS,
+    S3,
+    URL,
     LOCAL,
     UNKNOWN,
 }
@@ -8,6 +11,8 @@ impl From<String> for FileSources {
     fn from(value: String) -> Self {
         match value.as_str() {
             "gcs" => FileSources::GCS,
+            "s3" => FileSources::S3,
+            "url" => FileSources::URL,
             "local" => FileSources::LOCAL,
             _ => FileSources::UNKNOWN
         }
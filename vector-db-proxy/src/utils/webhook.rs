@@ -28,3 +28,40 @@ pub async fn send_webapp_embed_ready(datasource_id: &str) -> Result<(), anyhow::
         Err(anyhow!("Failed to notify webapp. Status: {}", res.status()))
     }
 }
+
+///
+///
+/// # Arguments
+///
+/// * `datasource_id`: The datasource whose upload could not be embedded
+/// * `reason`: Why it failed, shown to the user in the webapp notification
+///
+/// returns: Result<(), Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn send_webapp_embed_failed(datasource_id: &str, reason: &str) -> Result<(), anyhow::Error> {
+    let global_data = GLOBAL_DATA.read().await;
+    let url = format!("http://{}:{}{}", global_data.webapp_host, global_data.webapp_port, "/webhook/embed-failed");
+
+    let body = json!({
+        "datasourceId": datasource_id,
+        "reason": reason
+    });
+
+    let client = Client::new();
+
+    let res = client.post(&url)
+        .json(&body)
+        .send()
+        .await?;
+
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow!("Failed to notify webapp. Status: {}", res.status()))
+    }
+}
@@ -191,3 +191,34 @@ export async function handleSuccessfulEmbeddingWebhook(req, res, next) {
 
 }
 
+
+export async function handleFailedEmbeddingWebhook(req, res, next) {
+	console.log('handleFailedEmbeddingWebhook body', req.body);
+
+	//TODO: validate some kind of webhook key
+
+	// TODO: body validation
+	const { datasourceId, reason } = req.body;
+
+	const datasource = await getDatasourceByIdUnsafe(datasourceId);
+	if (datasource) {
+		await addNotification({
+		    orgId: toObjectId(datasource.orgId.toString()),
+		    teamId: toObjectId(datasource.teamId.toString()),
+		    target: {
+				id: datasourceId,
+				collection: 'notifications',
+				property: '_id',
+				objectId: true,
+		    },
+		    title: 'Embedding Failed',
+		    description: `Embedding failed for datasource "${datasource.name}"${reason ? `: ${reason}` : '.'}`,
+		    date: new Date(),
+		    seen: false,
+		});
+		io.to(datasource.teamId.toString()).emit('notification', datasourceId);
+	}
+
+	return dynamicResponse(req, res, 200, { });
+
+}
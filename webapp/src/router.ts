@@ -215,6 +215,7 @@ export default function router(server, app) {
 	const webhookRouter = Router({ mergeParams: true, caseSensitive: true });
 	webhookRouter.use('/sync-successful', airbyteProxyController.handleSuccessfulSyncWebhook);
 	webhookRouter.use('/embed-successful', airbyteProxyController.handleSuccessfulEmbeddingWebhook); //TODO: move these to webhooks controller?
+	webhookRouter.use('/embed-failed', airbyteProxyController.handleFailedEmbeddingWebhook);
 	server.use('/webhook', webhookRouter);
 
 	server.use('/:resourceSlug([a-f0-9]{24})', authedMiddlewareChain, checkResourceSlug, setPermissions, teamRouter);